@@ -0,0 +1,126 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use pubky_messenger::{ConversationKey, DecryptedMessage, Keypair, PrivateMessage};
+
+fn crypto(c: &mut Criterion) {
+    let alice = Keypair::random();
+    let bob = Keypair::random();
+    let alice_pubky = alice.public_key();
+    let bob_pubky = bob.public_key();
+
+    c.bench_function("shared_secret_derivation", |b| {
+        b.iter(|| ConversationKey::derive(black_box(&alice), black_box(&bob_pubky)).unwrap())
+    });
+
+    let content = "The quick brown fox jumps over the lazy dog";
+    c.bench_function("encrypt", |b| {
+        b.iter(|| PrivateMessage::new(&alice, &bob_pubky, black_box(content)).unwrap())
+    });
+
+    let message = PrivateMessage::new(&alice, &bob_pubky, content).unwrap();
+    c.bench_function("decrypt", |b| {
+        b.iter(|| {
+            let content = message.decrypt_content(&bob, &alice_pubky).unwrap();
+            let sender = message.decrypt_sender(&bob, &alice_pubky).unwrap();
+            (content, sender)
+        })
+    });
+
+    let sender = alice_pubky.to_string();
+    c.bench_function("verify_signature", |b| {
+        b.iter(|| {
+            message
+                .verify_signature(black_box(content), &sender)
+                .unwrap()
+        })
+    });
+}
+
+/// Decrypt, verify, and sort a 1000-message conversation the way
+/// `get_messages` does once the records are fetched
+fn conversation_assembly(c: &mut Criterion) {
+    let alice = Keypair::random();
+    let bob = Keypair::random();
+    let alice_pubky = alice.public_key();
+    let bob_pubky = bob.public_key();
+
+    let records: Vec<(String, PrivateMessage)> = (0..1000u64)
+        .map(|i| {
+            let (from, to) = if i % 2 == 0 {
+                (&alice, &bob_pubky)
+            } else {
+                (&bob, &alice_pubky)
+            };
+            let message =
+                PrivateMessage::new_at(from, to, &format!("Message {}", i), 1_700_000_000 + i)
+                    .unwrap();
+            (PrivateMessage::generate_id(), message)
+        })
+        .collect();
+
+    c.bench_function("assemble_1k_messages", |b| {
+        b.iter(|| {
+            let mut messages: Vec<DecryptedMessage> = records
+                .iter()
+                .filter_map(|(id, message)| {
+                    let content = message.decrypt_content(&alice, &bob_pubky).ok()?;
+                    let sender = message.decrypt_sender(&alice, &bob_pubky).ok()?;
+                    let verified = message.verify_signature(&content, &sender).unwrap_or(false);
+                    Some(DecryptedMessage::new(
+                        id.clone(),
+                        sender,
+                        content,
+                        message.timestamp,
+                        verified,
+                    ))
+                })
+                .collect();
+            messages.sort_by(|a, b| a.timestamp.cmp(&b.timestamp));
+            messages
+        })
+    });
+
+    // Signature checks alone, one by one and as a single batch
+    let decrypted: Vec<(String, String)> = records
+        .iter()
+        .map(|(_, message)| {
+            (
+                message.decrypt_content(&alice, &bob_pubky).unwrap(),
+                message.decrypt_sender(&alice, &bob_pubky).unwrap(),
+            )
+        })
+        .collect();
+    let entries: Vec<(&PrivateMessage, &str, &str)> = records
+        .iter()
+        .zip(decrypted.iter())
+        .map(|((_, message), (content, sender))| (message, content.as_str(), sender.as_str()))
+        .collect();
+    c.bench_function("verify_1k_signatures", |b| {
+        b.iter(|| {
+            entries
+                .iter()
+                .map(|(message, content, sender)| {
+                    message.verify_signature(content, sender).unwrap_or(false)
+                })
+                .collect::<Vec<bool>>()
+        })
+    });
+    c.bench_function("verify_1k_signatures_batched", |b| {
+        b.iter(|| PrivateMessage::verify_batch(black_box(&entries)))
+    });
+
+    // The same with the conversation key derived once up front
+    let key = ConversationKey::derive(&alice, &bob_pubky).unwrap();
+    c.bench_function("assemble_1k_messages_cached_key", |b| {
+        b.iter(|| {
+            let mut messages: Vec<DecryptedMessage> = records
+                .iter()
+                .filter_map(|(id, message)| key.decrypt_message(id, message).ok())
+                .collect();
+            messages.sort_by(|a, b| a.timestamp.cmp(&b.timestamp));
+            messages
+        })
+    });
+}
+
+criterion_group!(benches, crypto, conversation_assembly);
+criterion_main!(benches);
@@ -61,10 +61,10 @@ async fn main() -> Result<()> {
 
         // Send a test message
         println!("\nSending test message...");
-        let message_id = client
+        let sent = client
             .send_message(&recipient, "Hello from pubky-private-messenger-lib!")
             .await?;
-        println!("Message sent with ID: {}", message_id);
+        println!("Message sent with ID: {}", sent.id);
 
         // Get conversation messages
         println!("\nFetching conversation messages...");
@@ -111,15 +111,15 @@ async fn main() -> Result<()> {
 
                 // Send message
                 match client.send_message(&peer, input).await {
-                    Ok(_message_id) => {
-                        // Create a local message to display immediately
-                        let timestamp = chrono::Utc::now().timestamp() as u64;
-                        let local_msg = DecryptedMessage {
-                            sender: client.public_key_string(),
-                            content: input.to_string(),
-                            timestamp,
-                            verified: true,
-                        };
+                    Ok(sent) => {
+                        // Display the stored message immediately
+                        let local_msg = DecryptedMessage::new(
+                            sent.id,
+                            client.public_key_string(),
+                            sent.content,
+                            sent.timestamp,
+                            true,
+                        );
 
                         // Update state
                         let msg_hash = local_msg.timestamp ^ local_msg.sender.bytes().fold(0u64, |acc, b| acc.rotate_left(7) ^ b as u64);
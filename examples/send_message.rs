@@ -38,13 +38,16 @@ async fn main() -> Result<()> {
     let recipient = PublicKey::try_from(recipient_pubky_str.as_str())?;
     println!("\nSending message to {}...", recipient_pubky_str);
 
-    let message_id = client.send_message(&recipient, message_content).await?;
+    let sent = client.send_message(&recipient, message_content).await?;
     println!("✓ Message sent successfully!");
-    println!("Message ID: {}", message_id);
-    println!("Content: {}", message_content);
+    println!("Message ID: {}", sent.id);
+    println!("Content: {}", sent.content);
+    println!("URL: {}", sent.url);
     println!(
         "Timestamp: {}",
-        chrono::Local::now().format("%Y-%m-%d %H:%M:%S")
+        chrono::DateTime::from_timestamp(sent.timestamp as i64, 0)
+            .map(|dt| dt.format("%Y-%m-%d %H:%M:%S").to_string())
+            .unwrap_or_else(|| "Unknown time".to_string())
     );
 
     Ok(())
@@ -0,0 +1,23 @@
+//! Decoding of decrypted content into a typed body, options, and mentions
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use pubky_messenger::{ContentPolicy, DecryptedMessage};
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(content) = std::str::from_utf8(data) else {
+        return;
+    };
+
+    let message = DecryptedMessage::new(
+        "fuzz".to_string(),
+        "sender".to_string(),
+        content.to_string(),
+        0,
+        false,
+    );
+    let _ = message.body.validate();
+
+    ContentPolicy::default().apply(content);
+});
@@ -0,0 +1,33 @@
+//! Decryption of arbitrary ciphertext under an arbitrary key
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use pubky_messenger::{CipherSuite, PrivateMessage};
+
+fuzz_target!(|data: &[u8]| {
+    if data.len() < 32 {
+        return;
+    }
+    let (key, ciphertext) = data.split_at(32);
+    let key: [u8; 32] = key.try_into().unwrap();
+
+    for cipher in [
+        CipherSuite::XSalsa20Poly1305,
+        CipherSuite::Aes256Gcm,
+        CipherSuite::XChaCha20Poly1305,
+    ] {
+        let message = PrivateMessage {
+            timestamp: 0,
+            encrypted_sender: ciphertext.to_vec(),
+            encrypted_content: ciphertext.to_vec(),
+            signature_bytes: Vec::new(),
+            wrapped_keys: Vec::new(),
+            cipher,
+            direction: None,
+        };
+
+        let _ = message.decrypt_content_with_key(&key);
+        let _ = message.decrypt_sender_with_key(&key);
+    }
+});
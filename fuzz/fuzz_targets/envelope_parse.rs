@@ -0,0 +1,32 @@
+//! A stored record as fetched from a peer's homeserver, run through the
+//! same steps as `get_messages`
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use pubky_messenger::{DecryptedMessage, Keypair, PrivateMessage};
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(message) = serde_json::from_slice::<PrivateMessage>(data) else {
+        return;
+    };
+
+    let receiver = Keypair::from_secret_key(&[1u8; 32]);
+    let sender = Keypair::from_secret_key(&[2u8; 32]).public_key();
+
+    let Ok(content) = message.decrypt_content(&receiver, &sender) else {
+        return;
+    };
+    let Ok(sender) = message.decrypt_sender(&receiver, &sender) else {
+        return;
+    };
+    let verified = message.verify_signature(&content, &sender).unwrap_or(false);
+
+    DecryptedMessage::new(
+        "fuzz".to_string(),
+        sender,
+        content,
+        message.timestamp,
+        verified,
+    );
+});
@@ -0,0 +1,35 @@
+//! Signature verification with an attacker-chosen sender, content, and
+//! signature
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use pubky_messenger::{CipherSuite, PrivateMessage};
+
+fuzz_target!(|data: &[u8]| {
+    // Input layout: timestamp (8 bytes), signature length (1 byte),
+    // signature, then `sender\ncontent`
+    if data.len() < 9 {
+        return;
+    }
+    let timestamp = u64::from_be_bytes(data[..8].try_into().unwrap());
+    let signature_len = usize::from(data[8]).min(data.len() - 9);
+    let (signature, rest) = data[9..].split_at(signature_len);
+
+    let Ok(rest) = std::str::from_utf8(rest) else {
+        return;
+    };
+    let (sender, content) = rest.split_once('\n').unwrap_or((rest, ""));
+
+    let message = PrivateMessage {
+        timestamp,
+        encrypted_sender: Vec::new(),
+        encrypted_content: Vec::new(),
+        signature_bytes: signature.to_vec(),
+        wrapped_keys: Vec::new(),
+        cipher: CipherSuite::default(),
+        direction: None,
+    };
+
+    let _ = message.verify_signature(content, sender);
+});
@@ -0,0 +1,52 @@
+use serde::{Deserialize, Serialize};
+
+/// Kind of action recorded in the audit log
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditAction {
+    SignIn,
+    Send,
+    Delete,
+    KeyRotation,
+    PolicyOverride,
+    ProfileUpdate,
+}
+
+impl AuditAction {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            Self::SignIn => "sign_in",
+            Self::Send => "send",
+            Self::Delete => "delete",
+            Self::KeyRotation => "key_rotation",
+            Self::PolicyOverride => "policy_override",
+            Self::ProfileUpdate => "profile_update",
+        }
+    }
+
+    pub(crate) fn parse(action: &str) -> Option<Self> {
+        match action {
+            "sign_in" => Some(Self::SignIn),
+            "send" => Some(Self::Send),
+            "delete" => Some(Self::Delete),
+            "key_rotation" => Some(Self::KeyRotation),
+            "policy_override" => Some(Self::PolicyOverride),
+            "profile_update" => Some(Self::ProfileUpdate),
+            _ => None,
+        }
+    }
+}
+
+/// An entry of the local audit log
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditEntry {
+    /// Position in the log, increasing with every entry
+    pub seq: i64,
+    /// Unix timestamp in seconds
+    pub timestamp: u64,
+    pub action: AuditAction,
+    /// The conversation peer the action concerned, if any
+    pub peer: Option<String>,
+    /// What the action did, e.g. the affected message IDs
+    pub detail: String,
+}
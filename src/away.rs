@@ -0,0 +1,181 @@
+use anyhow::{anyhow, Result};
+use pkarr::Keypair;
+use serde::{Deserialize, Serialize};
+use ulid::Ulid;
+use zeroize::Zeroizing;
+
+use crate::message::TIMESTAMP_MS_DIGITS;
+
+/// Key derivation context for the IDs of automatic replies
+const AWAY_REPLY_ID_CONTEXT: &str = "pubky-messenger away reply id v1";
+
+/// Largest away message text, in bytes
+pub const MAX_AWAY_MESSAGE_BYTES: usize = 4096;
+
+/// Metadata key marking automatic replies, so they are never answered
+/// automatically in turn
+pub const AUTO_REPLY_METADATA_KEY: &str = "auto_reply";
+
+const MINUTES_PER_DAY: u16 = 24 * 60;
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// When an away message is in effect
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum AwaySchedule {
+    /// From when the away message is set until it is cleared
+    Always,
+    /// Once, between two Unix times in seconds
+    Between { starts_at: u64, ends_at: u64 },
+    /// Every day, between two times given in minutes after midnight UTC
+    ///
+    /// A window ending before it starts runs past midnight, so `22:00` to
+    /// `07:00` is `Daily { start_minute: 1320, end_minute: 420 }`.
+    Daily { start_minute: u16, end_minute: u16 },
+}
+
+impl AwaySchedule {
+    /// Check that the schedule describes a non-empty window
+    pub fn validate(&self) -> Result<()> {
+        match *self {
+            Self::Always => Ok(()),
+            Self::Between { starts_at, ends_at } if starts_at >= ends_at => {
+                Err(anyhow!("Away schedules must end after they start"))
+            }
+            Self::Between { .. } => Ok(()),
+            Self::Daily {
+                start_minute,
+                end_minute,
+            } => {
+                if start_minute >= MINUTES_PER_DAY || end_minute >= MINUTES_PER_DAY {
+                    return Err(anyhow!(
+                        "Daily away times must be below {} minutes",
+                        MINUTES_PER_DAY
+                    ));
+                }
+                if start_minute == end_minute {
+                    return Err(anyhow!("Daily away windows must not be empty"));
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Start of the away period `timestamp` falls in, if any
+    ///
+    /// A period is one occurrence of the schedule: the whole window for
+    /// `Between`, each day's window for `Daily`, and for `Always` the time
+    /// the away message was set (`since`).
+    pub fn period_start(&self, timestamp: u64, since: u64) -> Option<u64> {
+        match *self {
+            Self::Always => (timestamp >= since).then_some(since),
+            Self::Between { starts_at, ends_at } => {
+                (starts_at <= timestamp && timestamp < ends_at).then_some(starts_at)
+            }
+            Self::Daily {
+                start_minute,
+                end_minute,
+            } => {
+                let midnight = timestamp - timestamp % SECONDS_PER_DAY;
+                let minute = ((timestamp % SECONDS_PER_DAY) / 60) as u16;
+                let start = u64::from(start_minute) * 60;
+                if start_minute < end_minute {
+                    (start_minute <= minute && minute < end_minute).then_some(midnight + start)
+                } else if minute >= start_minute {
+                    Some(midnight + start)
+                } else if minute < end_minute {
+                    // Window that started before midnight
+                    (midnight + start).checked_sub(SECONDS_PER_DAY)
+                } else {
+                    None
+                }
+            }
+        }
+    }
+}
+
+/// An automatic reply sent while we're away
+///
+/// Set with `PrivateMessengerClient::set_away_message`. While a local store
+/// is configured and the schedule is in effect, `SyncService` answers the
+/// first message each sender writes in an away period, once.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AwayMessage {
+    pub text: String,
+    pub schedule: AwaySchedule,
+}
+
+impl AwayMessage {
+    /// An away message in effect until it is cleared
+    pub fn new(text: impl Into<String>) -> Self {
+        Self {
+            text: text.into(),
+            schedule: AwaySchedule::Always,
+        }
+    }
+
+    /// Only reply while the schedule is in effect
+    pub fn with_schedule(mut self, schedule: AwaySchedule) -> Self {
+        self.schedule = schedule;
+        self
+    }
+
+    /// Check that the text and schedule are within limits
+    pub fn validate(&self) -> Result<()> {
+        if self.text.trim().is_empty() || self.text.len() > MAX_AWAY_MESSAGE_BYTES {
+            return Err(anyhow!(
+                "Away message text must be 1 to {} bytes",
+                MAX_AWAY_MESSAGE_BYTES
+            ));
+        }
+        self.schedule.validate()
+    }
+}
+
+/// The away message, or its removal, and when it was last changed
+///
+/// Cleared away messages are kept as `None` so that the removal reaches
+/// other devices.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AwaySetting {
+    pub message: Option<AwayMessage>,
+    /// Unix time in seconds of the change
+    pub updated_at: u64,
+}
+
+impl AwaySetting {
+    /// Whether this change wins over `other`, ordered like
+    /// `TrustSetting::supersedes`
+    ///
+    /// Changes made in the same second are ordered by their serialized
+    /// message, so every device settles on the same one.
+    pub fn supersedes(&self, other: &AwaySetting) -> bool {
+        let message = |setting: &AwaySetting| serde_json::to_string(&setting.message).ok();
+        (self.updated_at, message(self)) > (other.updated_at, message(other))
+    }
+}
+
+/// ID of our automatic reply to `sender` in the away period starting at
+/// `period_start`
+///
+/// Every device of the identity derives the same ID, so devices answering
+/// the same sender write the same record rather than replying twice. It
+/// looks like any other message ID, timestamped at the start of the period.
+pub(crate) fn away_reply_id(keypair: &Keypair, sender: &str, period_start: u64) -> String {
+    let secret = Zeroizing::new(keypair.secret_key());
+    let mut hasher = blake3::Hasher::new_derive_key(AWAY_REPLY_ID_CONTEXT);
+    hasher.update(&secret[..]);
+    hasher.update(sender.as_bytes());
+    hasher.update(&period_start.to_be_bytes());
+    let hash = hasher.finalize();
+
+    let mut random = [0u8; 16];
+    random[6..].copy_from_slice(&hash.as_bytes()[..10]);
+    let timestamp_ms = period_start.saturating_mul(1000);
+    format!(
+        "{:0width$}-{}",
+        timestamp_ms,
+        Ulid::from_parts(timestamp_ms, u128::from_be_bytes(random)),
+        width = TIMESTAMP_MS_DIGITS
+    )
+}
@@ -0,0 +1,129 @@
+use anyhow::Result;
+use pkarr::{Keypair, PublicKey};
+use tokio::runtime::Runtime;
+
+use crate::client::{FollowedUser, PrivateMessengerClient, PubkyProfile};
+use crate::message::{DecryptedMessage, SentMessage};
+use bip39::Language;
+
+/// Synchronous wrapper around `PrivateMessengerClient`
+///
+/// Owns a tokio runtime and blocks on each call, for CLI tools, non-async
+/// code, and FFI layers. Do not use it from inside an async context: blocking
+/// on a runtime from within another runtime panics.
+pub struct BlockingMessengerClient {
+    runtime: Runtime,
+    client: PrivateMessengerClient,
+}
+
+impl BlockingMessengerClient {
+    /// Create a new client from a keypair
+    pub fn new(keypair: Keypair) -> Result<Self> {
+        Self::build(|| PrivateMessengerClient::new(keypair))
+    }
+
+    /// Create a new client from a recovery file
+    pub fn from_recovery_file(
+        recovery_file_bytes: &[u8],
+        passphrase: Option<&str>,
+    ) -> Result<Self> {
+        Self::build(|| PrivateMessengerClient::from_recovery_file(recovery_file_bytes, passphrase))
+    }
+
+    /// Create a new client from a BIP39 recovery phrase
+    pub fn from_recovery_phrase(
+        mnemonic_phrase: &str,
+        passphrase: Option<&str>,
+        language: Option<Language>,
+    ) -> Result<Self> {
+        Self::build(|| {
+            PrivateMessengerClient::from_recovery_phrase(mnemonic_phrase, passphrase, language)
+        })
+    }
+
+    /// Create the runtime, then the async client inside it
+    fn build<F>(make_client: F) -> Result<Self>
+    where
+        F: FnOnce() -> Result<PrivateMessengerClient>,
+    {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()?;
+        let client = {
+            let _guard = runtime.enter();
+            make_client()?
+        };
+
+        Ok(Self { runtime, client })
+    }
+
+    /// The wrapped async client, for its synchronous methods
+    pub fn client(&self) -> &PrivateMessengerClient {
+        &self.client
+    }
+
+    /// Sign in to the homeserver
+    pub fn sign_in(&self) -> Result<pubky_common::session::Session> {
+        self.runtime.block_on(self.client.sign_in())
+    }
+
+    /// Send an encrypted message
+    pub fn send_message(&self, recipient: &PublicKey, content: &str) -> Result<SentMessage> {
+        self.runtime
+            .block_on(self.client.send_message(recipient, content))
+    }
+
+    /// Send an encrypted message using a caller-supplied message ID
+    pub fn send_message_with_id(
+        &self,
+        recipient: &PublicKey,
+        content: &str,
+        msg_id: &str,
+    ) -> Result<SentMessage> {
+        self.runtime
+            .block_on(self.client.send_message_with_id(recipient, content, msg_id))
+    }
+
+    /// Get all messages in a conversation
+    pub fn get_messages(&self, other_pubky: &PublicKey) -> Result<Vec<DecryptedMessage>> {
+        self.runtime.block_on(self.client.get_messages(other_pubky))
+    }
+
+    /// Delete a single message by its ID from a conversation
+    pub fn delete_message(&self, message_id: &str, other_pubky: &PublicKey) -> Result<()> {
+        self.runtime
+            .block_on(self.client.delete_message(message_id, other_pubky))
+    }
+
+    /// Delete multiple messages from a conversation
+    pub fn delete_messages(&self, message_ids: Vec<String>, other_pubky: &PublicKey) -> Result<()> {
+        self.runtime
+            .block_on(self.client.delete_messages(message_ids, other_pubky))
+    }
+
+    /// Delete all messages we sent in a conversation
+    pub fn clear_messages(&self, other_pubky: &PublicKey) -> Result<()> {
+        self.runtime
+            .block_on(self.client.clear_messages(other_pubky))
+    }
+
+    /// Get the user's own profile
+    pub fn get_own_profile(&self) -> Result<Option<PubkyProfile>> {
+        self.runtime.block_on(self.client.get_own_profile())
+    }
+
+    /// Get the users we follow
+    pub fn get_followed_users(&self) -> Result<Vec<FollowedUser>> {
+        self.runtime.block_on(self.client.get_followed_users())
+    }
+
+    /// Get our public key
+    pub fn public_key(&self) -> PublicKey {
+        self.client.public_key()
+    }
+
+    /// Get our public key as a string
+    pub fn public_key_string(&self) -> String {
+        self.client.public_key_string()
+    }
+}
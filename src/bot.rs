@@ -0,0 +1,308 @@
+//! Scaffolding for bots and auto-responders
+//!
+//! A [`Bot`] keeps a set of conversations in sync with a [`SyncService`] and
+//! hands every new message from a peer to the handler registered for its
+//! command prefix or content kind. Handlers answer through the
+//! [`BotContext`] they are given.
+//!
+//! ```no_run
+//! use pubky_messenger::bot::{Bot, BotContext};
+//! # use std::sync::Arc;
+//! # async fn example(
+//! #     client: Arc<pubky_messenger::PrivateMessengerClient>,
+//! #     peer: pkarr::PublicKey,
+//! # ) -> anyhow::Result<()> {
+//! let handle = Bot::new(client)
+//!     .with_peer(peer)
+//!     .on_command("/ping", |ctx: BotContext| async move {
+//!         ctx.reply("pong").await?;
+//!         Ok(())
+//!     })
+//!     .spawn();
+//! # Ok(())
+//! # }
+//! ```
+
+use anyhow::Result;
+use futures::future::{BoxFuture, FutureExt};
+use pkarr::PublicKey;
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::sync::Arc;
+use tokio::time::{Duration, Instant};
+
+use crate::client::PrivateMessengerClient;
+use crate::content::{Command, MessageContent};
+use crate::message::{DecryptedMessage, SentMessage};
+use crate::sync::{PollingConfig, SyncHandle, SyncReport, SyncService};
+
+/// A registered handler, boxed so handlers of any type can share a table
+type Handler = Arc<dyn Fn(BotContext) -> BoxFuture<'static, Result<()>> + Send + Sync>;
+
+/// Box an async handler
+fn boxed<F, Fut>(handler: F) -> Handler
+where
+    F: Fn(BotContext) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<()>> + Send + 'static,
+{
+    Arc::new(move |ctx| handler(ctx).boxed())
+}
+
+/// A message handed to a bot handler, with helpers to answer it
+#[derive(Clone)]
+pub struct BotContext {
+    client: Arc<PrivateMessengerClient>,
+    peer: PublicKey,
+    message: DecryptedMessage,
+    args: Option<String>,
+}
+
+impl BotContext {
+    /// The client the bot runs on
+    pub fn client(&self) -> &Arc<PrivateMessengerClient> {
+        &self.client
+    }
+
+    /// The conversation the message arrived in
+    pub fn peer(&self) -> &PublicKey {
+        &self.peer
+    }
+
+    /// The message being handled
+    pub fn message(&self) -> &DecryptedMessage {
+        &self.message
+    }
+
+    /// For command handlers, the text after the command prefix with
+    /// surrounding whitespace trimmed
+    pub fn args(&self) -> Option<&str> {
+        self.args.as_deref()
+    }
+
+    /// The handled message as a command with typed arguments: structured
+    /// command content as sent, or text parsed with `Command::parse`
+    pub fn command(&self) -> Option<Command> {
+        match &self.message.body {
+            MessageContent::Command(command) => Some(command.clone()),
+            MessageContent::Text { text } => Command::parse(text),
+            _ => None,
+        }
+    }
+
+    /// Answer with text, as a reply to the handled message
+    pub async fn reply(&self, text: impl Into<String>) -> Result<SentMessage> {
+        self.reply_content(MessageContent::text(text)).await
+    }
+
+    /// Answer with any content, as a reply to the handled message
+    pub async fn reply_content(&self, content: MessageContent) -> Result<SentMessage> {
+        self.client
+            .message(&self.peer)
+            .content(content)
+            .reply_to(self.message.id.clone())
+            .send()
+            .await
+    }
+
+    /// Answer with a typed JSON payload, see
+    /// `PrivateMessengerClient::send_json`
+    pub async fn reply_json<T: Serialize>(&self, value: &T, type_tag: &str) -> Result<SentMessage> {
+        self.reply_content(MessageContent::Json {
+            type_tag: type_tag.to_string(),
+            value: serde_json::to_value(value)?,
+        })
+        .await
+    }
+}
+
+/// Most messages a sender may have handled within a window of time
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct RateLimit {
+    max_messages: usize,
+    window: Duration,
+}
+
+/// A service bot: routes new messages to handlers and keeps polling
+///
+/// Messages are routed by command prefix first, for text messages starting
+/// with a registered prefix followed by whitespace or nothing (the longest
+/// matching prefix wins) and for `Command` content, as rendered by
+/// `Command::to_text`, then by content kind (see `MessageContent::kind`),
+/// then to the fallback handler. Our own messages, unverified ones, and
+/// quarantined ones are never handled. Like `SyncService`, the bot only
+/// sees messages that arrive after it starts. A handler that fails does not
+/// stop the bot; its error is collected in the round's report.
+pub struct Bot {
+    client: Arc<PrivateMessengerClient>,
+    sync: SyncService,
+    commands: Vec<(String, Handler)>,
+    content: HashMap<&'static str, Handler>,
+    fallback: Option<Handler>,
+    rate_limit: Option<RateLimit>,
+    handled: HashMap<String, VecDeque<Instant>>,
+}
+
+impl Bot {
+    /// Create a bot running on a client
+    pub fn new(client: Arc<PrivateMessengerClient>) -> Self {
+        Self {
+            sync: SyncService::new(client.clone()),
+            client,
+            commands: Vec::new(),
+            content: HashMap::new(),
+            fallback: None,
+            rate_limit: None,
+            handled: HashMap::new(),
+        }
+    }
+
+    /// Add a conversation the bot answers in
+    pub fn with_peer(mut self, peer: PublicKey) -> Self {
+        self.sync = self.sync.with_peer(peer);
+        self
+    }
+
+    /// Poll conversations as configured, see
+    /// `SyncService::with_adaptive_polling`
+    pub fn with_polling(mut self, polling: PollingConfig) -> Self {
+        self.sync = self.sync.with_adaptive_polling(polling);
+        self
+    }
+
+    /// Handle at most `max_messages` messages per sender within `window`
+    ///
+    /// Messages beyond the limit are dropped without being handled.
+    pub fn with_rate_limit(mut self, max_messages: usize, window: Duration) -> Self {
+        self.rate_limit = Some(RateLimit {
+            max_messages,
+            window,
+        });
+        self
+    }
+
+    /// Handle text messages starting with `prefix`, such as `/help`
+    pub fn on_command<F, Fut>(mut self, prefix: &str, handler: F) -> Self
+    where
+        F: Fn(BotContext) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        self.commands.push((prefix.to_string(), boxed(handler)));
+        self.commands
+            .sort_by(|a, b| b.0.len().cmp(&a.0.len()).then_with(|| a.0.cmp(&b.0)));
+        self
+    }
+
+    /// Handle messages of a content kind, such as `"poll"` or
+    /// `"payment_request"`
+    pub fn on_content<F, Fut>(mut self, kind: &'static str, handler: F) -> Self
+    where
+        F: Fn(BotContext) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        self.content.insert(kind, boxed(handler));
+        self
+    }
+
+    /// Handle every message no other handler took
+    pub fn on_other<F, Fut>(mut self, handler: F) -> Self
+    where
+        F: Fn(BotContext) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        self.fallback = Some(boxed(handler));
+        self
+    }
+
+    /// Run a single round: sync the conversations and handle what arrived
+    pub async fn poll_once(&mut self) -> SyncReport {
+        let mut report = self.sync.sync_once().await;
+        let own_pubky = self.client.public_key_string();
+
+        for (peer, message) in report.new_messages.iter() {
+            if message.sender == own_pubky || !message.verified || message.quarantined {
+                continue;
+            }
+            let Some((handler, args)) = self.route(message) else {
+                continue;
+            };
+            if !self.allow(&message.sender) {
+                continue;
+            }
+
+            let ctx = BotContext {
+                client: self.client.clone(),
+                peer: peer.clone(),
+                message: message.clone(),
+                args,
+            };
+            if let Err(e) = handler(ctx).await {
+                report.errors.push(e);
+            }
+        }
+
+        report
+    }
+
+    /// Run the bot in a background task until the handle is stopped
+    ///
+    /// Errors of each round, handler errors included, are passed to the
+    /// handle (see `SyncHandle`).
+    pub fn spawn(mut self) -> SyncHandle {
+        let (errors, receiver) = SyncHandle::error_channel();
+        let task = tokio::spawn(async move {
+            loop {
+                let report = self.poll_once().await;
+                SyncHandle::forward_errors(&errors, report);
+                self.sync.wait_for_round().await;
+            }
+        });
+
+        SyncHandle::new(task, receiver)
+    }
+
+    /// The handler of a message, with the arguments of a command
+    fn route(&self, message: &DecryptedMessage) -> Option<(Handler, Option<String>)> {
+        let text = match &message.body {
+            MessageContent::Text { text } => Some(text.trim_start().to_string()),
+            MessageContent::Command(command) => Some(command.to_text()),
+            _ => None,
+        };
+        if let Some(text) = text {
+            for (prefix, handler) in self.commands.iter() {
+                let Some(rest) = text.strip_prefix(prefix.as_str()) else {
+                    continue;
+                };
+                if rest.is_empty() || rest.starts_with(char::is_whitespace) {
+                    return Some((handler.clone(), Some(rest.trim().to_string())));
+                }
+            }
+        }
+
+        self.content
+            .get(message.body.kind())
+            .or(self.fallback.as_ref())
+            .map(|handler| (handler.clone(), None))
+    }
+
+    /// Count a message from `sender` against the rate limit, if it's within
+    fn allow(&mut self, sender: &str) -> bool {
+        let Some(limit) = self.rate_limit else {
+            return true;
+        };
+
+        let now = Instant::now();
+        let handled = self.handled.entry(sender.to_string()).or_default();
+        while handled
+            .front()
+            .is_some_and(|at| now.duration_since(*at) >= limit.window)
+        {
+            handled.pop_front();
+        }
+        if handled.len() >= limit.max_messages {
+            return false;
+        }
+        handled.push_back(now);
+        true
+    }
+}
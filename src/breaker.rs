@@ -0,0 +1,139 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::error::MessengerError;
+
+/// When to stop sending requests to a failing homeserver
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CircuitBreakerConfig {
+    /// Consecutive failures after which requests to the host are paused
+    pub failure_threshold: u32,
+    /// How long requests stay paused before one is let through again
+    pub cooldown: Duration,
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        Self {
+            failure_threshold: 3,
+            cooldown: Duration::from_secs(30),
+        }
+    }
+}
+
+/// How long a resolved homeserver keys the circuit of its owner's URLs
+/// before it is resolved again
+const HOMESERVER_TTL: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Default)]
+struct HostState {
+    consecutive_failures: u32,
+    open_until: Option<Instant>,
+    /// When the single request let through after the cool-down was sent
+    probe_started: Option<Instant>,
+}
+
+/// Per-homeserver circuit breaker
+///
+/// Circuits are keyed by the homeserver serving a URL, so identities hosted
+/// on the same homeserver share one; callers resolve it and cache it with
+/// `remember_homeserver`. After `failure_threshold` consecutive failures
+/// the circuit opens and requests fail immediately with
+/// `MessengerError::PeerUnreachable`. Once the cool-down has passed a single
+/// request is let through while the others keep failing fast; another
+/// failure reopens the circuit, a success closes it. A probe that never
+/// reports back is replaced after another cool-down.
+pub(crate) struct CircuitBreaker {
+    config: CircuitBreakerConfig,
+    hosts: Mutex<HashMap<String, HostState>>,
+    homeservers: Mutex<HashMap<String, (String, Instant)>>,
+}
+
+impl CircuitBreaker {
+    pub fn new(config: CircuitBreakerConfig) -> Self {
+        Self {
+            config,
+            hosts: Mutex::new(HashMap::new()),
+            homeservers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The homeserver of `owner`, if it was resolved recently
+    pub fn homeserver_of(&self, owner: &str) -> Option<String> {
+        let homeservers = self.homeservers.lock().unwrap_or_else(|e| e.into_inner());
+        homeservers
+            .get(owner)
+            .filter(|(_, resolved_at)| resolved_at.elapsed() < HOMESERVER_TTL)
+            .map(|(homeserver, _)| homeserver.clone())
+    }
+
+    /// Cache the homeserver `owner` resolved to
+    pub fn remember_homeserver(&self, owner: &str, homeserver: &str) {
+        let mut homeservers = self.homeservers.lock().unwrap_or_else(|e| e.into_inner());
+        homeservers.insert(owner.to_string(), (homeserver.to_string(), Instant::now()));
+    }
+
+    /// Fail fast if the circuit of `host` is open or being probed
+    ///
+    /// `peer` is who the error names.
+    pub fn check(&self, host: &str, peer: &str) -> Result<()> {
+        self.enter(host, peer, false)
+    }
+
+    /// Like `check`, but once the cool-down has passed the first caller gets
+    /// through as the probe
+    pub fn admit(&self, host: &str, peer: &str) -> Result<()> {
+        self.enter(host, peer, true)
+    }
+
+    fn enter(&self, host: &str, peer: &str, probe: bool) -> Result<()> {
+        let mut hosts = self.hosts.lock().unwrap_or_else(|e| e.into_inner());
+        let Some(state) = hosts.get_mut(host) else {
+            return Ok(());
+        };
+        let Some(open_until) = state.open_until else {
+            return Ok(());
+        };
+
+        let now = Instant::now();
+        let retry_at = if open_until > now {
+            open_until
+        } else {
+            match state.probe_started {
+                // Another request is already probing the homeserver
+                Some(started) if started + self.config.cooldown > now => {
+                    started + self.config.cooldown
+                }
+                _ if probe => {
+                    state.probe_started = Some(now);
+                    return Ok(());
+                }
+                _ => return Ok(()),
+            }
+        };
+
+        Err(MessengerError::PeerUnreachable {
+            peer: peer.to_string(),
+            retry_after_secs: retry_at.saturating_duration_since(now).as_secs().max(1),
+        }
+        .into())
+    }
+
+    pub fn record_success(&self, host: &str) {
+        let mut hosts = self.hosts.lock().unwrap_or_else(|e| e.into_inner());
+        hosts.remove(host);
+    }
+
+    pub fn record_failure(&self, host: &str) {
+        let mut hosts = self.hosts.lock().unwrap_or_else(|e| e.into_inner());
+        let state = hosts.entry(host.to_string()).or_default();
+        state.consecutive_failures += 1;
+        state.probe_started = None;
+        if state.consecutive_failures >= self.config.failure_threshold {
+            state.open_until = Some(Instant::now() + self.config.cooldown);
+        }
+    }
+}
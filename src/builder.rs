@@ -0,0 +1,183 @@
+use anyhow::{anyhow, Result};
+use pkarr::PublicKey;
+use std::collections::BTreeMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::client::PrivateMessengerClient;
+use crate::content::{Mention, MessageContent, MessageOptions};
+use crate::message::{PrivateMessage, SentMessage};
+
+/// Builder for an outgoing message, returned by `PrivateMessengerClient::message`
+///
+/// ```no_run
+/// # async fn example(
+/// #     client: pubky_messenger::PrivateMessengerClient,
+/// #     peer: pkarr::PublicKey,
+/// #     previous_id: &str,
+/// # ) -> anyhow::Result<()> {
+/// client
+///     .message(&peer)
+///     .text("See you there")
+///     .reply_to(previous_id)
+///     .expires_in(7)
+///     .padding(true)
+///     .send()
+///     .await?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct MessageBuilder<'a> {
+    client: &'a PrivateMessengerClient,
+    recipient: PublicKey,
+    content: Option<MessageContent>,
+    id: Option<String>,
+    reply_to: Option<String>,
+    expires_in_days: Option<u64>,
+    padding: bool,
+    metadata: BTreeMap<String, String>,
+    mentions: Vec<PublicKey>,
+    language: Option<String>,
+}
+
+impl<'a> MessageBuilder<'a> {
+    pub(crate) fn new(client: &'a PrivateMessengerClient, recipient: &PublicKey) -> Self {
+        Self {
+            client,
+            recipient: recipient.clone(),
+            content: None,
+            id: None,
+            reply_to: None,
+            expires_in_days: None,
+            padding: false,
+            metadata: BTreeMap::new(),
+            mentions: Vec::new(),
+            language: None,
+        }
+    }
+
+    /// Send plain text
+    pub fn text(mut self, text: impl Into<String>) -> Self {
+        self.content = Some(MessageContent::text(text));
+        self
+    }
+
+    /// Send typed content
+    pub fn content(mut self, content: MessageContent) -> Self {
+        self.content = Some(content);
+        self
+    }
+
+    /// Use a caller-supplied message ID, as in `send_message_with_id`
+    pub fn id(mut self, msg_id: impl Into<String>) -> Self {
+        self.id = Some(msg_id.into());
+        self
+    }
+
+    /// Mark the message as a reply to an earlier message
+    pub fn reply_to(mut self, msg_id: impl Into<String>) -> Self {
+        self.reply_to = Some(msg_id.into());
+        self
+    }
+
+    /// Hide the message from fetches after the given number of days
+    ///
+    /// Expiry is enforced by the receiving clients; the record stays on the
+    /// homeserver until it is deleted.
+    pub fn expires_in(mut self, days: u64) -> Self {
+        self.expires_in_days = Some(days);
+        self
+    }
+
+    /// Pad the encrypted content to a multiple of 256 bytes, so its size
+    /// reveals less about the message
+    pub fn padding(mut self, padding: bool) -> Self {
+        self.padding = padding;
+        self
+    }
+
+    /// Attach an application-defined key-value pair
+    ///
+    /// Metadata is encrypted with the message and exposed as
+    /// `DecryptedMessage::metadata`. At most `MAX_METADATA_ENTRIES` entries
+    /// of `MAX_METADATA_BYTES` combined are allowed.
+    pub fn metadata(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.metadata.insert(key.into(), value.into());
+        self
+    }
+
+    /// Mention a user referenced as `@pubky` in the text
+    ///
+    /// Every occurrence of the reference is recorded as a mention span.
+    pub fn mention(mut self, pubky: &PublicKey) -> Self {
+        self.mentions.push(pubky.clone());
+        self
+    }
+
+    /// Tag the text with its BCP-47 language, such as `en` or `pt-BR`
+    ///
+    /// The tag is encrypted with the message and exposed as
+    /// `DecryptedMessage::language`, so receivers can offer translation or
+    /// pick suitable fonts.
+    pub fn language(mut self, tag: impl Into<String>) -> Self {
+        self.language = Some(tag.into());
+        self
+    }
+
+    /// Validate, encrypt, and send the message
+    pub async fn send(self) -> Result<SentMessage> {
+        let content = self
+            .content
+            .ok_or_else(|| anyhow!("Message has no content"))?;
+        content.validate()?;
+
+        if let Some(reply_to) = self.reply_to.as_deref() {
+            PrivateMessage::validate_id(reply_to)?;
+        }
+
+        let mut options = MessageOptions {
+            reply_to: self.reply_to,
+            // Without a lifetime of its own, the conversation's is added when
+            // the message is sent
+            expires_at: self.expires_in_days.map(|days| {
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap()
+                    .as_secs();
+                now.saturating_add(days.saturating_mul(24 * 60 * 60))
+            }),
+            padding: None,
+            metadata: self.metadata,
+            mentions: Vec::new(),
+            seq: None,
+            sent_with: None,
+            language: self.language,
+        };
+
+        for pubky in self.mentions.iter() {
+            let MessageContent::Text { text } = &content else {
+                return Err(anyhow!("Only text messages can mention users"));
+            };
+            let spans = Mention::find(text, pubky);
+            if spans.is_empty() {
+                return Err(anyhow!(
+                    "Mentioned user @{} does not appear in the text",
+                    pubky
+                ));
+            }
+            options.mentions.extend(spans);
+        }
+        options.mentions.sort_by_key(|mention| mention.start);
+        options.validate()?;
+
+        if self.padding {
+            options.pad(&content)?;
+        }
+
+        let encoded = content.encode_with(&options)?;
+        let msg_id = self.id.unwrap_or_else(|| self.client.new_message_id());
+
+        self.client
+            .send_message_with_id(&self.recipient, &encoded, &msg_id)
+            .await
+    }
+}
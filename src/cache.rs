@@ -0,0 +1,123 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::message::DecryptedMessage;
+
+/// Size and lifetime of the in-memory cache of decrypted messages
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DecryptionCacheConfig {
+    /// Maximum number of cached messages; 0 disables the cache
+    pub capacity: usize,
+    /// How long a cached message is reused before it is decrypted again
+    pub ttl: Duration,
+}
+
+impl Default for DecryptionCacheConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 1024,
+            ttl: Duration::from_secs(600),
+        }
+    }
+}
+
+struct CacheEntry {
+    message: DecryptedMessage,
+    inserted_at: Instant,
+    last_used: u64,
+}
+
+#[derive(Default)]
+struct CacheState {
+    entries: HashMap<(String, [u8; 32]), CacheEntry>,
+    clock: u64,
+}
+
+/// Least-recently-used cache of decrypted messages
+///
+/// Keyed by record URL and a hash of the stored ciphertext, so a record that
+/// was overwritten in place is decrypted again.
+pub(crate) struct DecryptionCache {
+    config: DecryptionCacheConfig,
+    state: Mutex<CacheState>,
+}
+
+impl DecryptionCache {
+    pub fn new(config: DecryptionCacheConfig) -> Self {
+        Self {
+            config,
+            state: Mutex::new(CacheState::default()),
+        }
+    }
+
+    pub fn get(&self, url: &str, record: &str) -> Option<DecryptedMessage> {
+        if self.config.capacity == 0 {
+            return None;
+        }
+
+        let key = cache_key(url, record);
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.clock += 1;
+        let clock = state.clock;
+
+        match state.entries.get_mut(&key) {
+            Some(entry) if entry.inserted_at.elapsed() < self.config.ttl => {
+                entry.last_used = clock;
+                Some(entry.message.clone())
+            }
+            Some(_) => {
+                state.entries.remove(&key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    pub fn insert(&self, url: &str, record: &str, message: &DecryptedMessage) {
+        if self.config.capacity == 0 {
+            return;
+        }
+
+        let key = cache_key(url, record);
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.clock += 1;
+        let clock = state.clock;
+
+        if !state.entries.contains_key(&key) && state.entries.len() >= self.config.capacity {
+            let oldest = state
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                state.entries.remove(&oldest);
+            }
+        }
+
+        state.entries.insert(
+            key,
+            CacheEntry {
+                message: message.clone(),
+                inserted_at: Instant::now(),
+                last_used: clock,
+            },
+        );
+    }
+
+    /// Drop every cached version of the record at `url`
+    pub fn remove_url(&self, url: &str) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.entries.retain(|(entry_url, _), _| entry_url != url);
+    }
+
+    pub fn clear(&self) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.entries.clear();
+    }
+}
+
+fn cache_key(url: &str, record: &str) -> (String, [u8; 32]) {
+    (url.to_string(), *blake3::hash(record.as_bytes()).as_bytes())
+}
@@ -1,12 +1,149 @@
 use anyhow::{anyhow, Result};
 use bip39::{Language, Mnemonic};
-use futures::future::join_all;
+use ed25519_dalek::Signature;
+use futures::future::{self, join_all};
+use futures::stream::{self, BoxStream, StreamExt, TryStreamExt};
 use pkarr::{Keypair, PublicKey};
+use pubky_common::crypto::{decrypt, encrypt};
 use pubky_common::recovery_file;
+use rand::distributions::{Alphanumeric, DistString};
+use rand::Rng;
+use rayon::prelude::*;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::{broadcast, Notify};
+use zeroize::Zeroizing;
 
-use crate::crypto::generate_conversation_path;
-use crate::message::{DecryptedMessage, PrivateMessage};
+use crate::audit::{AuditAction, AuditEntry};
+use crate::away::{away_reply_id, AwayMessage, AwaySetting, AUTO_REPLY_METADATA_KEY};
+use crate::breaker::{CircuitBreaker, CircuitBreakerConfig};
+use crate::builder::MessageBuilder;
+use crate::cache::{DecryptionCache, DecryptionCacheConfig};
+use crate::cipher::CipherSuite;
+use crate::connectivity::{ConnectivityMonitor, ConnectivityState};
+use crate::contacts::Contact;
+use crate::content::{
+    Command, FetchMarker, MessageContent, MessageEdit, PaymentProof, PaymentRequest, Poll,
+    PollResults, PollVote, Sticker, StickerSource, SystemMessage, MAX_STICKER_BYTES,
+};
+use crate::conversation::{Conversation, ConversationId};
+use crate::crypto::{
+    self, decode_key_hex, device_settings_key, device_settings_path, encode_key_hex,
+    epoch_conversation_path, hash_matches_hex, static_conversation_path, sticker_key_and_path,
+    ConversationKey, KeyDirection, PathRotation, SharedSecretCache, MIN_EPOCH_SECONDS,
+};
+use crate::delivery::DeliveryState;
+use crate::diagnostics::{DiagnosticCheck, DiagnosticsReport, HealthStatus};
+use crate::error::{Homeserver, MessengerError};
+use crate::events::{ConversationEvent, KeyChange, NotificationEvent, EVENT_CHANNEL_CAPACITY};
+use crate::filters::{MuteFilter, MuteRule};
+use crate::identity::IdentityBundle;
+use crate::message::{DecryptedMessage, PrivateMessage, SentMessage, TIMESTAMP_MS_DIGITS};
+use crate::middleware::MessageMiddleware;
+use crate::recovery::{self, RecoveryKdfParams};
+use crate::rotation::KeyRotationProof;
+use crate::sanitize::ContentPolicy;
+use crate::search::{search_key, search_path, search_token, search_words, MAX_SEARCH_TOKENS};
+use crate::settings::{DeviceSettings, LabelSetting, MuteSetting, TrustSetting};
+use crate::snapshot::{ConversationSnapshot, SnapshotDiff};
+use crate::spam::{SenderActivity, SpamFilter, SpamVerdict};
+use crate::stats::{ClientStats, StatsRecorder};
+use crate::store::{
+    ContactPin, LocalStore, MessageRequest, QueuedEvent, RequestStatus, StoredMessage, SyncEntry,
+    TrashedMessage,
+};
+use crate::sync::SyncDelta;
+use crate::templates::{template_file, templates_key, templates_path, ReplyTemplate};
+use crate::transcript::{TranscriptHash, TRANSCRIPT_GRACE_SECS};
+use crate::trust::TrustLevel;
+use crate::uri::{self, PubkyUri};
+use crate::usage::{ConversationUsage, StorageUsage};
+
+/// Number of requests sent in parallel by batched operations
+const BATCH_SIZE: usize = 5;
+
+/// Most rotation epochs `delete_messages_between` lists one by one before
+/// listing our whole messenger directory instead
+const MAX_LISTED_EPOCHS: u64 = 32;
+
+/// Delay between batches to avoid rate limiting
+const BATCH_DELAY_MS: u64 = 200;
+
+/// Delay before retrying a rate-limited request
+const RATE_LIMIT_RETRY_MS: u64 = 1000;
+
+/// Attempts at uploading a message before the send fails
+const SEND_ATTEMPTS: u32 = 3;
+
+/// Number of the newest messages decrypted ahead by `warm_conversation`
+const WARM_MESSAGES: usize = 50;
+
+/// Scratch file written and deleted by the diagnostics read/write probe
+const DIAGNOSTICS_PROBE_PATH: &str = "/pub/pubky-messenger/diagnostics-probe";
+
+/// Empty file overwritten by every health check
+const HEALTH_PROBE_PATH: &str = "/pub/pubky-messenger/health-probe";
+
+/// Path of the detached signature over our profile JSON
+const PROFILE_SIGNATURE_PATH: &str = "/pub/pubky-messenger/profile.json.sig";
+
+/// Path of the proof that we rotated to a new keypair
+const KEY_ROTATION_PATH: &str = "/pub/pubky-messenger/key-rotation.json";
+
+/// Bytes `pubky_common::crypto::encrypt` adds to a sticker image: a 24-byte
+/// nonce and a 16-byte tag
+const STICKER_BLOB_OVERHEAD: usize = 24 + 16;
+
+/// Conversation setting holding the lifetime of new messages in days
+const EXPIRY_SETTING: &str = "expires_in_days";
+
+/// Conversation setting holding the cipher suite of new messages
+const CIPHER_SUITE_SETTING: &str = "cipher_suite";
+
+/// Conversation setting marking a conversation read-only on this side
+const READ_ONLY_SETTING: &str = "read_only";
+
+/// Range of the random padding length of cover messages, so their size
+/// resembles short text messages
+const COVER_PADDING_MIN: usize = 16;
+const COVER_PADDING_MAX: usize = 256;
+
+/// How far back `sync_conversation` re-fetches known records to catch edits
+const SYNC_EDIT_WINDOW_SECS: u64 = 24 * 60 * 60;
+
+/// How often `sync_conversation` lists a whole conversation, rather than
+/// only from its cursor, to catch deletions of older records
+const SYNC_FULL_LISTING_SECS: u64 = 60 * 60;
+
+/// Windows over which sender activity is counted for spam filters
+const ACTIVITY_HOUR_SECS: u64 = 60 * 60;
+const ACTIVITY_DAY_SECS: u64 = 24 * 60 * 60;
+
+/// How far in the future a follow's `created_at` may be before it is flagged
+const FOLLOW_CLOCK_SKEW_SECS: u64 = 5 * 60;
+
+/// Number of entries requested per homeserver listing page
+const LIST_PAGE_SIZE: u16 = 100;
+
+/// Conversations summarized at once by `list_conversations`
+const CONVERSATION_LIST_CONCURRENCY: usize = 8;
+
+/// Newest records looked at for the last message of a conversation summary
+const SUMMARY_LOOKBACK: usize = 5;
+
+/// Longest application name or version accepted by `with_app_identity`
+const MAX_APP_IDENTITY_CHARS: usize = 64;
+
+/// Follows requested per page from a Nexus indexer
+const NEXUS_PAGE_SIZE: usize = 100;
+
+/// Most follows read from a Nexus indexer, so a misbehaving indexer can't
+/// page forever
+const MAX_NEXUS_FOLLOWS: usize = 10_000;
 
 /// Profile information from Pubky
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -15,6 +152,10 @@ pub struct PubkyProfile {
     pub bio: Option<String>,
     pub image: Option<String>,
     pub status: Option<String>,
+    /// Whether the profile JSON carries a valid signature by its owner, so a
+    /// homeserver cannot have altered it. Not part of the stored profile.
+    #[serde(default, skip_serializing)]
+    pub profile_verified: bool,
 }
 
 /// A user that is being followed
@@ -22,12 +163,171 @@ pub struct PubkyProfile {
 pub struct FollowedUser {
     pub name: Option<String>,
     pub pubky: String,
+    /// Whether `name` comes from a signed, verified profile
+    #[serde(default)]
+    pub profile_verified: bool,
+    /// Problems found with the follow record, when follow verification is
+    /// enabled (see `PrivateMessengerClient::with_follow_verification`)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub anomalies: Vec<FollowAnomaly>,
+}
+
+/// A problem with a follow record that suggests it was not written by the
+/// user whose follows are being read
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum FollowAnomaly {
+    /// The listing returned a URL outside the user's own follows directory
+    ForeignUrl,
+    /// The user has no homeserver published in their pkarr record, so the
+    /// storage that served the follows can't be attributed to them
+    UnresolvedHomeserver,
+    /// The record is missing or doesn't match the follow schema
+    InvalidRecord,
+    /// The record claims to have been created in the future
+    FutureTimestamp,
+}
+
+impl FollowAnomaly {
+    /// Problems with a follow record listed in `owner`'s follows directory,
+    /// given its URL and its contents if it could be fetched
+    ///
+    /// `now` is the current Unix time in seconds. `UnresolvedHomeserver`
+    /// depends on the owner's pkarr record and is not checked here.
+    pub fn of_record(owner: &str, follow_url: &str, record: Option<&str>, now: u64) -> Vec<Self> {
+        if !follow_in_directory(owner, follow_url) {
+            return vec![Self::ForeignUrl];
+        }
+
+        let created_at = record
+            .and_then(|record| serde_json::from_str::<serde_json::Value>(record).ok())
+            .and_then(|value| value.get("created_at")?.as_u64());
+        match created_at {
+            None => vec![Self::InvalidRecord],
+            Some(created_at) if created_at > now + FOLLOW_CLOCK_SKEW_SECS => {
+                vec![Self::FutureTimestamp]
+            }
+            Some(_) => Vec::new(),
+        }
+    }
+}
+
+/// A difference between the follows on our homeserver and those seen by a
+/// Nexus indexer
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum FollowDivergence {
+    /// The indexer has a follow that our homeserver's listing doesn't
+    /// surface, although the record itself can be fetched
+    Unlisted { pubky: String },
+    /// The indexer has a follow whose record our homeserver doesn't have
+    MissingFromHomeserver { pubky: String },
+    /// Our homeserver has a follow the indexer hasn't seen
+    MissingFromIndexer { pubky: String },
+}
+
+/// Result of reconciling our follows with a Nexus indexer
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct FollowSyncReport {
+    /// Every follow whose record is on our homeserver, listed or not, sorted
+    pub follows: Vec<String>,
+    /// Where the homeserver and the indexer disagree
+    pub divergences: Vec<FollowDivergence>,
+}
+
+impl FollowSyncReport {
+    /// Whether the homeserver and the indexer agree on every follow
+    pub fn is_in_sync(&self) -> bool {
+        self.divergences.is_empty()
+    }
+}
+
+/// Progress of a bulk message import
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct ImportProgress {
+    /// Number of messages in the import
+    pub total: usize,
+    /// Messages uploaded during this run
+    pub uploaded: usize,
+    /// Messages skipped because an earlier run already uploaded them
+    pub skipped: usize,
+}
+
+/// A stored message found in a homeserver listing, not yet fetched
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct MessageEntry {
+    /// Message ID, taken from the file name
+    pub id: String,
+    /// URL of the encrypted message
+    pub url: String,
+}
+
+/// A conversation as shown in a chat list, see
+/// `PrivateMessengerClient::list_conversations`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConversationSummary {
+    pub peer: String,
+    pub conversation_id: ConversationId,
+    /// Set for the conversation with our own pubky, which UIs show as Saved
+    /// Messages, without a sender on each message
+    pub saved_messages: bool,
+    /// The conversation's labels, in order, see
+    /// `PrivateMessengerClient::set_conversation_labels`
+    pub labels: Vec<String>,
+    /// The newest message on either side, if any of the newest few records
+    /// is a readable message rather than an edit, cover traffic, or expired
+    pub last_message: Option<DecryptedMessage>,
+}
+
+/// Paging options for `PrivateMessengerClient::list_message_page`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ListOptions {
+    /// Maximum number of entries to return; every entry if unset
+    pub limit: Option<usize>,
+    /// ID of the last entry of the previous page, to continue after it
+    pub cursor: Option<String>,
+    /// List newest first instead of oldest first
+    pub reverse: bool,
 }
 
 /// Main client for private messaging
 pub struct PrivateMessengerClient {
     client: pubky::Client,
     keypair: Keypair,
+    store: Option<LocalStore>,
+    rotation: Option<PathRotation>,
+    events: broadcast::Sender<NotificationEvent>,
+    timeline: broadcast::Sender<(ConversationId, ConversationEvent)>,
+    receipts: Mutex<HashMap<String, u64>>,
+    last_sends: Mutex<HashMap<String, Instant>>,
+    empty_listings: Mutex<HashSet<(String, String)>>,
+    full_listings: Mutex<HashMap<String, u64>>,
+    sent: Notify,
+    audit: bool,
+    breaker: CircuitBreaker,
+    stats: StatsRecorder,
+    connectivity: ConnectivityMonitor,
+    cache: DecryptionCache,
+    secrets: SharedSecretCache,
+    content_policy: ContentPolicy,
+    fetch_markers: Option<Mutex<HashMap<String, u64>>>,
+    seal_envelopes: bool,
+    cipher_suite: CipherSuite,
+    direction_keys: bool,
+    sequence_numbers: bool,
+    search_tokens: bool,
+    coarse_timestamps: Option<Duration>,
+    verify_follows: bool,
+    nexus_url: Option<String>,
+    message_requests: bool,
+    event_queue: bool,
+    spam_filter: Option<Arc<dyn SpamFilter>>,
+    middleware: Vec<Arc<dyn MessageMiddleware>>,
+    storage_quota: Option<u64>,
+    trash_window: Option<Duration>,
+    user_agent: String,
+    app_identity: Option<String>,
+    embed_app_identity: bool,
 }
 
 impl PrivateMessengerClient {
@@ -37,7 +337,548 @@ impl PrivateMessengerClient {
             .build()
             .map_err(|e| anyhow!("Failed to create pubky client: {}", e))?;
 
-        Ok(Self { client, keypair })
+        Ok(Self::from_pubky_client(keypair, client))
+    }
+
+    /// Create a new client from a keypair and a configured pubky client
+    ///
+    /// Useful to point the messenger at a different network, such as a local
+    /// testnet.
+    pub fn from_pubky_client(keypair: Keypair, client: pubky::Client) -> Self {
+        let (events, _) = broadcast::channel(EVENT_CHANNEL_CAPACITY);
+        let (timeline, _) = broadcast::channel(EVENT_CHANNEL_CAPACITY);
+
+        Self {
+            client,
+            keypair,
+            store: None,
+            rotation: None,
+            events,
+            timeline,
+            receipts: Mutex::new(HashMap::new()),
+            last_sends: Mutex::new(HashMap::new()),
+            empty_listings: Mutex::new(HashSet::new()),
+            full_listings: Mutex::new(HashMap::new()),
+            sent: Notify::new(),
+            audit: false,
+            breaker: CircuitBreaker::new(CircuitBreakerConfig::default()),
+            stats: StatsRecorder::default(),
+            connectivity: ConnectivityMonitor::default(),
+            cache: DecryptionCache::new(DecryptionCacheConfig::default()),
+            secrets: SharedSecretCache::default(),
+            content_policy: ContentPolicy::default(),
+            fetch_markers: None,
+            seal_envelopes: false,
+            cipher_suite: CipherSuite::default(),
+            direction_keys: false,
+            sequence_numbers: false,
+            search_tokens: false,
+            coarse_timestamps: None,
+            verify_follows: false,
+            nexus_url: None,
+            message_requests: false,
+            event_queue: false,
+            spam_filter: None,
+            middleware: Vec::new(),
+            storage_quota: None,
+            trash_window: None,
+            user_agent: default_user_agent(),
+            app_identity: None,
+            embed_app_identity: false,
+        }
+    }
+
+    /// Subscribe to notification events raised by this client
+    pub fn subscribe(&self) -> broadcast::Receiver<NotificationEvent> {
+        self.events.subscribe()
+    }
+
+    /// Raise an event; it is dropped if nobody is subscribed
+    fn emit(&self, event: NotificationEvent) {
+        let _ = self.events.send(event);
+    }
+
+    /// Record a delivery state change of one of our messages and tell
+    /// subscribers
+    ///
+    /// Best effort, like the audit log: sending doesn't fail because the
+    /// state couldn't be saved.
+    fn track_delivery(
+        &self,
+        recipient: &PublicKey,
+        message_id: &str,
+        sent_at: u64,
+        state: DeliveryState,
+    ) {
+        let peer = recipient.to_string();
+        if let Some(store) = self.store.as_ref().filter(|store| !store.is_locked()) {
+            // Stale updates, e.g. from an earlier attempt, are ignored
+            let changed = store.set_delivery_state(&peer, message_id, state, sent_at, unix_now());
+            if matches!(changed, Ok(false)) {
+                return;
+            }
+        }
+        self.emit(NotificationEvent::DeliveryStateChanged {
+            peer,
+            message_id: message_id.to_string(),
+            state,
+        });
+    }
+
+    /// Delivery state of one of our messages
+    ///
+    /// For a message sent to several recipients, this is the state of the
+    /// recipient it got least far with. Returns `None` for messages that
+    /// weren't sent from this device. Requires a local store.
+    pub fn message_state(&self, message_id: &str) -> Result<Option<DeliveryState>> {
+        let states = self.store()?.delivery_states(message_id)?;
+        if states.contains(&DeliveryState::Failed) {
+            return Ok(Some(DeliveryState::Failed));
+        }
+        Ok(states.into_iter().min())
+    }
+
+    /// Subscribe to the timeline of the conversation with a peer
+    ///
+    /// Yields every message, edit, deletion, receipt, and membership change
+    /// found by `sync_conversation`, in the order they were synced. Events
+    /// raised before subscribing are not replayed.
+    pub fn subscribe_conversation(
+        &self,
+        other_pubky: &PublicKey,
+    ) -> BoxStream<'static, ConversationEvent> {
+        let id = self.conversation_id(other_pubky);
+        stream::unfold(self.timeline.subscribe(), move |mut receiver| {
+            let id = id.clone();
+            async move {
+                loop {
+                    match receiver.recv().await {
+                        Ok((event_id, event)) if event_id == id => return Some((event, receiver)),
+                        Ok(_) => continue,
+                        Err(broadcast::error::RecvError::Lagged(missed)) => {
+                            return Some((ConversationEvent::Lagged { missed }, receiver))
+                        }
+                        Err(broadcast::error::RecvError::Closed) => return None,
+                    }
+                }
+            }
+        })
+        .boxed()
+    }
+
+    /// Add an event to a conversation's timeline
+    fn emit_timeline(&self, id: &ConversationId, event: ConversationEvent) {
+        let _ = self.timeline.send((id.clone(), event));
+    }
+
+    /// Store new messages under conversation paths that rotate over time
+    ///
+    /// See `PathRotation`. Rotation only affects where this client writes and
+    /// which extra paths it reads, so it works with peers that don't rotate;
+    /// both sides need it for either side's traffic to be rotated.
+    pub fn with_path_rotation(mut self, rotation: PathRotation) -> Result<Self> {
+        if rotation.epoch_seconds < MIN_EPOCH_SECONDS {
+            return Err(anyhow!(
+                "Path rotation epochs must be at least {} seconds",
+                MIN_EPOCH_SECONDS
+            ));
+        }
+
+        self.rotation = Some(rotation);
+        Ok(self)
+    }
+
+    /// Configure when requests to a failing homeserver are paused
+    ///
+    /// The breaker is always on; by default it opens after 3 consecutive
+    /// failures and stays open for 30 seconds. While open, fetching from that
+    /// homeserver fails immediately with `MessengerError::PeerUnreachable`.
+    pub fn with_circuit_breaker(mut self, config: CircuitBreakerConfig) -> Self {
+        self.breaker = CircuitBreaker::new(config);
+        self
+    }
+
+    /// Configure the in-memory cache of decrypted messages
+    ///
+    /// Fetching a conversation reuses cached results for records whose
+    /// ciphertext hasn't changed, instead of repeating key agreement,
+    /// decryption, and signature checks on every poll. Defaults to 1024
+    /// messages kept for 10 minutes; a capacity of 0 disables the cache.
+    pub fn with_decryption_cache(mut self, config: DecryptionCacheConfig) -> Self {
+        self.cache = DecryptionCache::new(config);
+        self
+    }
+
+    /// Set how the text of received messages is sanitized
+    ///
+    /// By default text is normalized to NFC, stripped of control and
+    /// bidirectional override characters, and truncated at 64 KiB. Use
+    /// `ContentPolicy::disabled()` to receive text untouched.
+    pub fn with_content_policy(mut self, policy: ContentPolicy) -> Self {
+        self.content_policy = policy;
+        self
+    }
+
+    /// Leave a "fetched up to" marker for peers after fetching a conversation
+    ///
+    /// After each fetch that returns newer messages from the peer, a small
+    /// encrypted record with the timestamp of the newest one is written to our
+    /// side of the conversation, so the peer can tell with `peer_last_fetched`
+    /// whether their messages were pulled at all. Off by default.
+    pub fn with_fetch_markers(mut self) -> Self {
+        self.fetch_markers = Some(Mutex::new(HashMap::new()));
+        self
+    }
+
+    /// Store new records as sealed envelopes
+    ///
+    /// The JSON envelope of a message carries its timestamp and signature in
+    /// plaintext, which lets the homeserver time activity precisely and link
+    /// signatures. Sealed envelopes are encrypted once more under the
+    /// conversation key. Sealed and unsealed records are both read
+    /// transparently; enable this once peers run a version that reads them.
+    pub fn with_sealed_envelopes(mut self) -> Self {
+        self.seal_envelopes = true;
+        self
+    }
+
+    /// Encrypt new messages under a sending key of our own
+    ///
+    /// Both sending keys of a conversation are derived from the conversation
+    /// key, one per direction, so the same text sent by both participants
+    /// never produces related ciphertexts. The envelope names the key used,
+    /// and messages of both kinds are always read; enable this once peers
+    /// run a version that reads them.
+    pub fn with_per_direction_keys(mut self) -> Self {
+        self.direction_keys = true;
+        self
+    }
+
+    /// Encrypt new messages with `suite` unless a conversation picked its
+    /// own, see `set_cipher_suite`
+    ///
+    /// The suite is named in every envelope, so messages are read with the
+    /// suite they were written with regardless of this setting. Peers running
+    /// a version without the suite cannot read the messages.
+    pub fn with_cipher_suite(mut self, suite: CipherSuite) -> Self {
+        self.cipher_suite = suite;
+        self
+    }
+
+    /// Round the timing visible to the homeserver to buckets of `bucket`
+    ///
+    /// Message IDs, which appear in listings, only reveal the bucket a
+    /// message was sent in. The precise timestamp stays inside the signed
+    /// envelope, which this also seals (see `with_sealed_envelopes`), so
+    /// conversations still sort precisely. The homeserver can still observe
+    /// when records are written.
+    pub fn with_coarse_timestamps(mut self, bucket: Duration) -> Result<Self> {
+        if bucket.is_zero() {
+            return Err(anyhow!("Timestamp bucket must not be zero"));
+        }
+
+        self.coarse_timestamps = Some(bucket);
+        self.seal_envelopes = true;
+        Ok(self)
+    }
+
+    /// Check follow records when reading follows
+    ///
+    /// Follows returned by `get_followed_users` and `get_followed_users_for`
+    /// get their `anomalies` filled in: URLs outside the user's follows
+    /// directory, users without a published homeserver, records that don't
+    /// match the follow schema, and creation times in the future. Anomalous
+    /// follows are flagged rather than dropped, so applications decide how to
+    /// treat them. This costs one extra request per follow.
+    pub fn with_follow_verification(mut self) -> Self {
+        self.verify_follows = true;
+        self
+    }
+
+    /// Use a Nexus indexer as the authoritative view of our follows
+    ///
+    /// `url` is the base URL of the indexer's API, such as
+    /// `https://nexus.pubky.app`. See `sync_follows_from_nexus`.
+    pub fn with_nexus(mut self, url: &str) -> Result<Self> {
+        let parsed =
+            reqwest::Url::parse(url).map_err(|e| anyhow!("Invalid Nexus URL {}: {}", url, e))?;
+        if !matches!(parsed.scheme(), "https" | "http") {
+            return Err(anyhow!("Nexus URL must be http or https: {}", url));
+        }
+
+        self.nexus_url = Some(url.trim_end_matches('/').to_string());
+        Ok(self)
+    }
+
+    /// Snapshot of request latencies and bytes transferred so far
+    ///
+    /// Covers message sends, fetches, listings, and deletes. Rising latency
+    /// percentiles or error counts point at a degrading homeserver.
+    pub fn stats(&self) -> ClientStats {
+        self.stats.snapshot()
+    }
+
+    /// How well the client currently reaches homeservers
+    ///
+    /// Driven by the outcomes of requests: a few failures in a row make the
+    /// client `Degraded`, then `Offline`; a single success brings it back
+    /// `Online`. Changes are raised as `NotificationEvent::ConnectivityChanged`,
+    /// so apps can queue messages locally while offline rather than keep
+    /// sending into failures.
+    pub fn connectivity(&self) -> ConnectivityState {
+        self.connectivity.state()
+    }
+
+    /// Record a request in the statistics and the connectivity state
+    fn record_request(&self, operation: &str, elapsed: Duration, ok: bool) {
+        self.stats.record(operation, elapsed, ok);
+        self.record_connectivity(ok);
+    }
+
+    fn record_connectivity(&self, ok: bool) {
+        if let Some(state) = self.connectivity.record(ok) {
+            self.emit(NotificationEvent::ConnectivityChanged { state });
+        }
+    }
+
+    /// Clear the collected statistics
+    pub fn reset_stats(&self) {
+        self.stats.reset();
+    }
+
+    /// Enable the local store at the given path
+    ///
+    /// Fetched messages are then kept on this device, which enables local-only
+    /// features such as starred messages. Nothing in the store is published.
+    pub fn with_local_store(mut self, path: impl AsRef<Path>) -> Result<Self> {
+        self.store = Some(LocalStore::open(path)?);
+        Ok(self)
+    }
+
+    /// Enable a local store encrypted under a key derived from `pin`
+    ///
+    /// The PIN is independent of the identity key, so a copy of the store
+    /// does not expose history even to someone holding the recovery file.
+    /// The store starts unlocked; see `lock` and `unlock`.
+    pub fn with_encrypted_local_store(mut self, path: impl AsRef<Path>, pin: &str) -> Result<Self> {
+        self.store = Some(LocalStore::open_encrypted(path, pin)?);
+        Ok(self)
+    }
+
+    /// Record sign-ins, sends, deletes, and overrides in an append-only
+    /// audit log kept in the local store
+    ///
+    /// Needs the local store. Entries are only written while the store is
+    /// unlocked, and a failure to write one never fails the action itself.
+    pub fn with_audit_log(mut self) -> Result<Self> {
+        self.store()?;
+        self.audit = true;
+        Ok(self)
+    }
+
+    /// Hold messages from strangers as message requests
+    ///
+    /// A conversation we never wrote to is a request until it is accepted:
+    /// its messages are marked `message_request` and the sender is listed by
+    /// `list_message_requests`. Once declined, the sender's messages are
+    /// dropped when fetching. Needs the local store.
+    pub fn with_message_requests(mut self) -> Result<Self> {
+        self.store()?;
+        self.message_requests = true;
+        Ok(self)
+    }
+
+    /// Number our messages in each conversation so peers can spot gaps
+    ///
+    /// Every message sent to a conversation carries the next number of our
+    /// sequence inside its encrypted payload; a message resent under the
+    /// same ID keeps its number. Fetching a conversation sets `seq`,
+    /// `missing_before`, and `out_of_order` on numbered messages, revealing
+    /// messages a homeserver dropped, withheld, or replayed. A send that
+    /// fails gives its number back, unless a later message was numbered in
+    /// the meantime. Messages we delete leave gaps too, which the peer can't
+    /// tell from withheld ones. Broadcasts and imported history are not
+    /// numbered. Numbers are kept per device, so an identity sending from
+    /// several devices shows up as out of order. Only verified messages are
+    /// checked. Needs the local store.
+    pub fn with_sequence_numbers(mut self) -> Result<Self> {
+        self.store()?;
+        self.sequence_numbers = true;
+        Ok(self)
+    }
+
+    /// Identify the embedding application to homeserver operators
+    ///
+    /// Every homeserver request then carries a `User-Agent` of
+    /// `name/version pubky-messenger/<crate version>` instead of just the
+    /// crate's, which helps operators and developers tell apps apart when
+    /// triaging issues. Name and version may only hold ASCII letters, digits,
+    /// `.`, `-`, and `_`.
+    pub fn with_app_identity(mut self, name: &str, version: &str) -> Result<Self> {
+        for (field, value) in [("name", name), ("version", version)] {
+            let valid = !value.is_empty()
+                && value.len() <= MAX_APP_IDENTITY_CHARS
+                && value
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_'));
+            if !valid {
+                return Err(anyhow!(
+                    "App {} must be 1 to {} ASCII letters, digits, '.', '-', or '_'",
+                    field,
+                    MAX_APP_IDENTITY_CHARS
+                ));
+            }
+        }
+
+        let identity = format!("{}/{}", name, version);
+        self.user_agent = format!("{} {}", identity, default_user_agent());
+        self.app_identity = Some(identity);
+        Ok(self)
+    }
+
+    /// Also embed the app identity in the encrypted payload of our messages
+    ///
+    /// For debugging between consenting peers: the recipient sees which app
+    /// and version sent each message as `DecryptedMessage::sent_with`. The
+    /// homeserver doesn't, as the identity is encrypted with the content.
+    /// Needs `with_app_identity` first.
+    pub fn with_app_identity_in_messages(mut self) -> Result<Self> {
+        if self.app_identity.is_none() {
+            return Err(anyhow!("No app identity set, see with_app_identity"));
+        }
+        self.embed_app_identity = true;
+        Ok(self)
+    }
+
+    /// The `User-Agent` sent with every homeserver request
+    pub fn user_agent(&self) -> &str {
+        &self.user_agent
+    }
+
+    /// Publish keyed search tokens beside every text message we send
+    ///
+    /// For clients without a local store: each distinct word of a sent text
+    /// message, up to `MAX_SEARCH_TOKENS` of them, is turned into a token
+    /// with a key derived from the conversation key, and an empty record is
+    /// written under it, so that `search_remote` can find candidate messages
+    /// by keyword. The homeserver never sees the words, but it does see how
+    /// many each message has and which messages share one, which leaks
+    /// enough for frequency analysis over a long conversation. Messages whose
+    /// tokens fail to upload are sent anyway and stay unsearchable. Deleting
+    /// messages deletes their tokens too. Off by default.
+    pub fn with_search_tokens(mut self) -> Self {
+        self.search_tokens = true;
+        self
+    }
+
+    /// Keep timeline events in the local store until they are acknowledged
+    ///
+    /// Every event `sync_conversation` raises on a conversation timeline is
+    /// also queued, so applications whose consumers fall behind or are
+    /// paused, like a backgrounded UI, don't lose any: read them with
+    /// `pending_events` and drop them with `ack_events` once handled.
+    /// Events are delivered at least once. Needs the local store.
+    pub fn with_event_queue(mut self) -> Result<Self> {
+        self.store()?;
+        self.event_queue = true;
+        Ok(self)
+    }
+
+    /// Hold deleted messages in the trash for `window` before deleting them
+    /// from the homeserver
+    ///
+    /// `delete_message`, `delete_messages`, `clear_messages`, and
+    /// `delete_messages_between` then only move our messages to the trash
+    /// in the local store: they disappear from our fetches at once, but stay
+    /// on the homeserver, encrypted as before, until the window passes, so
+    /// `undo_delete` can bring them back. The peer keeps seeing them until
+    /// then, and `sync_conversation` reports them deleted once they are
+    /// gone. `purge_trash` deletes expired messages remotely; `SyncService`
+    /// calls it every round. Needs the local store.
+    pub fn with_trash(mut self, window: Duration) -> Result<Self> {
+        self.store()?;
+        self.trash_window = Some(window);
+        Ok(self)
+    }
+
+    /// Compare `storage_usage` against a storage quota, in bytes
+    ///
+    /// Homeservers don't publish their quotas, so pass the one yours
+    /// enforces. `storage_usage` raises `NotificationEvent::StorageNearLimit`
+    /// once 90% of it is used.
+    pub fn with_storage_quota(mut self, bytes: u64) -> Self {
+        self.storage_quota = Some(bytes);
+        self
+    }
+
+    /// Classify inbound messages with a spam filter
+    ///
+    /// Every message from the peer is passed to the filter when a
+    /// conversation is fetched or synced. Blocked messages are dropped, and
+    /// messages it routes to requests are marked `message_request` and, with
+    /// a local store, listed by `list_message_requests`.
+    pub fn with_spam_filter(mut self, filter: Arc<dyn SpamFilter>) -> Self {
+        self.spam_filter = Some(filter);
+        self
+    }
+
+    /// Add a middleware to the chain that transforms message content
+    ///
+    /// Outgoing content runs through the chain in the order middleware was
+    /// added, right before it is encrypted; fetched content runs through it
+    /// in reverse, right after it is decrypted. Messages we keep locally
+    /// hold the content as we wrote it. See `MessageMiddleware`.
+    pub fn with_middleware(mut self, middleware: Arc<dyn MessageMiddleware>) -> Self {
+        self.middleware.push(middleware);
+        self
+    }
+
+    /// All audit log entries, oldest first
+    pub fn audit_log(&self) -> Result<Vec<AuditEntry>> {
+        self.store()?.audit_entries()
+    }
+
+    /// Export the audit log as JSON Lines, one entry per line
+    pub fn export_audit_log(&self) -> Result<String> {
+        let mut out = String::new();
+        for entry in self.audit_log()? {
+            out.push_str(&serde_json::to_string(&entry)?);
+            out.push('\n');
+        }
+        Ok(out)
+    }
+
+    /// Append an entry to the audit log, if enabled
+    fn audit(&self, action: AuditAction, peer: Option<&PublicKey>, detail: impl Into<String>) {
+        if !self.audit {
+            return;
+        }
+        if let Some(store) = self.store.as_ref().filter(|store| !store.is_locked()) {
+            let peer = peer.map(|peer| peer.to_string());
+            let _ = store.append_audit(unix_now(), action, peer.as_deref(), &detail.into());
+        }
+    }
+
+    /// Lock the encrypted local store
+    ///
+    /// While locked, fetched messages are not saved and local-only features
+    /// return an error.
+    pub fn lock(&self) -> Result<()> {
+        self.cache.clear();
+        self.store()?.lock()
+    }
+
+    /// Unlock the encrypted local store with its PIN
+    pub fn unlock(&self, pin: &str) -> Result<()> {
+        self.store()?.unlock(pin)
+    }
+
+    /// Whether the local store is enabled but locked
+    pub fn is_locked(&self) -> bool {
+        self.store
+            .as_ref()
+            .map(|store| store.is_locked())
+            .unwrap_or(false)
     }
 
     /// Create a new client from a recovery file
@@ -52,12 +893,38 @@ impl PrivateMessengerClient {
         // Use provided passphrase or default to empty string
         let pass = passphrase.unwrap_or("");
 
-        let keypair = recovery_file::decrypt_recovery_file(recovery_file_bytes, pass)
-            .map_err(|e| anyhow!("Failed to decrypt recovery file: {:?}", e))?;
+        let keypair = recovery::decrypt_recovery_file(recovery_file_bytes, pass)?;
 
         Self::new(keypair)
     }
 
+    /// Export the identity as a pubky-compatible recovery file
+    ///
+    /// # Parameters
+    /// - `passphrase`: Optional passphrase to encrypt the file (defaults to empty string)
+    pub fn export_recovery_file(&self, passphrase: Option<&str>) -> Result<Vec<u8>> {
+        let pass = passphrase.unwrap_or("");
+        Ok(recovery_file::create_recovery_file(&self.keypair, pass))
+    }
+
+    /// Export the identity as a recovery file with custom KDF work factors
+    ///
+    /// Files created this way record their parameters and can be opened with
+    /// `from_recovery_file`, but not by apps that only understand the
+    /// pubky-common format.
+    ///
+    /// # Parameters
+    /// - `passphrase`: Optional passphrase to encrypt the file (defaults to empty string)
+    /// - `params`: Argon2id work factors, e.g. `RecoveryKdfParams::hardened()`
+    pub fn export_recovery_file_with_params(
+        &self,
+        passphrase: Option<&str>,
+        params: &RecoveryKdfParams,
+    ) -> Result<Vec<u8>> {
+        let pass = passphrase.unwrap_or("");
+        recovery::create_recovery_file(&self.keypair, pass, params)
+    }
+
     /// Create a new client from a 12-word mnemonic recovery phrase
     ///
     /// # Parameters
@@ -93,116 +960,3960 @@ impl PrivateMessengerClient {
         Self::new(keypair)
     }
 
-    /// Sign in to Pubky
-    pub async fn sign_in(&self) -> Result<pubky_common::session::Session> {
-        self.client
-            .signin(&self.keypair)
-            .await
-            .map_err(|e| anyhow!("Failed to sign in: {}", e))
+    /// Create a new client from a raw 32-byte ed25519 secret key
+    pub fn from_secret_key(secret_key: &[u8; 32]) -> Result<Self> {
+        Self::new(Keypair::from_secret_key(secret_key))
     }
 
-    /// Send an encrypted message to a recipient
-    pub async fn send_message(&self, recipient: &PublicKey, content: &str) -> Result<String> {
-        let message = PrivateMessage::new(&self.keypair, recipient, content)?;
-        let msg_id = PrivateMessage::generate_id();
-        let serialized = serde_json::to_string(&message)?;
-
-        let private_path = generate_conversation_path(&self.keypair, recipient)?;
-        let path = format!(
-            "pubky://{}{}{}",
-            self.keypair.public_key(),
-            private_path,
-            format!("{}.json", msg_id)
+    /// Create a new client from a hex-encoded 32-byte ed25519 secret key
+    pub fn from_hex_secret_key(hex_secret_key: &str) -> Result<Self> {
+        let bytes = Zeroizing::new(
+            hex::decode(hex_secret_key.trim())
+                .map_err(|e| anyhow!("Invalid hex secret key: {}", e))?,
         );
 
-        let response = self.client.put(&path).body(serialized).send().await?;
-
-        if !response.status().is_success() {
-            return Err(anyhow!("Failed to store message: {}", response.status()));
-        }
-
-        Ok(msg_id)
+        Self::from_secret_key_slice(&bytes)
     }
 
-    /// Get all messages in a conversation
-    pub async fn get_messages(&self, other_pubky: &PublicKey) -> Result<Vec<DecryptedMessage>> {
-        let mut all_messages = Vec::new();
-        let private_path = generate_conversation_path(&self.keypair, other_pubky)?;
-
-        // Check both user's paths
-        let self_path = format!("pubky://{}{}", self.keypair.public_key(), private_path);
-        let other_path = format!("pubky://{}{}", other_pubky, private_path);
-
-        let mut urls = Vec::new();
+    /// Create a new client from a Bech32-encoded 32-byte secret key
+    ///
+    /// # Parameters
+    /// - `encoded`: The Bech32 or Bech32m string
+    /// - `expected_hrp`: Optional human-readable prefix the key must use
+    pub fn from_bech32_secret_key(encoded: &str, expected_hrp: Option<&str>) -> Result<Self> {
+        let (hrp, bytes) = bech32::decode(encoded.trim())
+            .map_err(|e| anyhow!("Invalid Bech32 secret key: {}", e))?;
+        let bytes = Zeroizing::new(bytes);
 
-        // Collect URLs from both paths
-        if let Ok(list_builder) = self.client.list(&self_path) {
-            if let Ok(self_urls) = list_builder.send().await {
-                urls.extend(self_urls);
+        if let Some(expected) = expected_hrp {
+            if !hrp.to_string().eq_ignore_ascii_case(expected) {
+                return Err(anyhow!(
+                    "Unexpected Bech32 prefix: expected {}, got {}",
+                    expected,
+                    hrp
+                ));
             }
         }
 
-        if let Ok(list_builder) = self.client.list(&other_path) {
-            if let Ok(other_urls) = list_builder.send().await {
-                urls.extend(other_urls);
-            }
-        }
+        Self::from_secret_key_slice(&bytes)
+    }
 
-        // Process each message
-        for url in urls.iter() {
-            let response = self.client.get(url).send().await?;
-            if response.status().is_success() {
-                let response_text = response.text().await?;
+    /// Create a new client from a Nostr `nsec` secret key
+    ///
+    /// Nostr keys are secp256k1 keys. The 32 secret bytes are reused as the
+    /// ed25519 secret, so the resulting pubky is stable for a given `nsec` but
+    /// is not related to the matching Nostr `npub`.
+    pub fn from_nsec(nsec: &str) -> Result<Self> {
+        Self::from_bech32_secret_key(nsec, Some("nsec"))
+    }
 
-                if let Ok(message) = serde_json::from_str::<PrivateMessage>(&response_text) {
-                    if let Ok(content) = message.decrypt_content(&self.keypair, other_pubky) {
-                        if let Ok(sender) = message.decrypt_sender(&self.keypair, other_pubky) {
-                            let verified =
-                                message.verify_signature(&content, &sender).unwrap_or(false);
-
-                            all_messages.push(DecryptedMessage {
-                                sender,
-                                content,
-                                timestamp: message.timestamp,
-                                verified,
-                            });
-                        }
-                    }
-                }
-            }
-        }
+    fn from_secret_key_slice(bytes: &[u8]) -> Result<Self> {
+        let secret_key: Zeroizing<[u8; 32]> = Zeroizing::new(
+            bytes
+                .try_into()
+                .map_err(|_| anyhow!("Secret key must be 32 bytes, got {}", bytes.len()))?,
+        );
 
-        // Sort by timestamp
-        all_messages.sort_by(|a, b| a.timestamp.cmp(&b.timestamp));
-        Ok(all_messages)
+        Self::from_secret_key(&secret_key)
     }
 
-    /// Get the user's own profile
-    pub async fn get_own_profile(&self) -> Result<Option<PubkyProfile>> {
-        let profile_url = format!(
-            "pubky://{}/pub/pubky.app/profile.json",
-            self.keypair.public_key()
-        );
-        let response = self.client.get(&profile_url).send().await?;
+    /// Sign in to Pubky
+    pub async fn sign_in(&self) -> Result<pubky_common::session::Session> {
+        let session = self
+            .client
+            .signin(&self.keypair)
+            .await
+            .map_err(|e| anyhow!("Failed to sign in: {}", e))?;
 
-        if response.status().is_success() {
-            let profile_data = response.text().await?;
-            match serde_json::from_str::<PubkyProfile>(&profile_data) {
-                Ok(profile) => Ok(Some(profile)),
-                Err(_) => Ok(None),
-            }
-        } else {
-            Ok(None)
-        }
+        self.audit(AuditAction::SignIn, None, "signed in to homeserver");
+        Ok(session)
     }
 
-    /// Get followed users with their profiles
-    pub async fn get_followed_users(&self) -> Result<Vec<FollowedUser>> {
-        let follows_url = format!(
-            "pubky://{}/pub/pubky.app/follows/",
+    /// Create an account on a homeserver and sign in to it
+    ///
+    /// Only needed once for a new identity; afterwards use `sign_in`.
+    pub async fn sign_up(
+        &self,
+        homeserver: &PublicKey,
+        signup_token: Option<&str>,
+    ) -> Result<pubky_common::session::Session> {
+        let session = self
+            .client
+            .signup(&self.keypair, homeserver, signup_token)
+            .await
+            .map_err(|e| anyhow!("Failed to sign up: {}", e))?;
+
+        self.audit(
+            AuditAction::SignIn,
+            None,
+            format!("signed up at homeserver {}", homeserver),
+        );
+        Ok(session)
+    }
+
+    /// The ID of the conversation with `other_pubky`
+    ///
+    /// Both participants derive the same ID; see `ConversationId`.
+    pub fn conversation_id(&self, other_pubky: &PublicKey) -> ConversationId {
+        ConversationId::direct(&self.keypair.public_key(), other_pubky)
+    }
+
+    /// A handle on the conversation with `other_pubky`
+    ///
+    /// Saves passing the peer's key into every call; see `Conversation`.
+    pub fn conversation(&self, other_pubky: &PublicKey) -> Result<Conversation<'_>> {
+        Conversation::new(self, other_pubky)
+    }
+
+    /// Start building a message with per-message options
+    ///
+    /// See `MessageBuilder`.
+    pub fn message(&self, recipient: &PublicKey) -> MessageBuilder<'_> {
+        MessageBuilder::new(self, recipient)
+    }
+
+    /// Send an encrypted message to a recipient
+    ///
+    /// Returns the stored message record so it can be displayed right away.
+    pub async fn send_message(&self, recipient: &PublicKey, content: &str) -> Result<SentMessage> {
+        let msg_id = self.new_message_id();
+        self.send_message_with_id(recipient, content, &msg_id).await
+    }
+
+    /// Send typed content, validating it first
+    ///
+    /// Plain text is sent exactly like `send_message`; other content types are
+    /// encoded as a structured payload inside the encrypted content.
+    pub async fn send_content(
+        &self,
+        recipient: &PublicKey,
+        content: &MessageContent,
+    ) -> Result<SentMessage> {
+        content.validate()?;
+        self.send_message(recipient, &content.encode()?).await
+    }
+
+    /// Send an opaque binary payload, such as a protobuf or game state
+    ///
+    /// `content_type` is a MIME type or application-defined tag that
+    /// receivers can dispatch on. Payloads are limited to `MAX_BINARY_BYTES`.
+    pub async fn send_bytes(
+        &self,
+        recipient: &PublicKey,
+        data: Vec<u8>,
+        content_type: &str,
+    ) -> Result<SentMessage> {
+        let content = MessageContent::Binary {
+            content_type: content_type.to_string(),
+            data,
+        };
+        self.send_content(recipient, &content).await
+    }
+
+    /// Send a value of an application protocol as JSON, tagged with its type
+    ///
+    /// Receivers pick these messages out with `messages_of_type`.
+    pub async fn send_json<T: Serialize>(
+        &self,
+        recipient: &PublicKey,
+        value: &T,
+        type_tag: &str,
+    ) -> Result<SentMessage> {
+        let content = MessageContent::Json {
+            type_tag: type_tag.to_string(),
+            value: serde_json::to_value(value)?,
+        };
+        self.send_content(recipient, &content).await
+    }
+
+    /// Get the messages of a conversation sent with `send_json` under
+    /// `type_tag`, along with their decoded values
+    ///
+    /// Messages whose value doesn't deserialize into `T` are skipped.
+    pub async fn messages_of_type<T: DeserializeOwned>(
+        &self,
+        other_pubky: &PublicKey,
+        type_tag: &str,
+    ) -> Result<Vec<(DecryptedMessage, T)>> {
+        let messages = self.get_messages(other_pubky).await?;
+
+        Ok(messages
+            .into_iter()
+            .filter_map(|message| {
+                let value = match &message.body {
+                    MessageContent::Json {
+                        type_tag: tag,
+                        value,
+                    } if tag == type_tag => serde_json::from_value(value.clone()).ok()?,
+                    _ => return None,
+                };
+                Some((message, value))
+            })
+            .collect())
+    }
+
+    /// Edit the text of one of our messages
+    ///
+    /// `message` is the current version as fetched, with earlier edits
+    /// folded in. Instead of rewriting the original record, this sends a
+    /// `MessageEdit` carrying only the changed span of the text, or the full
+    /// text every `EDIT_SNAPSHOT_INTERVAL` edits. Fetches fold the edit into
+    /// the message, bumping its `edit_version`.
+    pub async fn edit_message(
+        &self,
+        other_pubky: &PublicKey,
+        message: &DecryptedMessage,
+        new_text: &str,
+    ) -> Result<SentMessage> {
+        if message.sender != self.public_key_string() {
+            return Err(anyhow!("Only the sender of a message can edit it"));
+        }
+        let (MessageContent::Text { text }, _) =
+            MessageContent::decode_with_options(&message.content)
+        else {
+            return Err(anyhow!("Only text messages can be edited"));
+        };
+        let version = message
+            .edit_version
+            .checked_add(1)
+            .ok_or_else(|| anyhow!("Message has too many edits"))?;
+
+        let edit = MessageEdit::between(&message.id, version, &text, new_text);
+        self.send_content(other_pubky, &MessageContent::Edit(edit))
+            .await
+    }
+
+    /// Send a payment request (BIP21 URI or Lightning invoice)
+    pub async fn send_payment_request(
+        &self,
+        recipient: &PublicKey,
+        request: PaymentRequest,
+    ) -> Result<SentMessage> {
+        self.send_content(recipient, &MessageContent::PaymentRequest(request))
+            .await
+    }
+
+    /// Send proof that a payment request from this conversation was paid
+    pub async fn send_payment_proof(
+        &self,
+        recipient: &PublicKey,
+        proof: PaymentProof,
+    ) -> Result<SentMessage> {
+        self.send_content(recipient, &MessageContent::PaymentProof(proof))
+            .await
+    }
+
+    /// Post a poll into a conversation
+    ///
+    /// The ID of the returned message identifies the poll.
+    pub async fn create_poll(
+        &self,
+        recipient: &PublicKey,
+        question: &str,
+        options: Vec<String>,
+    ) -> Result<SentMessage> {
+        let poll = Poll {
+            question: question.to_string(),
+            options,
+        };
+        self.send_content(recipient, &MessageContent::Poll(poll))
+            .await
+    }
+
+    /// Vote for an option of a poll, replacing any earlier vote
+    ///
+    /// `option` is the index into the poll's options. The vote is encrypted
+    /// like a message and stored as a sidecar record, so it does not show up
+    /// in `get_messages`.
+    pub async fn vote_on_poll(
+        &self,
+        other_pubky: &PublicKey,
+        poll_id: &str,
+        option: usize,
+    ) -> Result<()> {
+        let (poll, private_path) = self.fetch_poll(other_pubky, poll_id).await?;
+        if option >= poll.options.len() {
+            return Err(anyhow!("Poll has no option {}", option));
+        }
+
+        let vote = serde_json::to_string(&PollVote {
+            poll_id: poll_id.to_string(),
+            option,
+        })?;
+        let record = self.encrypt_message(other_pubky, &vote, unix_now())?;
+        let url = Self::poll_vote_url(&self.keypair.public_key(), &private_path, poll_id);
+
+        let response = self
+            .http_put(&url)
+            .body(self.message_record(other_pubky, &record)?)
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            return Err(anyhow!("Failed to store vote: {}", response.status()));
+        }
+
+        self.audit(
+            AuditAction::Send,
+            Some(other_pubky),
+            format!("vote on poll {}", poll_id),
+        );
+
+        Ok(())
+    }
+
+    /// Tally the votes cast on a poll by both participants
+    ///
+    /// Votes that are not signed by the participant storing them, or that
+    /// name an option the poll does not have, are ignored.
+    pub async fn get_poll_results(
+        &self,
+        other_pubky: &PublicKey,
+        poll_id: &str,
+    ) -> Result<PollResults> {
+        let (poll, poll_path) = self.fetch_poll(other_pubky, poll_id).await?;
+        let mut counts = vec![0; poll.options.len()];
+        let mut votes = HashMap::new();
+
+        // Votes are next to the poll, or where earlier versions put them
+        let mut paths = vec![poll_path];
+        for private_path in self.record_paths(other_pubky, poll_id)? {
+            if !paths.contains(&private_path) {
+                paths.push(private_path);
+            }
+        }
+
+        for voter in self.conversation_owners(other_pubky) {
+            let mut found = None;
+            for private_path in paths.iter() {
+                let url = Self::poll_vote_url(&voter, private_path, poll_id);
+                let response = self.guarded_get(&url).await?;
+                if !response.status().is_success() {
+                    continue;
+                }
+                let response_text = response.text().await?;
+                self.stats.record_received(response_text.len());
+
+                // A vote that can't be decrypted counts as no vote, rather
+                // than hiding the other participant's
+                found = self
+                    .decrypt_record(&url, &response_text, other_pubky, poll_id)
+                    .ok();
+                if found.is_some() {
+                    break;
+                }
+            }
+            let Some(record) = found else {
+                continue;
+            };
+            if !record.verified || record.sender != voter.to_string() {
+                continue;
+            }
+            let Ok(vote) = serde_json::from_str::<PollVote>(&record.content) else {
+                continue;
+            };
+            if vote.poll_id != poll_id || vote.option >= counts.len() {
+                continue;
+            }
+
+            counts[vote.option] += 1;
+            votes.insert(record.sender, vote.option);
+        }
+
+        Ok(PollResults {
+            poll_id: poll_id.to_string(),
+            poll,
+            counts,
+            votes,
+        })
+    }
+
+    /// Send a bot command with typed arguments, see `Command`
+    pub async fn send_command(
+        &self,
+        recipient: &PublicKey,
+        command: Command,
+    ) -> Result<SentMessage> {
+        self.send_content(recipient, &MessageContent::Command(command))
+            .await
+    }
+
+    /// Send a sticker from a built-in pack or from `upload_sticker`
+    pub async fn send_sticker(
+        &self,
+        recipient: &PublicKey,
+        sticker: Sticker,
+    ) -> Result<SentMessage> {
+        self.send_content(recipient, &MessageContent::Sticker(sticker))
+            .await
+    }
+
+    /// Upload a sticker image to our homeserver, returning the sticker to
+    /// send
+    ///
+    /// The image is encrypted under a key derived from our secret key and the
+    /// image, and stored under a path derived the same way, so uploading the
+    /// same image again reuses the stored copy. Only recipients of a message
+    /// carrying the sticker learn its key. Images are limited to
+    /// `MAX_STICKER_BYTES`.
+    pub async fn upload_sticker(&self, image: &[u8], content_type: &str) -> Result<Sticker> {
+        if image.len() > MAX_STICKER_BYTES {
+            return Err(anyhow!(
+                "Sticker images cannot exceed {} bytes",
+                MAX_STICKER_BYTES
+            ));
+        }
+        if !content_type.starts_with("image/") {
+            return Err(anyhow!("Sticker content type must be an image type"));
+        }
+
+        let (key, path) = sticker_key_and_path(&self.keypair, image);
+        let url = format!("pubky://{}{}", self.keypair.public_key(), path);
+
+        // Keep an earlier upload, whose hash may be in messages already sent
+        let response = self.guarded_get(&url).await?;
+        let blob = if response.status().is_success() {
+            response.bytes().await?.to_vec()
+        } else {
+            let blob = encrypt(image, &key);
+            let response = self.http_put(&url).body(blob.clone()).send().await?;
+            if !response.status().is_success() {
+                return Err(anyhow!("Failed to store sticker: {}", response.status()));
+            }
+            self.stats.record_sent(blob.len());
+            blob
+        };
+
+        Ok(Sticker {
+            source: StickerSource::Blob {
+                url,
+                hash: blake3::hash(&blob).to_hex().to_string(),
+                key: std::str::from_utf8(&encode_key_hex(&key)[..])?.to_string(),
+                content_type: content_type.to_string(),
+            },
+            emoji: None,
+        })
+    }
+
+    /// Download and decrypt the image of an uploaded sticker
+    ///
+    /// Fails for pack stickers, which clients draw from their own copy of the
+    /// pack, and for images that do not match the sticker's hash. Downloads
+    /// are cut off once they are larger than an encrypted `MAX_STICKER_BYTES`
+    /// image could be.
+    pub async fn fetch_sticker(&self, sticker: &Sticker) -> Result<Vec<u8>> {
+        sticker.validate()?;
+        let StickerSource::Blob { url, hash, key, .. } = &sticker.source else {
+            return Err(anyhow!("Pack stickers have no image to fetch"));
+        };
+
+        let response = self.guarded_get(url).await?;
+        if !response.status().is_success() {
+            return Err(anyhow!("Failed to fetch sticker: {}", response.status()));
+        }
+        let blob = read_capped(response, MAX_STICKER_BYTES + STICKER_BLOB_OVERHEAD).await?;
+        self.stats.record_received(blob.len());
+
+        if !hash_matches_hex(&blake3::hash(&blob), hash) {
+            return Err(anyhow!("Sticker image does not match its hash"));
+        }
+        let sticker_key = decode_key_hex(key)?;
+        Ok(decrypt(&blob, &sticker_key)?)
+    }
+
+    /// Fetch the poll posted by either participant under `poll_id`, along
+    /// with the conversation path it was found under
+    async fn fetch_poll(&self, other_pubky: &PublicKey, poll_id: &str) -> Result<(Poll, String)> {
+        for private_path in self.record_paths(other_pubky, poll_id)? {
+            for owner in self.conversation_owners(other_pubky) {
+                let url = format!("pubky://{}{}{}.json", owner, private_path, poll_id);
+                let Some(message) = self.fetch_record(&url, other_pubky, poll_id).await? else {
+                    continue;
+                };
+                if !message.verified {
+                    return Err(anyhow!("Poll signature is invalid: {}", poll_id));
+                }
+                return match message.body {
+                    MessageContent::Poll(poll) => Ok((poll, private_path)),
+                    _ => Err(anyhow!("Message is not a poll: {}", poll_id)),
+                };
+            }
+        }
+
+        Err(anyhow!("Poll not found: {}", poll_id))
+    }
+
+    /// URL of a participant's vote on a poll, kept next to the poll
+    fn poll_vote_url(voter: &PublicKey, private_path: &str, poll_id: &str) -> String {
+        PubkyUri::new(voter, private_path)
+            .join(&format!("votes/{}.json", poll_id))
+            .to_string()
+    }
+
+    /// Pubkys whose homeservers hold a side of the conversation: ours and
+    /// the peer's, or just ours for Saved Messages
+    fn conversation_owners(&self, other_pubky: &PublicKey) -> Vec<PublicKey> {
+        if self.is_self(other_pubky) {
+            vec![self.keypair.public_key()]
+        } else {
+            vec![self.keypair.public_key(), other_pubky.clone()]
+        }
+    }
+
+    /// Whether `other_pubky` is our own, i.e. the Saved Messages conversation
+    fn is_self(&self, other_pubky: &PublicKey) -> bool {
+        *other_pubky == self.keypair.public_key()
+    }
+
+    /// URL of the "fetched up to" marker `owner` keeps for a conversation
+    ///
+    /// Markers live on the static path even with path rotation, so peers can
+    /// always find them.
+    fn fetch_marker_url(&self, owner: &PublicKey, other_pubky: &PublicKey) -> Result<String> {
+        let private_path = self.static_conversation_path(other_pubky)?;
+        Ok(PubkyUri::new(owner, &private_path)
+            .join("markers/fetched.json")
+            .to_string())
+    }
+
+    /// Write our "fetched up to" marker if fetch markers are enabled and the
+    /// newest message from the peer is newer than the last marker written
+    ///
+    /// Best effort: a failed write is retried after the next fetch.
+    async fn write_fetch_marker(&self, other_pubky: &PublicKey, messages: &[DecryptedMessage]) {
+        let Some(markers) = self
+            .fetch_markers
+            .as_ref()
+            .filter(|_| !self.is_self(other_pubky))
+        else {
+            return;
+        };
+        let peer = other_pubky.to_string();
+        let Some(fetched_up_to) = messages
+            .iter()
+            .filter(|message| message.sender == peer)
+            .map(|message| message.timestamp)
+            .max()
+        else {
+            return;
+        };
+        {
+            let markers = markers.lock().unwrap_or_else(|e| e.into_inner());
+            if markers
+                .get(&peer)
+                .is_some_and(|last| *last >= fetched_up_to)
+            {
+                return;
+            }
+        }
+
+        let written: Result<()> = async {
+            let marker = serde_json::to_string(&FetchMarker { fetched_up_to })?;
+            let record = self.encrypt_message(other_pubky, &marker, unix_now())?;
+            let url = self.fetch_marker_url(&self.keypair.public_key(), other_pubky)?;
+            let serialized = self.message_record(other_pubky, &record)?;
+
+            let started = Instant::now();
+            self.stats.record_sent(serialized.len());
+            let result = self.http_put(&url).body(serialized).send().await;
+            let ok = result
+                .as_ref()
+                .is_ok_and(|response| !response.status().is_server_error());
+            self.record_request("put", started.elapsed(), ok);
+            let response = result?;
+
+            if !response.status().is_success() {
+                return Err(anyhow!(
+                    "Failed to store fetch marker: {}",
+                    response.status()
+                ));
+            }
+            Ok(())
+        }
+        .await;
+
+        if written.is_ok() {
+            let mut markers = markers.lock().unwrap_or_else(|e| e.into_inner());
+            markers.insert(peer, fetched_up_to);
+        }
+    }
+
+    /// Fetch and decrypt a single record of a conversation, if it exists
+    async fn fetch_record(
+        &self,
+        url: &str,
+        other_pubky: &PublicKey,
+        id: &str,
+    ) -> Result<Option<DecryptedMessage>> {
+        let response = self.guarded_get(url).await?;
+        if !response.status().is_success() {
+            return Ok(None);
+        }
+
+        let response_text = response.text().await?;
+        self.stats.record_received(response_text.len());
+
+        Ok(Some(self.decrypt_record(
+            url,
+            &response_text,
+            other_pubky,
+            id,
+        )?))
+    }
+
+    /// A new message ID, coarsened if coarse timestamps are enabled
+    pub(crate) fn new_message_id(&self) -> String {
+        let now_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        self.message_id_at(now_ms)
+    }
+
+    /// A new message ID for a message sent at `timestamp_ms`
+    fn message_id_at(&self, timestamp_ms: u64) -> String {
+        match self.coarse_timestamps {
+            Some(bucket) => PrivateMessage::generate_coarse_id(timestamp_ms, bucket),
+            None => PrivateMessage::generate_id_at(timestamp_ms),
+        }
+    }
+
+    /// Encrypt and sign a message for a conversation, reusing the cached
+    /// shared secret
+    fn encrypt_message(
+        &self,
+        recipient: &PublicKey,
+        content: &str,
+        timestamp: u64,
+    ) -> Result<PrivateMessage> {
+        let content = self.middleware_outbound(recipient, content)?;
+        let key = self.secrets.key(&self.keypair, recipient)?;
+        let cipher = self.conversation_cipher(recipient);
+        let direction = self
+            .direction_keys
+            .then(|| KeyDirection::of(&self.keypair.public_key(), recipient));
+        PrivateMessage::new_with_key(&self.keypair, &content, timestamp, &key, cipher, direction)
+    }
+
+    /// Run outgoing content through the middleware chain
+    fn middleware_outbound(&self, recipient: &PublicKey, content: &str) -> Result<String> {
+        let mut content = content.to_string();
+        for middleware in self.middleware.iter() {
+            content = middleware.outbound(recipient, content)?;
+        }
+        Ok(content)
+    }
+
+    /// Run decrypted content back through the middleware chain, skipping
+    /// middleware that fails
+    fn middleware_inbound(&self, other_pubky: &PublicKey, content: String) -> String {
+        self.middleware
+            .iter()
+            .rev()
+            .fold(content, |content, middleware| {
+                middleware
+                    .inbound(other_pubky, content.clone())
+                    .unwrap_or(content)
+            })
+    }
+
+    /// Serialize a message for a conversation, sealed if enabled
+    fn message_record(&self, recipient: &PublicKey, message: &PrivateMessage) -> Result<String> {
+        let key = self.secrets.key(&self.keypair, recipient)?;
+        message.to_record(&key, self.seal_envelopes)
+    }
+
+    /// Decrypt and verify a stored record, reusing the decryption cache
+    fn decrypt_record(
+        &self,
+        url: &str,
+        record: &str,
+        other_pubky: &PublicKey,
+        id: &str,
+    ) -> Result<DecryptedMessage> {
+        if let Some(message) = self.cache.get(url, record) {
+            return Ok(message);
+        }
+
+        let key = self.secrets.key(&self.keypair, other_pubky)?;
+        let message = PrivateMessage::from_record(record, &key)?;
+        let content = message.decrypt_content_with_key(&key)?;
+        let sender = message.decrypt_sender_with_key(&key)?;
+        let verified = message.verify_signature(&content, &sender).unwrap_or(false);
+
+        Ok(self.finish_record(
+            url,
+            record,
+            other_pubky,
+            id,
+            sender,
+            content,
+            message.timestamp,
+            verified,
+        ))
+    }
+
+    /// Decrypt a batch of fetched records, verifying their signatures together
+    ///
+    /// Records are `(url, id, record)` triples. Cached records are returned
+    /// as-is; the rest are decrypted in parallel on the blocking thread pool,
+    /// so large history loads don't stall the async executor, and their
+    /// signatures are checked in a single batch. Records that fail to parse
+    /// or decrypt are skipped.
+    async fn decrypt_records(
+        &self,
+        records: Vec<(String, String, String)>,
+        other_pubky: &PublicKey,
+    ) -> Result<Vec<DecryptedMessage>> {
+        let mut messages = Vec::with_capacity(records.len());
+        let mut misses = Vec::new();
+        for (url, id, record) in records {
+            match self.cache.get(&url, &record) {
+                Some(message) => messages.push(message),
+                None => misses.push((url, id, record)),
+            }
+        }
+        if misses.is_empty() {
+            return Ok(messages);
+        }
+
+        let key = self.secrets.key(&self.keypair, other_pubky)?;
+        let opened = tokio::task::spawn_blocking(move || open_records(&key, misses)).await?;
+
+        for record in opened {
+            messages.push(self.finish_record(
+                &record.url,
+                &record.record,
+                other_pubky,
+                &record.id,
+                record.sender,
+                record.content,
+                record.timestamp,
+                record.verified,
+            ));
+        }
+
+        Ok(messages)
+    }
+
+    /// Build a decrypted message, apply the middleware and content policy
+    /// and cache it
+    #[allow(clippy::too_many_arguments)]
+    fn finish_record(
+        &self,
+        url: &str,
+        record: &str,
+        other_pubky: &PublicKey,
+        id: &str,
+        sender: String,
+        content: String,
+        timestamp: u64,
+        verified: bool,
+    ) -> DecryptedMessage {
+        let content = self.middleware_inbound(other_pubky, content);
+        let mut message =
+            DecryptedMessage::new(id.to_string(), sender, content, timestamp, verified);
+        self.content_policy.apply_to_message(&mut message);
+        message.mentioned_me = message.is_mentioned(&self.keypair.public_key());
+        self.cache.insert(url, record, &message);
+        message
+    }
+
+    /// Write a dummy record into a conversation as cover traffic
+    ///
+    /// The record looks like any other message to the homeserver; the flag
+    /// marking it as cover is inside the ciphertext, and fetches drop it.
+    pub(crate) async fn send_cover_message(&self, recipient: &PublicKey) -> Result<SentMessage> {
+        let padding = {
+            let mut rng = rand::thread_rng();
+            let length = rng.gen_range(COVER_PADDING_MIN..=COVER_PADDING_MAX);
+            Alphanumeric.sample_string(&mut rng, length)
+        };
+
+        self.send_protocol_content(recipient, &MessageContent::Cover { padding })
+            .await
+    }
+
+    /// Send the same message to several recipients
+    ///
+    /// The content is encrypted and signed once, with its key wrapped for each
+    /// recipient (see `PrivateMessage::new_multi`), and the resulting envelope
+    /// is stored in each conversation under one shared message ID. Returns
+    /// the sent message per recipient, in order.
+    ///
+    /// With middleware, which may transform the content differently per
+    /// recipient, the content is encrypted for each recipient on its own,
+    /// still under one message ID.
+    pub async fn send_broadcast(
+        &self,
+        recipients: &[PublicKey],
+        content: &str,
+    ) -> Result<Vec<SentMessage>> {
+        for recipient in recipients {
+            self.ensure_writable(recipient)?;
+        }
+
+        if !self.middleware.is_empty() {
+            let msg_id = self.new_message_id();
+            let mut sent = Vec::with_capacity(recipients.len());
+            for chunk in recipients.chunks(BATCH_SIZE) {
+                let uploads = chunk.iter().map(|recipient| async {
+                    let message = self.encrypt_message(recipient, content, unix_now())?;
+                    self.store_message(recipient, &message, &msg_id, content)
+                        .await
+                });
+                for result in join_all(uploads).await {
+                    sent.push(result?);
+                }
+            }
+            return Ok(sent);
+        }
+
+        let keys = recipients
+            .iter()
+            .map(|recipient| self.secrets.key(&self.keypair, recipient))
+            .collect::<Result<Vec<_>>>()?;
+        let message = PrivateMessage::new_multi_with_keys(
+            &self.keypair,
+            content,
+            unix_now(),
+            &keys,
+            self.cipher_suite,
+        )?;
+        let msg_id = self.new_message_id();
+
+        let mut sent = Vec::with_capacity(recipients.len());
+        for chunk in recipients.chunks(BATCH_SIZE) {
+            let uploads = chunk
+                .iter()
+                .map(|recipient| self.store_message(recipient, &message, &msg_id, content));
+            for result in join_all(uploads).await {
+                sent.push(result?);
+            }
+        }
+
+        Ok(sent)
+    }
+
+    /// Send an encrypted message using a caller-supplied message ID
+    ///
+    /// The message is stored at a path derived from the ID, so retrying a send
+    /// with the same ID after an ambiguous network failure overwrites the
+    /// earlier upload instead of creating a duplicate.
+    ///
+    /// # Parameters
+    /// - `recipient`: The public key of the recipient
+    /// - `content`: The message content
+    /// - `msg_id`: The message ID, e.g. from `PrivateMessage::generate_id()`
+    pub async fn send_message_with_id(
+        &self,
+        recipient: &PublicKey,
+        content: &str,
+        msg_id: &str,
+    ) -> Result<SentMessage> {
+        self.ensure_writable(recipient)?;
+        self.send_with_id(recipient, content, msg_id).await
+    }
+
+    /// Send content the client writes on its own, such as system messages
+    /// and cover traffic, even to read-only conversations
+    async fn send_protocol_content(
+        &self,
+        recipient: &PublicKey,
+        content: &MessageContent,
+    ) -> Result<SentMessage> {
+        content.validate()?;
+        let msg_id = self.new_message_id();
+        self.send_with_id(recipient, &content.encode()?, &msg_id)
+            .await
+    }
+
+    /// Stamp, encrypt, and upload a message under `msg_id`
+    async fn send_with_id(
+        &self,
+        recipient: &PublicKey,
+        content: &str,
+        msg_id: &str,
+    ) -> Result<SentMessage> {
+        PrivateMessage::validate_id(msg_id)?;
+
+        let content = self.stamp_message(recipient, content, msg_id)?;
+        let result = match self.encrypt_message(recipient, &content, unix_now()) {
+            Ok(message) => {
+                self.store_message(recipient, &message, msg_id, &content)
+                    .await
+            }
+            Err(e) => Err(e),
+        };
+        // A message that wasn't sent doesn't keep its number
+        if result.is_err() && self.sequence_numbers {
+            let _ = self
+                .store()
+                .and_then(|store| store.release_sequence(&recipient.to_string(), msg_id));
+        }
+        result
+    }
+
+    /// Add our next sequence number for the conversation and our app
+    /// identity to outgoing content, if enabled, and the conversation's
+    /// disappearing messages lifetime unless the content has its own
+    ///
+    /// System messages and cover traffic never expire. Padded content is
+    /// padded again, so it still fills whole blocks.
+    fn stamp_message(&self, recipient: &PublicKey, content: &str, msg_id: &str) -> Result<String> {
+        let expiry = self.conversation_expiry(recipient);
+        if !self.sequence_numbers && !self.embed_app_identity && expiry.is_none() {
+            return Ok(content.to_string());
+        }
+
+        let (body, mut options) = MessageContent::decode_with_options(content);
+        let protocol = matches!(
+            body,
+            MessageContent::System(_) | MessageContent::Cover { .. }
+        );
+        if let (Some(days), None, false) = (expiry, options.expires_at, protocol) {
+            options.expires_at = Some(unix_now().saturating_add(days.saturating_mul(24 * 60 * 60)));
+        }
+        if self.sequence_numbers {
+            let seq = self
+                .store()?
+                .next_sequence(&recipient.to_string(), msg_id)?;
+            options.seq = Some(seq);
+        }
+        if self.embed_app_identity {
+            options.sent_with = self.app_identity.clone();
+        }
+        if options.padding.is_some() {
+            options.pad(&body)?;
+        }
+        body.encode_with(&options)
+    }
+
+    /// Send an encrypted message with an explicit timestamp (Unix seconds)
+    ///
+    /// Intended for migration tools that republish historical conversations
+    /// with their original times rather than the current time.
+    pub async fn send_message_at(
+        &self,
+        recipient: &PublicKey,
+        content: &str,
+        timestamp: u64,
+    ) -> Result<SentMessage> {
+        self.ensure_writable(recipient)?;
+        let msg_id = self.message_id_at(timestamp.saturating_mul(1000));
+        let message = self.encrypt_message(recipient, content, timestamp)?;
+        self.store_message(recipient, &message, &msg_id, content)
+            .await
+    }
+
+    /// Upload an already encrypted message to our side of the conversation
+    async fn store_message(
+        &self,
+        recipient: &PublicKey,
+        message: &PrivateMessage,
+        msg_id: &str,
+        content: &str,
+    ) -> Result<SentMessage> {
+        // Cover traffic is invisible to the user, so it has no delivery state
+        let track = |state| {
+            if !MessageContent::decode(content).is_cover() {
+                self.track_delivery(recipient, msg_id, message.timestamp, state);
+            }
+        };
+
+        track(DeliveryState::Queued);
+        let serialized = self.message_record(recipient, message)?;
+        let path = self.message_url(recipient, msg_id)?;
+
+        track(DeliveryState::Uploading);
+        let result = self.upload_message(&path, serialized).await;
+        track(if result.is_ok() {
+            DeliveryState::Stored
+        } else {
+            DeliveryState::Failed
+        });
+        result?;
+        let body = MessageContent::decode(content);
+        if !body.is_cover() && !body.is_system() {
+            self.record_send(recipient);
+        }
+
+        self.audit(
+            AuditAction::Send,
+            Some(recipient),
+            format!("message {}", msg_id),
+        );
+        if self.search_tokens {
+            self.publish_search_tokens(recipient, msg_id, content).await;
+        }
+
+        Ok(SentMessage {
+            id: msg_id.to_string(),
+            timestamp: message.timestamp,
+            url: path,
+            content: content.to_string(),
+        })
+    }
+
+    /// Write the search tokens of a sent text message
+    ///
+    /// Best effort: tokens that fail to upload are not retried.
+    async fn publish_search_tokens(&self, recipient: &PublicKey, msg_id: &str, content: &str) {
+        let MessageContent::Text { text } = MessageContent::decode(content) else {
+            return;
+        };
+        let Ok(urls) = self.search_token_urls(&self.keypair.public_key(), recipient, &text) else {
+            return;
+        };
+
+        let uploads = urls.iter().take(MAX_SEARCH_TOKENS).map(|dir| {
+            let url = dir.join(msg_id).to_string();
+            async move {
+                let _ = self.http_put(&url).body(Vec::new()).send().await;
+            }
+        });
+        join_all(uploads).await;
+    }
+
+    /// Directories on `owner`'s homeserver holding the search tokens of the
+    /// words of `text`, one per word
+    ///
+    /// The search directory doesn't depend on path rotation, so searches find
+    /// messages of every epoch.
+    fn search_token_urls(
+        &self,
+        owner: &PublicKey,
+        other_pubky: &PublicKey,
+        text: &str,
+    ) -> Result<Vec<PubkyUri>> {
+        let conversation_key = self.secrets.key(&self.keypair, other_pubky)?;
+        let key = search_key(&conversation_key);
+        let dir = PubkyUri::new(owner, &search_path(&conversation_key));
+        Ok(search_words(text)
+            .iter()
+            .map(|word| dir.join(&format!("{}/", search_token(&key, word))))
+            .collect())
+    }
+
+    /// Delete our search tokens of the messages `ids` in a conversation, or
+    /// all of them if `ids` is `None`
+    ///
+    /// The tokens of a message can't be computed without its text, so the
+    /// search directory is listed and records are matched by message ID.
+    /// Does nothing unless `with_search_tokens` is set.
+    async fn delete_search_tokens(
+        &self,
+        other_pubky: &PublicKey,
+        ids: Option<&HashSet<String>>,
+    ) -> Result<()> {
+        if !self.search_tokens {
+            return Ok(());
+        }
+        let conversation_key = self.secrets.key(&self.keypair, other_pubky)?;
+        let dir = PubkyUri::new(&self.keypair.public_key(), &search_path(&conversation_key));
+        let listed: Result<Vec<String>> = self
+            .list_pages(dir.to_string(), None, false)
+            .map_ok(|page| stream::iter(page.into_iter().map(Ok::<_, anyhow::Error>)))
+            .try_flatten()
+            .try_filter(|url| {
+                let matches = ids.map_or(true, |ids| {
+                    PubkyUri::parse(url)
+                        .ok()
+                        .and_then(|uri| uri.file_name().map(|id| ids.contains(id)))
+                        .unwrap_or(false)
+                });
+                future::ready(matches)
+            })
+            .try_collect()
+            .await;
+        let urls = match listed {
+            Ok(urls) => urls,
+            Err(e) if is_not_found(&e) => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        self.delete_urls(&urls).await
+    }
+
+    /// Upload a message record, resending it after transient failures
+    ///
+    /// Network errors, rate limiting, and server errors are retried up to
+    /// `SEND_ATTEMPTS` times. Before each resend the record is read back: if
+    /// the previous attempt was stored and only its response got lost, the
+    /// resend is skipped. Together with the ID-derived path, this stores
+    /// every message exactly once.
+    async fn upload_message(&self, url: &str, record: String) -> Result<()> {
+        let mut attempt = 1;
+        loop {
+            let started = Instant::now();
+            self.stats.record_sent(record.len());
+            let result = self.http_put(url).body(record.clone()).send().await;
+            let ok = result
+                .as_ref()
+                .is_ok_and(|response| !response.status().is_server_error());
+            self.record_request("put", started.elapsed(), ok);
+
+            let error: anyhow::Error = match result {
+                Ok(response) if response.status().is_success() => return Ok(()),
+                Ok(response)
+                    if response.status() == reqwest::StatusCode::TOO_MANY_REQUESTS
+                        || response.status().is_server_error() =>
+                {
+                    self.request_failed(url, Some(&response)).into()
+                }
+                Ok(response) => return Err(self.request_failed(url, Some(&response)).into()),
+                Err(e) => anyhow::Error::from(e).context(self.request_failed(url, None)),
+            };
+            if attempt >= SEND_ATTEMPTS {
+                return Err(error);
+            }
+            attempt += 1;
+
+            tokio::time::sleep(Duration::from_millis(RATE_LIMIT_RETRY_MS)).await;
+            if self.is_stored(url, &record).await {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Whether `url` already holds exactly `record`
+    async fn is_stored(&self, url: &str, record: &str) -> bool {
+        let Ok(response) = self.guarded_get(url).await else {
+            return false;
+        };
+        if !response.status().is_success() {
+            return false;
+        }
+        response.text().await.is_ok_and(|stored| stored == record)
+    }
+
+    /// Import a history of `(timestamp, content)` pairs into a conversation
+    ///
+    /// See `import_messages_with_progress` for details.
+    pub async fn import_messages(
+        &self,
+        recipient: &PublicKey,
+        messages: Vec<(u64, String)>,
+    ) -> Result<ImportProgress> {
+        self.import_messages_with_progress(recipient, messages, |_| {})
+            .await
+    }
+
+    /// Import a history of `(timestamp, content)` pairs into a conversation,
+    /// reporting progress after every batch
+    ///
+    /// Messages keep their original timestamps and are uploaded in small
+    /// rate-limited batches, retried after transient failures and counted in
+    /// `stats` like normal sends. Each message gets a deterministic ID
+    /// derived from its timestamp and content, so re-running an interrupted
+    /// import with the same input skips everything that was already uploaded.
+    pub async fn import_messages_with_progress<F>(
+        &self,
+        recipient: &PublicKey,
+        messages: Vec<(u64, String)>,
+        mut on_progress: F,
+    ) -> Result<ImportProgress>
+    where
+        F: FnMut(&ImportProgress),
+    {
+        let mut progress = ImportProgress {
+            total: messages.len(),
+            ..Default::default()
+        };
+
+        // Collect the IDs of messages already stored by a previous run
+        let existing: HashSet<String> = self
+            .own_records(recipient)
+            .await?
+            .iter()
+            .filter_map(|(private_path, url)| message_id_from_url(url, private_path))
+            .collect();
+
+        // Derive stable IDs, counting repeats of identical messages
+        let mut occurrences: HashMap<(u64, &str), u32> = HashMap::new();
+        let mut pending = Vec::new();
+        for (timestamp, content) in messages.iter() {
+            let occurrence = occurrences
+                .entry((*timestamp, content.as_str()))
+                .or_insert(0);
+            let msg_id = import_message_id(recipient, *timestamp, content, *occurrence);
+            *occurrence += 1;
+
+            if existing.contains(&msg_id) {
+                progress.skipped += 1;
+            } else {
+                pending.push((msg_id, *timestamp, content));
+            }
+        }
+
+        on_progress(&progress);
+
+        for chunk in pending.chunks(BATCH_SIZE) {
+            // Encrypt the batch up front so uploads only do network work
+            let mut uploads = Vec::with_capacity(chunk.len());
+            for (msg_id, timestamp, content) in chunk {
+                let message = self.encrypt_message(recipient, content, *timestamp)?;
+                let url = self.message_url(recipient, msg_id)?;
+                uploads.push((url, self.message_record(recipient, &message)?));
+            }
+
+            // Uploads are retried and counted like any other send
+            let results = join_all(
+                uploads
+                    .into_iter()
+                    .map(|(url, record)| async move { self.upload_message(&url, record).await }),
+            )
+            .await;
+            for result in results {
+                result?;
+            }
+
+            progress.uploaded += chunk.len();
+            on_progress(&progress);
+            self.audit(
+                AuditAction::Send,
+                Some(recipient),
+                format!("imported {} messages", chunk.len()),
+            );
+
+            // Add a small delay between batches to avoid rate limiting
+            if chunk.len() == BATCH_SIZE {
+                tokio::time::sleep(tokio::time::Duration::from_millis(BATCH_DELAY_MS)).await;
+            }
+        }
+
+        Ok(progress)
+    }
+
+    /// Get all messages in a conversation
+    ///
+    /// Messages past their expiry (see `MessageBuilder::expires_in`) are left
+    /// out. While the circuit breaker for the peer's homeserver is open, only
+    /// our side of the conversation is returned.
+    pub async fn get_messages(&self, other_pubky: &PublicKey) -> Result<Vec<DecryptedMessage>> {
+        let mut urls = Vec::new();
+
+        // Collect URLs from both users' paths
+        for private_path in self.conversation_paths(other_pubky)? {
+            for owner in self.conversation_owners(other_pubky) {
+                let path = format!("pubky://{}{}", owner, private_path);
+                if let Ok(owner_urls) = self.guarded_list(&path, None, None, false).await {
+                    urls.extend(
+                        owner_urls
+                            .into_iter()
+                            .map(|url| (private_path.clone(), url)),
+                    );
+                }
+            }
+        }
+
+        // Fetch each message, skipping the peer's side while their
+        // homeserver is unreachable
+        let own_pubky = self.keypair.public_key().to_string();
+        let mut records = Vec::with_capacity(urls.len());
+        for (private_path, url) in urls.iter() {
+            let Some(id) = message_id_from_url(url, private_path) else {
+                continue;
+            };
+
+            let response = match self.guarded_get(url).await {
+                Ok(response) => response,
+                Err(e) if uri::host_of(url) != own_pubky && is_peer_unreachable(&e) => continue,
+                Err(e) => return Err(e),
+            };
+            if response.status().is_success() {
+                let response_text = response.text().await?;
+                self.stats.record_received(response_text.len());
+                records.push((url.clone(), id, response_text));
+            }
+        }
+
+        self.process_fetched(other_pubky, records, true).await
+    }
+
+    /// Get the newest `n` messages of a conversation, oldest first
+    ///
+    /// Lists both sides of the conversation newest first and fetches only
+    /// the entries needed, which is what opening a chat screen needs. Fewer
+    /// than `n` messages come back if some of them expired.
+    pub async fn get_recent_messages(
+        &self,
+        other_pubky: &PublicKey,
+        n: usize,
+    ) -> Result<Vec<DecryptedMessage>> {
+        self.check_breaker(&other_pubky.to_string()).await?;
+
+        let options = ListOptions {
+            limit: Some(n),
+            reverse: true,
+            ..ListOptions::default()
+        };
+        let entries = self.list_message_page(other_pubky, &options).await?;
+        let records = self.fetch_entries(&entries).await?;
+
+        self.process_fetched(other_pubky, records, false).await
+    }
+
+    /// Prepare a conversation so that opening it feels instant
+    ///
+    /// Derives the conversation keys, resolves the peer's homeserver, and
+    /// fetches and decrypts the newest `WARM_MESSAGES` messages into the
+    /// decryption cache, so a following `get_recent_messages` only lists and
+    /// downloads. Nothing is written: messages aren't saved to the local
+    /// store and no fetch marker is left.
+    pub async fn warm_conversation(&self, other_pubky: &PublicKey) -> Result<()> {
+        self.check_breaker(&other_pubky.to_string()).await?;
+        self.secrets.key(&self.keypair, other_pubky)?;
+
+        let options = ListOptions {
+            limit: Some(WARM_MESSAGES),
+            reverse: true,
+            ..ListOptions::default()
+        };
+        let (_, entries) = future::join(
+            self.client.get_homeserver(other_pubky),
+            self.list_message_page(other_pubky, &options),
+        )
+        .await;
+
+        let records = self.fetch_entries(&entries?).await?;
+        self.decrypt_records(records, other_pubky).await?;
+        Ok(())
+    }
+
+    /// Download the records of listing entries in batches, as
+    /// `(url, id, record)` triples
+    ///
+    /// Entries deleted since they were listed are skipped.
+    async fn fetch_entries(
+        &self,
+        entries: &[MessageEntry],
+    ) -> Result<Vec<(String, String, String)>> {
+        let mut records = Vec::with_capacity(entries.len());
+        for chunk in entries.chunks(BATCH_SIZE) {
+            let fetches = chunk.iter().map(|entry| self.guarded_get(&entry.url));
+            for (entry, response) in chunk.iter().zip(join_all(fetches).await) {
+                let response = response?;
+                if response.status().is_success() {
+                    let response_text = response.text().await?;
+                    self.stats.record_received(response_text.len());
+                    records.push((entry.url.clone(), entry.id.clone(), response_text));
+                }
+            }
+        }
+        Ok(records)
+    }
+
+    /// Decrypt fetched `(url, id, record)` triples of a conversation and run
+    /// them through the filters, the local store, and fetch markers
+    ///
+    /// `complete` tells whether the records are the whole conversation, so
+    /// that messages missing before the first fetched one of a sender are
+    /// flagged too.
+    async fn process_fetched(
+        &self,
+        other_pubky: &PublicKey,
+        records: Vec<(String, String, String)>,
+        complete: bool,
+    ) -> Result<Vec<DecryptedMessage>> {
+        // Decrypt and verify them as one batch
+        let now = unix_now();
+        let mut decrypted = self.decrypt_records(records, other_pubky).await?;
+
+        // Check sequence numbers before anything is dropped, so cover
+        // traffic, edits, and expired messages don't look missing. A partial
+        // fetch starts each sender's sequence at their first fetched message.
+        let mut last_seen = HashMap::new();
+        if !complete {
+            for message in decrypted.iter() {
+                if let Some(seq) = message.seq {
+                    let first = last_seen
+                        .entry(message.sender.clone())
+                        .or_insert(seq.saturating_sub(1));
+                    *first = (*first).min(seq.saturating_sub(1));
+                }
+            }
+        }
+        DecryptedMessage::check_sequence(&mut decrypted, &mut last_seen);
+
+        let mut all_messages: Vec<DecryptedMessage> = decrypted
+            .into_iter()
+            .filter(|message| !message.body.is_cover() && !message.is_expired(now))
+            .collect();
+
+        let store = self.store.as_ref().filter(|store| !store.is_locked());
+        let peer = other_pubky.to_string();
+        let edited_elsewhere = self.fold_edits(store, &peer, &mut all_messages)?;
+
+        // Sort by timestamp
+        all_messages.sort_by(|a, b| a.timestamp.cmp(&b.timestamp));
+        DecryptedMessage::attribute_replies(&mut all_messages);
+
+        // Same order as `sync_conversation`, so a conversation reads the same
+        // whichever way it is fetched
+        if let Some(store) = store {
+            self.apply_trash(store, &peer, &mut all_messages)?;
+            self.check_contact_identity(store, other_pubky, &mut all_messages)
+                .await?;
+            self.apply_trust(store, other_pubky, &mut all_messages)?;
+            self.apply_mute_rules(store, &mut all_messages)?;
+            self.apply_message_requests(store, other_pubky, &mut all_messages)?;
+        }
+        self.apply_spam_filter(store, other_pubky, &mut all_messages)?;
+
+        if let Some(store) = store {
+            self.apply_system_messages(store, other_pubky, &all_messages)?;
+            store.save_messages(&peer, &all_messages)?;
+            store.save_messages(&peer, &edited_elsewhere)?;
+        }
+
+        self.write_fetch_marker(other_pubky, &all_messages).await;
+
+        Ok(all_messages)
+    }
+
+    /// Fold edit records into the messages they change
+    ///
+    /// Edited messages get the content policy applied again. Edits of
+    /// messages that were not fetched along with them are applied to the
+    /// copies in the local store, if there is one; the messages changed that
+    /// way are returned.
+    fn fold_edits(
+        &self,
+        store: Option<&LocalStore>,
+        peer: &str,
+        messages: &mut Vec<DecryptedMessage>,
+    ) -> Result<Vec<DecryptedMessage>> {
+        let orphans = DecryptedMessage::fold_edits(messages);
+
+        let mut edited_elsewhere = Vec::new();
+        if let Some(store) = store {
+            let targets: BTreeSet<&str> = orphans
+                .iter()
+                .filter_map(|edit| match &edit.body {
+                    MessageContent::Edit(change) => Some(change.target.as_str()),
+                    _ => None,
+                })
+                .collect();
+            for target in targets {
+                if let Some(mut message) = store.stored_message(peer, target)? {
+                    if message.apply_edits(&orphans) {
+                        edited_elsewhere.push(message);
+                    }
+                }
+            }
+        }
+
+        let own_pubky = self.keypair.public_key();
+        for message in messages
+            .iter_mut()
+            .chain(edited_elsewhere.iter_mut())
+            .filter(|message| message.edit_version > 0)
+        {
+            self.content_policy.apply_to_message(message);
+            message.mentioned_me = message.is_mentioned(&own_pubky);
+        }
+
+        Ok(edited_elsewhere)
+    }
+
+    /// Fetch only what changed in a conversation since the previous call
+    ///
+    /// Lists both sides of the conversation and compares the listing with the
+    /// sync state persisted in the local store: records not seen before are
+    /// fetched and returned as new, records that disappeared are reported as
+    /// deleted (and raise `NotificationEvent::MessageDeleted`), and records
+    /// of the last `SYNC_EDIT_WINDOW_SECS` are re-fetched to catch edits.
+    /// New and edited messages are saved to the store, deleted ones are
+    /// removed from it and from the decryption cache. This is the primitive
+    /// pollers should use instead of `get_messages`. Every change is also
+    /// raised on the conversation's timeline, see `subscribe_conversation`.
+    ///
+    /// Listing starts `SYNC_EDIT_WINDOW_SECS` before the cursor of the
+    /// previous sync, so a long conversation isn't walked on every call. The
+    /// whole conversation is listed on the first sync of a client and then
+    /// every `SYNC_FULL_LISTING_SECS`, which is when deletions of older
+    /// records, and records dated further back than the window, are found.
+    ///
+    /// A side of the conversation that suddenly lists nothing is more likely
+    /// a homeserver hiccup than every message deleted at once, so its
+    /// records are only reported as deleted once a second sync in a row
+    /// lists it empty.
+    ///
+    /// Requires a local store.
+    pub async fn sync_conversation(&self, other_pubky: &PublicKey) -> Result<SyncDelta> {
+        self.check_breaker(&other_pubky.to_string()).await?;
+        let store = self.store()?;
+        let peer = other_pubky.to_string();
+
+        let sync_state = store.sync_state(&peer)?;
+        let initial = sync_state.is_none();
+        let known = store.sync_entries(&peer)?;
+        let now = unix_now();
+
+        // List from the cursor, unless a full listing is due
+        let full_listing_due = self
+            .full_listings
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(&peer)
+            .map_or(true, |listed_at| listed_at + SYNC_FULL_LISTING_SECS <= now);
+        let previous_cursor = sync_state.and_then(|(cursor, _)| cursor);
+        let since_ms = previous_cursor
+            .as_deref()
+            .and_then(PrivateMessage::id_timestamp_ms)
+            .filter(|_| !full_listing_due)
+            .map(|cursor_ms| cursor_ms.saturating_sub(SYNC_EDIT_WINDOW_SECS * 1000));
+        let entries: Vec<MessageEntry> = self
+            .list_entries(other_pubky, since_ms.map(|since_ms| (since_ms, u64::MAX)))?
+            .try_collect()
+            .await?;
+        if since_ms.is_none() {
+            self.full_listings
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .insert(peer.clone(), now);
+        }
+
+        // Records gone from the listing were deleted, if it covered them
+        let listed: HashSet<&str> = entries.iter().map(|entry| entry.url.as_str()).collect();
+        let covered = |entry: &SyncEntry| {
+            since_ms.map_or(true, |since_ms| {
+                PrivateMessage::id_timestamp_ms(&entry.id)
+                    .is_some_and(|timestamp_ms| timestamp_ms >= since_ms)
+            })
+        };
+        let removed: Vec<&SyncEntry> = known
+            .values()
+            .filter(|entry| covered(entry) && !listed.contains(entry.url.as_str()))
+            .collect();
+        let removed = self.confirm_removals(other_pubky, &entries, removed);
+
+        // Fetch records never seen before, and recent ones to catch edits
+        let mut seen = Vec::new();
+        let mut records = Vec::new();
+        let mut edited_ids = HashSet::new();
+        for entry in entries.iter() {
+            let previous = known.get(&entry.url);
+            let recent = PrivateMessage::id_timestamp_ms(&entry.id)
+                .is_some_and(|timestamp_ms| timestamp_ms / 1000 + SYNC_EDIT_WINDOW_SECS >= now);
+            if previous.is_some() && !recent {
+                continue;
+            }
+
+            let response = self.guarded_get(&entry.url).await?;
+            if !response.status().is_success() {
+                continue;
+            }
+            let response_text = response.text().await?;
+            self.stats.record_received(response_text.len());
+
+            let record_hash = blake3::hash(response_text.as_bytes()).to_hex().to_string();
+            match previous {
+                Some(previous) if previous.record_hash == record_hash => continue,
+                Some(_) => {
+                    edited_ids.insert(entry.id.clone());
+                }
+                None => {}
+            }
+            seen.push(SyncEntry {
+                url: entry.url.clone(),
+                id: entry.id.clone(),
+                record_hash,
+            });
+            records.push((entry.url.clone(), entry.id.clone(), response_text));
+        }
+
+        // Continue each sender's sequence from the previous sync; records
+        // re-fetched because they changed were counted then
+        let mut last_seen = if initial {
+            HashMap::new()
+        } else {
+            store.seen_sequences(&peer)?
+        };
+        let (refetched, mut fresh): (Vec<_>, Vec<_>) = self
+            .decrypt_records(records, other_pubky)
+            .await?
+            .into_iter()
+            .partition(|message| edited_ids.contains(&message.id));
+        DecryptedMessage::check_sequence(&mut fresh, &mut last_seen);
+
+        let mut messages: Vec<DecryptedMessage> = fresh
+            .into_iter()
+            .chain(refetched)
+            .filter(|message| !message.body.is_cover() && !message.is_expired(now))
+            .collect();
+
+        // Edits of messages synced earlier are applied to their stored copies,
+        // which are reported as edited
+        let edited_elsewhere = self.fold_edits(Some(store), &peer, &mut messages)?;
+        edited_ids.extend(edited_elsewhere.iter().map(|message| message.id.clone()));
+        messages.extend(edited_elsewhere);
+        messages.sort_by(|a, b| a.timestamp.cmp(&b.timestamp));
+
+        self.apply_trash(store, &peer, &mut messages)?;
+        self.check_contact_identity(store, other_pubky, &mut messages)
+            .await?;
+        self.apply_trust(store, other_pubky, &mut messages)?;
+        self.apply_mute_rules(store, &mut messages)?;
+        self.apply_message_requests(store, other_pubky, &mut messages)?;
+        self.apply_spam_filter(Some(store), other_pubky, &mut messages)?;
+        self.apply_system_messages(store, other_pubky, &messages)?;
+        store.save_messages(&peer, &messages)?;
+
+        // Drop deleted messages from the store and cache, and tell subscribers
+        let deleted_ids: Vec<String> = removed.iter().map(|entry| entry.id.clone()).collect();
+        store.delete_messages(&peer, &deleted_ids)?;
+        for entry in removed.iter() {
+            self.cache.remove_url(&entry.url);
+        }
+
+        // Queue the timeline before the sync state moves on, so events that
+        // fail to queue are found again by the next sync
+        let timeline: Vec<ConversationEvent> = deleted_ids
+            .iter()
+            .map(|message_id| ConversationEvent::Deleted {
+                message_id: message_id.clone(),
+            })
+            .chain(
+                messages
+                    .iter()
+                    .map(|message| timeline_event(message, &edited_ids)),
+            )
+            .collect();
+        if self.event_queue {
+            store.enqueue_events(&peer, &timeline, now)?;
+        }
+
+        let cursor = entries
+            .iter()
+            .map(|entry| entry.id.clone())
+            .chain(previous_cursor)
+            .max();
+        let removed_urls: Vec<String> = removed.iter().map(|entry| entry.url.clone()).collect();
+        store.record_seen_sequences(&peer, &last_seen)?;
+        store.apply_sync(&peer, &seen, &removed_urls, cursor.as_deref(), now)?;
+        let mismatches = self.check_transcript_hashes(store, &peer, &messages)?;
+
+        for message_id in deleted_ids.iter() {
+            self.emit(NotificationEvent::MessageDeleted {
+                peer: peer.clone(),
+                message_id: message_id.clone(),
+            });
+        }
+        let id = self.conversation_id(other_pubky);
+        for event in timeline {
+            self.emit_timeline(&id, event);
+        }
+        for mismatch in mismatches {
+            self.emit(mismatch);
+        }
+
+        self.write_fetch_marker(other_pubky, &messages).await;
+        if self.fetch_markers.is_some() {
+            self.sync_receipt(other_pubky).await;
+        }
+
+        let (edited_messages, new_messages) = messages
+            .into_iter()
+            .partition(|message| edited_ids.contains(&message.id));
+        Ok(SyncDelta {
+            initial,
+            new_messages,
+            edited_messages,
+            deleted_ids,
+            cursor,
+        })
+    }
+
+    /// Hold back the removals of a side of a conversation that listed
+    /// nothing, unless it also listed nothing the previous time
+    fn confirm_removals<'a>(
+        &self,
+        other_pubky: &PublicKey,
+        entries: &[MessageEntry],
+        removed: Vec<&'a SyncEntry>,
+    ) -> Vec<&'a SyncEntry> {
+        let owner_of = |url: &str| {
+            PubkyUri::parse(url)
+                .map(|uri| uri.public_key().to_string())
+                .ok()
+        };
+        let listed_owners: HashSet<String> = entries
+            .iter()
+            .filter_map(|entry| owner_of(&entry.url))
+            .collect();
+
+        let mut empty_listings = self
+            .empty_listings
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        let mut held = HashSet::new();
+        for owner in self.conversation_owners(other_pubky) {
+            let owner = owner.to_string();
+            let key = (other_pubky.to_string(), owner.clone());
+            if listed_owners.contains(&owner) {
+                empty_listings.remove(&key);
+                continue;
+            }
+            let emptied = removed
+                .iter()
+                .any(|entry| owner_of(&entry.url).as_ref() == Some(&owner));
+            // Inserting succeeds on the first empty listing only
+            if emptied && empty_listings.insert(key) {
+                held.insert(owner);
+            }
+        }
+
+        removed
+            .into_iter()
+            .filter(|entry| owner_of(&entry.url).map_or(true, |owner| !held.contains(&owner)))
+            .collect()
+    }
+
+    /// When a peer last fetched our messages, if they leave fetch markers
+    ///
+    /// Returns the timestamp of the newest of our messages the peer had
+    /// fetched, from the marker they keep beside the conversation (see
+    /// `with_fetch_markers`). `None` means the peer has not fetched the
+    /// conversation since enabling markers, or does not use them.
+    pub async fn peer_last_fetched(&self, other_pubky: &PublicKey) -> Result<Option<u64>> {
+        if self.is_self(other_pubky) {
+            return Ok(None);
+        }
+        let url = self.fetch_marker_url(other_pubky, other_pubky)?;
+        let Some(record) = self.fetch_record(&url, other_pubky, "fetched").await? else {
+            return Ok(None);
+        };
+        if !record.verified || record.sender != other_pubky.to_string() {
+            return Ok(None);
+        }
+
+        Ok(serde_json::from_str::<FetchMarker>(&record.content)
+            .ok()
+            .map(|marker| marker.fetched_up_to))
+    }
+
+    /// Raise a `ConversationEvent::Receipt` when the peer's fetch marker
+    /// moved since the previous sync, and mark the messages it covers as
+    /// delivered
+    ///
+    /// Best effort: a marker that can't be read is picked up next time.
+    async fn sync_receipt(&self, other_pubky: &PublicKey) {
+        let Ok(Some(fetched_up_to)) = self.peer_last_fetched(other_pubky).await else {
+            return;
+        };
+
+        let peer = other_pubky.to_string();
+        let previous = self
+            .receipts
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(peer.clone(), fetched_up_to);
+        if previous == Some(fetched_up_to) {
+            return;
+        }
+
+        let store = self.store.as_ref().filter(|store| !store.is_locked());
+        let receipt = ConversationEvent::Receipt { fetched_up_to };
+        if let Some(store) = store.filter(|_| self.event_queue) {
+            // A lost receipt is superseded by the next one
+            let _ = store.enqueue_events(&peer, std::slice::from_ref(&receipt), unix_now());
+        }
+        self.emit_timeline(&self.conversation_id(other_pubky), receipt);
+
+        let Some(store) = store else {
+            return;
+        };
+        let Ok(delivered) = store.mark_delivered(&peer, fetched_up_to, unix_now()) else {
+            return;
+        };
+        for message_id in delivered {
+            self.emit(NotificationEvent::DeliveryStateChanged {
+                peer: peer.clone(),
+                message_id,
+                state: DeliveryState::Delivered,
+            });
+        }
+    }
+
+    /// Check a contact against the identity pinned on first contact
+    ///
+    /// Pins the homeserver from the contact's pkarr record the first time the
+    /// conversation is fetched. Raises `NotificationEvent::KeyChanged` when
+    /// that homeserver changes or a new message claiming to be from the
+    /// contact fails signature verification, and sets `key_warning` on such
+    /// messages and on new messages received while a change is unacknowledged.
+    async fn check_contact_identity(
+        &self,
+        store: &LocalStore,
+        other_pubky: &PublicKey,
+        messages: &mut [DecryptedMessage],
+    ) -> Result<()> {
+        let peer = other_pubky.to_string();
+        let now = unix_now();
+
+        if let Some(homeserver) = self.client.get_homeserver(other_pubky).await {
+            match store.contact_pin(&peer)? {
+                None => store.pin_contact(&peer, &homeserver, now)?,
+                Some(pin)
+                    if pin.homeserver != homeserver
+                        && pin.changed_homeserver.as_deref() != Some(homeserver.as_str()) =>
+                {
+                    store.record_key_change(&peer, &homeserver, now)?;
+                    self.emit(NotificationEvent::KeyChanged {
+                        peer: peer.clone(),
+                        change: KeyChange::HomeserverChanged {
+                            previous: pin.homeserver,
+                            current: homeserver,
+                        },
+                    });
+                }
+                Some(_) => {}
+            }
+        }
+
+        let change_pending = store
+            .contact_pin(&peer)?
+            .is_some_and(|pin| pin.changed_homeserver.is_some());
+        let known = store.key_warnings(&peer)?;
+        let own_pubky = self.keypair.public_key().to_string();
+
+        for message in messages.iter_mut() {
+            if message.sender == own_pubky {
+                continue;
+            }
+
+            match known.get(&message.id) {
+                Some(key_warning) => message.key_warning = *key_warning,
+                None if !message.verified => {
+                    message.key_warning = true;
+                    self.emit(NotificationEvent::KeyChanged {
+                        peer: peer.clone(),
+                        change: KeyChange::InvalidSignature {
+                            message_id: message.id.clone(),
+                        },
+                    });
+                }
+                None => message.key_warning = change_pending,
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The identity pinned for a contact on first contact, if any
+    pub fn contact_pin(&self, other_pubky: &PublicKey) -> Result<Option<ContactPin>> {
+        self.store()?.contact_pin(&other_pubky.to_string())
+    }
+
+    /// Accept a contact's changed identity as the new pin
+    ///
+    /// Messages already marked with `key_warning` keep the mark.
+    pub fn acknowledge_key_change(&self, other_pubky: &PublicKey) -> Result<()> {
+        if !self
+            .store()?
+            .acknowledge_key_change(&other_pubky.to_string(), unix_now())?
+        {
+            return Err(anyhow!("No key change to acknowledge for {}", other_pubky));
+        }
+
+        self.audit(
+            AuditAction::PolicyOverride,
+            Some(other_pubky),
+            "acknowledged key change",
+        );
+        Ok(())
+    }
+
+    /// The trust level of a contact
+    ///
+    /// Requires a local store.
+    pub fn trust_level(&self, other_pubky: &PublicKey) -> Result<TrustLevel> {
+        effective_trust(self.store()?, &other_pubky.to_string())
+    }
+
+    /// Set the trust level of a contact
+    ///
+    /// Fetched messages carry the sender's level in `sender_trust`, and
+    /// messages from `Distrusted` contacts are marked `quarantined`. Requires
+    /// a local store.
+    pub fn set_trust(&self, other_pubky: &PublicKey, level: TrustLevel) -> Result<()> {
+        self.store()?
+            .set_trust(&other_pubky.to_string(), level, unix_now())?;
+
+        self.audit(
+            AuditAction::PolicyOverride,
+            Some(other_pubky),
+            format!("trust set to {}", level.as_str()),
+        );
+        Ok(())
+    }
+
+    /// Set the alias and tags of a contact in the contact book
+    ///
+    /// Replaces the contact's earlier alias and tags. Requires a local store.
+    pub fn set_contact(
+        &self,
+        other_pubky: &PublicKey,
+        alias: Option<&str>,
+        tags: &[&str],
+    ) -> Result<()> {
+        let contact = Contact {
+            pubky: other_pubky.to_string(),
+            alias: alias.map(str::to_string),
+            tags: tags.iter().map(|tag| tag.to_string()).collect(),
+            trust: TrustLevel::Unknown,
+        };
+        contact.validate()?;
+
+        self.store()?.set_contact(
+            &contact.pubky,
+            contact.alias.as_deref(),
+            &contact.tags,
+            unix_now(),
+        )
+    }
+
+    /// Every contact in the contact book, or with an explicit trust level,
+    /// ordered by pubky
+    ///
+    /// Requires a local store.
+    pub fn contacts(&self) -> Result<Vec<Contact>> {
+        let store = self.store()?;
+        let mut contacts: BTreeMap<String, (Option<String>, Vec<String>)> = store
+            .contacts()?
+            .into_iter()
+            .map(|(peer, alias, tags)| (peer, (alias, tags)))
+            .collect();
+        for (peer, _, _) in store.trust_entries()? {
+            contacts.entry(peer).or_default();
+        }
+
+        contacts
+            .into_iter()
+            .map(|(pubky, (alias, tags))| {
+                let trust = effective_trust(store, &pubky)?;
+                Ok(Contact {
+                    pubky,
+                    alias,
+                    tags,
+                    trust,
+                })
+            })
+            .collect()
+    }
+
+    /// Export the contact book in the JSON interchange format
+    ///
+    /// See `Contact` for the format. Requires a local store.
+    pub fn export_contacts(&self) -> Result<Vec<u8>> {
+        Contact::to_json(&self.contacts()?)
+    }
+
+    /// Export the contact book in the CSV interchange format
+    ///
+    /// See `Contact` for the format. Requires a local store.
+    pub fn export_contacts_csv(&self) -> Result<Vec<u8>> {
+        Ok(Contact::to_csv(&self.contacts()?))
+    }
+
+    /// Import contacts exported by this or another app, in either
+    /// interchange format
+    ///
+    /// Every contact is checked before any is imported. Aliases and tags
+    /// replace those of existing contacts. Only `Verified` and `Distrusted`
+    /// trust levels are imported, since `Tofu` depends on the identity this
+    /// device pinned. Returns the number of contacts imported. Requires a
+    /// local store.
+    pub fn import_contacts(&self, bytes: &[u8]) -> Result<usize> {
+        let store = self.store()?;
+        let contacts = Contact::parse(bytes)?;
+        let now = unix_now();
+
+        for contact in contacts.iter() {
+            if contact.alias.is_some() || !contact.tags.is_empty() {
+                store.set_contact(&contact.pubky, contact.alias.as_deref(), &contact.tags, now)?;
+            }
+            if matches!(contact.trust, TrustLevel::Verified | TrustLevel::Distrusted) {
+                store.set_trust(&contact.pubky, contact.trust, now)?;
+            }
+        }
+
+        self.audit(
+            AuditAction::PolicyOverride,
+            None,
+            format!("imported {} contacts", contacts.len()),
+        );
+        Ok(contacts.len())
+    }
+
+    /// Mute messages matching a rule
+    ///
+    /// Messages fetched or synced from then on that match the rule are
+    /// marked `muted` rather than dropped; our own messages never are. Mute
+    /// rules are synced with our other devices by `sync_device_settings`.
+    /// Requires a local store.
+    pub fn mute(&self, rule: MuteRule) -> Result<()> {
+        rule.validate()?;
+        let setting = MuteSetting {
+            rule,
+            muted: true,
+            updated_at: unix_now(),
+        };
+        self.store()?
+            .set_mute_setting(&setting.rule.key(), &setting)
+    }
+
+    /// Stop muting messages matching a rule, returning whether it was muted
+    ///
+    /// Requires a local store.
+    pub fn unmute(&self, rule: &MuteRule) -> Result<bool> {
+        let store = self.store()?;
+        let key = rule.key();
+        let Some(setting) = store.mute_settings()?.remove(&key) else {
+            return Ok(false);
+        };
+        if !setting.muted {
+            return Ok(false);
+        }
+
+        store.set_mute_setting(
+            &key,
+            &MuteSetting {
+                muted: false,
+                updated_at: unix_now(),
+                ..setting
+            },
+        )?;
+        Ok(true)
+    }
+
+    /// The mute rules in effect
+    ///
+    /// Requires a local store.
+    pub fn mute_rules(&self) -> Result<Vec<MuteRule>> {
+        Ok(self
+            .store()?
+            .mute_settings()?
+            .into_values()
+            .filter(|setting| setting.muted)
+            .map(|setting| setting.rule)
+            .collect())
+    }
+
+    /// Replace the labels of a conversation, such as `Work` or `Family`
+    ///
+    /// Labels are trimmed, and an empty list removes them all. They are
+    /// synced with our other devices by `sync_device_settings`, shown on
+    /// `ConversationSummary`, and `list_conversations_with_label` lists the
+    /// conversations with a given label. Requires a local store.
+    pub fn set_conversation_labels(&self, other_pubky: &PublicKey, labels: &[&str]) -> Result<()> {
+        let setting = LabelSetting {
+            labels: labels
+                .iter()
+                .map(|label| label.trim().to_string())
+                .collect(),
+            updated_at: unix_now(),
+        };
+        setting.validate()?;
+        self.store()?
+            .set_label_setting(&other_pubky.to_string(), &setting)
+    }
+
+    /// Add a label to a conversation, returning whether it was new
+    ///
+    /// Requires a local store.
+    pub fn add_conversation_label(&self, other_pubky: &PublicKey, label: &str) -> Result<bool> {
+        self.change_conversation_labels(other_pubky, |labels| {
+            labels.insert(label.trim().to_string())
+        })
+    }
+
+    /// Remove a label from a conversation, returning whether it had it
+    ///
+    /// Requires a local store.
+    pub fn remove_conversation_label(&self, other_pubky: &PublicKey, label: &str) -> Result<bool> {
+        self.change_conversation_labels(other_pubky, |labels| labels.remove(label.trim()))
+    }
+
+    /// Apply a change to the labels of a conversation, storing them if
+    /// `change` reports that they changed
+    fn change_conversation_labels(
+        &self,
+        other_pubky: &PublicKey,
+        change: impl FnOnce(&mut BTreeSet<String>) -> bool,
+    ) -> Result<bool> {
+        let store = self.store()?;
+        let peer = other_pubky.to_string();
+        let mut labels = store
+            .label_setting(&peer)?
+            .map(|setting| setting.labels)
+            .unwrap_or_default();
+        if !change(&mut labels) {
+            return Ok(false);
+        }
+
+        let setting = LabelSetting {
+            labels,
+            updated_at: unix_now(),
+        };
+        setting.validate()?;
+        store.set_label_setting(&peer, &setting)?;
+        Ok(true)
+    }
+
+    /// The labels of a conversation, in order
+    ///
+    /// Requires a local store.
+    pub fn conversation_labels(&self, other_pubky: &PublicKey) -> Result<Vec<String>> {
+        Ok(self
+            .store()?
+            .label_setting(&other_pubky.to_string())?
+            .map(|setting| setting.labels.into_iter().collect())
+            .unwrap_or_default())
+    }
+
+    /// Every label on at least one conversation, in order, to show as
+    /// folders
+    ///
+    /// Requires a local store.
+    pub fn labels(&self) -> Result<Vec<String>> {
+        let labels: BTreeSet<String> = self
+            .store()?
+            .label_settings()?
+            .into_values()
+            .flat_map(|setting| setting.labels)
+            .collect();
+        Ok(labels.into_iter().collect())
+    }
+
+    /// Reply automatically while we're away
+    ///
+    /// Replaces any earlier away message. From then on, `SyncService`
+    /// answers the first message each sender writes while the schedule is in
+    /// effect, once per away period (see `AwaySchedule::period_start`). The
+    /// away message is synced with our other devices by
+    /// `sync_device_settings`, and every device derives the same record for
+    /// a reply, so senders get one reply however many devices are syncing.
+    /// Requires a local store.
+    pub fn set_away_message(&self, message: AwayMessage) -> Result<()> {
+        message.validate()?;
+        self.store()?.set_away_setting(&AwaySetting {
+            message: Some(message),
+            updated_at: unix_now(),
+        })
+    }
+
+    /// Stop replying automatically, returning whether an away message was
+    /// set
+    ///
+    /// Requires a local store.
+    pub fn clear_away_message(&self) -> Result<bool> {
+        if self.away_message()?.is_none() {
+            return Ok(false);
+        }
+        self.store()?.set_away_setting(&AwaySetting {
+            message: None,
+            updated_at: unix_now(),
+        })?;
+        Ok(true)
+    }
+
+    /// The away message, if one is set
+    ///
+    /// Requires a local store.
+    pub fn away_message(&self) -> Result<Option<AwayMessage>> {
+        Ok(self
+            .store()?
+            .away_setting()?
+            .and_then(|setting| setting.message))
+    }
+
+    /// Answer a new message with the away message, if we're away and its
+    /// sender was not answered in this away period yet
+    ///
+    /// Only messages that arrive within the period they were sent in are
+    /// answered, and never our own, automatic, or protocol messages. Returns
+    /// the reply, or `None` if none was due.
+    pub(crate) async fn send_away_reply(
+        &self,
+        other_pubky: &PublicKey,
+        message: &DecryptedMessage,
+    ) -> Result<Option<SentMessage>> {
+        let peer = other_pubky.to_string();
+        if self.is_self(other_pubky)
+            || message.sender != peer
+            || !message.verified
+            || message.quarantined
+            || message.muted
+            || message.body.is_cover()
+            || message.body.is_system()
+            || message.body.is_edit()
+            || message.metadata.contains_key(AUTO_REPLY_METADATA_KEY)
+        {
+            return Ok(None);
+        }
+
+        let store = self.store()?;
+        let Some(AwaySetting {
+            message: Some(away),
+            updated_at,
+        }) = store.away_setting()?
+        else {
+            return Ok(None);
+        };
+        let Some(period_start) = away.schedule.period_start(message.timestamp, updated_at) else {
+            return Ok(None);
+        };
+        if away.schedule.period_start(unix_now(), updated_at) != Some(period_start)
+            || store.away_reply(&peer, period_start)?.is_some()
+        {
+            return Ok(None);
+        }
+
+        // Another device of ours may have answered already
+        let msg_id = away_reply_id(&self.keypair, &peer, period_start);
+        let response = self
+            .guarded_get(&self.message_url(other_pubky, &msg_id)?)
+            .await?;
+        let sent = if response.status().is_success() {
+            None
+        } else if response.status() == reqwest::StatusCode::NOT_FOUND {
+            let sent = self
+                .message(other_pubky)
+                .text(away.text)
+                .id(msg_id.clone())
+                .reply_to(message.id.clone())
+                .metadata(AUTO_REPLY_METADATA_KEY, "away")
+                .send()
+                .await?;
+            Some(sent)
+        } else {
+            return Err(anyhow!(
+                "Failed to check for an away reply: {}",
+                response.status()
+            ));
+        };
+
+        store.record_away_reply(&peer, period_start, &msg_id)?;
+        Ok(sent)
+    }
+
+    /// Sync contact settings with our other devices
+    ///
+    /// Merges the encrypted settings record on our homeserver with the local
+    /// store, keeping the latest change to each contact, mute rule,
+    /// conversation's labels, and the away message, and writes the merged
+    /// settings back if this device had newer ones. Returns the number of
+    /// contacts, mute rules, labeled conversations, and away messages updated
+    /// locally. Requires a local store.
+    pub async fn sync_device_settings(&self) -> Result<usize> {
+        let store = self.store()?;
+        let key = device_settings_key(&self.keypair);
+        let url = PubkyUri::new(
+            &self.keypair.public_key(),
+            &device_settings_path(&self.keypair),
+        )
+        .to_string();
+
+        let response = self.guarded_get(&url).await?;
+        let remote = if response.status() == reqwest::StatusCode::NOT_FOUND {
+            DeviceSettings::default()
+        } else if response.status().is_success() {
+            DeviceSettings::open(&response.text().await?, &key)?
+        } else {
+            return Err(anyhow!(
+                "Failed to fetch device settings: {}",
+                response.status()
+            ));
+        };
+
+        let mut merged = DeviceSettings::default();
+        for (peer, level, updated_at) in store.trust_entries()? {
+            merged
+                .trust
+                .insert(peer, TrustSetting { level, updated_at });
+        }
+        merged.mutes = store.mute_settings()?;
+        merged.labels = store.label_settings()?;
+        merged.away = store.away_setting()?;
+
+        let mut updated = 0;
+        for peer in merged.merge(&remote) {
+            let setting = merged.trust[&peer];
+            if store.merge_trust(&peer, setting.level, setting.updated_at)? {
+                updated += 1;
+                let detail = format!("trust set to {} on another device", setting.level.as_str());
+                match PublicKey::try_from(peer.as_str()) {
+                    Ok(pubky) => self.audit(AuditAction::PolicyOverride, Some(&pubky), detail),
+                    Err(_) => self.audit(AuditAction::PolicyOverride, None, detail),
+                }
+            }
+        }
+        for (key, setting) in merged.mutes.iter() {
+            if store.merge_mute_setting(key, setting)? {
+                updated += 1;
+            }
+        }
+        for (peer, setting) in merged.labels.iter() {
+            if store.merge_label_setting(peer, setting)? {
+                updated += 1;
+            }
+        }
+        if let Some(setting) = merged.away.as_ref() {
+            if store.merge_away_setting(setting)? {
+                updated += 1;
+            }
+        }
+
+        if merged != remote {
+            let response = self.http_put(&url).body(merged.seal(&key)?).send().await?;
+            if !response.status().is_success() {
+                return Err(anyhow!(
+                    "Failed to store device settings: {}",
+                    response.status()
+                ));
+            }
+        }
+
+        Ok(updated)
+    }
+
+    /// Save a reply template under `name`, replacing any with that name
+    ///
+    /// Templates are stored encrypted on our homeserver, under a path and
+    /// file names only our keypair can derive, so every device of the
+    /// identity sees them. The text may contain `{placeholder}`s filled in
+    /// by `send_template`.
+    pub async fn save_template(&self, name: &str, text: &str) -> Result<ReplyTemplate> {
+        let template = ReplyTemplate {
+            name: name.trim().to_string(),
+            text: text.to_string(),
+            updated_at: unix_now(),
+        };
+        template.validate()?;
+
+        let key = templates_key(&self.keypair);
+        let response = self
+            .http_put(self.template_url(&key, name))
+            .body(template.seal(&key)?)
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            return Err(anyhow!("Failed to save template: {}", response.status()));
+        }
+
+        Ok(template)
+    }
+
+    /// The reply template saved under `name`, if any
+    pub async fn template(&self, name: &str) -> Result<Option<ReplyTemplate>> {
+        let key = templates_key(&self.keypair);
+        let response = self.guarded_get(&self.template_url(&key, name)).await?;
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        if !response.status().is_success() {
+            return Err(anyhow!("Failed to fetch template: {}", response.status()));
+        }
+
+        Ok(Some(ReplyTemplate::open(&response.text().await?, &key)?))
+    }
+
+    /// Every saved reply template, ordered by name
+    ///
+    /// Records that don't decrypt are skipped.
+    pub async fn templates(&self) -> Result<Vec<ReplyTemplate>> {
+        let key = templates_key(&self.keypair);
+        let dir =
+            PubkyUri::new(&self.keypair.public_key(), &templates_path(&self.keypair)).to_string();
+
+        // A missing directory means no templates were saved yet
+        let pages: Vec<Vec<String>> = self
+            .list_pages(dir, None, false)
+            .try_collect()
+            .await
+            .unwrap_or_default();
+        let urls: Vec<String> = pages.into_iter().flatten().collect();
+
+        let key = &key;
+        let records = stream::iter(urls.iter().map(|url| async move {
+            let response = self.guarded_get(url).await.ok()?;
+            if !response.status().is_success() {
+                return None;
+            }
+            ReplyTemplate::open(&response.text().await.ok()?, key).ok()
+        }))
+        .buffered(BATCH_SIZE)
+        .collect::<Vec<_>>()
+        .await;
+
+        let mut templates: Vec<ReplyTemplate> = records.into_iter().flatten().collect();
+        templates.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(templates)
+    }
+
+    /// Delete the reply template saved under `name`
+    pub async fn delete_template(&self, name: &str) -> Result<()> {
+        let key = templates_key(&self.keypair);
+        let response = self
+            .http_delete(self.template_url(&key, name))
+            .send()
+            .await?;
+        if !response.status().is_success() && response.status() != reqwest::StatusCode::NOT_FOUND {
+            return Err(anyhow!("Failed to delete template: {}", response.status()));
+        }
+        Ok(())
+    }
+
+    /// Send the reply template saved under `name`, with its placeholders
+    /// filled in from `values` (see `ReplyTemplate::render`)
+    pub async fn send_template(
+        &self,
+        recipient: &PublicKey,
+        name: &str,
+        values: &[(&str, &str)],
+    ) -> Result<SentMessage> {
+        let template = self
+            .template(name)
+            .await?
+            .ok_or_else(|| anyhow!("No template named {}", name.trim()))?;
+        self.send_message(recipient, &template.render(values)).await
+    }
+
+    /// URL of the record of the template saved under `name`
+    fn template_url(&self, key: &[u8; 32], name: &str) -> String {
+        PubkyUri::new(
+            &self.keypair.public_key(),
+            &format!(
+                "{}{}",
+                templates_path(&self.keypair),
+                template_file(key, name)
+            ),
+        )
+        .to_string()
+    }
+
+    /// Mark fetched messages that match a mute rule
+    fn apply_mute_rules(
+        &self,
+        store: &LocalStore,
+        messages: &mut [DecryptedMessage],
+    ) -> Result<()> {
+        let rules: Vec<MuteRule> = store
+            .mute_settings()?
+            .into_values()
+            .filter(|setting| setting.muted)
+            .map(|setting| setting.rule)
+            .collect();
+        let filter = MuteFilter::new(&rules);
+        if filter.is_empty() {
+            return Ok(());
+        }
+
+        let own_pubky = self.keypair.public_key().to_string();
+        for message in messages.iter_mut() {
+            message.muted = message.sender != own_pubky && filter.matches(message);
+        }
+        Ok(())
+    }
+
+    /// Mark fetched messages with the trust level of their sender,
+    /// quarantining those from distrusted contacts
+    fn apply_trust(
+        &self,
+        store: &LocalStore,
+        other_pubky: &PublicKey,
+        messages: &mut [DecryptedMessage],
+    ) -> Result<()> {
+        let trust = effective_trust(store, &other_pubky.to_string())?;
+        let own_pubky = self.keypair.public_key().to_string();
+
+        for message in messages.iter_mut() {
+            if message.sender == own_pubky {
+                message.sender_trust = TrustLevel::Verified;
+            } else {
+                message.sender_trust = trust;
+                message.quarantined = trust == TrustLevel::Distrusted;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Move to a new keypair, keeping continuity with our contacts
+    ///
+    /// Publishes a proof signed by both keys on our current homeserver and
+    /// sends it to each of `contacts` as an encrypted rotation notice. Their
+    /// clients carry the trust they placed in us over to the new identity.
+    /// The new keypair should already be signed up with a homeserver; this
+    /// client keeps using the old one.
+    pub async fn rotate_identity(
+        &self,
+        new_keypair: &Keypair,
+        contacts: &[PublicKey],
+    ) -> Result<KeyRotationProof> {
+        let proof = KeyRotationProof::new(&self.keypair, new_keypair, unix_now());
+        proof.verify()?;
+
+        let url = PubkyUri::new(&self.keypair.public_key(), KEY_ROTATION_PATH).to_string();
+        let response = self
+            .http_put(&url)
+            .body(serde_json::to_vec(&proof)?)
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            return Err(anyhow!(
+                "Failed to publish key rotation: {}",
+                response.status()
+            ));
+        }
+
+        let notice = MessageContent::System(SystemMessage::KeyRotation(proof.clone()));
+        for contact in contacts {
+            self.send_protocol_content(contact, &notice).await?;
+        }
+
+        self.audit(
+            AuditAction::KeyRotation,
+            None,
+            format!("rotated identity to {}", proof.new_pubky),
+        );
+
+        Ok(proof)
+    }
+
+    /// Fetch the key rotation a user published, if any
+    ///
+    /// Returns `None` if there is none, or it is not a valid rotation away
+    /// from `pubky`.
+    pub async fn fetch_key_rotation(&self, pubky: &PublicKey) -> Result<Option<KeyRotationProof>> {
+        let url = PubkyUri::new(pubky, KEY_ROTATION_PATH).to_string();
+        let response = self.guarded_get(&url).await?;
+        if !response.status().is_success() {
+            return Ok(None);
+        }
+
+        let proof: KeyRotationProof = match serde_json::from_slice(&response.bytes().await?) {
+            Ok(proof) => proof,
+            Err(_) => return Ok(None),
+        };
+        let valid = proof.old_pubky == pubky.to_string() && proof.verify().is_ok();
+        Ok(valid.then_some(proof))
+    }
+
+    /// The identity a contact rotated to, as learned from their rotation
+    /// notices. Requires a local store.
+    pub fn key_successor(&self, other_pubky: &PublicKey) -> Result<Option<PublicKey>> {
+        self.store()?
+            .key_successor(&other_pubky.to_string())?
+            .map(|pubky| {
+                PublicKey::try_from(pubky.as_str())
+                    .map_err(|e| anyhow!("Invalid successor pubky: {}", e))
+            })
+            .transpose()
+    }
+
+    /// Senders of pending message requests, most recently active first.
+    /// Requires a local store.
+    pub fn list_message_requests(&self) -> Result<Vec<MessageRequest>> {
+        self.store()?.pending_message_requests()
+    }
+
+    /// Up to `limit` queued timeline events, oldest first, see
+    /// `with_event_queue`
+    ///
+    /// Events stay queued until acknowledged, so they come back after a
+    /// restart. Requires a local store.
+    pub fn pending_events(&self, limit: usize) -> Result<Vec<QueuedEvent>> {
+        self.store()?.pending_events(limit)
+    }
+
+    /// Acknowledge queued events up to and including `seq`, returning how
+    /// many were dropped. Requires a local store.
+    pub fn ack_events(&self, seq: u64) -> Result<usize> {
+        self.store()?.ack_events(seq)
+    }
+
+    /// Accept a sender's message request, so their messages are treated like
+    /// any other conversation. Requires a local store.
+    pub fn accept_message_request(&self, other_pubky: &PublicKey) -> Result<()> {
+        self.set_request_status(other_pubky, RequestStatus::Accepted)
+    }
+
+    /// Decline a sender's message request, dropping their messages from
+    /// future fetches. Requires a local store.
+    pub fn decline_message_request(&self, other_pubky: &PublicKey) -> Result<()> {
+        self.set_request_status(other_pubky, RequestStatus::Declined)
+    }
+
+    fn set_request_status(&self, other_pubky: &PublicKey, status: RequestStatus) -> Result<()> {
+        self.store()?
+            .set_request_status(&other_pubky.to_string(), status, unix_now())?;
+
+        let verb = match status {
+            RequestStatus::Accepted => "accepted",
+            RequestStatus::Declined => "declined",
+            RequestStatus::Pending => "reopened",
+        };
+        self.audit(
+            AuditAction::PolicyOverride,
+            Some(other_pubky),
+            format!("message request {}", verb),
+        );
+        Ok(())
+    }
+
+    /// Route fetched messages from strangers through message requests
+    ///
+    /// Conversations we wrote to are never requests, and replying to a
+    /// pending request accepts it. Otherwise the peer's messages open or
+    /// refresh a pending request and are marked `message_request`, or are
+    /// dropped if the request was declined.
+    fn apply_message_requests(
+        &self,
+        store: &LocalStore,
+        other_pubky: &PublicKey,
+        messages: &mut Vec<DecryptedMessage>,
+    ) -> Result<()> {
+        if !self.message_requests {
+            return Ok(());
+        }
+
+        let peer = other_pubky.to_string();
+        let status = store.request_status(&peer)?;
+        match status {
+            Some(RequestStatus::Accepted) => return Ok(()),
+            Some(RequestStatus::Declined) => {
+                messages.retain(|message| message.sender != peer);
+                return Ok(());
+            }
+            Some(RequestStatus::Pending) | None => {}
+        }
+
+        let own_pubky = self.keypair.public_key().to_string();
+        let replied = messages.iter().any(|message| message.sender == own_pubky)
+            || store.has_messages_from(&peer, &own_pubky)?;
+        if replied {
+            if status == Some(RequestStatus::Pending) {
+                self.set_request_status(other_pubky, RequestStatus::Accepted)?;
+            }
+            return Ok(());
+        }
+
+        let timestamps = messages
+            .iter()
+            .filter(|message| message.sender == peer)
+            .map(|message| message.timestamp);
+        let (Some(first_seen), Some(last_seen)) = (timestamps.clone().min(), timestamps.max())
+        else {
+            return Ok(());
+        };
+        store.record_message_request(&peer, first_seen, last_seen)?;
+
+        for message in messages.iter_mut().filter(|message| message.sender == peer) {
+            message.message_request = true;
+        }
+        Ok(())
+    }
+
+    /// Run the spam filter over the peer's messages, dropping blocked ones
+    /// and holding others back as message requests
+    fn apply_spam_filter(
+        &self,
+        store: Option<&LocalStore>,
+        other_pubky: &PublicKey,
+        messages: &mut Vec<DecryptedMessage>,
+    ) -> Result<()> {
+        let Some(filter) = self.spam_filter.as_ref() else {
+            return Ok(());
+        };
+        let peer = other_pubky.to_string();
+
+        // Count activity over fetched and stored messages, by ID so messages
+        // that are both are counted once
+        let mut times: HashMap<String, u64> = messages
+            .iter()
+            .filter(|message| message.sender == peer)
+            .map(|message| (message.id.clone(), message.timestamp))
+            .collect();
+        let Some(oldest) = times.values().min().copied() else {
+            return Ok(());
+        };
+        if let Some(store) = store {
+            times.extend(store.message_times_since(
+                &peer,
+                &peer,
+                oldest.saturating_sub(ACTIVITY_DAY_SECS),
+            )?);
+        }
+        // Each message is judged by the activity up to when it was sent, so
+        // a burst doesn't taint the messages before it. Messages sent in the
+        // same second are ordered by ID.
+        let activity_at = |message: &DecryptedMessage| {
+            let count_since = |window: u64| {
+                let since = message.timestamp.saturating_sub(window);
+                times
+                    .iter()
+                    .filter(|(id, &timestamp)| {
+                        timestamp >= since
+                            && (timestamp, id.as_str()) <= (message.timestamp, message.id.as_str())
+                    })
+                    .count()
+            };
+            SenderActivity {
+                last_hour: count_since(ACTIVITY_HOUR_SECS),
+                last_day: count_since(ACTIVITY_DAY_SECS),
+            }
+        };
+
+        let mut requested = Vec::new();
+        messages.retain_mut(|message| {
+            if message.sender != peer {
+                return true;
+            }
+            let activity = activity_at(message);
+            match filter.classify(message, &activity) {
+                SpamVerdict::Inbox => true,
+                SpamVerdict::Request => {
+                    message.message_request = true;
+                    requested.push(message.timestamp);
+                    true
+                }
+                SpamVerdict::Block => false,
+            }
+        });
+
+        if let (Some(store), Some(first_seen), Some(last_seen)) = (
+            store,
+            requested.iter().min().copied(),
+            requested.iter().max().copied(),
+        ) {
+            store.record_message_request(&peer, first_seen, last_seen)?;
+        }
+        Ok(())
+    }
+
+    /// Change an application-defined setting of a conversation
+    ///
+    /// Sent to the peer as a system message, so both sides' clients record
+    /// it. Requires a local store.
+    pub async fn set_conversation_setting(
+        &self,
+        other_pubky: &PublicKey,
+        key: &str,
+        value: serde_json::Value,
+    ) -> Result<SentMessage> {
+        if key == READ_ONLY_SETTING {
+            return Err(anyhow!(
+                "Use set_read_only to make a conversation read-only"
+            ));
+        }
+        self.send_system_message(
+            other_pubky,
+            SystemMessage::SettingChanged {
+                key: key.to_string(),
+                value,
+            },
+        )
+        .await
+    }
+
+    /// Make new messages in a conversation disappear after `days`, or stop
+    /// with `None`
+    ///
+    /// Applies to messages sent by either participant once their client has
+    /// fetched the change, unless a message sets its own lifetime with
+    /// `MessageBuilder::expires_in`. Requires a local store.
+    pub async fn set_message_expiry(
+        &self,
+        other_pubky: &PublicKey,
+        days: Option<u64>,
+    ) -> Result<SentMessage> {
+        self.send_system_message(
+            other_pubky,
+            SystemMessage::ExpiryChanged {
+                expires_in_days: days,
+            },
+        )
+        .await
+    }
+
+    /// Encrypt new messages in a conversation with `suite`
+    ///
+    /// Sent to the peer as a conversation setting, so both sides switch once
+    /// their clients have fetched the change. Overrides
+    /// `with_cipher_suite` for the conversation. Requires a local store.
+    pub async fn set_cipher_suite(
+        &self,
+        other_pubky: &PublicKey,
+        suite: CipherSuite,
+    ) -> Result<SentMessage> {
+        self.set_conversation_setting(
+            other_pubky,
+            CIPHER_SUITE_SETTING,
+            serde_json::json!(suite.as_str()),
+        )
+        .await
+    }
+
+    /// Make a conversation read-only, or writable again
+    ///
+    /// Sends to a read-only conversation fail with
+    /// `MessengerError::ConversationReadOnly` until the flag is cleared,
+    /// which guards archived or closed threads against accidental replies.
+    /// Messages can still be fetched, and the client still sends system
+    /// messages. The flag is kept in the conversation settings on this
+    /// device only; the peer is not told. Requires a local store.
+    pub fn set_read_only(&self, other_pubky: &PublicKey, read_only: bool) -> Result<()> {
+        self.store()?.set_conversation_setting(
+            &other_pubky.to_string(),
+            READ_ONLY_SETTING,
+            &serde_json::json!(read_only),
+            unix_now(),
+        )?;
+        Ok(())
+    }
+
+    /// Whether a conversation is read-only, see `set_read_only`
+    pub fn is_read_only(&self, other_pubky: &PublicKey) -> bool {
+        self.store
+            .as_ref()
+            .filter(|store| !store.is_locked())
+            .and_then(|store| store.conversation_settings(&other_pubky.to_string()).ok())
+            .and_then(|settings| settings.get(READ_ONLY_SETTING)?.as_bool())
+            .unwrap_or(false)
+    }
+
+    /// Fail with `MessengerError::ConversationReadOnly` if the conversation
+    /// is read-only
+    fn ensure_writable(&self, other_pubky: &PublicKey) -> Result<()> {
+        if self.is_read_only(other_pubky) {
+            return Err(MessengerError::ConversationReadOnly {
+                peer: other_pubky.to_string(),
+            }
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Take a content-addressed snapshot of a conversation
+    ///
+    /// Fetches the conversation and digests every message, see
+    /// `ConversationSnapshot`. Keep it to verify a backup or compare devices
+    /// later with `diff`.
+    pub async fn snapshot(&self, other_pubky: &PublicKey) -> Result<ConversationSnapshot> {
+        let messages = self.get_messages(other_pubky).await?;
+        Ok(ConversationSnapshot::from_messages(
+            &other_pubky.to_string(),
+            &messages,
+            unix_now(),
+        ))
+    }
+
+    /// Messages added, removed, and edited in a conversation since a
+    /// snapshot was taken
+    ///
+    /// Fetches the snapshot's conversation again. Messages that expired or
+    /// were deleted since count as removed. Fails if the snapshot was altered
+    /// (see `ConversationSnapshot::verify`).
+    pub async fn diff(&self, snapshot: &ConversationSnapshot) -> Result<SnapshotDiff> {
+        if !snapshot.verify() {
+            return Err(anyhow!("Snapshot root does not match its messages"));
+        }
+        let other_pubky = PublicKey::try_from(snapshot.peer.as_str())
+            .map_err(|e| anyhow!("Invalid snapshot peer: {}", e))?;
+
+        let messages = self.get_messages(&other_pubky).await?;
+        Ok(snapshot.diff_messages(&messages))
+    }
+
+    /// Send the peer a digest of the conversation as we see it
+    ///
+    /// Syncs the conversation first, then hashes every record dated up to
+    /// `TRANSCRIPT_GRACE_SECS` before the sync, or the coarse timestamp
+    /// bucket if longer (see `TranscriptHash`), and sends the digest as a
+    /// system message. When the peer's client syncs it, it hashes its
+    /// own view up to the same time and raises
+    /// `NotificationEvent::TranscriptMismatch` if they differ. Call it
+    /// periodically, or let `SyncService::with_transcript_checks` do so.
+    /// Requires a local store.
+    pub async fn send_transcript_hash(&self, other_pubky: &PublicKey) -> Result<SentMessage> {
+        let up_to = unix_now().saturating_sub(1);
+        self.sync_conversation(other_pubky).await?;
+        self.send_synced_transcript_hash(other_pubky, up_to).await
+    }
+
+    /// Send the digest of the records a sync started after `synced_up_to`
+    /// has just recorded, leaving out the newest ones that may still be
+    /// arriving
+    pub(crate) async fn send_synced_transcript_hash(
+        &self,
+        other_pubky: &PublicKey,
+        synced_up_to: u64,
+    ) -> Result<SentMessage> {
+        let margin = self
+            .coarse_timestamps
+            .map_or(0, |bucket| bucket.as_secs_f64().ceil() as u64)
+            .max(TRANSCRIPT_GRACE_SECS);
+        let up_to = synced_up_to.saturating_sub(margin);
+        let entries = self.store()?.sync_entries(&other_pubky.to_string())?;
+        let hash = TranscriptHash::from_entries(entries.values(), up_to);
+        self.send_system_message(other_pubky, SystemMessage::TranscriptHash(hash))
+            .await
+    }
+
+    /// The settings of a conversation, as changed by either participant.
+    /// Requires a local store.
+    pub fn conversation_settings(
+        &self,
+        other_pubky: &PublicKey,
+    ) -> Result<BTreeMap<String, serde_json::Value>> {
+        self.store()?
+            .conversation_settings(&other_pubky.to_string())
+    }
+
+    /// Default lifetime in days of new messages to a peer, if the
+    /// conversation has disappearing messages on
+    pub(crate) fn conversation_expiry(&self, other_pubky: &PublicKey) -> Option<u64> {
+        let store = self.store.as_ref().filter(|store| !store.is_locked())?;
+        store
+            .conversation_settings(&other_pubky.to_string())
+            .ok()?
+            .get(EXPIRY_SETTING)?
+            .as_u64()
+    }
+
+    /// Cipher suite for new messages to a peer: the conversation's choice if
+    /// it made one, the client default otherwise
+    fn conversation_cipher(&self, other_pubky: &PublicKey) -> CipherSuite {
+        self.store
+            .as_ref()
+            .filter(|store| !store.is_locked())
+            .and_then(|store| store.conversation_settings(&other_pubky.to_string()).ok())
+            .and_then(|settings| {
+                settings
+                    .get(CIPHER_SUITE_SETTING)?
+                    .as_str()
+                    .and_then(CipherSuite::parse)
+            })
+            .unwrap_or(self.cipher_suite)
+    }
+
+    /// Send a system message and apply it locally right away
+    async fn send_system_message(
+        &self,
+        other_pubky: &PublicKey,
+        system: SystemMessage,
+    ) -> Result<SentMessage> {
+        let store = self.store()?;
+        let sent = self
+            .send_protocol_content(other_pubky, &MessageContent::System(system.clone()))
+            .await?;
+
+        if let Some((key, value)) = setting_change(&system) {
+            store.set_conversation_setting(
+                &other_pubky.to_string(),
+                key,
+                &value,
+                sent.timestamp,
+            )?;
+        }
+
+        Ok(sent)
+    }
+
+    /// Act on the system messages of a conversation
+    ///
+    /// Setting changes by either participant are recorded in the local
+    /// store, except the peer's while their message request is pending or
+    /// declined (see `with_message_requests`), so strangers can't change a
+    /// conversation we never accepted. Rotation notices from the contact
+    /// carry their explicit trust level over to the new identity and raise
+    /// `NotificationEvent::KeyRotated`. Group membership changes are left to
+    /// the application.
+    fn apply_system_messages(
+        &self,
+        store: &LocalStore,
+        other_pubky: &PublicKey,
+        messages: &[DecryptedMessage],
+    ) -> Result<()> {
+        let peer = other_pubky.to_string();
+        let own_pubky = self.keypair.public_key().to_string();
+        let peer_is_stranger = self.message_requests
+            && matches!(
+                store.request_status(&peer)?,
+                Some(RequestStatus::Pending | RequestStatus::Declined)
+            );
+
+        for message in messages {
+            let MessageContent::System(system) = &message.body else {
+                continue;
+            };
+            if !message.verified || (message.sender != peer && message.sender != own_pubky) {
+                continue;
+            }
+
+            if let Some((key, value)) = setting_change(system) {
+                if message.sender == peer && peer_is_stranger {
+                    continue;
+                }
+                // Read-only is a local flag the peer can't set for us
+                if key != READ_ONLY_SETTING {
+                    store.set_conversation_setting(&peer, key, &value, message.timestamp)?;
+                }
+                continue;
+            }
+
+            let SystemMessage::KeyRotation(proof) = system else {
+                continue;
+            };
+            if message.sender != peer || proof.old_pubky != peer || proof.verify().is_err() {
+                continue;
+            }
+            if !store.record_key_rotation(&peer, &proof.new_pubky, proof.rotated_at)? {
+                continue;
+            }
+
+            if let Some(level) = store.explicit_trust(&peer)? {
+                store.set_trust(&proof.new_pubky, level, unix_now())?;
+            }
+            self.audit(
+                AuditAction::KeyRotation,
+                Some(other_pubky),
+                format!("contact rotated identity to {}", proof.new_pubky),
+            );
+            self.emit(NotificationEvent::KeyRotated {
+                peer: peer.clone(),
+                new_pubky: proof.new_pubky.clone(),
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Compare the peer's transcript hashes among synced messages with our
+    /// own view of the conversation, once it's recorded
+    ///
+    /// Returns a `NotificationEvent::TranscriptMismatch` for every verified
+    /// hash from the peer that differs from ours over the same period.
+    fn check_transcript_hashes(
+        &self,
+        store: &LocalStore,
+        peer: &str,
+        messages: &[DecryptedMessage],
+    ) -> Result<Vec<NotificationEvent>> {
+        let remote: Vec<(&DecryptedMessage, &TranscriptHash)> = messages
+            .iter()
+            .filter(|message| message.verified && message.sender == peer)
+            .filter_map(|message| match &message.body {
+                MessageContent::System(SystemMessage::TranscriptHash(hash)) => {
+                    Some((message, hash))
+                }
+                _ => None,
+            })
+            .collect();
+        if remote.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let entries = store.sync_entries(peer)?;
+        let mut mismatches = Vec::new();
+        for (message, hash) in remote {
+            let local = TranscriptHash::from_entries(entries.values(), hash.up_to);
+            if local.hash == hash.hash {
+                continue;
+            }
+            mismatches.push(NotificationEvent::TranscriptMismatch {
+                peer: peer.to_string(),
+                message_id: message.id.clone(),
+                up_to: hash.up_to,
+                local_records: local.records,
+                remote_records: hash.records,
+            });
+        }
+        Ok(mismatches)
+    }
+
+    /// List the messages of a conversation lazily, one listing page at a time
+    ///
+    /// Entries from our side of the conversation come first, then the other
+    /// participant's. Pages of `LIST_PAGE_SIZE` entries are only requested as
+    /// the stream is consumed, so nothing beyond the current page is held in
+    /// memory. A failed listing request is yielded as an error.
+    pub fn list_message_entries(
+        &self,
+        other_pubky: &PublicKey,
+    ) -> Result<BoxStream<'_, Result<MessageEntry>>> {
+        self.list_entries(other_pubky, None)
+    }
+
+    /// List the messages of a conversation sent within a time range
+    ///
+    /// Both bounds are inclusive Unix timestamps in seconds. Message IDs start
+    /// with their send time, so the listing starts at `since` and stops after
+    /// `until` instead of walking the whole conversation. Messages stored
+    /// before IDs were timestamp-prefixed are not returned.
+    pub fn list_message_entries_between(
+        &self,
+        other_pubky: &PublicKey,
+        since: u64,
+        until: u64,
+    ) -> Result<BoxStream<'_, Result<MessageEntry>>> {
+        if since > until {
+            return Err(anyhow!("Invalid time range"));
+        }
+
+        let since_ms = since.saturating_mul(1000);
+        let until_ms = until.saturating_mul(1000).saturating_add(999);
+        self.list_entries(other_pubky, Some((since_ms, until_ms)))
+    }
+
+    /// List message entries on both sides of a conversation, optionally
+    /// restricted to an inclusive range of ID timestamps in milliseconds
+    fn list_entries(
+        &self,
+        other_pubky: &PublicKey,
+        range_ms: Option<(u64, u64)>,
+    ) -> Result<BoxStream<'_, Result<MessageEntry>>> {
+        let paths = self.conversation_paths(other_pubky)?;
+
+        let mut listings = Vec::new();
+        for owner in self.conversation_owners(other_pubky) {
+            for private_path in paths.iter() {
+                listings.push((owner.clone(), private_path.clone()));
+            }
+        }
+
+        let entries = stream::iter(listings)
+            .map(move |(owner, private_path)| self.path_entries(&owner, private_path, range_ms))
+            .flatten();
+
+        Ok(entries.boxed())
+    }
+
+    /// List the message entries stored by `owner` under one conversation
+    /// path, optionally restricted to an inclusive range of ID timestamps in
+    /// milliseconds
+    ///
+    /// Listings are ordered by key, so a restricted listing starts at the
+    /// first ID of the range and stops after the last one.
+    fn path_entries(
+        &self,
+        owner: &PublicKey,
+        private_path: String,
+        range_ms: Option<(u64, u64)>,
+    ) -> BoxStream<'_, Result<MessageEntry>> {
+        let base = format!("pubky://{}{}", owner, private_path);
+        let start = range_ms.map(|(since_ms, _)| {
+            format!("{}{:0width$}", base, since_ms, width = TIMESTAMP_MS_DIGITS)
+        });
+
+        self.list_pages(base, start, false)
+            .map_ok(move |page| {
+                let entries: Vec<Result<MessageEntry>> = page
+                    .into_iter()
+                    .filter_map(|url| {
+                        let id = message_id_from_url(&url, &private_path)?;
+                        Some(Ok(MessageEntry { id, url }))
+                    })
+                    .collect();
+                stream::iter(entries)
+            })
+            .try_flatten()
+            .try_filter(move |entry| {
+                let in_range = match range_ms {
+                    Some((since_ms, _)) => PrivateMessage::id_timestamp_ms(&entry.id)
+                        .is_some_and(|timestamp_ms| timestamp_ms >= since_ms),
+                    None => true,
+                };
+                future::ready(in_range)
+            })
+            .try_take_while(move |entry| {
+                let before_end = match range_ms {
+                    Some((_, until_ms)) => PrivateMessage::id_timestamp_ms(&entry.id)
+                        .map_or(true, |timestamp_ms| timestamp_ms <= until_ms),
+                    None => true,
+                };
+                future::ready(Ok(before_end))
+            })
+            .boxed()
+    }
+
+    /// List one page of the messages of a conversation
+    ///
+    /// Entries from both sides of the conversation are merged in ID order,
+    /// which is send order, oldest first or newest first with
+    /// `ListOptions::reverse`. Each side is listed only as far as the page
+    /// needs, so asking for the newest 50 entries of a long conversation
+    /// doesn't walk all of it. Pass the ID of the last entry as the cursor of
+    /// the next page.
+    pub async fn list_message_page(
+        &self,
+        other_pubky: &PublicKey,
+        options: &ListOptions,
+    ) -> Result<Vec<MessageEntry>> {
+        if let Some(cursor) = options.cursor.as_deref() {
+            PrivateMessage::validate_id(cursor)?;
+        }
+
+        // Small pages only ask the homeserver for as many entries as needed
+        let page_size = options.limit.map_or(LIST_PAGE_SIZE, |limit| {
+            limit.clamp(1, LIST_PAGE_SIZE as usize) as u16
+        });
+
+        let paths = self.conversation_paths(other_pubky)?;
+        let mut listings = Vec::new();
+        for owner in self.conversation_owners(other_pubky) {
+            for private_path in paths.iter() {
+                let dir = PubkyUri::new(&owner, private_path);
+                let start = options
+                    .cursor
+                    .as_ref()
+                    .map(|cursor| dir.join(&format!("{}.json", cursor)).to_string());
+
+                let entries = self
+                    .list_pages_of(dir.to_string(), start, options.reverse, page_size)
+                    .map_ok(|page| stream::iter(page.into_iter().map(Ok::<_, anyhow::Error>)))
+                    .try_flatten()
+                    .try_filter_map(move |url| {
+                        let entry = message_id_from_url(&url, private_path)
+                            .map(|id| MessageEntry { id, url });
+                        future::ready(Ok(entry))
+                    })
+                    .take(options.limit.unwrap_or(usize::MAX))
+                    .try_collect::<Vec<_>>();
+                listings.push(entries);
+            }
+        }
+
+        let mut entries = Vec::new();
+        for listing in join_all(listings).await {
+            entries.extend(listing?);
+        }
+        entries.sort_by(|a, b| a.id.cmp(&b.id));
+        if options.reverse {
+            entries.reverse();
+        }
+        if let Some(limit) = options.limit {
+            entries.truncate(limit);
+        }
+
+        Ok(entries)
+    }
+
+    /// Summarize conversations with many contacts for a chat list
+    ///
+    /// Conversations are listed `CONVERSATION_LIST_CONCURRENCY` at a time and
+    /// yielded as each one completes, so a list can fill in progressively
+    /// instead of waiting for the slowest homeserver. Our own homeserver is
+    /// resolved once up front, and every peer's pkarr resolution goes
+    /// through the client's cache, so it is reused by later calls. A
+    /// conversation that fails is yielded as an error naming its peer;
+    /// the others carry on. Duplicate peers are summarized once.
+    ///
+    /// Saved Messages, the conversation with our own pubky, is included
+    /// once it holds a message, whether or not `peers` names it.
+    pub fn list_conversations(
+        &self,
+        peers: &[PublicKey],
+    ) -> BoxStream<'_, Result<ConversationSummary>> {
+        let own_pubky = self.keypair.public_key();
+        let listed_self = peers.contains(&own_pubky);
+        let peers = std::iter::once(&own_pubky).chain(peers).cloned().collect();
+        self.summarize_conversations(peers, !listed_self)
+    }
+
+    /// Summarize the conversations among `peers` that have a label, as
+    /// `list_conversations` does
+    ///
+    /// Conversations are filtered by their labels in the local store before
+    /// anything is fetched. Saved Messages is included if it has the label.
+    /// Requires a local store.
+    pub fn list_conversations_with_label(
+        &self,
+        peers: &[PublicKey],
+        label: &str,
+    ) -> BoxStream<'_, Result<ConversationSummary>> {
+        let settings = match self.store().and_then(|store| store.label_settings()) {
+            Ok(settings) => settings,
+            Err(e) => return stream::once(future::ready(Err(e))).boxed(),
+        };
+        let label = label.trim();
+        let own_pubky = self.keypair.public_key();
+        let peers = peers
+            .iter()
+            .chain(std::iter::once(&own_pubky))
+            .filter(|peer| {
+                settings
+                    .get(&peer.to_string())
+                    .is_some_and(|setting| setting.labels.contains(label))
+            })
+            .cloned()
+            .collect();
+        self.summarize_conversations(peers, false)
+    }
+
+    /// Summarize conversations concurrently, yielding each as it completes
+    ///
+    /// With `implicit_self`, Saved Messages was added without being asked
+    /// for and is skipped while empty.
+    fn summarize_conversations(
+        &self,
+        peers: Vec<PublicKey>,
+        implicit_self: bool,
+    ) -> BoxStream<'_, Result<ConversationSummary>> {
+        let own_pubky = self.keypair.public_key();
+
+        let mut unique = HashSet::new();
+        let peers: Vec<PublicKey> = peers
+            .into_iter()
+            .filter(|peer| unique.insert(peer.to_string()))
+            .collect();
+
+        stream::once(async move { self.client.get_homeserver(&own_pubky).await })
+            .map(move |_| {
+                stream::iter(peers.clone())
+                    .map(move |peer| async move {
+                        // Saved Messages nobody asked for only shows up
+                        // when there is something in it
+                        let implicit = implicit_self && self.is_self(&peer);
+                        match self.conversation_summary(peer).await {
+                            Ok(summary) if implicit && summary.last_message.is_none() => None,
+                            Err(_) if implicit => None,
+                            summary => Some(summary),
+                        }
+                    })
+                    .buffer_unordered(CONVERSATION_LIST_CONCURRENCY)
+                    .filter_map(future::ready)
+            })
+            .flatten()
+            .boxed()
+    }
+
+    /// Find the newest readable message of a conversation
+    async fn conversation_summary(&self, other_pubky: PublicKey) -> Result<ConversationSummary> {
+        let options = ListOptions {
+            limit: Some(SUMMARY_LOOKBACK),
+            reverse: true,
+            ..ListOptions::default()
+        };
+        let fail = |e: anyhow::Error| anyhow!("Conversation with {} failed: {}", other_pubky, e);
+        let entries = self
+            .list_message_page(&other_pubky, &options)
+            .await
+            .map_err(fail)?;
+
+        let mut last_message = None;
+        for entry in entries.iter() {
+            let message = self
+                .fetch_message(&other_pubky, entry)
+                .await
+                .map_err(fail)?;
+            if let Some(message) = message {
+                if !matches!(message.body, MessageContent::Edit(_)) {
+                    last_message = Some(message);
+                    break;
+                }
+            }
+        }
+
+        // Labels are only known with an unlocked local store
+        let labels = self
+            .store
+            .as_ref()
+            .filter(|store| !store.is_locked())
+            .and_then(|store| store.label_setting(&other_pubky.to_string()).ok()?)
+            .map(|setting| setting.labels.into_iter().collect())
+            .unwrap_or_default();
+
+        Ok(ConversationSummary {
+            peer: other_pubky.to_string(),
+            conversation_id: self.conversation_id(&other_pubky),
+            saved_messages: self.is_self(&other_pubky),
+            labels,
+            last_message,
+        })
+    }
+
+    /// Fetch and decrypt a message from a listing entry
+    ///
+    /// Returns `None` if the message no longer exists, has expired, or is
+    /// cover traffic.
+    pub async fn fetch_message(
+        &self,
+        other_pubky: &PublicKey,
+        entry: &MessageEntry,
+    ) -> Result<Option<DecryptedMessage>> {
+        let message = self
+            .fetch_record(&entry.url, other_pubky, &entry.id)
+            .await?;
+        let now = unix_now();
+        Ok(message.filter(|message| !message.body.is_cover() && !message.is_expired(now)))
+    }
+
+    /// Stream the pages of a homeserver listing, following the cursor
+    ///
+    /// Listing starts after `start` if given, otherwise at the beginning, or
+    /// at the end when `reverse` is set.
+    fn list_pages(
+        &self,
+        base: String,
+        start: Option<String>,
+        reverse: bool,
+    ) -> BoxStream<'_, Result<Vec<String>>> {
+        self.list_pages_of(base, start, reverse, LIST_PAGE_SIZE)
+    }
+
+    /// `list_pages` with pages of at most `page_size` entries
+    fn list_pages_of(
+        &self,
+        base: String,
+        start: Option<String>,
+        reverse: bool,
+        page_size: u16,
+    ) -> BoxStream<'_, Result<Vec<String>>> {
+        // The state is the cursor of the next page, or `None` once done
+        stream::try_unfold(Some(start), move |state| {
+            let base = base.clone();
+            async move {
+                let Some(cursor) = state else {
+                    return Ok(None);
+                };
+
+                let page = self
+                    .guarded_list(&base, Some(page_size), cursor.as_deref(), reverse)
+                    .await?;
+
+                let next = if page.len() < page_size as usize {
+                    None
+                } else {
+                    page.last().cloned().map(Some)
+                };
+
+                Ok(Some((page, next)))
+            }
+        })
+        .boxed()
+    }
+
+    /// Every URL under `base`, across all pages of the listing
+    ///
+    /// A directory that doesn't exist lists as empty; any other listing
+    /// error is returned.
+    async fn list_all(&self, base: String) -> Result<Vec<String>> {
+        match self.list_pages(base, None, false).try_concat().await {
+            Ok(urls) => Ok(urls),
+            Err(e) if is_not_found(&e) => Ok(Vec::new()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Key of the circuit breaker guarding `owner`'s URLs
+    ///
+    /// That's their homeserver, so identities hosted together share a
+    /// circuit and a peer who moved homeservers gets a fresh one. Owners
+    /// whose homeserver can't be resolved are keyed by their pubky.
+    async fn breaker_key(&self, owner: &str) -> String {
+        if let Some(homeserver) = self.breaker.homeserver_of(owner) {
+            return homeserver;
+        }
+        let Ok(public_key) = PublicKey::try_from(owner) else {
+            return owner.to_string();
+        };
+        match self.client.get_homeserver(&public_key).await {
+            Some(homeserver) => {
+                self.breaker.remember_homeserver(owner, &homeserver);
+                homeserver
+            }
+            None => owner.to_string(),
+        }
+    }
+
+    /// Fail fast while the circuit of `owner`'s homeserver is open
+    async fn check_breaker(&self, owner: &str) -> Result<()> {
+        let host = self.breaker_key(owner).await;
+        self.breaker.check(&host, owner)
+    }
+
+    /// Let a request to `owner`'s homeserver through the circuit breaker,
+    /// returning the key to record its outcome under
+    async fn admit_request(&self, owner: &str) -> Result<String> {
+        let host = self.breaker_key(owner).await;
+        self.breaker.admit(&host, owner)?;
+        Ok(host)
+    }
+
+    /// GET a URL through the circuit breaker of its homeserver
+    ///
+    /// Transport errors and 5xx responses count as failures.
+    async fn guarded_get(&self, url: &str) -> Result<reqwest::Response> {
+        let host = &self.admit_request(uri::host_of(url)).await?;
+
+        let started = Instant::now();
+        let result = self.http_get(url).send().await;
+        let ok = result
+            .as_ref()
+            .is_ok_and(|response| !response.status().is_server_error());
+        self.record_request("get", started.elapsed(), ok);
+
+        match result {
+            Ok(response) => {
+                if response.status().is_server_error() {
+                    self.breaker.record_failure(host);
+                } else {
+                    self.breaker.record_success(host);
+                }
+                Ok(response)
+            }
+            Err(e) => {
+                self.breaker.record_failure(host);
+                Err(anyhow::Error::from(e).context(self.request_failed(url, None)))
+            }
+        }
+    }
+
+    /// List a directory through the circuit breaker of its homeserver
+    ///
+    /// Errors count as failures unless the homeserver answered with a 4xx
+    /// status, which shows it is up.
+    async fn guarded_list(
+        &self,
+        url: &str,
+        limit: Option<u16>,
+        cursor: Option<&str>,
+        reverse: bool,
+    ) -> Result<Vec<String>> {
+        let host = &self.admit_request(uri::host_of(url)).await?;
+
+        // The query `pubky::Client::list` would build, sent through
+        // `http_get` so that listings carry our `User-Agent` too
+        let mut list_url = reqwest::Url::parse(url)?;
+        {
+            let mut query = list_url.query_pairs_mut();
+            if reverse {
+                query.append_key_only("reverse");
+            }
+            if let Some(limit) = limit {
+                query.append_pair("limit", &limit.to_string());
+            }
+            if let Some(cursor) = cursor {
+                query.append_pair("cursor", cursor);
+            }
+        }
+
+        let started = Instant::now();
+        let result: Result<Vec<String>> = async {
+            let response = self.http_get(list_url).send().await?.error_for_status()?;
+            let body = response.text().await?;
+            Ok(body.lines().map(str::to_string).collect())
+        }
+        .await;
+        self.stats.record("list", started.elapsed(), result.is_ok());
+
+        match result {
+            Ok(urls) => {
+                self.breaker.record_success(host);
+                self.record_connectivity(true);
+                self.stats
+                    .record_received(urls.iter().map(|url| url.len()).sum());
+                Ok(urls)
+            }
+            Err(e) => {
+                let status = e
+                    .chain()
+                    .filter_map(|cause| cause.downcast_ref::<reqwest::Error>())
+                    .find_map(|error| error.status());
+                if status.is_some_and(|status| status.is_client_error()) {
+                    self.breaker.record_success(host);
+                } else {
+                    self.breaker.record_failure(host);
+                }
+                self.record_connectivity(status.is_some_and(|status| !status.is_server_error()));
+                let mut failure = self.request_failed(url, None);
+                if let MessengerError::RequestFailed { status: code, .. } = &mut failure {
+                    *code = status.map(|status| status.as_u16());
+                }
+                Err(e.context(failure))
+            }
+        }
+    }
+
+    /// Describe a failed request to `url`, with the status and `Retry-After`
+    /// of the response if the homeserver answered
+    fn request_failed(&self, url: &str, response: Option<&reqwest::Response>) -> MessengerError {
+        let owner = uri::host_of(url).to_string();
+        let homeserver = if owner == self.keypair.public_key().to_string() {
+            Homeserver::Ours
+        } else {
+            Homeserver::Peer
+        };
+        MessengerError::RequestFailed {
+            url: url.to_string(),
+            owner,
+            homeserver,
+            status: response.map(|response| response.status().as_u16()),
+            retry_after_secs: response
+                .and_then(|response| response.headers().get(reqwest::header::RETRY_AFTER))
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.trim().parse().ok()),
+        }
+    }
+
+    /// Star a message previously fetched into the local store
+    ///
+    /// Stars are local to this device and never published.
+    pub fn star_message(&self, message_id: &str) -> Result<()> {
+        if !self.store()?.set_starred(message_id, true)? {
+            return Err(anyhow!("Message not found in local store: {}", message_id));
+        }
+        Ok(())
+    }
+
+    /// Remove the star from a message
+    pub fn unstar_message(&self, message_id: &str) -> Result<()> {
+        if !self.store()?.set_starred(message_id, false)? {
+            return Err(anyhow!("Message not found in local store: {}", message_id));
+        }
+        Ok(())
+    }
+
+    /// List starred messages across all conversations, oldest first
+    pub fn list_starred(&self) -> Result<Vec<StoredMessage>> {
+        let messages = self.store()?.starred_messages()?;
+        Ok(self.sanitize_stored(messages))
+    }
+
+    /// Search the content of stored messages across all conversations
+    ///
+    /// Backed by a full-text index in the local store that is updated every
+    /// time messages are fetched, so it stays fast for large histories.
+    /// Results are ordered by relevance.
+    pub fn search_messages(&self, query: &str, limit: usize) -> Result<Vec<StoredMessage>> {
+        let messages = self.store()?.search_messages(query, limit)?;
+        Ok(self.sanitize_stored(messages))
+    }
+
+    /// Find candidate messages of a conversation containing every word of
+    /// `query`, using the search tokens published by both participants
+    ///
+    /// Needs no local store, but only finds text messages sent by clients
+    /// with `with_search_tokens`. Candidates may include messages deleted
+    /// since, and rare token collisions, so fetch them with `fetch_message`
+    /// to confirm. Entries are returned in ID order.
+    pub async fn search_remote(
+        &self,
+        other_pubky: &PublicKey,
+        query: &str,
+    ) -> Result<Vec<MessageEntry>> {
+        let mut entries = Vec::new();
+        for owner in self.conversation_owners(other_pubky) {
+            let mut candidates: Option<BTreeSet<String>> = None;
+            for dir in self.search_token_urls(&owner, other_pubky, query)? {
+                let listed: Result<BTreeSet<String>> = self
+                    .list_pages(dir.to_string(), None, false)
+                    .map_ok(|page| stream::iter(page.into_iter().map(Ok::<_, anyhow::Error>)))
+                    .try_flatten()
+                    .try_filter_map(|url| {
+                        let id = PubkyUri::parse(&url)
+                            .ok()
+                            .and_then(|uri| uri.file_name().map(str::to_string))
+                            .filter(|id| PrivateMessage::validate_id(id).is_ok());
+                        future::ready(Ok(id))
+                    })
+                    .try_collect()
+                    .await;
+                // Nobody used a word yet when its directory doesn't exist
+                let ids = match listed {
+                    Ok(ids) => ids,
+                    Err(e) if is_not_found(&e) => BTreeSet::new(),
+                    Err(e) => return Err(e),
+                };
+
+                let narrowed = match candidates {
+                    Some(candidates) => candidates.intersection(&ids).cloned().collect(),
+                    None => ids,
+                };
+                let done = narrowed.is_empty();
+                candidates = Some(narrowed);
+                if done {
+                    break;
+                }
+            }
+
+            for id in candidates.unwrap_or_default() {
+                let private_path = self.record_path(other_pubky, &id)?;
+                let url = PubkyUri::new(&owner, &private_path)
+                    .join(&format!("{}.json", id))
+                    .to_string();
+                entries.push(MessageEntry { id, url });
+            }
+        }
+
+        entries.sort_by(|a, b| a.id.cmp(&b.id));
+        Ok(entries)
+    }
+
+    /// Apply the content policy to messages read back from the local store,
+    /// which keeps their content as signed
+    fn sanitize_stored(&self, mut messages: Vec<StoredMessage>) -> Vec<StoredMessage> {
+        let own_pubky = self.keypair.public_key();
+        for stored in messages.iter_mut() {
+            self.content_policy.apply_to_message(&mut stored.message);
+            stored.message.mentioned_me = stored.message.is_mentioned(&own_pubky);
+        }
+        messages
+    }
+
+    /// Run connectivity checks and return a structured report
+    ///
+    /// Checks, in order: resolving our pkarr record to a homeserver, reaching
+    /// the homeserver, having a valid session, and writing, reading back, and
+    /// deleting a probe file. Every check runs even if an earlier one failed.
+    pub async fn diagnostics(&self) -> DiagnosticsReport {
+        let own_pubky = self.keypair.public_key();
+        let mut checks = Vec::new();
+
+        let started = Instant::now();
+        let (ok, detail) = match self.client.get_homeserver(&own_pubky).await {
+            Some(homeserver) => (true, format!("homeserver {}", homeserver)),
+            None => (false, "no homeserver found in pkarr record".to_string()),
+        };
+        checks.push(diagnostic_check("pkarr_resolution", started, ok, detail));
+
+        let started = Instant::now();
+        let probe_url = format!("pubky://{}{}", own_pubky, DIAGNOSTICS_PROBE_PATH);
+        let (ok, detail) = match self.http_get(&probe_url).send().await {
+            Ok(response) => (true, format!("responded with {}", response.status())),
+            Err(e) => (false, e.to_string()),
+        };
+        checks.push(diagnostic_check(
+            "homeserver_reachable",
+            started,
+            ok,
+            detail,
+        ));
+
+        let started = Instant::now();
+        let (ok, detail) = match self.client.session(&own_pubky).await {
+            Ok(Some(_)) => (true, "signed in".to_string()),
+            Ok(None) => (false, "no session, call sign_in first".to_string()),
+            Err(e) => (false, e.to_string()),
+        };
+        checks.push(diagnostic_check("authentication", started, ok, detail));
+
+        let started = Instant::now();
+        let (ok, detail) = match self.probe_read_write(&probe_url).await {
+            Ok(()) => (true, "wrote, read back, and deleted probe".to_string()),
+            Err(e) => (false, e.to_string()),
+        };
+        checks.push(diagnostic_check("read_write", started, ok, detail));
+
+        DiagnosticsReport {
+            pubky: own_pubky.to_string(),
+            timestamp: unix_now(),
+            checks,
+        }
+    }
+
+    /// Check that the client is ready for work with a single write
+    ///
+    /// Writing an empty record to our homeserver at once shows that it is
+    /// reachable, that our session is valid, and that we may still write.
+    /// Cheap enough for long-running services to call before every batch;
+    /// use `diagnostics` to find out more when it fails.
+    pub async fn health_check(&self) -> HealthStatus {
+        let probe_url = format!("pubky://{}{}", self.keypair.public_key(), HEALTH_PROBE_PATH);
+        let response = match self.http_put(&probe_url).body(Vec::new()).send().await {
+            Ok(response) => response,
+            Err(e) => {
+                return HealthStatus::Unreachable {
+                    error: e.to_string(),
+                }
+            }
+        };
+
+        let status = response.status();
+        if status.is_success() {
+            HealthStatus::Healthy
+        } else if status == reqwest::StatusCode::UNAUTHORIZED {
+            HealthStatus::SessionInvalid
+        } else if status.is_server_error() {
+            HealthStatus::ServerError {
+                status: status.as_u16(),
+            }
+        } else {
+            HealthStatus::WriteDenied {
+                status: status.as_u16(),
+            }
+        }
+    }
+
+    /// Write random data to `url`, read it back, and delete it
+    async fn probe_read_write(&self, url: &str) -> Result<()> {
+        let probe = PrivateMessage::generate_id();
+
+        let response = self.http_put(url).body(probe.clone()).send().await?;
+        if !response.status().is_success() {
+            return Err(anyhow!("Write failed: {}", response.status()));
+        }
+
+        let response = self.http_get(url).send().await?;
+        if !response.status().is_success() {
+            return Err(anyhow!("Read failed: {}", response.status()));
+        }
+        if response.text().await? != probe {
+            return Err(anyhow!("Read back different data than was written"));
+        }
+
+        let response = self.http_delete(url).send().await?;
+        if !response.status().is_success() {
+            return Err(anyhow!("Delete failed: {}", response.status()));
+        }
+
+        Ok(())
+    }
+
+    /// Get the user's own profile
+    pub async fn get_own_profile(&self) -> Result<Option<PubkyProfile>> {
+        self.get_profile(&self.keypair.public_key()).await
+    }
+
+    /// Get a user's profile, checking its detached signature
+    ///
+    /// `profile_verified` is set when the profile JSON is signed by the
+    /// user's key; profiles written by other apps are unsigned.
+    pub async fn get_profile(&self, pubky: &PublicKey) -> Result<Option<PubkyProfile>> {
+        let profile_url = format!("pubky://{}/pub/pubky.app/profile.json", pubky);
+        let response = self.http_get(&profile_url).send().await?;
+
+        if !response.status().is_success() {
+            return Ok(None);
+        }
+
+        let profile_data = response.bytes().await?;
+        let Ok(mut profile) = serde_json::from_slice::<PubkyProfile>(&profile_data) else {
+            return Ok(None);
+        };
+
+        let signature_url = format!("pubky://{}{}", pubky, PROFILE_SIGNATURE_PATH);
+        let response = self.http_get(&signature_url).send().await?;
+        if response.status().is_success() {
+            let signature = response.text().await?;
+            profile.profile_verified = verify_profile_signature(pubky, &profile_data, &signature);
+        }
+
+        Ok(Some(profile))
+    }
+
+    /// Publish our profile along with a detached signature over its JSON
+    ///
+    /// `name`, `bio`, `image` and `status` are merged into the stored
+    /// profile, so fields written by other apps (such as pubky.app's `links`)
+    /// are kept. A stored profile that isn't a JSON object is replaced.
+    pub async fn put_profile(&self, profile: &PubkyProfile) -> Result<()> {
+        let profile_url = format!(
+            "pubky://{}/pub/pubky.app/profile.json",
             self.keypair.public_key()
         );
-        let response = self.client.get(&follows_url).send().await?;
+
+        let response = self.http_get(&profile_url).send().await?;
+        let mut stored = serde_json::Map::new();
+        if response.status().is_success() {
+            if let Ok(serde_json::Value::Object(fields)) =
+                serde_json::from_slice(&response.bytes().await?)
+            {
+                stored = fields;
+            }
+        } else if response.status() != reqwest::StatusCode::NOT_FOUND {
+            return Err(anyhow!("Failed to read profile: {}", response.status()));
+        }
+        if let serde_json::Value::Object(fields) = serde_json::to_value(profile)? {
+            stored.extend(fields);
+        }
+
+        let profile_data = serde_json::to_vec(&stored)?;
+        let signature = self.keypair.sign(&profile_data);
+        let response = self
+            .http_put(&profile_url)
+            .body(profile_data)
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            return Err(anyhow!("Failed to store profile: {}", response.status()));
+        }
+
+        let signature_url = format!(
+            "pubky://{}{}",
+            self.keypair.public_key(),
+            PROFILE_SIGNATURE_PATH
+        );
+        let response = self
+            .http_put(&signature_url)
+            .body(hex::encode(signature.to_bytes()))
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            return Err(anyhow!(
+                "Failed to store profile signature: {}",
+                response.status()
+            ));
+        }
+
+        self.audit(AuditAction::ProfileUpdate, None, "published signed profile");
+
+        Ok(())
+    }
+
+    /// Get followed users with their profiles
+    pub async fn get_followed_users(&self) -> Result<Vec<FollowedUser>> {
+        self.get_followed_users_for(&self.keypair.public_key().to_string())
+            .await
+    }
+
+    /// Check a follow record listed in `owner`'s follows directory
+    async fn follow_anomalies(&self, owner: &str, follow_url: &str) -> Vec<FollowAnomaly> {
+        // Foreign URLs are not fetched
+        let record = if follow_in_directory(owner, follow_url) {
+            match self.http_get(follow_url).send().await {
+                Ok(response) if response.status().is_success() => response.text().await.ok(),
+                _ => None,
+            }
+        } else {
+            None
+        };
+        FollowAnomaly::of_record(owner, follow_url, record.as_deref(), unix_now())
+    }
+
+    /// Get profile for a specific user
+    async fn get_user_profile(&self, follow_url: &str) -> Result<FollowedUser> {
+        let follow_uri = PubkyUri::parse(follow_url)?;
+        let pubky_id = follow_uri
+            .file_name()
+            .ok_or_else(|| anyhow!("Failed to extract pubky from URL"))?;
+
+        let pubky = PublicKey::try_from(pubky_id)?;
+
+        match self.get_profile(&pubky).await? {
+            Some(profile) => Ok(FollowedUser {
+                name: Some(profile.name),
+                pubky: pubky_id.to_string(),
+                profile_verified: profile.profile_verified,
+                anomalies: Vec::new(),
+            }),
+            None => Ok(FollowedUser {
+                name: None,
+                pubky: pubky_id.to_string(),
+                profile_verified: false,
+                anomalies: Vec::new(),
+            }),
+        }
+    }
+
+    /// Get followed users for a specific pubky
+    pub async fn get_followed_users_for(&self, pubky: &str) -> Result<Vec<FollowedUser>> {
+        let follows_url = format!("pubky://{}/pub/pubky.app/follows/", pubky);
+        let response = self.http_get(&follows_url).send().await?;
 
         if !response.status().is_success() {
             return Ok(Vec::new());
@@ -215,12 +4926,27 @@ impl PrivateMessengerClient {
             .map(|url| url.to_string())
             .collect();
 
+        // A user without a published homeserver can't own any storage
+        let homeserver_resolved = match (self.verify_follows, PublicKey::try_from(pubky)) {
+            (true, Ok(owner)) => self.client.get_homeserver(&owner).await.is_some(),
+            _ => true,
+        };
+
         // Fetch profiles in parallel
         let profile_futures: Vec<_> = follow_urls
             .iter()
             .map(|follow_url| {
                 let url = follow_url.clone();
-                async move { self.get_user_profile(&url).await }
+                async move {
+                    let mut user = self.get_user_profile(&url).await?;
+                    if self.verify_follows {
+                        user.anomalies = self.follow_anomalies(pubky, &url).await;
+                        if !homeserver_resolved {
+                            user.anomalies.push(FollowAnomaly::UnresolvedHomeserver);
+                        }
+                    }
+                    Ok::<_, anyhow::Error>(user)
+                }
             })
             .collect();
 
@@ -236,154 +4962,555 @@ impl PrivateMessengerClient {
         Ok(users)
     }
 
-    /// Get profile for a specific user
-    async fn get_user_profile(&self, follow_url: &str) -> Result<FollowedUser> {
-        let pubky_id = follow_url
-            .split('/')
-            .last()
-            .ok_or_else(|| anyhow!("Failed to extract pubky from URL"))?;
+    /// Reconcile our follows with a Nexus indexer
+    ///
+    /// Homeservers that serve stale listings can hide follow records, so the
+    /// follows listed on our homeserver are compared with those the indexer
+    /// has seen. Follows only the indexer knows of are fetched directly to
+    /// tell a stale listing from a record that is really gone. Nothing is
+    /// written; the report lists each divergence for the application to act
+    /// on. Requires `with_nexus`.
+    pub async fn sync_follows_from_nexus(&self) -> Result<FollowSyncReport> {
+        let nexus_url = self
+            .nexus_url
+            .as_deref()
+            .ok_or_else(|| anyhow!("No Nexus indexer configured"))?;
+        let own_pubky = self.keypair.public_key().to_string();
 
-        let profile_url = format!("pubky://{}/pub/pubky.app/profile.json", pubky_id);
-        let response = self.client.get(&profile_url).send().await?;
+        let listed: BTreeSet<String> = self.list_follows(&own_pubky).await?.into_iter().collect();
+        let indexed = self.nexus_follows(nexus_url, &own_pubky).await?;
 
-        if response.status().is_success() {
-            let profile_data = response.text().await?;
-            match serde_json::from_str::<PubkyProfile>(&profile_data) {
-                Ok(profile) => Ok(FollowedUser {
-                    name: Some(profile.name),
-                    pubky: pubky_id.to_string(),
-                }),
-                Err(_) => Ok(FollowedUser {
-                    name: None,
-                    pubky: pubky_id.to_string(),
-                }),
+        let unlisted: Vec<String> = indexed.difference(&listed).cloned().collect();
+        let fetched: Vec<Result<bool>> = stream::iter(unlisted.iter().map(|pubky| async move {
+            let url =
+                PubkyUri::new(&self.keypair.public_key(), uri::FOLLOWS_PATH).to_string() + pubky;
+            let response = self.guarded_get(&url).await?;
+            if response.status() == reqwest::StatusCode::NOT_FOUND {
+                Ok(false)
+            } else if response.status().is_success() {
+                Ok(true)
+            } else {
+                Err(anyhow!(
+                    "Failed to fetch follow {}: {}",
+                    pubky,
+                    response.status()
+                ))
+            }
+        }))
+        .buffered(BATCH_SIZE)
+        .collect()
+        .await;
+
+        let mut report = FollowSyncReport::default();
+        let mut follows = listed.clone();
+        for (pubky, found) in unlisted.into_iter().zip(fetched) {
+            if found? {
+                follows.insert(pubky.clone());
+                report
+                    .divergences
+                    .push(FollowDivergence::Unlisted { pubky });
+            } else {
+                report
+                    .divergences
+                    .push(FollowDivergence::MissingFromHomeserver { pubky });
             }
-        } else {
-            Ok(FollowedUser {
-                name: None,
-                pubky: pubky_id.to_string(),
-            })
         }
-    }
+        for pubky in listed.difference(&indexed) {
+            report
+                .divergences
+                .push(FollowDivergence::MissingFromIndexer {
+                    pubky: pubky.clone(),
+                });
+        }
 
-    /// Get followed users for a specific pubky
-    pub async fn get_followed_users_for(&self, pubky: &str) -> Result<Vec<FollowedUser>> {
-        let follows_url = format!("pubky://{}/pub/pubky.app/follows/", pubky);
-        let response = self.client.get(&follows_url).send().await?;
+        report.follows = follows.into_iter().collect();
+        Ok(report)
+    }
 
-        if !response.status().is_success() {
+    /// The pubkys listed in a user's follows directory
+    async fn list_follows(&self, pubky: &str) -> Result<Vec<String>> {
+        let follows_url = format!("pubky://{}{}", pubky, uri::FOLLOWS_PATH);
+        let response = self.http_get(&follows_url).send().await?;
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
             return Ok(Vec::new());
         }
+        if !response.status().is_success() {
+            return Err(anyhow!("Failed to list follows: {}", response.status()));
+        }
 
-        let follows_response = response.text().await?;
-        let follow_urls: Vec<String> = follows_response
+        Ok(response
+            .text()
+            .await?
             .lines()
-            .filter(|line| !line.is_empty())
-            .map(|url| url.to_string())
-            .collect();
+            .filter_map(|url| PubkyUri::parse(url).ok())
+            .filter_map(|uri| uri.file_name().map(str::to_string))
+            .filter(|target| PublicKey::try_from(target.as_str()).is_ok())
+            .collect())
+    }
 
-        // Fetch profiles in parallel
-        let profile_futures: Vec<_> = follow_urls
-            .iter()
-            .map(|follow_url| {
-                let url = follow_url.clone();
-                async move { self.get_user_profile(&url).await }
-            })
-            .collect();
+    /// The pubkys a Nexus indexer has seen a user follow
+    async fn nexus_follows(&self, nexus_url: &str, pubky: &str) -> Result<BTreeSet<String>> {
+        let mut follows = BTreeSet::new();
+        let mut skip = 0;
+        while skip < MAX_NEXUS_FOLLOWS {
+            let url = format!(
+                "{}/v0/user/{}/following?skip={}&limit={}",
+                nexus_url, pubky, skip, NEXUS_PAGE_SIZE
+            );
+            let response = self.http_get(&url).send().await?;
+            if response.status() == reqwest::StatusCode::NOT_FOUND {
+                break;
+            }
+            if !response.status().is_success() {
+                return Err(anyhow!(
+                    "Failed to fetch follows from Nexus: {}",
+                    response.status()
+                ));
+            }
+
+            let page: Vec<String> = serde_json::from_slice(&response.bytes().await?)?;
+            let count = page.len();
+            follows.extend(
+                page.into_iter()
+                    .filter(|target| PublicKey::try_from(target.as_str()).is_ok()),
+            );
+            if count < NEXUS_PAGE_SIZE {
+                break;
+            }
+            skip += count;
+        }
+        Ok(follows)
+    }
+
+    /// Follow a user by adding them to our follow list
+    pub async fn put_follow(&self, target_pubky: &str) -> Result<()> {
+        // Get current timestamp
+        let timestamp = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)?
+            .as_secs();
+
+        // Create follow data with timestamp
+        let follow_data = serde_json::json!({
+            "created_at": timestamp
+        });
+
+        // Construct the follow URL
+        let follow_url = format!(
+            "pubky://{}/pub/pubky.app/follows/{}",
+            self.keypair.public_key(),
+            target_pubky
+        );
+
+        // Send PUT request with follow data
+        let response = self
+            .http_put(&follow_url)
+            .body(follow_data.to_string())
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            return Err(anyhow!("Failed to create follow: {}", response.status()));
+        }
+
+        Ok(())
+    }
+
+    /// Unfollow a user by removing them from our follow list
+    pub async fn delete_follow(&self, target_pubky: &str) -> Result<()> {
+        // Construct the follow URL
+        let follow_url = format!(
+            "pubky://{}/pub/pubky.app/follows/{}",
+            self.keypair.public_key(),
+            target_pubky
+        );
+
+        // Send DELETE request
+        let response = self.http_delete(&follow_url).send().await?;
+
+        if !response.status().is_success() {
+            return Err(anyhow!("Failed to delete follow: {}", response.status()));
+        }
+
+        Ok(())
+    }
+
+    /// Export the key of a single conversation so a third party can read it
+    ///
+    /// **Warning:** the exported key decrypts every past and future message
+    /// with `other_pubky` and cannot be revoked. It does not expose the
+    /// identity key, so the holder cannot read other conversations or send
+    /// messages as you. Intended for cases such as dispute resolution where a
+    /// trusted auditor needs to read one conversation.
+    pub fn export_conversation_key(&self, other_pubky: &PublicKey) -> Result<ConversationKey> {
+        ConversationKey::derive(&self.keypair, other_pubky)
+    }
+
+    /// Get the public key of this client
+    pub fn public_key(&self) -> PublicKey {
+        self.keypair.public_key()
+    }
+
+    /// A shareable contact card for this identity
+    ///
+    /// Includes the display name from our profile, if we have one. Render it
+    /// with `IdentityBundle::to_invite_link` to share as a link.
+    pub async fn identity_bundle(&self) -> Result<IdentityBundle> {
+        let name = self.get_own_profile().await?.map(|profile| profile.name);
+        Ok(IdentityBundle::new(&self.keypair.public_key(), name))
+    }
+
+    /// Get the public key as a string
+    pub fn public_key_string(&self) -> String {
+        self.keypair.public_key().to_string()
+    }
+
+    /// Check offline that the loaded keypair works for messaging
+    ///
+    /// Signs and verifies a random challenge, checks that the ed25519 to
+    /// X25519 conversion yields a valid point matching the secret key, and
+    /// round-trips an encryption. Call it at startup to catch a corrupted
+    /// recovery file or a bad seed before the first send does.
+    pub fn self_test(&self) -> Result<()> {
+        crypto::self_test(&self.keypair)
+    }
+
+    /// Whether an unlocked local store is enabled
+    pub(crate) fn has_local_store(&self) -> bool {
+        self.store.as_ref().is_some_and(|store| !store.is_locked())
+    }
+
+    /// Note that we sent a message to `recipient`, waking sync services
+    fn record_send(&self, recipient: &PublicKey) {
+        if let Ok(mut last_sends) = self.last_sends.lock() {
+            last_sends.insert(recipient.to_string(), Instant::now());
+        }
+        self.sent.notify_waiters();
+    }
+
+    /// Whether we sent a message to `peer` after `since`
+    pub(crate) fn sent_since(&self, peer: &PublicKey, since: Instant) -> bool {
+        self.last_sends.lock().is_ok_and(|last_sends| {
+            last_sends
+                .get(&peer.to_string())
+                .is_some_and(|sent| *sent > since)
+        })
+    }
+
+    /// Wait until we send the next message
+    pub(crate) async fn next_send(&self) {
+        self.sent.notified().await
+    }
+
+    /// Homeserver requests, identified with our `User-Agent`
+    fn http_get<U: reqwest::IntoUrl>(&self, url: U) -> reqwest::RequestBuilder {
+        self.client
+            .get(url)
+            .header(reqwest::header::USER_AGENT, &self.user_agent)
+    }
+
+    fn http_put<U: reqwest::IntoUrl>(&self, url: U) -> reqwest::RequestBuilder {
+        self.client
+            .put(url)
+            .header(reqwest::header::USER_AGENT, &self.user_agent)
+    }
+
+    fn http_delete<U: reqwest::IntoUrl>(&self, url: U) -> reqwest::RequestBuilder {
+        self.client
+            .delete(url)
+            .header(reqwest::header::USER_AGENT, &self.user_agent)
+    }
+
+    /// The local store, if enabled
+    pub(crate) fn local_store(&self) -> Option<&LocalStore> {
+        self.store.as_ref()
+    }
+
+    /// The local store, or an error if it was not enabled
+    fn store(&self) -> Result<&LocalStore> {
+        self.store
+            .as_ref()
+            .ok_or_else(|| anyhow!("Local store is not enabled"))
+    }
+
+    /// Static conversation path, used when path rotation is off
+    pub(crate) fn static_conversation_path(&self, other_pubky: &PublicKey) -> Result<String> {
+        let shared_secret = self.secrets.key(&self.keypair, other_pubky)?;
+        Ok(static_conversation_path(&shared_secret))
+    }
+
+    /// Conversation path holding the record with the given ID
+    ///
+    /// With path rotation, records live in the epoch of their ID's timestamp
+    /// prefix. IDs without one, and all records without rotation, use the
+    /// static path.
+    fn record_path(&self, other_pubky: &PublicKey, id: &str) -> Result<String> {
+        PrivateMessage::validate_id(id)?;
 
-        let results = join_all(profile_futures).await;
+        let shared_secret = self.secrets.key(&self.keypair, other_pubky)?;
+        Ok(match (self.rotation, PrivateMessage::id_timestamp_ms(id)) {
+            (Some(rotation), Some(timestamp_ms)) => {
+                epoch_conversation_path(&shared_secret, rotation.epoch_of(timestamp_ms / 1000))
+            }
+            _ => static_conversation_path(&shared_secret),
+        })
+    }
 
-        let mut users = Vec::new();
-        for result in results {
-            if let Ok(user) = result {
-                users.push(user);
+    /// Conversation paths a record with the given ID may be under, the one
+    /// `record_path` writes to first
+    ///
+    /// With path rotation, records stored before it was turned on, and
+    /// records of peers who don't rotate, are on the static path even when
+    /// their ID has a timestamp prefix.
+    fn record_paths(&self, other_pubky: &PublicKey, id: &str) -> Result<Vec<String>> {
+        let path = self.record_path(other_pubky, id)?;
+        let static_path = static_conversation_path(&self.secrets.key(&self.keypair, other_pubky)?);
+        Ok(if path == static_path {
+            vec![path]
+        } else {
+            vec![path, static_path]
+        })
+    }
+
+    /// Conversation paths read when fetching messages, oldest first
+    ///
+    /// Without rotation this is just the static path. With rotation it is the
+    /// static path followed by the lookback epochs and the current epoch.
+    fn conversation_paths(&self, other_pubky: &PublicKey) -> Result<Vec<String>> {
+        let shared_secret = self.secrets.key(&self.keypair, other_pubky)?;
+        let mut paths = vec![static_conversation_path(&shared_secret)];
+
+        if let Some(rotation) = self.rotation {
+            let current = rotation.epoch_of(unix_now());
+            for epoch in current.saturating_sub(rotation.lookback_epochs)..=current {
+                paths.push(epoch_conversation_path(&shared_secret, epoch));
             }
         }
 
-        Ok(users)
+        Ok(paths)
     }
 
-    /// Follow a user by adding them to our follow list
-    pub async fn put_follow(&self, target_pubky: &str) -> Result<()> {
-        // Get current timestamp
-        let timestamp = std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)?
-            .as_secs();
+    /// Our entries in a conversation that may have been sent within a time
+    /// range, given as inclusive Unix timestamps in seconds
+    ///
+    /// The static path is listed in full, since IDs stored before they were
+    /// timestamp-prefixed carry no time. With rotation, only the epochs the
+    /// range overlaps are listed, each by ID range, unless there are so many
+    /// that listing our whole directory once is cheaper.
+    async fn own_entries_between(
+        &self,
+        other_pubky: &PublicKey,
+        from_timestamp: u64,
+        to_timestamp: u64,
+    ) -> Result<Vec<MessageEntry>> {
+        let own_pubky = self.keypair.public_key();
+        let shared_secret = self.secrets.key(&self.keypair, other_pubky)?;
 
-        // Create follow data with timestamp
-        let follow_data = serde_json::json!({
-            "created_at": timestamp
-        });
+        let mut entries: Vec<MessageEntry> = self
+            .path_entries(&own_pubky, static_conversation_path(&shared_secret), None)
+            .try_collect()
+            .await?;
 
-        // Construct the follow URL
-        let follow_url = format!(
-            "pubky://{}/pub/pubky.app/follows/{}",
-            self.keypair.public_key(),
-            target_pubky
+        let Some(rotation) = self.rotation else {
+            return Ok(entries);
+        };
+        let first = rotation.epoch_of(from_timestamp);
+        let last = rotation
+            .epoch_of(to_timestamp)
+            .min(rotation.epoch_of(unix_now()) + 1);
+        if last.saturating_sub(first) >= MAX_LISTED_EPOCHS {
+            return Ok(self
+                .own_records(other_pubky)
+                .await?
+                .into_iter()
+                .filter_map(|(private_path, url)| {
+                    let id = message_id_from_url(&url, &private_path)?;
+                    Some(MessageEntry { id, url })
+                })
+                .collect());
+        }
+
+        let range_ms = (
+            from_timestamp.saturating_mul(1000),
+            to_timestamp.saturating_mul(1000).saturating_add(999),
         );
+        for epoch in first..=last {
+            let private_path = epoch_conversation_path(&shared_secret, epoch);
+            let listed: Vec<MessageEntry> = self
+                .path_entries(&own_pubky, private_path, Some(range_ms))
+                .try_collect()
+                .await?;
+            entries.extend(listed);
+        }
 
-        // Send PUT request with follow data
-        let response = self.client
-            .put(&follow_url)
-            .body(follow_data.to_string())
-            .send()
-            .await?;
+        Ok(entries)
+    }
 
-        if !response.status().is_success() {
-            return Err(anyhow!("Failed to create follow: {}", response.status()));
-        }
+    /// Every record we stored in a conversation, as `(conversation path, url)`
+    ///
+    /// With path rotation this covers all epochs, not just the lookback
+    /// window: our whole messenger directory is listed and matched against
+    /// the paths of every epoch up to now. Every page of the listing is read,
+    /// and listing errors are returned rather than treated as no records.
+    async fn own_records(&self, other_pubky: &PublicKey) -> Result<Vec<(String, String)>> {
+        let own_pubky = self.keypair.public_key();
+        let shared_secret = self.secrets.key(&self.keypair, other_pubky)?;
 
-        Ok(())
+        let Some(rotation) = self.rotation else {
+            let private_path = static_conversation_path(&shared_secret);
+            let self_path = PubkyUri::new(&own_pubky, &private_path).to_string();
+            let urls = self.list_all(self_path).await?;
+            return Ok(urls
+                .into_iter()
+                .map(|url| (private_path.clone(), url))
+                .collect());
+        };
+
+        let paths = all_conversation_paths(&shared_secret, rotation);
+
+        let root = PubkyUri::new(&own_pubky, uri::PRIVATE_MESSAGES_PATH).to_string();
+        let urls = self.list_all(root).await?;
+
+        Ok(urls
+            .into_iter()
+            .filter_map(|url| {
+                let private_path = PubkyUri::parse(&url).ok()?.conversation_path()?.to_string();
+                paths.contains(&private_path).then_some((private_path, url))
+            })
+            .collect())
     }
 
-    /// Unfollow a user by removing them from our follow list
-    pub async fn delete_follow(&self, target_pubky: &str) -> Result<()> {
-        // Construct the follow URL
-        let follow_url = format!(
-            "pubky://{}/pub/pubky.app/follows/{}",
-            self.keypair.public_key(),
-            target_pubky
-        );
+    /// Measure the storage our messenger data takes up on our homeserver
+    ///
+    /// Lists and downloads every record we stored, so this costs as much as
+    /// fetching all of our sent messages. Conversation paths are matched to
+    /// peers from the local store, if any.
+    pub async fn storage_usage(&self) -> Result<StorageUsage> {
+        let root =
+            PubkyUri::new(&self.keypair.public_key(), uri::PRIVATE_MESSAGES_PATH).to_string();
+        let urls: Vec<String> = self.list_pages(root, None, false).try_concat().await?;
 
-        // Send DELETE request
-        let response = self.client
-            .delete(&follow_url)
-            .send()
-            .await?;
+        let mut usage_by_path: HashMap<String, ConversationUsage> = HashMap::new();
+        for chunk in urls.chunks(BATCH_SIZE) {
+            let sizes = chunk.iter().map(|url| async move {
+                let response = self.guarded_get(url).await?;
+                if !response.status().is_success() {
+                    // Deleted since it was listed
+                    return Ok::<_, anyhow::Error>(None);
+                }
+                Ok(Some(response.bytes().await?.len() as u64))
+            });
+            for (url, size) in chunk.iter().zip(join_all(sizes).await) {
+                let Some(bytes) = size? else {
+                    continue;
+                };
+                let Some(path) = PubkyUri::parse(url)
+                    .ok()
+                    .and_then(|uri| uri.conversation_path().map(str::to_string))
+                else {
+                    continue;
+                };
+                let usage = usage_by_path.entry(path.clone()).or_default();
+                usage.path = path;
+                usage.records += 1;
+                usage.bytes += bytes;
+            }
+        }
 
-        if !response.status().is_success() {
-            return Err(anyhow!("Failed to delete follow: {}", response.status()));
+        if let Some(store) = self.store.as_ref().filter(|store| !store.is_locked()) {
+            for peer in store.conversation_peers()? {
+                let Ok(other_pubky) = PublicKey::try_from(peer.as_str()) else {
+                    continue;
+                };
+                let shared_secret = self.secrets.key(&self.keypair, &other_pubky)?;
+                let paths = match self.rotation {
+                    Some(rotation) => all_conversation_paths(&shared_secret, rotation),
+                    None => HashSet::from([static_conversation_path(&shared_secret)]),
+                };
+                for path in paths {
+                    if let Some(usage) = usage_by_path.get_mut(&path) {
+                        usage.peer = Some(peer.clone());
+                    }
+                }
+            }
         }
 
-        Ok(())
+        let mut conversations: Vec<ConversationUsage> = usage_by_path.into_values().collect();
+        conversations.sort_by(|a, b| b.bytes.cmp(&a.bytes).then_with(|| a.path.cmp(&b.path)));
+        let usage = StorageUsage {
+            total_bytes: conversations.iter().map(|usage| usage.bytes).sum(),
+            conversations,
+            quota_bytes: self.storage_quota,
+        };
+
+        if let Some(quota_bytes) = usage.quota_bytes.filter(|_| usage.is_near_limit()) {
+            self.emit(NotificationEvent::StorageNearLimit {
+                used_bytes: usage.total_bytes,
+                quota_bytes,
+            });
+        }
+
+        Ok(usage)
     }
 
-    /// Get the public key of this client
-    pub fn public_key(&self) -> PublicKey {
-        self.keypair.public_key()
+    /// Build the URL a message is written to on our side of a conversation
+    fn message_url(&self, other_pubky: &PublicKey, message_id: &str) -> Result<String> {
+        let private_path = self.record_path(other_pubky, message_id)?;
+        Ok(PubkyUri::new(&self.keypair.public_key(), &private_path)
+            .join(&format!("{}.json", message_id))
+            .to_string())
     }
 
-    /// Get the public key as a string
-    pub fn public_key_string(&self) -> String {
-        self.keypair.public_key().to_string()
+    /// Find the URL of a message stored on our side of a conversation
+    ///
+    /// With path rotation a message may be on the static path instead of its
+    /// epoch's (see `record_paths`), so each candidate is checked in turn.
+    /// Falls back to `message_url` if none of them holds the message.
+    async fn stored_message_url(
+        &self,
+        other_pubky: &PublicKey,
+        message_id: &str,
+    ) -> Result<String> {
+        let paths = self.record_paths(other_pubky, message_id)?;
+        let urls: Vec<String> = paths
+            .iter()
+            .map(|private_path| {
+                PubkyUri::new(&self.keypair.public_key(), private_path)
+                    .join(&format!("{}.json", message_id))
+                    .to_string()
+            })
+            .collect();
+        if urls.len() > 1 {
+            for url in urls.iter() {
+                if self.guarded_get(url).await?.status().is_success() {
+                    return Ok(url.clone());
+                }
+            }
+        }
+        Ok(urls[0].clone())
     }
 
     /// Delete a single message by its ID from a conversation
     pub async fn delete_message(&self, message_id: &str, other_pubky: &PublicKey) -> Result<()> {
-        let private_path = generate_conversation_path(&self.keypair, other_pubky)?;
-        let url = format!(
-            "pubky://{}{}{}",
-            self.keypair.public_key(),
-            private_path,
-            format!("{}.json", message_id)
-        );
+        let url = self.stored_message_url(other_pubky, message_id).await?;
 
-        let response = self.client.delete(&url).send().await?;
+        if self.trash_window.is_some() {
+            self.trash(other_pubky, &[(message_id.to_string(), url)])?;
+        } else {
+            let response = self.http_delete(&url).send().await?;
 
-        if !response.status().is_success() {
-            return Err(anyhow!("Failed to delete message: {}", response.status()));
+            if !response.status().is_success() {
+                return Err(anyhow!("Failed to delete message: {}", response.status()));
+            }
+            let ids = HashSet::from([message_id.to_string()]);
+            self.delete_search_tokens(other_pubky, Some(&ids)).await?;
         }
 
+        self.audit(
+            AuditAction::Delete,
+            Some(other_pubky),
+            format!("message {}", message_id),
+        );
+
         Ok(())
     }
 
@@ -393,20 +5520,30 @@ impl PrivateMessengerClient {
         message_ids: Vec<String>,
         other_pubky: &PublicKey,
     ) -> Result<()> {
-        let private_path = generate_conversation_path(&self.keypair, other_pubky)?;
+        let urls = join_all(
+            message_ids
+                .iter()
+                .map(|msg_id| self.stored_message_url(other_pubky, msg_id)),
+        )
+        .await
+        .into_iter()
+        .collect::<Result<Vec<_>>>()?;
+
+        if self.trash_window.is_some() {
+            let entries: Vec<(String, String)> = message_ids.iter().cloned().zip(urls).collect();
+            self.trash(other_pubky, &entries)?;
+            self.audit(
+                AuditAction::Delete,
+                Some(other_pubky),
+                format!("messages {}", message_ids.join(", ")),
+            );
+            return Ok(());
+        }
 
         // Create delete futures for all messages
-        let delete_futures: Vec<_> = message_ids
+        let delete_futures: Vec<_> = urls
             .iter()
-            .map(|msg_id| {
-                let url = format!(
-                    "pubky://{}{}{}",
-                    self.keypair.public_key(),
-                    private_path,
-                    format!("{}.json", msg_id)
-                );
-                async move { self.client.delete(&url).send().await }
-            })
+            .map(|url| async move { self.http_delete(url).send().await })
             .collect();
 
         // Execute all deletions in parallel
@@ -423,47 +5560,311 @@ impl PrivateMessengerClient {
                     ));
                 }
                 Err(e) => {
-                    return Err(anyhow!("Failed to delete message {}: {}", message_ids[i], e));
+                    return Err(anyhow!(
+                        "Failed to delete message {}: {}",
+                        message_ids[i],
+                        e
+                    ));
                 }
                 _ => {}
             }
         }
+        let ids: HashSet<String> = message_ids.iter().cloned().collect();
+        self.delete_search_tokens(other_pubky, Some(&ids)).await?;
+
+        self.audit(
+            AuditAction::Delete,
+            Some(other_pubky),
+            format!("messages {}", message_ids.join(", ")),
+        );
 
         Ok(())
     }
 
     /// Clear all sent messages in a conversation with a specific pubky
     pub async fn clear_messages(&self, other_pubky: &PublicKey) -> Result<()> {
-        let private_path = generate_conversation_path(&self.keypair, other_pubky)?;
-        let self_path = format!("pubky://{}{}", self.keypair.public_key(), private_path);
-
         // List all messages in the conversation
-        let urls = match self.client.list(&self_path) {
-            Ok(list_builder) => match list_builder.send().await {
-                Ok(urls) => urls,
-                Err(_) => {
-                    // No messages to clear
-                    return Ok(());
+        let records = self.own_records(other_pubky).await?;
+
+        // If no messages, return early
+        if records.is_empty() {
+            return Ok(());
+        }
+        let count = records.len();
+
+        // Messages go to the trash, other records such as fetch markers
+        // are deleted right away
+        let mut urls = Vec::new();
+        let mut trashed = Vec::new();
+        for (private_path, url) in records {
+            match message_id_from_url(&url, &private_path) {
+                Some(id) if self.trash_window.is_some() => trashed.push((id, url)),
+                _ => urls.push(url),
+            }
+        }
+        self.trash(other_pubky, &trashed)?;
+        self.delete_urls(&urls).await?;
+        // Tokens of trashed messages go when the trash is purged
+        if trashed.is_empty() {
+            self.delete_search_tokens(other_pubky, None).await?;
+        }
+
+        self.audit(
+            AuditAction::Delete,
+            Some(other_pubky),
+            format!("cleared {} records", count),
+        );
+
+        Ok(())
+    }
+
+    /// Delete the messages we sent in a conversation within a time range
+    ///
+    /// Both bounds are inclusive Unix timestamps in seconds. Messages are
+    /// matched by the send time their ID starts with, so our side of the
+    /// conversation is listed by ID range and nothing is fetched. Messages
+    /// stored before IDs were timestamp-prefixed carry no time in their ID;
+    /// only their envelopes are fetched, for the timestamp readable without
+    /// decryption. Returns the IDs of the deleted messages.
+    pub async fn delete_messages_between(
+        &self,
+        other_pubky: &PublicKey,
+        from_timestamp: u64,
+        to_timestamp: u64,
+    ) -> Result<Vec<String>> {
+        if from_timestamp > to_timestamp {
+            return Err(anyhow!("Invalid time range"));
+        }
+
+        let since_ms = from_timestamp.saturating_mul(1000);
+        let until_ms = to_timestamp.saturating_mul(1000).saturating_add(999);
+        let mut matching = Vec::new();
+        let mut legacy = Vec::new();
+        for entry in self
+            .own_entries_between(other_pubky, from_timestamp, to_timestamp)
+            .await?
+        {
+            match PrivateMessage::id_timestamp_ms(&entry.id) {
+                Some(timestamp_ms) if (since_ms..=until_ms).contains(&timestamp_ms) => {
+                    matching.push(entry)
+                }
+                Some(_) => {}
+                None => legacy.push(entry),
+            }
+        }
+
+        let key = self.secrets.key(&self.keypair, other_pubky)?;
+        let key = &key;
+        for chunk in legacy.chunks(BATCH_SIZE) {
+            let fetch_futures: Vec<_> = chunk
+                .iter()
+                .map(|MessageEntry { url, .. }| async move {
+                    let response = self.http_get(url).send().await.ok()?;
+                    if !response.status().is_success() {
+                        return None;
+                    }
+                    let text = response.text().await.ok()?;
+                    PrivateMessage::from_record(&text, key).ok()
+                })
+                .collect();
+
+            let results = join_all(fetch_futures).await;
+
+            for (i, message) in results.into_iter().enumerate() {
+                if let Some(message) = message {
+                    if message.timestamp >= from_timestamp && message.timestamp <= to_timestamp {
+                        matching.push(chunk[i].clone());
+                    }
                 }
-            },
-            Err(_) => {
-                // No messages to clear
-                return Ok(());
             }
+        }
+
+        if self.trash_window.is_some() {
+            let entries: Vec<(String, String)> = matching
+                .iter()
+                .map(|entry| (entry.id.clone(), entry.url.clone()))
+                .collect();
+            self.trash(other_pubky, &entries)?;
+        } else {
+            let urls: Vec<String> = matching.iter().map(|entry| entry.url.clone()).collect();
+            self.delete_urls(&urls).await?;
+            let ids: HashSet<String> = matching.iter().map(|entry| entry.id.clone()).collect();
+            self.delete_search_tokens(other_pubky, Some(&ids)).await?;
+        }
+
+        let ids: Vec<String> = matching.into_iter().map(|entry| entry.id).collect();
+        self.audit(
+            AuditAction::Delete,
+            Some(other_pubky),
+            format!(
+                "messages between {} and {}: {}",
+                from_timestamp,
+                to_timestamp,
+                ids.join(", ")
+            ),
+        );
+
+        Ok(ids)
+    }
+
+    /// Move messages of a conversation to the trash, given as `(id, url)`
+    fn trash(&self, other_pubky: &PublicKey, entries: &[(String, String)]) -> Result<()> {
+        if entries.is_empty() {
+            return Ok(());
+        }
+        self.store()?
+            .trash_messages(&other_pubky.to_string(), entries, unix_now())?;
+        for (_, url) in entries {
+            self.cache.remove_url(url);
+        }
+        Ok(())
+    }
+
+    /// Bring a deleted message of a conversation back from the trash, see
+    /// `with_trash`
+    ///
+    /// Returns false if the message is not in the trash, e.g. because its
+    /// window passed and it was purged. The message shows up again in
+    /// fetches, and the next `sync_conversation` reports it as new.
+    pub fn undo_delete(&self, message_id: &str, other_pubky: &PublicKey) -> Result<bool> {
+        let store = self.store()?;
+        let peer = other_pubky.to_string();
+        let Some((url, _)) = store.restore_trashed(&peer, message_id)? else {
+            return Ok(false);
         };
+        store.forget_sync_entries(&peer, &[url])?;
 
-        // If no messages, return early
-        if urls.is_empty() {
+        self.audit(
+            AuditAction::PolicyOverride,
+            Some(other_pubky),
+            format!("undid delete of message {}", message_id),
+        );
+        Ok(true)
+    }
+
+    /// Messages in the trash, oldest deletion first. Requires a local store.
+    pub fn list_trash(&self) -> Result<Vec<TrashedMessage>> {
+        self.store()?.trashed_messages()
+    }
+
+    /// Delete trashed messages whose undo window has passed from the
+    /// homeserver, returning how many were deleted
+    ///
+    /// Does nothing unless `with_trash` is set.
+    pub async fn purge_trash(&self) -> Result<usize> {
+        let Some(window) = self.trash_window else {
+            return Ok(0);
+        };
+        let store = self.store()?;
+        let expired = store.expired_trash(unix_now().saturating_sub(window.as_secs()))?;
+
+        let mut by_peer: BTreeMap<String, (Vec<String>, Vec<String>)> = BTreeMap::new();
+        for (peer, id, url) in expired.iter() {
+            let (ids, urls) = by_peer.entry(peer.clone()).or_default();
+            ids.push(id.clone());
+            urls.push(url.clone());
+        }
+        for (peer, (ids, urls)) in by_peer.iter() {
+            self.delete_urls(urls).await?;
+            if let Ok(other_pubky) = PublicKey::try_from(peer.as_str()) {
+                let ids: HashSet<String> = ids.iter().cloned().collect();
+                self.delete_search_tokens(&other_pubky, Some(&ids)).await?;
+            }
+            store.remove_trashed(peer, ids)?;
+        }
+
+        Ok(expired.len())
+    }
+
+    /// Drop trashed messages from fetched ones, if the trash is enabled
+    fn apply_trash(
+        &self,
+        store: &LocalStore,
+        peer: &str,
+        messages: &mut Vec<DecryptedMessage>,
+    ) -> Result<()> {
+        if self.trash_window.is_none() {
             return Ok(());
         }
+        let trashed = store.trashed_ids(peer)?;
+        messages.retain(|message| !trashed.contains(&message.id));
+        Ok(())
+    }
+
+    /// Delete all messenger data from our homeserver
+    ///
+    /// Removes every message we stored in any conversation, following the
+    /// listing across pages. Fails if the listing or any deletion fails.
+    /// Messages stored by peers on their own homeservers are not affected.
+    pub async fn delete_all_data(&self) -> Result<()> {
+        let root =
+            PubkyUri::new(&self.keypair.public_key(), uri::PRIVATE_MESSAGES_PATH).to_string();
+
+        let urls = self.list_all(root).await?;
+
+        self.delete_urls(&urls).await?;
+
+        self.audit(
+            AuditAction::Delete,
+            None,
+            format!("deleted all data ({} records)", urls.len()),
+        );
+
+        Ok(())
+    }
+
+    /// Wipe this client in a single call, e.g. when a duress PIN is entered
+    ///
+    /// Optionally deletes all remote messenger data first (see
+    /// `delete_all_data`), then signs out to invalidate the cached session,
+    /// deletes the local store, and finally drops the client, zeroizing the
+    /// in-memory identity key. Every step runs even if an earlier one fails,
+    /// and the failures of all steps are returned together at the end.
+    pub async fn panic_wipe(mut self, delete_remote: bool) -> Result<()> {
+        let mut errors = Vec::new();
+
+        if delete_remote {
+            if let Err(e) = self.delete_all_data().await {
+                errors.push(format!("Failed to delete remote data: {}", e));
+            }
+        }
+
+        if let Err(e) = self.client.signout(&self.keypair.public_key()).await {
+            errors.push(format!("Failed to sign out: {}", e));
+        }
+
+        if let Some(store) = self.store.take() {
+            if let Err(e) = store.destroy() {
+                errors.push(format!("Failed to delete the local store: {}", e));
+            }
+        }
 
+        // Dropping the keypair zeroizes the secret key
+        drop(self);
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(anyhow!("Wipe incomplete: {}", errors.join("; ")))
+        }
+    }
+
+    /// Delete the given message URLs in rate-limited batches
+    async fn delete_urls(&self, urls: &[String]) -> Result<()> {
         // Delete messages in smaller batches to avoid rate limiting
-        const BATCH_SIZE: usize = 5;
         for chunk in urls.chunks(BATCH_SIZE) {
             // Create delete futures for this batch
             let delete_futures: Vec<_> = chunk
                 .iter()
-                .map(|url| async move { self.client.delete(url).send().await })
+                .map(|url| async move {
+                    let started = Instant::now();
+                    let result = self.http_delete(url).send().await;
+                    let ok = result
+                        .as_ref()
+                        .is_ok_and(|response| !response.status().is_server_error());
+                    self.record_request("delete", started.elapsed(), ok);
+                    result
+                })
                 .collect();
 
             // Execute batch deletions in parallel
@@ -475,8 +5876,11 @@ impl PrivateMessengerClient {
                     Ok(response) if !response.status().is_success() => {
                         // Retry once on rate limiting
                         if response.status() == 429 {
-                            tokio::time::sleep(tokio::time::Duration::from_millis(1000)).await;
-                            let retry = self.client.delete(&chunk[i]).send().await?;
+                            tokio::time::sleep(tokio::time::Duration::from_millis(
+                                RATE_LIMIT_RETRY_MS,
+                            ))
+                            .await;
+                            let retry = self.http_delete(&chunk[i]).send().await?;
                             if !retry.status().is_success() {
                                 return Err(anyhow!(
                                     "Failed to delete message at {} after retry: {}",
@@ -499,12 +5903,237 @@ impl PrivateMessengerClient {
                 }
             }
 
+            for url in chunk {
+                self.cache.remove_url(url);
+            }
+
             // Add a small delay between batches to avoid rate limiting
             if chunk.len() == BATCH_SIZE {
-                tokio::time::sleep(tokio::time::Duration::from_millis(200)).await;
+                tokio::time::sleep(tokio::time::Duration::from_millis(BATCH_DELAY_MS)).await;
             }
         }
 
         Ok(())
     }
 }
+
+/// A fetched record after decryption and signature verification
+struct OpenedRecord {
+    url: String,
+    id: String,
+    record: String,
+    timestamp: u64,
+    sender: String,
+    content: String,
+    verified: bool,
+}
+
+/// Decrypt `(url, id, record)` triples in parallel and batch-verify them
+///
+/// CPU-bound; run it off the async executor.
+fn open_records(key: &[u8; 32], records: Vec<(String, String, String)>) -> Vec<OpenedRecord> {
+    let decrypted: Vec<_> = records
+        .into_par_iter()
+        .filter_map(|(url, id, record)| {
+            let message = PrivateMessage::from_record(&record, key).ok()?;
+            let content = message.decrypt_content_with_key(key).ok()?;
+            let sender = message.decrypt_sender_with_key(key).ok()?;
+            Some((url, id, record, message, content, sender))
+        })
+        .collect();
+
+    let entries: Vec<(&PrivateMessage, &str, &str)> = decrypted
+        .iter()
+        .map(|(_, _, _, message, content, sender)| (message, content.as_str(), sender.as_str()))
+        .collect();
+    let verified = PrivateMessage::verify_batch(&entries);
+
+    decrypted
+        .into_iter()
+        .zip(verified)
+        .map(
+            |((url, id, record, message, content, sender), verified)| OpenedRecord {
+                url,
+                id,
+                record,
+                timestamp: message.timestamp,
+                sender,
+                content,
+                verified,
+            },
+        )
+        .collect()
+}
+
+/// Build a diagnostic check result timed from `started`
+fn diagnostic_check(name: &str, started: Instant, ok: bool, detail: String) -> DiagnosticCheck {
+    DiagnosticCheck {
+        name: name.to_string(),
+        ok,
+        duration_ms: started.elapsed().as_millis() as u64,
+        detail,
+    }
+}
+
+/// Check a hex-encoded detached signature over profile JSON
+fn verify_profile_signature(pubky: &PublicKey, profile_data: &[u8], signature: &str) -> bool {
+    let Ok(bytes) = hex::decode(signature.trim()) else {
+        return false;
+    };
+    let Ok(bytes) = <[u8; 64]>::try_from(bytes.as_slice()) else {
+        return false;
+    };
+
+    pubky
+        .verify(profile_data, &Signature::from_bytes(&bytes))
+        .is_ok()
+}
+
+/// Every conversation path a rotating client may have written to with a
+/// peer: the static path and the path of each epoch up to the next one
+fn all_conversation_paths(shared_secret: &[u8; 32], rotation: PathRotation) -> HashSet<String> {
+    let mut paths: HashSet<String> = (0..=rotation.epoch_of(unix_now()) + 1)
+        .map(|epoch| epoch_conversation_path(shared_secret, epoch))
+        .collect();
+    paths.insert(static_conversation_path(shared_secret));
+    paths
+}
+
+/// The timeline event of a synced message
+fn timeline_event(message: &DecryptedMessage, edited_ids: &HashSet<String>) -> ConversationEvent {
+    if let MessageContent::System(SystemMessage::MembershipChanged {
+        group_id,
+        added,
+        removed,
+    }) = &message.body
+    {
+        return ConversationEvent::MembershipChanged {
+            message_id: message.id.clone(),
+            sender: message.sender.clone(),
+            group_id: group_id.clone(),
+            added: added.clone(),
+            removed: removed.clone(),
+        };
+    }
+
+    let message = message.clone();
+    if edited_ids.contains(&message.id) {
+        ConversationEvent::Edited { message }
+    } else {
+        ConversationEvent::Message { message }
+    }
+}
+
+/// The conversation setting a system message changes, if any
+fn setting_change(system: &SystemMessage) -> Option<(&str, serde_json::Value)> {
+    match system {
+        SystemMessage::SettingChanged { key, value } => Some((key.as_str(), value.clone())),
+        SystemMessage::ExpiryChanged { expires_in_days } => {
+            Some((EXPIRY_SETTING, serde_json::json!(expires_in_days)))
+        }
+        SystemMessage::KeyRotation(_)
+        | SystemMessage::MembershipChanged { .. }
+        | SystemMessage::TranscriptHash(_) => None,
+    }
+}
+
+/// A contact's explicit trust level, or `Tofu` once their identity is pinned
+fn effective_trust(store: &LocalStore, peer: &str) -> Result<TrustLevel> {
+    if let Some(level) = store.explicit_trust(peer)? {
+        return Ok(level);
+    }
+
+    Ok(match store.contact_pin(peer)? {
+        Some(_) => TrustLevel::Tofu,
+        None => TrustLevel::Unknown,
+    })
+}
+
+/// `User-Agent` of requests from clients without an app identity
+fn default_user_agent() -> String {
+    format!("pubky-messenger/{}", env!("CARGO_PKG_VERSION"))
+}
+
+/// Current Unix time in seconds
+pub(crate) fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+/// Whether `follow_url` is a record directly in `owner`'s follows directory
+fn follow_in_directory(owner: &str, follow_url: &str) -> bool {
+    PubkyUri::parse(follow_url).is_ok_and(|uri| {
+        uri.public_key().to_string() == owner
+            && uri
+                .relative_to(uri::FOLLOWS_PATH)
+                .is_some_and(|target| !target.contains('/'))
+    })
+}
+
+/// Read a response body, failing as soon as it exceeds `limit` bytes
+async fn read_capped(mut response: reqwest::Response, limit: usize) -> Result<Vec<u8>> {
+    if response
+        .content_length()
+        .is_some_and(|length| length > limit as u64)
+    {
+        return Err(anyhow!("Response exceeds {} bytes", limit));
+    }
+    let mut body = Vec::new();
+    while let Some(chunk) = response.chunk().await? {
+        if body.len() + chunk.len() > limit {
+            return Err(anyhow!("Response exceeds {} bytes", limit));
+        }
+        body.extend_from_slice(&chunk);
+    }
+    Ok(body)
+}
+
+/// Whether a request failed because the resource doesn't exist
+fn is_not_found(error: &anyhow::Error) -> bool {
+    error
+        .chain()
+        .filter_map(|cause| cause.downcast_ref::<reqwest::Error>())
+        .any(|error| error.status() == Some(reqwest::StatusCode::NOT_FOUND))
+}
+
+/// Whether a request was refused by an open circuit breaker
+fn is_peer_unreachable(error: &anyhow::Error) -> bool {
+    matches!(
+        error.downcast_ref::<MessengerError>(),
+        Some(MessengerError::PeerUnreachable { .. })
+    )
+}
+
+/// Extract the message ID from a stored message URL
+///
+/// Returns `None` for sidecar records (such as poll votes) kept in
+/// subdirectories of the conversation path.
+fn message_id_from_url(url: &str, private_path: &str) -> Option<String> {
+    let uri = PubkyUri::parse(url).ok()?;
+    if uri.conversation_path()? != private_path {
+        return None;
+    }
+    uri.message_id().map(|id| id.to_string())
+}
+
+/// Derive a stable message ID for an imported message
+fn import_message_id(
+    recipient: &PublicKey,
+    timestamp: u64,
+    content: &str,
+    occurrence: u32,
+) -> String {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(recipient.as_bytes());
+    hasher.update(&timestamp.to_be_bytes());
+    hasher.update(&occurrence.to_be_bytes());
+    hasher.update(content.as_bytes());
+    format!(
+        "{:0width$}-import-{}",
+        timestamp.saturating_mul(1000),
+        &hasher.finalize().to_hex()[..32],
+        width = TIMESTAMP_MS_DIGITS
+    )
+}
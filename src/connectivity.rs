@@ -0,0 +1,65 @@
+use serde::{Deserialize, Serialize};
+use std::sync::Mutex;
+
+/// Consecutive failed requests after which the client counts as offline
+pub const OFFLINE_AFTER_FAILURES: u32 = 3;
+
+/// How well the client reaches homeservers, judged from its latest requests
+///
+/// See `PrivateMessengerClient::connectivity`. Changes are raised as
+/// `NotificationEvent::ConnectivityChanged`, so apps can show a banner and
+/// queue messages locally while offline instead of sending into failures.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConnectivityState {
+    /// The latest request succeeded
+    #[default]
+    Online,
+    /// Some of the latest requests failed
+    Degraded,
+    /// The last `OFFLINE_AFTER_FAILURES` requests all failed
+    Offline,
+}
+
+#[derive(Default)]
+struct MonitorState {
+    state: ConnectivityState,
+    consecutive_failures: u32,
+}
+
+/// Moves between connectivity states as request outcomes come in
+///
+/// A request fails when the homeserver can't be reached or answers with a
+/// server error. One success brings the client back online.
+#[derive(Default)]
+pub(crate) struct ConnectivityMonitor {
+    inner: Mutex<MonitorState>,
+}
+
+impl ConnectivityMonitor {
+    pub fn state(&self) -> ConnectivityState {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner()).state
+    }
+
+    /// Record the outcome of a request, returning the new state if it changed
+    pub fn record(&self, ok: bool) -> Option<ConnectivityState> {
+        let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        let state = if ok {
+            inner.consecutive_failures = 0;
+            ConnectivityState::Online
+        } else {
+            inner.consecutive_failures = inner.consecutive_failures.saturating_add(1);
+            if inner.consecutive_failures >= OFFLINE_AFTER_FAILURES {
+                ConnectivityState::Offline
+            } else {
+                ConnectivityState::Degraded
+            }
+        };
+
+        if state == inner.state {
+            return None;
+        }
+        inner.state = state;
+        Some(state)
+    }
+}
@@ -0,0 +1,241 @@
+use anyhow::{anyhow, Result};
+use pkarr::PublicKey;
+use serde::{Deserialize, Serialize};
+
+use crate::trust::TrustLevel;
+
+/// Version of the contact interchange format written by `export_contacts`
+pub const CONTACTS_FORMAT_VERSION: u32 = 1;
+
+/// Longest alias of a contact, in characters
+pub const MAX_CONTACT_ALIAS_CHARS: usize = 64;
+
+/// Most tags on one contact
+pub const MAX_CONTACT_TAGS: usize = 32;
+
+/// Longest tag, in characters
+const MAX_CONTACT_TAG_CHARS: usize = 32;
+
+/// Header row of the CSV format
+const CSV_HEADER: [&str; 4] = ["pubky", "alias", "tags", "trust"];
+
+/// An entry of the contact book
+///
+/// This is also one entry of the interchange format, so that contact books
+/// move between apps built on this crate. The JSON format is an object with
+/// the format version and the contacts:
+///
+/// ```json
+/// {"version": 1, "contacts": [
+///   {"pubky": "o4dksf...", "alias": "Alice", "tags": ["work"], "trust": "verified"}
+/// ]}
+/// ```
+///
+/// The CSV format has a `pubky,alias,tags,trust` header row, separates tags
+/// with `;`, and quotes fields as in RFC 4180. `alias`, `tags`, and `trust`
+/// may be left out or empty.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Contact {
+    pub pubky: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub alias: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub trust: TrustLevel,
+}
+
+/// The JSON interchange format
+#[derive(Debug, Serialize, Deserialize)]
+struct ContactBook {
+    version: u32,
+    contacts: Vec<Contact>,
+}
+
+impl Contact {
+    /// Check that the contact is well formed
+    pub fn validate(&self) -> Result<()> {
+        PublicKey::try_from(self.pubky.as_str())
+            .map_err(|e| anyhow!("Invalid contact pubky {}: {}", self.pubky, e))?;
+
+        if let Some(alias) = self.alias.as_deref() {
+            if alias.trim().is_empty() || alias.chars().count() > MAX_CONTACT_ALIAS_CHARS {
+                return Err(anyhow!(
+                    "Contact alias must be 1 to {} characters",
+                    MAX_CONTACT_ALIAS_CHARS
+                ));
+            }
+        }
+
+        if self.tags.len() > MAX_CONTACT_TAGS {
+            return Err(anyhow!(
+                "A contact can have at most {} tags",
+                MAX_CONTACT_TAGS
+            ));
+        }
+        for tag in self.tags.iter() {
+            if tag.trim().is_empty()
+                || tag.chars().count() > MAX_CONTACT_TAG_CHARS
+                || tag.contains(';')
+            {
+                return Err(anyhow!(
+                    "Contact tags must be 1 to {} characters without ';'",
+                    MAX_CONTACT_TAG_CHARS
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Write contacts in the JSON interchange format
+    pub fn to_json(contacts: &[Contact]) -> Result<Vec<u8>> {
+        let book = ContactBook {
+            version: CONTACTS_FORMAT_VERSION,
+            contacts: contacts.to_vec(),
+        };
+        Ok(serde_json::to_vec_pretty(&book)?)
+    }
+
+    /// Write contacts in the CSV interchange format
+    pub fn to_csv(contacts: &[Contact]) -> Vec<u8> {
+        let mut csv = CSV_HEADER.join(",");
+        csv.push_str("\r\n");
+        for contact in contacts {
+            let fields = [
+                contact.pubky.clone(),
+                contact.alias.clone().unwrap_or_default(),
+                contact.tags.join(";"),
+                contact.trust.as_str().to_string(),
+            ];
+            let row: Vec<String> = fields.iter().map(|field| csv_field(field)).collect();
+            csv.push_str(&row.join(","));
+            csv.push_str("\r\n");
+        }
+        csv.into_bytes()
+    }
+
+    /// Read contacts in either interchange format, checking every one
+    ///
+    /// JSON is recognized by its leading `{`, anything else is read as CSV.
+    pub fn parse(bytes: &[u8]) -> Result<Vec<Contact>> {
+        let text = std::str::from_utf8(bytes)
+            .map_err(|_| anyhow!("Contacts must be UTF-8 text"))?
+            .trim_start_matches('\u{feff}');
+
+        let contacts = if text.trim_start().starts_with('{') {
+            let book: ContactBook = serde_json::from_str(text)?;
+            if book.version > CONTACTS_FORMAT_VERSION {
+                return Err(anyhow!(
+                    "Unsupported contacts format version {}",
+                    book.version
+                ));
+            }
+            book.contacts
+        } else {
+            parse_csv(text)?
+        };
+
+        for contact in contacts.iter() {
+            contact.validate()?;
+        }
+        Ok(contacts)
+    }
+}
+
+/// Quote a CSV field if it needs it
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// Split CSV text into rows of fields, following RFC 4180 quoting
+fn csv_rows(text: &str) -> Result<Vec<Vec<String>>> {
+    let mut rows = Vec::new();
+    let mut row = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = text.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match (quoted, c) {
+            (true, '"') if chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            (true, '"') => quoted = false,
+            (true, c) => field.push(c),
+            (false, '"') if field.is_empty() => quoted = true,
+            (false, ',') => row.push(std::mem::take(&mut field)),
+            (false, '\r') if chars.peek() == Some(&'\n') => {}
+            (false, '\n') => {
+                row.push(std::mem::take(&mut field));
+                rows.push(std::mem::take(&mut row));
+            }
+            (false, c) => field.push(c),
+        }
+    }
+    if quoted {
+        return Err(anyhow!("Unterminated quoted field in contacts CSV"));
+    }
+    if !field.is_empty() || !row.is_empty() {
+        row.push(field);
+        rows.push(row);
+    }
+
+    rows.retain(|row| !(row.len() == 1 && row[0].trim().is_empty()));
+    Ok(rows)
+}
+
+/// Read contacts from CSV with a header row naming its columns
+fn parse_csv(text: &str) -> Result<Vec<Contact>> {
+    let mut rows = csv_rows(text)?.into_iter();
+    let header: Vec<String> = rows
+        .next()
+        .ok_or_else(|| anyhow!("Contacts CSV has no header row"))?
+        .iter()
+        .map(|name| name.trim().to_lowercase())
+        .collect();
+    let column = |name: &str| header.iter().position(|column| column == name);
+    let pubky_column =
+        column("pubky").ok_or_else(|| anyhow!("Contacts CSV has no pubky column"))?;
+    let (alias_column, tags_column, trust_column) =
+        (column("alias"), column("tags"), column("trust"));
+
+    let mut contacts = Vec::new();
+    for (line, row) in rows.enumerate() {
+        let get = |column: Option<usize>| {
+            column
+                .and_then(|column| row.get(column))
+                .map(|value| value.trim())
+                .filter(|value| !value.is_empty())
+        };
+
+        let pubky = get(Some(pubky_column))
+            .ok_or_else(|| anyhow!("Contacts CSV row {} has no pubky", line + 2))?;
+        let trust = match get(trust_column) {
+            Some(level) => TrustLevel::parse(&level.to_lowercase())
+                .ok_or_else(|| anyhow!("Unknown trust level: {}", level))?,
+            None => TrustLevel::Unknown,
+        };
+
+        contacts.push(Contact {
+            pubky: pubky.to_string(),
+            alias: get(alias_column).map(str::to_string),
+            tags: get(tags_column)
+                .map(|tags| {
+                    tags.split(';')
+                        .map(str::trim)
+                        .filter(|tag| !tag.is_empty())
+                        .map(str::to_string)
+                        .collect()
+                })
+                .unwrap_or_default(),
+            trust,
+        });
+    }
+    Ok(contacts)
+}
@@ -0,0 +1,1148 @@
+use anyhow::{anyhow, Result};
+use pkarr::PublicKey;
+use rand::distributions::{Alphanumeric, DistString};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use crate::message::{DecryptedMessage, PrivateMessage};
+use crate::rotation::KeyRotationProof;
+use crate::transcript::TranscriptHash;
+
+/// Version of the structured payload format
+pub const PAYLOAD_VERSION: u8 = 1;
+
+/// Typed content of a message
+///
+/// Plain text is sent as-is, so it stays readable by clients that predate
+/// structured content. Every other variant is sent as a versioned JSON
+/// payload inside the encrypted content.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum MessageContent {
+    /// A plain text message
+    Text { text: String },
+    /// A request to be paid on-chain or over Lightning
+    PaymentRequest(PaymentRequest),
+    /// Proof that an earlier payment request was paid
+    PaymentProof(PaymentProof),
+    /// A poll the participants can vote on
+    Poll(Poll),
+    /// A few emoji on their own, rendered large rather than as text
+    LargeEmoji { emoji: String },
+    /// A sticker from a built-in pack or an uploaded image, see `Sticker`
+    Sticker(Sticker),
+    /// Dummy record written as cover traffic, dropped when fetching
+    Cover { padding: String },
+    /// An opaque binary payload for applications, such as a protobuf
+    Binary {
+        /// MIME type or application-defined tag describing the data
+        content_type: String,
+        #[serde(with = "base64_bytes")]
+        data: Vec<u8>,
+    },
+    /// Structured data of an application protocol, tagged with its type
+    Json {
+        type_tag: String,
+        value: serde_json::Value,
+    },
+    /// A control message processed by the client, see `SystemMessage`
+    System(SystemMessage),
+    /// A correction to an earlier text message of the sender, see `MessageEdit`
+    Edit(MessageEdit),
+    /// A command for a bot, with typed arguments, see `Command`
+    Command(Command),
+}
+
+/// Maximum size of a binary payload, in bytes
+pub const MAX_BINARY_BYTES: usize = 64 * 1024;
+
+/// Structured payload stored inside the encrypted message content
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct MessagePayload {
+    pub v: u8,
+    #[serde(flatten)]
+    pub content: MessageContent,
+    #[serde(flatten)]
+    pub options: MessageOptions,
+}
+
+/// Per-message options carried in the payload next to the content
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub(crate) struct MessageOptions {
+    /// ID of the message this one replies to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reply_to: Option<String>,
+    /// Unix timestamp (seconds) after which the message is no longer shown
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<u64>,
+    /// Random filler that hides the length of the content
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub padding: Option<String>,
+    /// Application-defined key-value pairs
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub metadata: BTreeMap<String, String>,
+    /// Users mentioned in the text
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub mentions: Vec<Mention>,
+    /// Position of the message among the sender's messages in the
+    /// conversation, counting from 1
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seq: Option<u64>,
+    /// Name and version of the app that sent the message
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sent_with: Option<String>,
+    /// BCP-47 language tag of the text, such as `en` or `pt-BR`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub language: Option<String>,
+}
+
+/// A mention of a user in the text of a message
+///
+/// `start..end` is the byte range of the `@pubky` reference in the text.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Mention {
+    pub pubky: String,
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Mention {
+    /// Spans of every `@pubky` reference to `pubky` in `text`
+    pub fn find(text: &str, pubky: &PublicKey) -> Vec<Self> {
+        let reference = format!("@{}", pubky);
+        text.match_indices(&reference)
+            .map(|(start, _)| Self {
+                pubky: pubky.to_string(),
+                start,
+                end: start + reference.len(),
+            })
+            .collect()
+    }
+
+    /// Whether the span covers an `@pubky` reference to a valid key in `text`
+    pub fn is_valid_in(&self, text: &str) -> bool {
+        let reference_matches = text
+            .get(self.start..self.end)
+            .and_then(|reference| reference.strip_prefix('@'))
+            == Some(self.pubky.as_str());
+        reference_matches && PublicKey::try_from(self.pubky.as_str()).is_ok()
+    }
+}
+
+/// Block size that padded messages are rounded up to, in bytes
+const PADDING_BLOCK: usize = 256;
+
+/// Maximum number of metadata entries on a message
+pub const MAX_METADATA_ENTRIES: usize = 16;
+
+/// Maximum combined size of metadata keys and values, in bytes
+pub const MAX_METADATA_BYTES: usize = 1024;
+
+/// Longest language tag accepted, in characters
+const MAX_LANGUAGE_TAG_CHARS: usize = 35;
+
+/// Whether `tag` is a well-formed BCP-47 language tag
+///
+/// Checks the shape only: a 2 to 8 letter language subtag, or `x` or `i`
+/// for private use and legacy tags, followed by subtags of 1 to 8 letters
+/// and digits. Whether the subtags are registered is not checked.
+pub fn is_language_tag(tag: &str) -> bool {
+    if tag.len() > MAX_LANGUAGE_TAG_CHARS {
+        return false;
+    }
+
+    let mut subtags = tag.split('-');
+    let primary = subtags.next().unwrap_or_default();
+    let primary_ok = matches!(primary, "x" | "X" | "i" | "I")
+        || ((2..=8).contains(&primary.len()) && primary.chars().all(|c| c.is_ascii_alphabetic()));
+    primary_ok
+        && subtags.all(|subtag| {
+            (1..=8).contains(&subtag.len()) && subtag.chars().all(|c| c.is_ascii_alphanumeric())
+        })
+}
+
+impl MessageOptions {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Check that the metadata stays within its limits
+    pub fn validate(&self) -> Result<()> {
+        if self.metadata.len() > MAX_METADATA_ENTRIES {
+            return Err(anyhow!(
+                "Messages can carry at most {} metadata entries",
+                MAX_METADATA_ENTRIES
+            ));
+        }
+
+        let size: usize = self
+            .metadata
+            .iter()
+            .map(|(key, value)| key.len() + value.len())
+            .sum();
+        if size > MAX_METADATA_BYTES {
+            return Err(anyhow!(
+                "Message metadata cannot exceed {} bytes",
+                MAX_METADATA_BYTES
+            ));
+        }
+
+        if self.metadata.keys().any(|key| key.trim().is_empty()) {
+            return Err(anyhow!("Metadata keys cannot be empty"));
+        }
+
+        if let Some(language) = self.language.as_deref() {
+            if !is_language_tag(language) {
+                return Err(anyhow!("Invalid BCP-47 language tag: {}", language));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Fill `padding` so that `content` encodes to a multiple of the
+    /// padding block size
+    pub fn pad(&mut self, content: &MessageContent) -> Result<()> {
+        // Measure with an empty filler; each filler character adds one byte
+        self.padding = Some(String::new());
+        let unpadded = content.encode_with(self)?.len();
+        let length = (PADDING_BLOCK - unpadded % PADDING_BLOCK) % PADDING_BLOCK;
+        self.padding = Some(Alphanumeric.sample_string(&mut rand::thread_rng(), length));
+        Ok(())
+    }
+}
+
+impl MessageContent {
+    /// Create plain text content
+    pub fn text(text: impl Into<String>) -> Self {
+        Self::Text { text: text.into() }
+    }
+
+    /// Check that the content is well formed before sending
+    pub fn validate(&self) -> Result<()> {
+        match self {
+            Self::Text { .. } => Ok(()),
+            Self::PaymentRequest(request) => request.validate(),
+            Self::PaymentProof(proof) => proof.validate(),
+            Self::Poll(poll) => poll.validate(),
+            Self::LargeEmoji { emoji } => {
+                if !is_large_emoji(emoji) {
+                    return Err(anyhow!(
+                        "Large emoji must be at most {} emoji characters",
+                        MAX_LARGE_EMOJI_CHARS
+                    ));
+                }
+                Ok(())
+            }
+            Self::Sticker(sticker) => sticker.validate(),
+            Self::Cover { .. } => Ok(()),
+            Self::Binary { content_type, data } => {
+                if content_type.trim().is_empty() {
+                    return Err(anyhow!("Binary payloads need a content type"));
+                }
+                if data.len() > MAX_BINARY_BYTES {
+                    return Err(anyhow!(
+                        "Binary payloads cannot exceed {} bytes",
+                        MAX_BINARY_BYTES
+                    ));
+                }
+                Ok(())
+            }
+            Self::Json { type_tag, .. } => {
+                if type_tag.trim().is_empty() {
+                    return Err(anyhow!("JSON payloads need a type tag"));
+                }
+                Ok(())
+            }
+            Self::System(system) => system.validate(),
+            Self::Edit(edit) => edit.validate(),
+            Self::Command(command) => command.validate(),
+        }
+    }
+
+    /// Large emoji content for `text`, if it consists of a few emoji only
+    ///
+    /// Lets UIs send emoji-only input as `LargeEmoji` and everything else as
+    /// text.
+    pub fn large_emoji(text: &str) -> Option<Self> {
+        let emoji = text.trim();
+        is_large_emoji(emoji).then(|| Self::LargeEmoji {
+            emoji: emoji.to_string(),
+        })
+    }
+
+    /// The kind of content, as named in the payload's `type` field
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Text { .. } => "text",
+            Self::PaymentRequest(_) => "payment_request",
+            Self::PaymentProof(_) => "payment_proof",
+            Self::Poll(_) => "poll",
+            Self::LargeEmoji { .. } => "large_emoji",
+            Self::Sticker(_) => "sticker",
+            Self::Cover { .. } => "cover",
+            Self::Binary { .. } => "binary",
+            Self::Json { .. } => "json",
+            Self::System(_) => "system",
+            Self::Edit(_) => "edit",
+            Self::Command(_) => "command",
+        }
+    }
+
+    /// Whether this is cover traffic rather than a real message
+    pub fn is_cover(&self) -> bool {
+        matches!(self, Self::Cover { .. })
+    }
+
+    /// Whether this is a control message rather than chat
+    pub fn is_system(&self) -> bool {
+        matches!(self, Self::System(_))
+    }
+
+    /// Whether this edits an earlier message rather than being one
+    pub fn is_edit(&self) -> bool {
+        matches!(self, Self::Edit(_))
+    }
+
+    /// Encode the content as the string that gets encrypted
+    pub fn encode(&self) -> Result<String> {
+        self.encode_with(&MessageOptions::default())
+    }
+
+    /// Encode the content along with per-message options
+    ///
+    /// Text without options stays a plain string.
+    pub(crate) fn encode_with(&self, options: &MessageOptions) -> Result<String> {
+        match self {
+            Self::Text { text } if options.is_empty() => Ok(text.clone()),
+            _ => Ok(serde_json::to_string(&MessagePayload {
+                v: PAYLOAD_VERSION,
+                content: self.clone(),
+                options: options.clone(),
+            })?),
+        }
+    }
+
+    /// Decode decrypted content, treating anything that is not a structured
+    /// payload as plain text
+    pub fn decode(content: &str) -> Self {
+        Self::decode_with_options(content).0
+    }
+
+    /// Decode decrypted content along with its per-message options
+    pub(crate) fn decode_with_options(content: &str) -> (Self, MessageOptions) {
+        if content.starts_with('{') {
+            if let Ok(payload) = serde_json::from_str::<MessagePayload>(content) {
+                if payload.v == PAYLOAD_VERSION {
+                    return (payload.content, payload.options);
+                }
+            }
+        }
+
+        (Self::text(content), MessageOptions::default())
+    }
+}
+
+/// A control message, encrypted like any other message but acted on by the
+/// client and rendered as a notice instead of chat
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum SystemMessage {
+    /// The sender moved their identity to a new keypair
+    KeyRotation(KeyRotationProof),
+    /// The sender changed an application-defined conversation setting
+    SettingChanged {
+        key: String,
+        value: serde_json::Value,
+    },
+    /// The sender changed how long new messages in the conversation last;
+    /// `None` turns disappearing messages off
+    ExpiryChanged { expires_in_days: Option<u64> },
+    /// Members joined or left a group conversation
+    MembershipChanged {
+        group_id: String,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        added: Vec<String>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        removed: Vec<String>,
+    },
+    /// Digest of the conversation as the sender sees it, for the peer to
+    /// compare with their own
+    TranscriptHash(TranscriptHash),
+}
+
+impl SystemMessage {
+    /// Check that the message is well formed before sending
+    pub fn validate(&self) -> Result<()> {
+        match self {
+            Self::KeyRotation(proof) => proof.verify(),
+            Self::SettingChanged { key, .. } => {
+                if key.trim().is_empty() {
+                    return Err(anyhow!("Conversation setting keys cannot be empty"));
+                }
+                Ok(())
+            }
+            Self::ExpiryChanged { expires_in_days } => {
+                if *expires_in_days == Some(0) {
+                    return Err(anyhow!("Messages must last at least one day"));
+                }
+                Ok(())
+            }
+            Self::MembershipChanged {
+                group_id,
+                added,
+                removed,
+            } => {
+                if group_id.trim().is_empty() {
+                    return Err(anyhow!("Membership changes need a group ID"));
+                }
+                for pubky in added.iter().chain(removed) {
+                    PublicKey::try_from(pubky.as_str())
+                        .map_err(|e| anyhow!("Invalid group member {}: {}", pubky, e))?;
+                }
+                Ok(())
+            }
+            Self::TranscriptHash(hash) => hash.validate(),
+        }
+    }
+
+    /// One-line description to show in place of the message
+    pub fn summary(&self) -> String {
+        match self {
+            Self::KeyRotation(proof) => format!("Moved to a new identity: {}", proof.new_pubky),
+            Self::SettingChanged { key, value } => format!("Changed {} to {}", key, value),
+            Self::ExpiryChanged {
+                expires_in_days: Some(days),
+            } => format!("Set new messages to disappear after {} days", days),
+            Self::ExpiryChanged {
+                expires_in_days: None,
+            } => "Turned off disappearing messages".to_string(),
+            Self::MembershipChanged { added, removed, .. } => format!(
+                "Added {} and removed {} group members",
+                added.len(),
+                removed.len()
+            ),
+            Self::TranscriptHash(hash) => {
+                format!("Checked {} messages against the peer's view", hash.records)
+            }
+        }
+    }
+}
+
+/// Maximum number of characters of large emoji content, counting modifiers
+/// and joiners
+pub const MAX_LARGE_EMOJI_CHARS: usize = 24;
+
+/// Maximum size of an uploaded sticker image, in bytes
+pub const MAX_STICKER_BYTES: usize = 512 * 1024;
+
+/// A sticker, shown as an image instead of text
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Sticker {
+    pub source: StickerSource,
+    /// Emoji the sticker stands for, shown by clients that cannot render it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub emoji: Option<String>,
+}
+
+/// Where the image of a sticker comes from
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum StickerSource {
+    /// A sticker of a pack shipped with clients, identified by name
+    Pack { pack: String, sticker: String },
+    /// An encrypted image on the sender's homeserver, see
+    /// `PrivateMessengerClient::upload_sticker`
+    Blob {
+        /// `pubky://` URL of the encrypted image
+        url: String,
+        /// Hex-encoded BLAKE3 hash of the encrypted image
+        hash: String,
+        /// Hex-encoded key the image is encrypted with
+        key: String,
+        /// MIME type of the image, such as `image/webp`
+        content_type: String,
+    },
+}
+
+impl Sticker {
+    /// A sticker from a built-in pack
+    pub fn from_pack(pack: impl Into<String>, sticker: impl Into<String>) -> Self {
+        Self {
+            source: StickerSource::Pack {
+                pack: pack.into(),
+                sticker: sticker.into(),
+            },
+            emoji: None,
+        }
+    }
+
+    /// Check that the sticker names a pack entry or a well-formed blob
+    pub fn validate(&self) -> Result<()> {
+        match &self.source {
+            StickerSource::Pack { pack, sticker } => {
+                if pack.trim().is_empty() || sticker.trim().is_empty() {
+                    return Err(anyhow!("Pack stickers need a pack and a sticker name"));
+                }
+            }
+            StickerSource::Blob {
+                url,
+                hash,
+                key,
+                content_type,
+            } => {
+                if !url.starts_with("pubky://") {
+                    return Err(anyhow!("Sticker images must be pubky:// URLs"));
+                }
+                let is_hex_32 =
+                    |value: &str| value.len() == 64 && value.chars().all(|c| c.is_ascii_hexdigit());
+                if !is_hex_32(hash) || !is_hex_32(key) {
+                    return Err(anyhow!("Sticker hash and key must be 32 hex-encoded bytes"));
+                }
+                if !content_type.starts_with("image/") {
+                    return Err(anyhow!("Sticker content type must be an image type"));
+                }
+            }
+        }
+
+        if self
+            .emoji
+            .as_deref()
+            .is_some_and(|emoji| !is_large_emoji(emoji))
+        {
+            return Err(anyhow!("Sticker fallback must be emoji"));
+        }
+
+        Ok(())
+    }
+}
+
+/// Every this many edits of a message, the full text is sent instead of a
+/// delta
+pub const EDIT_SNAPSHOT_INTERVAL: u32 = 8;
+
+/// An edit of an earlier text message
+///
+/// Edits are sent as messages of their own and the edited message is never
+/// rewritten. Most edits carry only the changed span, as a delta against the
+/// previous version; every `EDIT_SNAPSHOT_INTERVAL`th edit, and any edit
+/// whose delta would not be smaller than the text, carries the full text so
+/// a reader that missed an edit recovers at the next snapshot. Fetches fold
+/// edits into the message they change.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MessageEdit {
+    /// ID of the edited message
+    pub target: String,
+    /// Number of the edit, 1 for the first edit of the message
+    pub version: u32,
+    pub change: EditChange,
+}
+
+/// The change an edit makes to the text of the previous version
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum EditChange {
+    /// The complete new text
+    Snapshot { text: String },
+    /// Replace `delete` bytes at byte offset `start` with `insert`
+    Delta {
+        start: usize,
+        delete: usize,
+        insert: String,
+    },
+}
+
+impl MessageEdit {
+    /// The edit number `version` of `target`, changing its text from `old`
+    /// to `new`
+    pub fn between(target: impl Into<String>, version: u32, old: &str, new: &str) -> Self {
+        let change = EditChange::delta(old, new)
+            .filter(|_| version % EDIT_SNAPSHOT_INTERVAL != 0)
+            .unwrap_or_else(|| EditChange::Snapshot {
+                text: new.to_string(),
+            });
+
+        Self {
+            target: target.into(),
+            version,
+            change,
+        }
+    }
+
+    /// Check that the edit names a message and counts from 1
+    pub fn validate(&self) -> Result<()> {
+        PrivateMessage::validate_id(&self.target)?;
+        if self.version == 0 {
+            return Err(anyhow!("Edit versions start at 1"));
+        }
+        Ok(())
+    }
+}
+
+impl EditChange {
+    /// The delta from `old` to `new`, if it is smaller than `new`
+    ///
+    /// Keeps the longest common prefix and suffix and replaces the span
+    /// between them, which is all a typical correction touches.
+    pub fn delta(old: &str, new: &str) -> Option<Self> {
+        let prefix: usize = old
+            .chars()
+            .zip(new.chars())
+            .take_while(|(a, b)| a == b)
+            .map(|(c, _)| c.len_utf8())
+            .sum();
+        let (old_rest, new_rest) = (&old[prefix..], &new[prefix..]);
+        let suffix: usize = old_rest
+            .chars()
+            .rev()
+            .zip(new_rest.chars().rev())
+            .take_while(|(a, b)| a == b)
+            .map(|(c, _)| c.len_utf8())
+            .sum();
+
+        let insert = &new_rest[..new_rest.len() - suffix];
+        (insert.len() < new.len()).then(|| Self::Delta {
+            start: prefix,
+            delete: old_rest.len() - suffix,
+            insert: insert.to_string(),
+        })
+    }
+
+    /// Apply the change to the text of the previous version
+    ///
+    /// Returns `None` for a delta whose span does not fit `text`.
+    pub fn apply(&self, text: &str) -> Option<String> {
+        match self {
+            Self::Snapshot { text } => Some(text.clone()),
+            Self::Delta {
+                start,
+                delete,
+                insert,
+            } => {
+                let end = start.checked_add(*delete)?;
+                if !text.is_char_boundary(*start) || !text.is_char_boundary(end) {
+                    return None;
+                }
+                Some(format!("{}{}{}", &text[..*start], insert, &text[end..]))
+            }
+        }
+    }
+}
+
+/// A payment request carrying a BIP21 URI or a BOLT11 Lightning invoice
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PaymentRequest {
+    /// A `bitcoin:` BIP21 URI, or a Lightning invoice (optionally `lightning:` prefixed)
+    pub uri: String,
+    /// Requested amount in satoshis, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub amount_sat: Option<u64>,
+    /// Human readable description of the payment
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub memo: Option<String>,
+}
+
+impl PaymentRequest {
+    /// Check that the URI is a BIP21 URI or Lightning invoice, and that any
+    /// amount it encodes agrees with `amount_sat`
+    pub fn validate(&self) -> Result<()> {
+        let uri = self.uri.trim();
+        let lower = uri.to_ascii_lowercase();
+
+        let encoded_msat = if let Some(rest) = lower.strip_prefix("bitcoin:") {
+            parse_bip21_amount_msat(rest)?
+        } else {
+            let invoice = lower.strip_prefix("lightning:").unwrap_or(&lower);
+            parse_bolt11_amount_msat(invoice)?
+        };
+
+        if let (Some(encoded_msat), Some(amount_sat)) = (encoded_msat, self.amount_sat) {
+            if encoded_msat != u128::from(amount_sat) * 1000 {
+                return Err(anyhow!(
+                    "Payment amount does not match the amount in the URI"
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Settlement of a payment request, sent back into the conversation
+///
+/// Exactly one of `preimage` (Lightning) or `txid` (on-chain) is set.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PaymentProof {
+    /// ID of the message carrying the payment request
+    pub request_id: String,
+    /// Hex-encoded Lightning payment preimage
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub preimage: Option<String>,
+    /// Hex-encoded on-chain transaction ID
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub txid: Option<String>,
+}
+
+impl PaymentProof {
+    /// Proof of a Lightning payment
+    pub fn lightning(request_id: impl Into<String>, preimage: impl Into<String>) -> Self {
+        Self {
+            request_id: request_id.into(),
+            preimage: Some(preimage.into()),
+            txid: None,
+        }
+    }
+
+    /// Proof of an on-chain payment
+    pub fn on_chain(request_id: impl Into<String>, txid: impl Into<String>) -> Self {
+        Self {
+            request_id: request_id.into(),
+            preimage: None,
+            txid: Some(txid.into()),
+        }
+    }
+
+    /// Check that the proof references a request and carries one 32-byte hex value
+    pub fn validate(&self) -> Result<()> {
+        PrivateMessage::validate_id(&self.request_id)?;
+
+        let value = match (&self.preimage, &self.txid) {
+            (Some(preimage), None) => preimage,
+            (None, Some(txid)) => txid,
+            _ => {
+                return Err(anyhow!(
+                    "Payment proof needs exactly one of a preimage or a txid"
+                ))
+            }
+        };
+
+        if value.len() != 64 || !value.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(anyhow!("Payment proof must be 32 bytes of hex"));
+        }
+
+        Ok(())
+    }
+
+    /// Whether this proof settles the payment request in `message`
+    pub fn settles(&self, message: &DecryptedMessage) -> bool {
+        message.id == self.request_id && matches!(message.body, MessageContent::PaymentRequest(_))
+    }
+}
+
+/// Maximum number of options in a poll
+pub const MAX_POLL_OPTIONS: usize = 20;
+
+/// A poll posted into a conversation
+///
+/// The poll is an ordinary message; its ID identifies the poll. Votes are
+/// kept as separate encrypted records next to the conversation, one per
+/// participant, so changing a vote overwrites the previous one.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Poll {
+    pub question: String,
+    pub options: Vec<String>,
+}
+
+impl Poll {
+    /// Check that the poll has a question and 2 to `MAX_POLL_OPTIONS` distinct options
+    pub fn validate(&self) -> Result<()> {
+        if self.question.trim().is_empty() {
+            return Err(anyhow!("Poll question cannot be empty"));
+        }
+
+        if self.options.len() < 2 || self.options.len() > MAX_POLL_OPTIONS {
+            return Err(anyhow!(
+                "A poll needs between 2 and {} options",
+                MAX_POLL_OPTIONS
+            ));
+        }
+
+        let mut seen = HashSet::new();
+        for option in self.options.iter() {
+            if option.trim().is_empty() || !seen.insert(option.trim()) {
+                return Err(anyhow!("Poll options must be non-empty and distinct"));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Maximum length of a command name
+pub const MAX_COMMAND_NAME_CHARS: usize = 32;
+
+/// A bot command such as `/remind 10 "buy milk" urgent=true`
+///
+/// Sent as structured content, so bots receive typed arguments instead of
+/// parsing free text. `Command::parse` turns typed-in text into a command,
+/// and `to_text` renders it back for clients that show commands as text.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Command {
+    /// Name without the leading `/`: ASCII letters, digits, `-`, and `_`
+    pub name: String,
+    /// Positional arguments
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub args: Vec<serde_json::Value>,
+    /// Named arguments
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub options: BTreeMap<String, serde_json::Value>,
+}
+
+impl Command {
+    /// A command without arguments
+    pub fn new(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            args: Vec::new(),
+            options: BTreeMap::new(),
+        }
+    }
+
+    /// Add a positional argument
+    pub fn arg(mut self, value: impl Into<serde_json::Value>) -> Self {
+        self.args.push(value.into());
+        self
+    }
+
+    /// Add a named argument
+    pub fn option(mut self, key: impl Into<String>, value: impl Into<serde_json::Value>) -> Self {
+        self.options.insert(key.into(), value.into());
+        self
+    }
+
+    /// Parse text typed as a command, such as `/remind 10 "buy milk"`
+    ///
+    /// Arguments are separated by whitespace; double quotes group words and
+    /// keep an argument a string, with `\"` and `\\` as escapes. Unquoted
+    /// integers, decimals, `true`, and `false` become numbers and booleans.
+    /// An unquoted `key=value` is a named argument. Returns `None` for text
+    /// that isn't a well-formed command.
+    pub fn parse(text: &str) -> Option<Self> {
+        let rest = text.trim().strip_prefix('/')?;
+        let (name, rest) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+        let mut command = Self::new(name);
+        command.validate().ok()?;
+
+        for (token, quote_start) in split_command_args(rest)? {
+            // Text quoted from its start stays a string
+            let value_at = |text: &str, start: usize| {
+                if quote_start == Some(start) {
+                    serde_json::Value::String(text.to_string())
+                } else {
+                    typed_command_arg(text)
+                }
+            };
+            if let Some((key, value)) = token.split_once('=') {
+                if is_command_name(key) && quote_start.map_or(true, |start| start > key.len()) {
+                    let value = value_at(value, key.len() + 1);
+                    command.options.insert(key.to_string(), value);
+                    continue;
+                }
+            }
+            command.args.push(value_at(&token, 0));
+        }
+
+        Some(command)
+    }
+
+    /// Check that the command has a valid name and named arguments
+    pub fn validate(&self) -> Result<()> {
+        if !is_command_name(&self.name) {
+            return Err(anyhow!(
+                "Command names must be 1 to {} ASCII letters, digits, '-' or '_'",
+                MAX_COMMAND_NAME_CHARS
+            ));
+        }
+        if self.options.keys().any(|key| !is_command_name(key)) {
+            return Err(anyhow!("Invalid command option name"));
+        }
+        Ok(())
+    }
+
+    /// The positional argument at `index`, converted to `T`
+    pub fn get<T: DeserializeOwned>(&self, index: usize) -> Result<T> {
+        let value = self
+            .args
+            .get(index)
+            .ok_or_else(|| anyhow!("Command /{} has no argument {}", self.name, index))?;
+        Ok(serde_json::from_value(value.clone())?)
+    }
+
+    /// The named argument `key` converted to `T`, if given
+    pub fn get_option<T: DeserializeOwned>(&self, key: &str) -> Result<Option<T>> {
+        self.options
+            .get(key)
+            .map(|value| Ok(serde_json::from_value(value.clone())?))
+            .transpose()
+    }
+
+    /// Render the command as text that `parse` reads back
+    ///
+    /// Arrays, objects, and nulls are written as JSON and read back as
+    /// strings.
+    pub fn to_text(&self) -> String {
+        let mut text = format!("/{}", self.name);
+        for arg in self.args.iter() {
+            text.push(' ');
+            text.push_str(&command_arg_text(arg));
+        }
+        for (key, value) in self.options.iter() {
+            text.push_str(&format!(" {}={}", key, command_arg_text(value)));
+        }
+        text
+    }
+}
+
+/// Whether `name` is a valid command or option name
+fn is_command_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_COMMAND_NAME_CHARS
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Split command arguments at whitespace, grouping quoted text
+///
+/// Returns each argument with the position its first quote started at, or
+/// `None` if a quote is left open.
+fn split_command_args(text: &str) -> Option<Vec<(String, Option<usize>)>> {
+    let mut tokens = Vec::new();
+    let mut chars = text.chars().peekable();
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        if chars.peek().is_none() {
+            return Some(tokens);
+        }
+
+        let mut token = String::new();
+        let mut quote_start = None;
+        while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+            if c != '"' {
+                token.push(c);
+                continue;
+            }
+            quote_start.get_or_insert(token.len());
+            loop {
+                match chars.next()? {
+                    '"' => break,
+                    '\\' => token.push(chars.next()?),
+                    c => token.push(c),
+                }
+            }
+        }
+        tokens.push((token, quote_start));
+    }
+}
+
+/// The typed value of an unquoted argument
+fn typed_command_arg(token: &str) -> serde_json::Value {
+    if let Ok(integer) = token.parse::<i64>() {
+        return integer.into();
+    }
+    match token {
+        "true" => return true.into(),
+        "false" => return false.into(),
+        _ => {}
+    }
+    token
+        .parse::<f64>()
+        .ok()
+        .filter(|number| number.is_finite())
+        .and_then(serde_json::Number::from_f64)
+        .map_or_else(|| token.into(), serde_json::Value::Number)
+}
+
+/// An argument as typed, quoting strings that would otherwise read back
+/// differently
+fn command_arg_text(value: &serde_json::Value) -> String {
+    match value {
+        serde_json::Value::String(text) => {
+            let plain = !text.is_empty()
+                && !text.contains(|c: char| c.is_whitespace() || c == '"' || c == '=')
+                && typed_command_arg(text) == *value;
+            if plain {
+                text.clone()
+            } else {
+                format!("\"{}\"", text.replace('\\', "\\\\").replace('"', "\\\""))
+            }
+        }
+        serde_json::Value::Bool(_) | serde_json::Value::Number(_) => value.to_string(),
+        other => command_arg_text(&serde_json::Value::String(other.to_string())),
+    }
+}
+
+/// A vote record, encrypted like a message but stored beside the conversation
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct PollVote {
+    pub poll_id: String,
+    pub option: usize,
+}
+
+/// A "fetched up to" marker, encrypted like a message and stored beside the
+/// conversation by the client that fetched it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct FetchMarker {
+    /// Timestamp of the newest message from the peer that was fetched
+    pub fetched_up_to: u64,
+}
+
+/// Tally of the votes cast on a poll
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PollResults {
+    pub poll_id: String,
+    pub poll: Poll,
+    /// Number of votes per option, in the order of `poll.options`
+    pub counts: Vec<usize>,
+    /// The option each participant voted for, keyed by public key
+    pub votes: HashMap<String, usize>,
+}
+
+/// Validate the part of a BIP21 URI after `bitcoin:` and return its amount
+fn parse_bip21_amount_msat(rest: &str) -> Result<Option<u128>> {
+    let (address, query) = rest.split_once('?').unwrap_or((rest, ""));
+
+    if address.is_empty() || !address.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return Err(anyhow!("Invalid bitcoin address in payment URI"));
+    }
+
+    let mut amount = None;
+    for param in query.split('&').filter(|param| !param.is_empty()) {
+        if let Some(value) = param.strip_prefix("amount=") {
+            amount = Some(parse_btc_amount_msat(value)?);
+        }
+    }
+
+    Ok(amount)
+}
+
+/// Parse a decimal BTC amount such as `0.0015` into millisatoshis
+fn parse_btc_amount_msat(value: &str) -> Result<u128> {
+    let (whole, fraction) = value.split_once('.').unwrap_or((value, ""));
+
+    let invalid = || anyhow!("Invalid amount in payment URI: {}", value);
+    if (whole.is_empty() && fraction.is_empty())
+        || fraction.len() > 8
+        || !whole.chars().all(|c| c.is_ascii_digit())
+        || !fraction.chars().all(|c| c.is_ascii_digit())
+    {
+        return Err(invalid());
+    }
+
+    let whole: u128 = if whole.is_empty() {
+        0
+    } else {
+        whole.parse().map_err(|_| invalid())?
+    };
+    let fraction: u128 = format!("{:0<8}", fraction).parse().map_err(|_| invalid())?;
+
+    whole
+        .checked_mul(100_000_000)
+        .and_then(|sat| sat.checked_add(fraction))
+        .and_then(|sat| sat.checked_mul(1000))
+        .ok_or_else(invalid)
+}
+
+/// Validate the shape of a BOLT11 invoice and return the amount in its prefix
+fn parse_bolt11_amount_msat(invoice: &str) -> Result<Option<u128>> {
+    // The human-readable part ends at the last '1'
+    let separator = invoice
+        .rfind('1')
+        .ok_or_else(|| anyhow!("Invalid Lightning invoice"))?;
+    let (hrp, data) = (&invoice[..separator], &invoice[separator + 1..]);
+
+    const BECH32_CHARSET: &str = "qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+    if data.len() < 104 || !data.chars().all(|c| BECH32_CHARSET.contains(c)) {
+        return Err(anyhow!("Invalid Lightning invoice"));
+    }
+
+    // Network prefixes: lnbc (mainnet), lntb (testnet), lntbs (signet), lnbcrt (regtest)
+    let amount = ["lnbcrt", "lntbs", "lnbc", "lntb"]
+        .iter()
+        .find_map(|prefix| hrp.strip_prefix(prefix))
+        .ok_or_else(|| anyhow!("Payment URI is neither BIP21 nor a Lightning invoice"))?;
+
+    if amount.is_empty() {
+        return Ok(None);
+    }
+
+    let (digits, multiplier) = match amount.char_indices().last() {
+        Some((_, last)) if last.is_ascii_digit() => (amount, None),
+        Some((index, last)) => (&amount[..index], Some(last)),
+        None => (amount, None),
+    };
+
+    let invalid = || anyhow!("Invalid amount in Lightning invoice");
+    let value: u128 = digits.parse().map_err(|_| invalid())?;
+
+    // Millisatoshis per unit of each multiplier (1 BTC = 10^11 msat)
+    let msat = match multiplier {
+        None => value.checked_mul(100_000_000_000),
+        Some('m') => value.checked_mul(100_000_000),
+        Some('u') => value.checked_mul(100_000),
+        Some('n') => value.checked_mul(100),
+        Some('p') if value % 10 == 0 => Some(value / 10),
+        _ => None,
+    };
+
+    msat.map(Some).ok_or_else(invalid)
+}
+
+/// Whether `text` is between one and `MAX_LARGE_EMOJI_CHARS` emoji
+/// characters and nothing else
+fn is_large_emoji(text: &str) -> bool {
+    let count = text.chars().count();
+    (1..=MAX_LARGE_EMOJI_CHARS).contains(&count)
+        && text.chars().any(is_emoji_base)
+        && text
+            .chars()
+            .all(|c| is_emoji_base(c) || is_emoji_modifier(c))
+}
+
+/// Characters that are emoji on their own
+fn is_emoji_base(c: char) -> bool {
+    matches!(
+        c as u32,
+        0x1F000..=0x1FAFF
+            | 0x2600..=0x27BF
+            | 0x2300..=0x23FF
+            | 0x2B00..=0x2BFF
+            | 0x2190..=0x21FF
+            | 0x25A0..=0x25FF
+            | 0x2934
+            | 0x2935
+            | 0x3030
+            | 0x303D
+            | 0x3297
+            | 0x3299
+            | 0xA9
+            | 0xAE
+    )
+}
+
+/// Characters that only change or join the emoji around them: the zero
+/// width joiner, variation selectors, the keycap mark, and tag characters
+fn is_emoji_modifier(c: char) -> bool {
+    matches!(
+        c as u32,
+        0x200D | 0xFE0E | 0xFE0F | 0x20E3 | 0xE0020..=0xE007F
+    )
+}
+
+/// Serialize bytes as base64 inside the JSON payload
+mod base64_bytes {
+    use base64::engine::general_purpose::STANDARD;
+    use base64::Engine;
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(data: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&STANDARD.encode(data))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let encoded = String::deserialize(deserializer)?;
+        STANDARD.decode(encoded).map_err(D::Error::custom)
+    }
+}
@@ -0,0 +1,264 @@
+use anyhow::{anyhow, Result};
+use futures::future;
+use futures::stream::{BoxStream, StreamExt, TryStreamExt};
+use pkarr::{Keypair, PublicKey};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+use uuid::Uuid;
+
+use crate::builder::MessageBuilder;
+use crate::client::PrivateMessengerClient;
+use crate::content::MessageContent;
+use crate::crypto::{generate_shared_secret, static_conversation_path};
+use crate::message::{DecryptedMessage, SentMessage};
+
+/// Key derivation context of group conversation paths
+const GROUP_PATH_CONTEXT: &str = "pubky-messenger group path v1";
+
+/// Identifies a conversation, one-to-one or group, the same way everywhere
+///
+/// A direct conversation is identified by its two participants in a fixed
+/// order, so both sides derive the same ID. A group conversation is
+/// identified by its UUID, the `group_id` of membership changes. The string
+/// form, `direct:<pubky>:<pubky>` or `group:<uuid>`, is stable and suitable
+/// as a key in application storage.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ConversationId {
+    /// A conversation between two pubkys, ordered by their key bytes
+    Direct { participants: [String; 2] },
+    /// A group conversation, by its lowercase hyphenated UUID
+    Group { group_id: String },
+}
+
+impl ConversationId {
+    /// The conversation between two pubkys, in either order
+    pub fn direct(a: &PublicKey, b: &PublicKey) -> Self {
+        let (lower, upper) = if a.as_bytes() <= b.as_bytes() {
+            (a, b)
+        } else {
+            (b, a)
+        };
+        Self::Direct {
+            participants: [lower.to_string(), upper.to_string()],
+        }
+    }
+
+    /// The group conversation with the given UUID
+    pub fn group(group_id: &str) -> Result<Self> {
+        let uuid = Uuid::parse_str(group_id.trim())
+            .map_err(|e| anyhow!("Invalid group ID {}: {}", group_id, e))?;
+        Ok(Self::Group {
+            group_id: uuid.hyphenated().to_string(),
+        })
+    }
+
+    /// A new group conversation with a random UUID
+    pub fn new_group() -> Self {
+        Self::Group {
+            group_id: Uuid::new_v4().hyphenated().to_string(),
+        }
+    }
+
+    pub fn is_group(&self) -> bool {
+        matches!(self, Self::Group { .. })
+    }
+
+    /// Whether this is someone's Saved Messages, the conversation of a pubky
+    /// with itself
+    pub fn is_self(&self) -> bool {
+        matches!(self, Self::Direct { participants: [a, b] } if a == b)
+    }
+
+    /// The other participant of a direct conversation `me` takes part in
+    pub fn peer_of(&self, me: &PublicKey) -> Option<PublicKey> {
+        let Self::Direct { participants } = self else {
+            return None;
+        };
+        let me = me.to_string();
+        match participants {
+            [a, b] if *a == me => PublicKey::try_from(b.as_str()).ok(),
+            [a, b] if *b == me => PublicKey::try_from(a.as_str()).ok(),
+            _ => None,
+        }
+    }
+
+    /// Storage path of the conversation as seen by `keypair`, without path
+    /// rotation
+    ///
+    /// A direct conversation lives under a path derived from the shared
+    /// secret of its participants, so `keypair` must be one of them. A group
+    /// conversation lives under a path derived from its UUID.
+    pub fn path(&self, keypair: &Keypair) -> Result<String> {
+        match self {
+            Self::Direct { .. } => {
+                let peer = self.peer_of(&keypair.public_key()).ok_or_else(|| {
+                    anyhow!("{} is not a participant of {}", keypair.public_key(), self)
+                })?;
+                let shared_secret = generate_shared_secret(keypair, &peer)?;
+                Ok(static_conversation_path(&shared_secret))
+            }
+            Self::Group { group_id } => {
+                let path_id = blake3::derive_key(GROUP_PATH_CONTEXT, group_id.as_bytes());
+                Ok(format!("/pub/private_messages/{}/", hex::encode(path_id)))
+            }
+        }
+    }
+}
+
+impl fmt::Display for ConversationId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Direct {
+                participants: [a, b],
+            } => write!(f, "direct:{}:{}", a, b),
+            Self::Group { group_id } => write!(f, "group:{}", group_id),
+        }
+    }
+}
+
+impl FromStr for ConversationId {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.split_once(':') {
+            Some(("direct", pair)) => {
+                let (a, b) = pair
+                    .split_once(':')
+                    .ok_or_else(|| anyhow!("Direct conversation IDs need two pubkys"))?;
+                let a =
+                    PublicKey::try_from(a).map_err(|e| anyhow!("Invalid pubky {}: {}", a, e))?;
+                let b =
+                    PublicKey::try_from(b).map_err(|e| anyhow!("Invalid pubky {}: {}", b, e))?;
+                Ok(Self::direct(&a, &b))
+            }
+            Some(("group", group_id)) => Self::group(group_id),
+            _ => Err(anyhow!("Invalid conversation ID: {}", s)),
+        }
+    }
+}
+
+/// A conversation with one peer, returned by `PrivateMessengerClient::conversation`
+///
+/// Wraps the client methods that take the peer's key. The shared secret is
+/// derived when the handle is created and reused by every call.
+pub struct Conversation<'a> {
+    client: &'a PrivateMessengerClient,
+    peer: PublicKey,
+    id: ConversationId,
+    path: String,
+}
+
+impl<'a> Conversation<'a> {
+    pub(crate) fn new(client: &'a PrivateMessengerClient, peer: &PublicKey) -> Result<Self> {
+        Ok(Self {
+            client,
+            peer: peer.clone(),
+            id: client.conversation_id(peer),
+            path: client.static_conversation_path(peer)?,
+        })
+    }
+
+    /// The other participant
+    pub fn peer(&self) -> &PublicKey {
+        &self.peer
+    }
+
+    /// The ID of the conversation
+    pub fn id(&self) -> &ConversationId {
+        &self.id
+    }
+
+    /// Storage path of the conversation, without path rotation
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Send a text message
+    pub async fn send(&self, content: &str) -> Result<SentMessage> {
+        self.client.send_message(&self.peer, content).await
+    }
+
+    /// Send typed content
+    pub async fn send_content(&self, content: &MessageContent) -> Result<SentMessage> {
+        self.client.send_content(&self.peer, content).await
+    }
+
+    /// Send an opaque binary payload
+    pub async fn send_bytes(&self, data: Vec<u8>, content_type: &str) -> Result<SentMessage> {
+        self.client.send_bytes(&self.peer, data, content_type).await
+    }
+
+    /// Send a value of an application protocol as JSON
+    pub async fn send_json<T: Serialize>(&self, value: &T, type_tag: &str) -> Result<SentMessage> {
+        self.client.send_json(&self.peer, value, type_tag).await
+    }
+
+    /// Get the messages sent with `send_json` under `type_tag`
+    pub async fn messages_of_type<T: DeserializeOwned>(
+        &self,
+        type_tag: &str,
+    ) -> Result<Vec<(DecryptedMessage, T)>> {
+        self.client.messages_of_type(&self.peer, type_tag).await
+    }
+
+    /// Edit the text of one of our messages, see
+    /// `PrivateMessengerClient::edit_message`
+    pub async fn edit(&self, message: &DecryptedMessage, new_text: &str) -> Result<SentMessage> {
+        self.client
+            .edit_message(&self.peer, message, new_text)
+            .await
+    }
+
+    /// Start building a message with per-message options
+    pub fn message(&self) -> MessageBuilder<'a> {
+        self.client.message(&self.peer)
+    }
+
+    /// Get all messages, sorted by timestamp
+    pub async fn messages(&self) -> Result<Vec<DecryptedMessage>> {
+        self.client.get_messages(&self.peer).await
+    }
+
+    /// Get the newest `n` messages, sorted by timestamp
+    pub async fn recent_messages(&self, n: usize) -> Result<Vec<DecryptedMessage>> {
+        self.client.get_recent_messages(&self.peer, n).await
+    }
+
+    /// Prepare the conversation so that opening it feels instant, see
+    /// `PrivateMessengerClient::warm_conversation`
+    pub async fn warm(&self) -> Result<()> {
+        self.client.warm_conversation(&self.peer).await
+    }
+
+    /// Fetch messages lazily, one at a time as the stream is consumed
+    ///
+    /// Messages come in listing order (see `list_message_entries`), not
+    /// sorted by timestamp.
+    pub fn stream(&self) -> Result<BoxStream<'a, Result<DecryptedMessage>>> {
+        let client = self.client;
+        let peer = self.peer.clone();
+
+        let messages = client
+            .list_message_entries(&self.peer)?
+            .and_then(move |entry| {
+                let peer = peer.clone();
+                async move { client.fetch_message(&peer, &entry).await }
+            })
+            .try_filter_map(|message| future::ready(Ok(message)));
+
+        Ok(messages.boxed())
+    }
+
+    /// Delete messages by their IDs
+    pub async fn delete(&self, message_ids: Vec<String>) -> Result<()> {
+        self.client.delete_messages(message_ids, &self.peer).await
+    }
+
+    /// Delete every message we sent in this conversation
+    pub async fn clear(&self) -> Result<()> {
+        self.client.clear_messages(&self.peer).await
+    }
+}
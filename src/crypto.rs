@@ -2,13 +2,29 @@ use anyhow::{anyhow, Result};
 use curve25519_dalek::edwards::CompressedEdwardsY;
 use hex;
 use pkarr::{Keypair, PublicKey};
+use pubky_common::crypto::{decrypt, encrypt};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha512};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use subtle::{Choice, ConditionallySelectable, ConstantTimeEq, ConstantTimeLess};
 use x25519_dalek::{PublicKey as X25519PublicKey, StaticSecret};
+use zeroize::{Zeroize, Zeroizing};
+
+use crate::error::MessengerError;
+use crate::message::{DecryptedMessage, PrivateMessage};
 
 /// Convert Ed25519 public key to X25519 public key
+///
+/// Returns `None` for bytes that aren't a curve point and for small-order
+/// points, whose Diffie-Hellman output is predictable by anyone.
 pub fn ed25519_public_to_x25519(ed_pub: &[u8; 32]) -> Option<X25519PublicKey> {
     let compressed = CompressedEdwardsY(*ed_pub);
     let edwards_point = compressed.decompress()?;
+    if edwards_point.is_small_order() {
+        return None;
+    }
     Some(X25519PublicKey::from(
         edwards_point.to_montgomery().to_bytes(),
     ))
@@ -18,42 +34,401 @@ pub fn ed25519_public_to_x25519(ed_pub: &[u8; 32]) -> Option<X25519PublicKey> {
 pub fn ed25519_secret_to_x25519(ed_secret: &[u8; 32]) -> StaticSecret {
     let mut hasher = Sha512::new();
     hasher.update(ed_secret);
-    let hash = hasher.finalize();
+    let mut hash = hasher.finalize();
 
-    let mut x25519_secret_bytes = [0u8; 32];
+    let mut x25519_secret_bytes = Zeroizing::new([0u8; 32]);
     x25519_secret_bytes.copy_from_slice(&hash[0..32]);
+    hash.as_mut_slice().zeroize();
 
     // Apply clamping as per RFC 7748
     x25519_secret_bytes[0] &= 248;
     x25519_secret_bytes[31] &= 127;
     x25519_secret_bytes[31] |= 64;
 
-    StaticSecret::from(x25519_secret_bytes)
+    StaticSecret::from(*x25519_secret_bytes)
 }
 
 /// Generate shared secret for encryption between two keypairs
-pub fn generate_shared_secret(keypair: &Keypair, other_pubky: &PublicKey) -> Result<String> {
-    let ed25519_secret = keypair.secret_key();
+///
+/// With our own pubky as `other_pubky`, this is the key of the Saved
+/// Messages conversation: the Diffie-Hellman of our key with itself, which
+/// only we can compute. Notes to ourselves were always encrypted this way,
+/// so keeping it keeps the ones written before Saved Messages readable.
+/// Fails with `MessengerError::InvalidPeerKey` if `other_pubky` is not a
+/// point we can safely agree on a secret with.
+pub fn generate_shared_secret(
+    keypair: &Keypair,
+    other_pubky: &PublicKey,
+) -> Result<Zeroizing<[u8; 32]>> {
+    let ed25519_secret = Zeroizing::new(keypair.secret_key());
     let x25519_secret = ed25519_secret_to_x25519(&ed25519_secret);
 
+    let invalid = || MessengerError::InvalidPeerKey {
+        peer: other_pubky.to_string(),
+    };
+
     let other_pubky_bytes = other_pubky.as_bytes();
     if other_pubky_bytes.len() != 32 {
-        return Err(anyhow!("Invalid public key length"));
+        return Err(invalid().into());
     }
 
     let mut other_ed_bytes = [0u8; 32];
     other_ed_bytes.copy_from_slice(other_pubky_bytes);
 
-    let other_x25519 = ed25519_public_to_x25519(&other_ed_bytes)
-        .ok_or_else(|| anyhow!("Failed to convert pubky to X25519"))?;
+    let other_x25519 = ed25519_public_to_x25519(&other_ed_bytes).ok_or_else(invalid)?;
 
     let shared = x25519_secret.diffie_hellman(&other_x25519);
-    Ok(hex::encode(shared.as_bytes()))
+    if !shared.was_contributory() {
+        return Err(invalid().into());
+    }
+    Ok(Zeroizing::new(*shared.as_bytes()))
+}
+
+/// Lowercase hex of a key, as used in conversation paths and exported keys
+///
+/// Computed without table lookups or branches on the key bytes, unlike
+/// `hex::encode`, so its timing doesn't depend on the key.
+pub(crate) fn encode_key_hex(key: &[u8; 32]) -> Zeroizing<[u8; 64]> {
+    let mut hex = Zeroizing::new([0u8; 64]);
+    for (i, byte) in key.iter().enumerate() {
+        hex[2 * i] = hex_digit(byte >> 4);
+        hex[2 * i + 1] = hex_digit(byte & 0x0f);
+    }
+    hex
+}
+
+/// Parse the hex of a key in constant time, accepting either case
+pub(crate) fn decode_key_hex(hex: &str) -> Result<Zeroizing<[u8; 32]>> {
+    let hex = hex.as_bytes();
+    if hex.len() != 64 {
+        return Err(anyhow!("Invalid key length"));
+    }
+
+    let mut key = Zeroizing::new([0u8; 32]);
+    let mut valid = Choice::from(1);
+    for (i, pair) in hex.chunks_exact(2).enumerate() {
+        let (high, high_valid) = hex_value(pair[0]);
+        let (low, low_valid) = hex_value(pair[1]);
+        key[i] = (high << 4) | low;
+        valid &= high_valid & low_valid;
+    }
+
+    if !bool::from(valid) {
+        return Err(anyhow!("Invalid hex in key"));
+    }
+    Ok(key)
+}
+
+/// Whether `hex` is the hex of `hash`, compared in constant time
+pub(crate) fn hash_matches_hex(hash: &blake3::Hash, hex: &str) -> bool {
+    decode_key_hex(hex).is_ok_and(|expected| bool::from(hash.as_bytes()[..].ct_eq(&expected[..])))
+}
+
+/// ASCII hex digit of a nibble: `'0' + nibble`, moved up to `'a'` past 9
+fn hex_digit(nibble: u8) -> u8 {
+    // 0xff if the nibble is above 9, from the borrow of the subtraction
+    let letter = (9u16.wrapping_sub(nibble as u16) >> 8) as u8;
+    b'0' + nibble + (letter & (b'a' - b'0' - 10))
+}
+
+/// Value of an ASCII hex digit, and whether it was one
+fn hex_value(c: u8) -> (u8, Choice) {
+    let digit = c.wrapping_sub(b'0');
+    // Setting the 0x20 bit lowercases letters
+    let letter = (c | 0x20).wrapping_sub(b'a');
+    let is_digit = digit.ct_lt(&10);
+    let is_letter = letter.ct_lt(&6);
+
+    let value = u8::conditional_select(&0, &digit, is_digit)
+        | u8::conditional_select(&0, &letter.wrapping_add(10), is_letter);
+    (value, is_digit | is_letter)
+}
+
+/// Key derivation context of the self-test encryption key
+const SELF_TEST_CONTEXT: &str = "pubky-messenger self-test key v1";
+
+/// Exercise the keypair offline, failing on the first primitive that
+/// doesn't round-trip
+///
+/// A keypair restored from a corrupted recovery file or a bad seed may
+/// still produce a public key, but then its signatures don't verify or its
+/// X25519 keys don't agree, which would otherwise only surface on first
+/// send or fetch.
+pub(crate) fn self_test(keypair: &Keypair) -> Result<()> {
+    let secret = Zeroizing::new(keypair.secret_key());
+    let public_key = keypair.public_key();
+    let mut challenge = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut challenge);
+    let signature = keypair.sign(&challenge);
+    if public_key.verify(&challenge, &signature).is_err() {
+        return Err(anyhow!("Self-test failed: signature doesn't verify"));
+    }
+
+    // The converted public key must be a valid point, and the one implied
+    // by the converted secret key, or peers derive a different shared secret
+    let x25519_secret = ed25519_secret_to_x25519(&secret);
+    let ed_public: [u8; 32] = public_key
+        .as_bytes()
+        .try_into()
+        .map_err(|_| anyhow!("Self-test failed: invalid public key length"))?;
+    let x25519_public = ed25519_public_to_x25519(&ed_public)
+        .ok_or_else(|| anyhow!("Self-test failed: public key is not a valid curve point"))?;
+    if !bool::from(
+        X25519PublicKey::from(&x25519_secret).as_bytes()[..].ct_eq(&x25519_public.as_bytes()[..]),
+    ) {
+        return Err(anyhow!(
+            "Self-test failed: X25519 conversion doesn't match the secret key"
+        ));
+    }
+    let shared = x25519_secret.diffie_hellman(&x25519_public);
+    if !shared.was_contributory() {
+        return Err(anyhow!("Self-test failed: X25519 key is of low order"));
+    }
+
+    let key = Zeroizing::new(blake3::derive_key(SELF_TEST_CONTEXT, shared.as_bytes()));
+    let sealed = encrypt(&challenge, &key);
+    match decrypt(&sealed, &key) {
+        Ok(opened) if bool::from(opened.ct_eq(&challenge[..])) => Ok(()),
+        _ => Err(anyhow!("Self-test failed: encryption doesn't round-trip")),
+    }
+}
+
+/// Static conversation path from an already computed shared secret
+///
+/// The path hashes the hex of the secret, as it always has.
+pub(crate) fn static_conversation_path(shared_secret: &[u8; 32]) -> String {
+    let path_id = blake3::hash(&encode_key_hex(shared_secret)[..]).to_hex();
+    format!("/pub/private_messages/{}/", path_id)
+}
+
+/// Key derivation contexts for the settings shared by our own devices
+const DEVICE_SETTINGS_KEY_CONTEXT: &str = "pubky-messenger device settings key v1";
+const DEVICE_SETTINGS_PATH_CONTEXT: &str = "pubky-messenger device settings path v1";
+
+/// Key encrypting the settings shared by devices of one identity
+pub(crate) fn device_settings_key(keypair: &Keypair) -> Zeroizing<[u8; 32]> {
+    let secret = Zeroizing::new(keypair.secret_key());
+    Zeroizing::new(blake3::derive_key(DEVICE_SETTINGS_KEY_CONTEXT, &secret[..]))
+}
+
+/// Path of the settings record shared by devices of one identity
+///
+/// The directory looks like any conversation directory to the homeserver.
+pub(crate) fn device_settings_path(keypair: &Keypair) -> String {
+    let secret = Zeroizing::new(keypair.secret_key());
+    let path_id = blake3::derive_key(DEVICE_SETTINGS_PATH_CONTEXT, &secret[..]);
+    format!(
+        "/pub/private_messages/{}/settings.json",
+        hex::encode(path_id)
+    )
+}
+
+/// Key derivation contexts for uploaded sticker images
+const STICKER_KEY_CONTEXT: &str = "pubky-messenger sticker key v1";
+const STICKER_PATH_CONTEXT: &str = "pubky-messenger sticker path v1";
+
+/// Key encrypting a sticker image and the path it is stored under
+///
+/// Both are derived from our secret key and the image, so uploading the same
+/// image again finds the earlier copy, while nobody else can tell which
+/// image a path holds.
+pub(crate) fn sticker_key_and_path(
+    keypair: &Keypair,
+    image: &[u8],
+) -> (Zeroizing<[u8; 32]>, String) {
+    let secret = Zeroizing::new(keypair.secret_key());
+    let derive = |context: &str| {
+        let mut hasher = blake3::Hasher::new_derive_key(context);
+        hasher.update(&secret[..]).update(image);
+        Zeroizing::new(*hasher.finalize().as_bytes())
+    };
+    let path_id = derive(STICKER_PATH_CONTEXT);
+    (
+        derive(STICKER_KEY_CONTEXT),
+        format!(
+            "/pub/pubky-messenger/stickers/{}",
+            hex::encode(&path_id[..])
+        ),
+    )
+}
+
+/// Key derivation context of per-direction sending keys
+const DIRECTION_KEY_CONTEXT: &str = "pubky-messenger direction key v1";
+
+/// Which participant's sending key encrypted a message
+///
+/// With per-direction keys, each participant encrypts with a key of their
+/// own derived from the conversation key, so the same plaintext sent by both
+/// sides never produces related ciphertexts. Participants are told apart by
+/// ordering their public keys, which lets a reader holding only the
+/// conversation key derive both sending keys.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyDirection {
+    /// Sent by the participant with the smaller public key
+    Lower,
+    /// Sent by the participant with the larger public key
+    Upper,
+}
+
+impl KeyDirection {
+    /// Direction of messages from `sender` to `recipient`
+    pub fn of(sender: &PublicKey, recipient: &PublicKey) -> Self {
+        if sender.as_bytes() <= recipient.as_bytes() {
+            Self::Lower
+        } else {
+            Self::Upper
+        }
+    }
+
+    /// The sending key of this direction, derived from the conversation key
+    pub fn key(&self, conversation_key: &[u8; 32]) -> Zeroizing<[u8; 32]> {
+        let label: &[u8] = match self {
+            Self::Lower => b"lower",
+            Self::Upper => b"upper",
+        };
+        let mut hasher = blake3::Hasher::new_derive_key(DIRECTION_KEY_CONTEXT);
+        hasher.update(conversation_key).update(label);
+        Zeroizing::new(*hasher.finalize().as_bytes())
+    }
+}
+
+/// Shared secrets already derived for each peer
+///
+/// Deriving a secret takes a SHA-512 of the identity key and a
+/// Diffie-Hellman exchange, which fetches would otherwise repeat for every
+/// message. Cached secrets are zeroized when the cache is dropped.
+#[derive(Default)]
+pub(crate) struct SharedSecretCache {
+    secrets: Mutex<HashMap<String, Zeroizing<[u8; 32]>>>,
+}
+
+impl SharedSecretCache {
+    /// The shared secret with `other_pubky` as an encryption key, derived on
+    /// first use
+    pub fn key(&self, keypair: &Keypair, other_pubky: &PublicKey) -> Result<Zeroizing<[u8; 32]>> {
+        let mut secrets = self.secrets.lock().unwrap_or_else(|e| e.into_inner());
+        let peer = other_pubky.to_string();
+        if let Some(secret) = secrets.get(&peer) {
+            return Ok(secret.clone());
+        }
+
+        let secret = generate_shared_secret(keypair, other_pubky)?;
+        secrets.insert(peer, secret.clone());
+        Ok(secret)
+    }
 }
 
-/// Generate deterministic conversation path for two parties
-pub fn generate_conversation_path(keypair: &Keypair, other_pubky: &PublicKey) -> Result<String> {
-    let shared_secret = generate_shared_secret(keypair, other_pubky)?;
-    let path_id = blake3::hash(shared_secret.as_bytes()).to_hex();
-    Ok(format!("/pub/private_messages/{}/", path_id))
+/// Default length of a path rotation epoch (30 days)
+const DEFAULT_EPOCH_SECONDS: u64 = 30 * 24 * 60 * 60;
+
+/// Shortest allowed path rotation epoch (1 day)
+pub(crate) const MIN_EPOCH_SECONDS: u64 = 24 * 60 * 60;
+
+/// Time-based rotation of conversation paths
+///
+/// Without rotation a conversation lives at one static path for its whole
+/// lifetime, so anyone who learns the path can watch its traffic volume
+/// forever. With rotation, each message is stored under a path derived from
+/// the shared secret and the epoch of its send time, and the paths of
+/// different epochs cannot be linked without the secret.
+///
+/// Fetches read the current epoch, the previous `lookback_epochs` epochs,
+/// and the static path used by peers that don't rotate. Messages older than
+/// that window, such as imported history, need a larger lookback to be found.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PathRotation {
+    /// Length of an epoch in seconds (at least one day)
+    pub epoch_seconds: u64,
+    /// Number of previous epochs read when fetching messages
+    pub lookback_epochs: u64,
+}
+
+impl Default for PathRotation {
+    fn default() -> Self {
+        Self {
+            epoch_seconds: DEFAULT_EPOCH_SECONDS,
+            lookback_epochs: 3,
+        }
+    }
+}
+
+impl PathRotation {
+    /// The epoch a Unix timestamp (seconds) falls in
+    pub fn epoch_of(&self, timestamp: u64) -> u64 {
+        timestamp / self.epoch_seconds.max(MIN_EPOCH_SECONDS)
+    }
+}
+
+/// Epoch conversation path from an already computed shared secret
+pub(crate) fn epoch_conversation_path(shared_secret: &[u8; 32], epoch: u64) -> String {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(&encode_key_hex(shared_secret)[..]);
+    hasher.update(b"/epoch/");
+    hasher.update(&epoch.to_be_bytes());
+    format!("/pub/private_messages/{}/", hasher.finalize().to_hex())
+}
+
+/// Key material granting read access to a single conversation
+///
+/// **Warning:** anyone holding this key can decrypt every past and future
+/// message between the two participants, and nothing can revoke that access
+/// short of both parties moving to new identities. It does not reveal either
+/// identity key and cannot be used to sign or send messages as a participant.
+/// Only hand it to a third party you trust with the full conversation.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConversationKey {
+    /// Public keys of both participants
+    pub participants: [String; 2],
+    /// Storage path of the conversation under each participant's pubky
+    pub conversation_path: String,
+    /// Hex-encoded symmetric key used for the conversation's encryption
+    pub key_hex: String,
+}
+
+impl ConversationKey {
+    /// Derive the conversation key shared by `keypair` and `other_pubky`
+    pub fn derive(keypair: &Keypair, other_pubky: &PublicKey) -> Result<Self> {
+        let shared_secret = generate_shared_secret(keypair, other_pubky)?;
+        Ok(Self {
+            participants: [keypair.public_key().to_string(), other_pubky.to_string()],
+            conversation_path: static_conversation_path(&shared_secret),
+            key_hex: std::str::from_utf8(&encode_key_hex(&shared_secret)[..])?.to_string(),
+        })
+    }
+
+    /// The raw symmetric key
+    pub fn key_bytes(&self) -> Result<[u8; 32]> {
+        decode_key_hex(&self.key_hex)
+            .map(|key| *key)
+            .map_err(|e| anyhow!("Invalid conversation key: {}", e))
+    }
+
+    /// Decrypt and verify a message from this conversation
+    pub fn decrypt_message(&self, id: &str, message: &PrivateMessage) -> Result<DecryptedMessage> {
+        let key = self.key_bytes()?;
+        let sender = message.decrypt_sender_with_key(&key)?;
+        let content = message.decrypt_content_with_key(&key)?;
+
+        if !self.participants.contains(&sender) {
+            return Err(anyhow!("Message sender is not a participant: {}", sender));
+        }
+
+        let verified = message.verify_signature(&content, &sender).unwrap_or(false);
+
+        Ok(DecryptedMessage::new(
+            id.to_string(),
+            sender,
+            content,
+            message.timestamp,
+            verified,
+        ))
+    }
+
+    /// Parse a stored record of this conversation, sealed or not, then
+    /// decrypt and verify it
+    pub fn decrypt_record(&self, id: &str, record: &str) -> Result<DecryptedMessage> {
+        let message = PrivateMessage::from_record(record, &self.key_bytes()?)?;
+        self.decrypt_message(id, &message)
+    }
 }
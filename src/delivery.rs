@@ -0,0 +1,46 @@
+use serde::{Deserialize, Serialize};
+
+/// How far an outgoing message got on its way to the recipient
+///
+/// Tracked in the local store, see `PrivateMessengerClient::message_state`.
+/// States only move forward, so late or repeated updates can't take a
+/// message back. `Failed` is only recorded before the message was stored,
+/// and sending it again starts over.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeliveryState {
+    /// Encrypted and waiting to be uploaded
+    Queued,
+    /// Being written to our homeserver
+    Uploading,
+    /// On our homeserver, where the recipient can fetch it
+    Stored,
+    /// Fetched by the recipient, according to their fetch marker (see
+    /// `PrivateMessengerClient::with_fetch_markers`)
+    Delivered,
+    /// The upload failed; send the message again
+    Failed,
+}
+
+impl DeliveryState {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            Self::Queued => "queued",
+            Self::Uploading => "uploading",
+            Self::Stored => "stored",
+            Self::Delivered => "delivered",
+            Self::Failed => "failed",
+        }
+    }
+
+    pub(crate) fn parse(state: &str) -> Option<Self> {
+        match state {
+            "queued" => Some(Self::Queued),
+            "uploading" => Some(Self::Uploading),
+            "stored" => Some(Self::Stored),
+            "delivered" => Some(Self::Delivered),
+            "failed" => Some(Self::Failed),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,54 @@
+use serde::{Deserialize, Serialize};
+
+/// Outcome of one connectivity check
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DiagnosticCheck {
+    /// Short identifier of the check, e.g. `pkarr_resolution`
+    pub name: String,
+    pub ok: bool,
+    /// How long the check took
+    pub duration_ms: u64,
+    /// What was found, or why the check failed
+    pub detail: String,
+}
+
+/// Structured result of `PrivateMessengerClient::diagnostics`
+///
+/// Serializes to JSON, so users can paste it into a support request.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DiagnosticsReport {
+    pub pubky: String,
+    /// Unix timestamp in seconds when the checks ran
+    pub timestamp: u64,
+    pub checks: Vec<DiagnosticCheck>,
+}
+
+impl DiagnosticsReport {
+    /// Whether every check passed
+    pub fn is_healthy(&self) -> bool {
+        self.checks.iter().all(|check| check.ok)
+    }
+}
+
+/// Readiness of the client, from `PrivateMessengerClient::health_check`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum HealthStatus {
+    /// Signed in and able to write to our homeserver
+    Healthy,
+    /// The homeserver doesn't accept our session, call `sign_in` again
+    SessionInvalid,
+    /// Signed in, but the homeserver refused the write, e.g. over quota
+    WriteDenied { status: u16 },
+    /// The homeserver failed to handle the write
+    ServerError { status: u16 },
+    /// Our homeserver couldn't be resolved or reached
+    Unreachable { error: String },
+}
+
+impl HealthStatus {
+    /// Whether batch operations can go ahead
+    pub fn is_healthy(&self) -> bool {
+        matches!(self, HealthStatus::Healthy)
+    }
+}
@@ -0,0 +1,143 @@
+use std::fmt;
+
+/// Whose homeserver a failed request went to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Homeserver {
+    /// Our own homeserver, where everything we send is stored
+    Ours,
+    /// The homeserver of the peer, where their side of a conversation lives
+    Peer,
+}
+
+/// Errors that callers may want to handle specifically
+///
+/// They are returned inside `anyhow::Error`; match on them with
+/// `error.downcast_ref::<MessengerError>()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MessengerError {
+    /// Requests to the peer's homeserver kept failing, so they are paused
+    /// for a cool-down period instead of timing out one by one
+    PeerUnreachable { peer: String, retry_after_secs: u64 },
+    /// The conversation was made read-only, so nothing can be sent to it
+    /// until the flag is cleared
+    ConversationReadOnly { peer: String },
+    /// The peer's pubky is a malformed or small-order curve point, which
+    /// can't be used to agree on a secret
+    InvalidPeerKey { peer: String },
+    /// A request to a homeserver failed
+    ///
+    /// `status` is `None` when the homeserver couldn't be reached at all.
+    /// `retry_after_secs` comes from the `Retry-After` header, if any.
+    RequestFailed {
+        url: String,
+        /// Pubky whose homeserver serves the URL
+        owner: String,
+        homeserver: Homeserver,
+        status: Option<u16>,
+        retry_after_secs: Option<u64>,
+    },
+}
+
+impl MessengerError {
+    /// Whether the operation may succeed if tried again later
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Self::PeerUnreachable { .. } => true,
+            Self::ConversationReadOnly { .. } | Self::InvalidPeerKey { .. } => false,
+            Self::RequestFailed { status, .. } => match status {
+                None => true,
+                Some(status) => *status == 429 || *status >= 500,
+            },
+        }
+    }
+
+    /// Seconds to wait before trying again, when known
+    pub fn retry_after_secs(&self) -> Option<u64> {
+        match self {
+            Self::PeerUnreachable {
+                retry_after_secs, ..
+            } => Some(*retry_after_secs),
+            Self::ConversationReadOnly { .. } | Self::InvalidPeerKey { .. } => None,
+            Self::RequestFailed {
+                retry_after_secs, ..
+            } => *retry_after_secs,
+        }
+    }
+
+    /// A short suggestion of what the user can do about the error
+    pub fn hint(&self) -> &'static str {
+        match self {
+            Self::PeerUnreachable { .. } => {
+                "Their homeserver may be offline. Try again in a moment."
+            }
+            Self::ConversationReadOnly { .. } => {
+                "Clear the read-only flag of the conversation to send again."
+            }
+            Self::InvalidPeerKey { .. } => {
+                "This pubky can't receive messages. Check that it was copied correctly."
+            }
+            Self::RequestFailed {
+                homeserver, status, ..
+            } => match (homeserver, status) {
+                (Homeserver::Ours, None) => {
+                    "Your homeserver could not be reached. Check your connection."
+                }
+                (Homeserver::Peer, None) => {
+                    "Their homeserver could not be reached. It may be offline."
+                }
+                (Homeserver::Ours, Some(401 | 403)) => {
+                    "Your session was rejected. Sign in to your homeserver again."
+                }
+                (_, Some(429)) => "Too many requests. Wait before trying again.",
+                (_, Some(507)) => "The homeserver is out of storage.",
+                (_, Some(status)) if *status >= 500 => {
+                    "The homeserver is having problems. Try again later."
+                }
+                _ => "The homeserver refused the request.",
+            },
+        }
+    }
+}
+
+impl fmt::Display for MessengerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::PeerUnreachable {
+                peer,
+                retry_after_secs,
+            } => write!(
+                f,
+                "Homeserver of {} is unreachable, retry in {}s",
+                peer, retry_after_secs
+            ),
+            Self::ConversationReadOnly { peer } => {
+                write!(f, "Conversation with {} is read-only", peer)
+            }
+            Self::InvalidPeerKey { peer } => {
+                write!(f, "Public key {} can't be used for encryption", peer)
+            }
+            Self::RequestFailed {
+                url,
+                owner,
+                homeserver,
+                status,
+                retry_after_secs,
+            } => {
+                match homeserver {
+                    Homeserver::Ours => write!(f, "Our homeserver")?,
+                    Homeserver::Peer => write!(f, "Homeserver of {}", owner)?,
+                }
+                match status {
+                    Some(status) => write!(f, " returned {} for {}", status, url)?,
+                    None => write!(f, " is unreachable ({})", url)?,
+                }
+                if let Some(secs) = retry_after_secs {
+                    write!(f, ", retry in {}s", secs)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for MessengerError {}
@@ -0,0 +1,111 @@
+use pkarr::PublicKey;
+use serde::{Deserialize, Serialize};
+
+use crate::connectivity::ConnectivityState;
+use crate::conversation::ConversationId;
+use crate::delivery::DeliveryState;
+use crate::message::DecryptedMessage;
+
+/// Number of events buffered per subscriber; slower subscribers miss the
+/// oldest events
+pub(crate) const EVENT_CHANNEL_CAPACITY: usize = 256;
+
+/// Events raised by the client, delivered through
+/// `PrivateMessengerClient::subscribe`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum NotificationEvent {
+    /// A contact's identity no longer matches what was pinned on first
+    /// contact, like a "safety number changed" warning
+    KeyChanged { peer: String, change: KeyChange },
+    /// A message was removed from the homeserver, by either participant or
+    /// another of our devices, and dropped from the local store
+    MessageDeleted { peer: String, message_id: String },
+    /// A contact moved their identity to a new keypair, proven by a
+    /// rotation notice signed by both keys
+    KeyRotated { peer: String, new_pubky: String },
+    /// Our messenger data reached 90% of the storage quota, as measured by
+    /// `PrivateMessengerClient::storage_usage`
+    StorageNearLimit { used_bytes: u64, quota_bytes: u64 },
+    /// One of our messages moved to a new delivery state
+    DeliveryStateChanged {
+        peer: String,
+        message_id: String,
+        state: DeliveryState,
+    },
+    /// The peer's transcript hash differs from ours over the same period:
+    /// a homeserver may be hiding records from one of us, or a message was
+    /// deleted between the two hashes
+    TranscriptMismatch {
+        peer: String,
+        /// The peer's system message carrying their hash
+        message_id: String,
+        up_to: u64,
+        /// Records we hold up to `up_to`
+        local_records: u64,
+        /// Records the peer held up to `up_to`
+        remote_records: u64,
+    },
+    /// Requests started failing or recovered, see
+    /// `PrivateMessengerClient::connectivity`
+    ConnectivityChanged { state: ConnectivityState },
+}
+
+impl NotificationEvent {
+    /// The conversation the event is about, as seen by `me`
+    ///
+    /// `None` for events that concern no single conversation, such as
+    /// storage or connectivity changes.
+    pub fn conversation_id(&self, me: &PublicKey) -> Option<ConversationId> {
+        let peer = match self {
+            Self::KeyChanged { peer, .. }
+            | Self::MessageDeleted { peer, .. }
+            | Self::KeyRotated { peer, .. }
+            | Self::DeliveryStateChanged { peer, .. }
+            | Self::TranscriptMismatch { peer, .. } => peer,
+            Self::StorageNearLimit { .. } | Self::ConnectivityChanged { .. } => return None,
+        };
+        let peer = PublicKey::try_from(peer.as_str()).ok()?;
+        Some(ConversationId::direct(me, &peer))
+    }
+}
+
+/// A change to one conversation, delivered in order through
+/// `PrivateMessengerClient::subscribe_conversation`
+///
+/// Raised by `sync_conversation`, so a UI can render the conversation's
+/// timeline from this one stream.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ConversationEvent {
+    /// A message appeared in the conversation
+    Message { message: DecryptedMessage },
+    /// The stored record of a known message changed
+    Edited { message: DecryptedMessage },
+    /// A message was removed from the homeserver
+    Deleted { message_id: String },
+    /// The peer fetched our messages up to this timestamp, from the fetch
+    /// marker they keep (see `with_fetch_markers`)
+    Receipt { fetched_up_to: u64 },
+    /// A participant announced a change of group members
+    MembershipChanged {
+        message_id: String,
+        sender: String,
+        group_id: String,
+        added: Vec<String>,
+        removed: Vec<String>,
+    },
+    /// The subscriber fell behind and missed this many events; sync the
+    /// conversation from the local store to catch up
+    Lagged { missed: u64 },
+}
+
+/// What changed about a contact's identity
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum KeyChange {
+    /// The homeserver published in the contact's pkarr record changed
+    HomeserverChanged { previous: String, current: String },
+    /// A message claiming to be from the contact failed signature verification
+    InvalidSignature { message_id: String },
+}
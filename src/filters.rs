@@ -0,0 +1,134 @@
+use anyhow::{anyhow, Result};
+use regex::{Regex, RegexBuilder};
+use serde::{Deserialize, Serialize};
+
+use crate::content::MessageContent;
+use crate::message::DecryptedMessage;
+
+/// Longest muted word or pattern, in characters
+pub const MAX_MUTE_RULE_CHARS: usize = 256;
+
+/// Largest compiled pattern, so a mute rule can't blow up matching
+const MAX_PATTERN_SIZE: usize = 64 * 1024;
+
+/// A client-side content filter
+///
+/// Messages matching a rule are marked `muted` when fetched, rather than
+/// dropped, so applications can collapse or hide them. Word and text
+/// patterns apply to text messages only.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum MuteRule {
+    /// A word or phrase, matched without regard to case where it stands
+    /// as a whole word in the text
+    Word { word: String },
+    /// A regular expression matched against the text
+    Pattern { pattern: String },
+    /// A regular expression matched against the sender's pubky
+    Sender { pattern: String },
+}
+
+impl MuteRule {
+    /// Mute a word or phrase
+    pub fn word(word: impl Into<String>) -> Self {
+        Self::Word { word: word.into() }
+    }
+
+    /// Mute text matching a regular expression
+    pub fn pattern(pattern: impl Into<String>) -> Self {
+        Self::Pattern {
+            pattern: pattern.into(),
+        }
+    }
+
+    /// Mute senders whose pubky matches a regular expression
+    pub fn sender(pattern: impl Into<String>) -> Self {
+        Self::Sender {
+            pattern: pattern.into(),
+        }
+    }
+
+    /// Check that the rule is well formed
+    pub fn validate(&self) -> Result<()> {
+        self.compile().map(|_| ())
+    }
+
+    /// Identifies the rule among the muted ones, so muting a word twice
+    /// keeps one rule
+    pub(crate) fn key(&self) -> String {
+        match self {
+            Self::Word { word } => format!("word:{}", word.trim().to_lowercase()),
+            Self::Pattern { pattern } => format!("pattern:{}", pattern),
+            Self::Sender { pattern } => format!("sender:{}", pattern),
+        }
+    }
+
+    fn compile(&self) -> Result<Regex> {
+        let (rule, source) = match self {
+            Self::Word { word } => (
+                word.trim(),
+                format!(r"(?:^|\W){}(?:\W|$)", regex::escape(word.trim())),
+            ),
+            Self::Pattern { pattern } | Self::Sender { pattern } => {
+                (pattern.as_str(), pattern.clone())
+            }
+        };
+        if rule.is_empty() || rule.chars().count() > MAX_MUTE_RULE_CHARS {
+            return Err(anyhow!(
+                "Mute rules must be 1 to {} characters",
+                MAX_MUTE_RULE_CHARS
+            ));
+        }
+
+        RegexBuilder::new(&source)
+            .case_insensitive(matches!(self, Self::Word { .. }))
+            .size_limit(MAX_PATTERN_SIZE)
+            .build()
+            .map_err(|e| anyhow!("Invalid mute pattern: {}", e))
+    }
+}
+
+/// Compiled mute rules, ready to match messages
+pub(crate) struct MuteFilter {
+    text: Vec<Regex>,
+    senders: Vec<Regex>,
+}
+
+impl MuteFilter {
+    /// Compile rules, skipping any that no longer compile
+    pub(crate) fn new(rules: &[MuteRule]) -> Self {
+        let mut filter = Self {
+            text: Vec::new(),
+            senders: Vec::new(),
+        };
+        for rule in rules {
+            let Ok(regex) = rule.compile() else {
+                continue;
+            };
+            match rule {
+                MuteRule::Sender { .. } => filter.senders.push(regex),
+                _ => filter.text.push(regex),
+            }
+        }
+        filter
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.text.is_empty() && self.senders.is_empty()
+    }
+
+    /// Whether a message matches any of the rules
+    pub(crate) fn matches(&self, message: &DecryptedMessage) -> bool {
+        if self
+            .senders
+            .iter()
+            .any(|regex| regex.is_match(&message.sender))
+        {
+            return true;
+        }
+        match &message.body {
+            MessageContent::Text { text } => self.text.iter().any(|regex| regex.is_match(text)),
+            _ => false,
+        }
+    }
+}
@@ -0,0 +1,142 @@
+use anyhow::{anyhow, Result};
+use pkarr::PublicKey;
+use serde::{Deserialize, Serialize};
+
+use crate::uri::SCHEME as INVITE_SCHEME;
+
+/// Number of bytes of the key hash shown in a fingerprint
+const FINGERPRINT_BYTES: usize = 16;
+
+/// A shareable messenger contact: who to message and how to check it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IdentityBundle {
+    pub pubky: String,
+    /// Display name from the owner's profile, if they have one
+    pub name: Option<String>,
+    /// Short hash of the public key, for comparing out of band
+    pub fingerprint: String,
+}
+
+impl IdentityBundle {
+    /// Create a bundle for a public key
+    pub fn new(pubky: &PublicKey, name: Option<String>) -> Self {
+        Self {
+            pubky: pubky.to_string(),
+            name,
+            fingerprint: Self::fingerprint_of(pubky),
+        }
+    }
+
+    /// Human-comparable fingerprint of a public key
+    ///
+    /// The first 16 bytes of the blake3 hash of the key, as eight groups of
+    /// four uppercase hex digits.
+    pub fn fingerprint_of(pubky: &PublicKey) -> String {
+        let hash = blake3::hash(pubky.as_bytes());
+        hash.as_bytes()[..FINGERPRINT_BYTES]
+            .chunks(2)
+            .map(hex::encode_upper)
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    /// The public key of the bundle
+    pub fn public_key(&self) -> Result<PublicKey> {
+        PublicKey::try_from(self.pubky.as_str())
+            .map_err(|e| anyhow!("Invalid pubky in identity bundle: {}", e))
+    }
+
+    /// Render the bundle as an invite link
+    ///
+    /// The link has the form `pubky://<pk>?name=<name>&fp=<fingerprint>`,
+    /// with the name percent-encoded and the fingerprint without spaces.
+    pub fn to_invite_link(&self) -> String {
+        let mut link = format!("{}{}?", INVITE_SCHEME, self.pubky);
+        if let Some(name) = &self.name {
+            link.push_str("name=");
+            link.push_str(&percent_encode(name));
+            link.push('&');
+        }
+        link.push_str("fp=");
+        link.push_str(&self.fingerprint.replace(' ', ""));
+        link
+    }
+
+    /// Parse an invite link
+    ///
+    /// Fails if the public key is invalid or the link carries a fingerprint
+    /// that doesn't match it, e.g. because the link was altered.
+    pub fn from_invite_link(link: &str) -> Result<Self> {
+        let rest = link
+            .trim()
+            .strip_prefix(INVITE_SCHEME)
+            .ok_or_else(|| anyhow!("Invite link must start with {}", INVITE_SCHEME))?;
+        let (pubky, query) = rest.split_once('?').unwrap_or((rest, ""));
+        let pubky = PublicKey::try_from(pubky.trim_end_matches('/'))
+            .map_err(|e| anyhow!("Invalid pubky in invite link: {}", e))?;
+
+        let mut name = None;
+        let mut fingerprint = None;
+        for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            match key {
+                "name" => name = Some(percent_decode(value)?),
+                "fp" => fingerprint = Some(value.to_ascii_uppercase()),
+                _ => {}
+            }
+        }
+
+        let bundle = Self::new(&pubky, name);
+        if let Some(fingerprint) = fingerprint {
+            if fingerprint != bundle.fingerprint.replace(' ', "") {
+                return Err(anyhow!("Invite link fingerprint does not match its pubky"));
+            }
+        }
+
+        Ok(bundle)
+    }
+}
+
+/// Percent-encode everything but RFC 3986 unreserved characters
+fn percent_encode(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+/// Decode a percent-encoded UTF-8 string
+fn percent_decode(value: &str) -> Result<String> {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => {
+                let hex = value
+                    .get(i + 1..i + 3)
+                    .ok_or_else(|| anyhow!("Truncated percent escape"))?;
+                decoded.push(
+                    u8::from_str_radix(hex, 16)
+                        .map_err(|_| anyhow!("Invalid percent escape: %{}", hex))?,
+                );
+                i += 3;
+            }
+            b'+' => {
+                decoded.push(b' ');
+                i += 1;
+            }
+            byte => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    Ok(String::from_utf8(decoded)?)
+}
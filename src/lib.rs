@@ -8,6 +8,7 @@
 //! - Authentication via pkarr recovery files
 //! - Message signature verification
 //! - Profile and contact management
+//! - Background sync with bridges to external systems
 //!
 //! ## Example
 //!
@@ -35,12 +36,112 @@
 //! # }
 //! ```
 
+mod audit;
+mod away;
+#[cfg(feature = "blocking")]
+mod blocking;
+pub mod bot;
+mod breaker;
+mod builder;
+mod cache;
+mod cipher;
 mod client;
+mod connectivity;
+mod contacts;
+mod content;
+mod conversation;
 mod crypto;
+mod delivery;
+mod diagnostics;
+mod error;
+mod events;
+mod filters;
+mod identity;
 mod message;
+#[cfg(feature = "prometheus")]
+mod metrics;
+mod middleware;
+#[cfg(feature = "qr")]
+mod qr;
+mod recovery;
+mod rotation;
+mod sanitize;
+mod search;
+mod settings;
+mod snapshot;
+mod spam;
+mod stats;
+mod store;
+mod sync;
+mod templates;
+mod transcript;
+mod trust;
+pub mod uri;
+mod usage;
+#[cfg(feature = "webhook")]
+mod webhook;
 
-pub use client::{FollowedUser, PrivateMessengerClient, PubkyProfile};
-pub use message::{DecryptedMessage, PrivateMessage};
+pub use audit::{AuditAction, AuditEntry};
+pub use away::{
+    AwayMessage, AwaySchedule, AwaySetting, AUTO_REPLY_METADATA_KEY, MAX_AWAY_MESSAGE_BYTES,
+};
+#[cfg(feature = "blocking")]
+pub use blocking::BlockingMessengerClient;
+pub use bot::{Bot, BotContext};
+pub use breaker::CircuitBreakerConfig;
+pub use builder::MessageBuilder;
+pub use cache::DecryptionCacheConfig;
+pub use cipher::CipherSuite;
+pub use client::{
+    ConversationSummary, FollowAnomaly, FollowDivergence, FollowSyncReport, FollowedUser,
+    ImportProgress, ListOptions, MessageEntry, PrivateMessengerClient, PubkyProfile,
+};
+pub use connectivity::{ConnectivityState, OFFLINE_AFTER_FAILURES};
+pub use contacts::{Contact, CONTACTS_FORMAT_VERSION, MAX_CONTACT_ALIAS_CHARS, MAX_CONTACT_TAGS};
+pub use content::{
+    is_language_tag, Command, EditChange, Mention, MessageContent, MessageEdit, PaymentProof,
+    PaymentRequest, Poll, PollResults, Sticker, StickerSource, SystemMessage,
+    EDIT_SNAPSHOT_INTERVAL, MAX_BINARY_BYTES, MAX_COMMAND_NAME_CHARS, MAX_LARGE_EMOJI_CHARS,
+    MAX_METADATA_BYTES, MAX_METADATA_ENTRIES, MAX_STICKER_BYTES,
+};
+pub use conversation::{Conversation, ConversationId};
+pub use crypto::{ConversationKey, KeyDirection, PathRotation};
+pub use delivery::DeliveryState;
+pub use diagnostics::{DiagnosticCheck, DiagnosticsReport, HealthStatus};
+pub use error::{Homeserver, MessengerError};
+pub use events::{ConversationEvent, KeyChange, NotificationEvent};
+pub use filters::{MuteRule, MAX_MUTE_RULE_CHARS};
+pub use identity::IdentityBundle;
+pub use message::{DecryptedMessage, PrivateMessage, SentMessage};
+#[cfg(feature = "prometheus")]
+pub use metrics::MetricsCollector;
+pub use middleware::MessageMiddleware;
+pub use recovery::{
+    RecoveryKdfParams, MAX_RECOVERY_ITERATIONS, MAX_RECOVERY_MEMORY_KIB, MAX_RECOVERY_PARALLELISM,
+};
+pub use rotation::KeyRotationProof;
+pub use sanitize::ContentPolicy;
+pub use settings::{
+    DeviceSettings, LabelSetting, MuteSetting, TrustSetting, MAX_CONVERSATION_LABELS,
+    MAX_LABEL_CHARS,
+};
+pub use snapshot::{ConversationSnapshot, SnapshotDiff};
+pub use spam::{SenderActivity, SpamFilter, SpamVerdict};
+pub use stats::{ClientStats, OperationStats};
+pub use store::{ContactPin, MessageRequest, QueuedEvent, StoredMessage, TrashedMessage};
+pub use sync::{
+    Bridge, BridgedMessage, PollingConfig, SyncDelta, SyncHandle, SyncReport, SyncService,
+};
+pub use templates::{ReplyTemplate, MAX_TEMPLATE_BYTES, MAX_TEMPLATE_NAME_CHARS};
+pub use transcript::{TranscriptHash, TRANSCRIPT_GRACE_SECS};
+pub use trust::TrustLevel;
+pub use uri::PubkyUri;
+pub use usage::{ConversationUsage, StorageUsage};
+#[cfg(feature = "webhook")]
+pub use webhook::{
+    webhook_signature, WebhookEvent, WebhookRelay, WEBHOOK_SIGNATURE_HEADER,
+    WEBHOOK_TIMESTAMP_HEADER,
+};
 
 pub use pkarr::{Keypair, PublicKey};
 pub use bip39::Language;
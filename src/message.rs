@@ -1,13 +1,25 @@
 use anyhow::{anyhow, Result};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
 use blake3::Hasher;
-use ed25519_dalek::Signature;
+use ed25519_dalek::{Signature, Verifier, VerifyingKey};
 use pkarr::{Keypair, PublicKey};
 use pubky_common::crypto::{decrypt, encrypt};
+use rand::seq::SliceRandom;
+use rand::RngCore;
 use serde::{Deserialize, Serialize};
-use std::time::{SystemTime, UNIX_EPOCH};
-use uuid::Uuid;
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use ulid::Ulid;
+use zeroize::Zeroizing;
 
-use crate::crypto::generate_shared_secret;
+use crate::cipher::CipherSuite;
+use crate::content::{is_language_tag, EditChange, Mention, MessageContent, MessageEdit};
+use crate::crypto::{generate_shared_secret, KeyDirection};
+use crate::trust::TrustLevel;
+
+/// Width of the zero-padded millisecond timestamp that prefixes message IDs
+pub(crate) const TIMESTAMP_MS_DIGITS: usize = 13;
 
 /// A private message with encrypted sender and content
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -16,17 +28,71 @@ pub struct PrivateMessage {
     pub encrypted_sender: Vec<u8>,
     pub encrypted_content: Vec<u8>,
     pub signature_bytes: Vec<u8>,
+    /// For multi-recipient messages, the random content key encrypted under
+    /// each recipient's conversation key, in random order
+    ///
+    /// Empty for ordinary messages, whose content is encrypted directly under
+    /// the conversation key.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub wrapped_keys: Vec<Vec<u8>>,
+    /// AEAD the sender, content, and wrapped keys are encrypted with
+    #[serde(default, skip_serializing_if = "CipherSuite::is_default")]
+    pub cipher: CipherSuite,
+    /// Sending key the sender and content are encrypted with, if the sender
+    /// uses per-direction keys
+    ///
+    /// `None` for messages encrypted directly under the conversation key.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub direction: Option<KeyDirection>,
 }
 
 impl PrivateMessage {
     /// Create a new encrypted message
     pub fn new(sender_keypair: &Keypair, recipient_pk: &PublicKey, content: &str) -> Result<Self> {
-        let content_bytes = content.as_bytes();
         let timestamp = SystemTime::now()
             .duration_since(UNIX_EPOCH)
             .unwrap()
             .as_secs();
 
+        Self::new_at(sender_keypair, recipient_pk, content, timestamp)
+    }
+
+    /// Create a new encrypted message with an explicit timestamp (Unix seconds)
+    ///
+    /// The timestamp is covered by the signature, so it cannot be changed after creation.
+    pub fn new_at(
+        sender_keypair: &Keypair,
+        recipient_pk: &PublicKey,
+        content: &str,
+        timestamp: u64,
+    ) -> Result<Self> {
+        // Generate encryption key from shared secret
+        let encryption_key = generate_shared_secret(sender_keypair, recipient_pk)?;
+
+        Self::new_with_key(
+            sender_keypair,
+            content,
+            timestamp,
+            &encryption_key,
+            CipherSuite::default(),
+            None,
+        )
+    }
+
+    /// Create a new encrypted message with an already derived conversation key
+    ///
+    /// With a `direction`, the message is encrypted under that direction's
+    /// sending key instead of the conversation key itself.
+    pub(crate) fn new_with_key(
+        sender_keypair: &Keypair,
+        content: &str,
+        timestamp: u64,
+        encryption_key: &[u8; 32],
+        cipher: CipherSuite,
+        direction: Option<KeyDirection>,
+    ) -> Result<Self> {
+        let content_bytes = content.as_bytes();
+
         // Create message digest for signing
         let mut hasher = Hasher::new();
         hasher.update(content_bytes);
@@ -38,39 +104,167 @@ impl PrivateMessage {
         let signature = sender_keypair.sign(message_digest.as_bytes());
         let signature_bytes = signature.to_bytes().to_vec();
 
-        // Generate encryption key from shared secret
-        let shared_secret = generate_shared_secret(sender_keypair, recipient_pk)?;
-        let shared_secret_bytes = hex::decode(&shared_secret)?;
-
-        let mut encryption_key = [0u8; 32];
-        encryption_key.copy_from_slice(&shared_secret_bytes);
-
         // Encrypt content and sender
-        let encrypted_content = encrypt(content_bytes, &encryption_key);
+        let sending_key = match direction {
+            Some(direction) => direction.key(encryption_key),
+            None => Zeroizing::new(*encryption_key),
+        };
+        let encrypted_content = cipher.encrypt(content_bytes, &sending_key)?;
         let sender_string = sender_keypair.public_key().to_string();
-        let encrypted_sender = encrypt(sender_string.as_bytes(), &encryption_key);
+        let encrypted_sender = cipher.encrypt(sender_string.as_bytes(), &sending_key)?;
 
         Ok(Self {
             timestamp,
             encrypted_sender,
             encrypted_content,
             signature_bytes,
+            wrapped_keys: Vec::new(),
+            cipher,
+            direction,
         })
     }
 
+    /// Create one encrypted message readable by several recipients
+    ///
+    /// The content is encrypted once under a random key, which is wrapped with
+    /// the conversation key of each recipient. Every recipient decrypts the
+    /// same envelope with the usual `decrypt_*` methods.
+    pub fn new_multi(
+        sender_keypair: &Keypair,
+        recipients: &[PublicKey],
+        content: &str,
+        timestamp: u64,
+    ) -> Result<Self> {
+        let mut conversation_keys = Vec::with_capacity(recipients.len());
+        for recipient in recipients {
+            conversation_keys.push(generate_shared_secret(sender_keypair, recipient)?);
+        }
+
+        Self::new_multi_with_keys(
+            sender_keypair,
+            content,
+            timestamp,
+            &conversation_keys,
+            CipherSuite::default(),
+        )
+    }
+
+    /// Create a multi-recipient message with already derived conversation keys
+    pub(crate) fn new_multi_with_keys(
+        sender_keypair: &Keypair,
+        content: &str,
+        timestamp: u64,
+        conversation_keys: &[Zeroizing<[u8; 32]>],
+        cipher: CipherSuite,
+    ) -> Result<Self> {
+        if conversation_keys.is_empty() {
+            return Err(anyhow!("A message needs at least one recipient"));
+        }
+
+        let mut content_key = Zeroizing::new([0u8; 32]);
+        rand::thread_rng().fill_bytes(&mut content_key[..]);
+
+        // The content key is random, so it needs no per-direction key
+        let mut message = Self::new_with_key(
+            sender_keypair,
+            content,
+            timestamp,
+            &content_key,
+            cipher,
+            None,
+        )?;
+        message.wrapped_keys = conversation_keys
+            .iter()
+            .map(|key| cipher.encrypt(&content_key[..], key))
+            .collect::<Result<_>>()?;
+        message.wrapped_keys.shuffle(&mut rand::thread_rng());
+
+        Ok(message)
+    }
+
+    /// The key the content and sender are encrypted under, given the
+    /// conversation key
+    ///
+    /// That is the conversation key itself or the sender's per-direction
+    /// key derived from it, or for multi-recipient messages the content key
+    /// wrapped for this conversation.
+    fn content_key(&self, encryption_key: &[u8; 32]) -> Result<Zeroizing<[u8; 32]>> {
+        if self.wrapped_keys.is_empty() {
+            return Ok(match self.direction {
+                Some(direction) => direction.key(encryption_key),
+                None => Zeroizing::new(*encryption_key),
+            });
+        }
+
+        for wrapped in self.wrapped_keys.iter() {
+            let Ok(unwrapped) = self.cipher.decrypt(wrapped, encryption_key) else {
+                continue;
+            };
+            let unwrapped = Zeroizing::new(unwrapped);
+            if unwrapped.len() == 32 {
+                let mut content_key = Zeroizing::new([0u8; 32]);
+                content_key.copy_from_slice(&unwrapped);
+                return Ok(content_key);
+            }
+        }
+
+        Err(anyhow!("Message has no key for this conversation"))
+    }
+
+    /// Nonces of the sender, content, and wrapped keys ciphertexts, for
+    /// checking that none repeats under one key
+    pub fn nonces(&self) -> Vec<&[u8]> {
+        [&self.encrypted_sender, &self.encrypted_content]
+            .into_iter()
+            .chain(self.wrapped_keys.iter())
+            .filter_map(|ciphertext| self.cipher.nonce(ciphertext))
+            .collect()
+    }
+
+    /// Serialize the message as a record for the homeserver
+    ///
+    /// Unsealed records are the JSON envelope, whose timestamp and signature
+    /// the homeserver can read. Sealed records encrypt the whole envelope
+    /// again under the conversation key and encode it as base64, so the
+    /// homeserver only sees an opaque string.
+    pub fn to_record(&self, conversation_key: &[u8; 32], seal: bool) -> Result<String> {
+        let envelope = serde_json::to_string(self)?;
+        if !seal {
+            return Ok(envelope);
+        }
+
+        Ok(STANDARD.encode(encrypt(envelope.as_bytes(), conversation_key)))
+    }
+
+    /// Parse a record fetched from the homeserver, sealed or not
+    pub fn from_record(record: &str, conversation_key: &[u8; 32]) -> Result<Self> {
+        let record = record.trim();
+        if record.starts_with('{') {
+            return Ok(serde_json::from_str(record)?);
+        }
+
+        let sealed = STANDARD
+            .decode(record)
+            .map_err(|e| anyhow!("Invalid sealed record: {}", e))?;
+        let envelope = decrypt(&sealed, conversation_key)?;
+        Ok(serde_json::from_slice(&envelope)?)
+    }
+
     /// Decrypt the message content
     pub fn decrypt_content(
         &self,
         receiver_keypair: &Keypair,
         other_participant: &PublicKey,
     ) -> Result<String> {
-        let shared_secret = generate_shared_secret(receiver_keypair, other_participant)?;
-        let shared_secret_bytes = hex::decode(&shared_secret)?;
+        let encryption_key = generate_shared_secret(receiver_keypair, other_participant)?;
 
-        let mut encryption_key = [0u8; 32];
-        encryption_key.copy_from_slice(&shared_secret_bytes);
+        self.decrypt_content_with_key(&encryption_key)
+    }
 
-        let decrypted = decrypt(&self.encrypted_content, &encryption_key)?;
+    /// Decrypt the message content with an exported conversation key
+    pub fn decrypt_content_with_key(&self, encryption_key: &[u8; 32]) -> Result<String> {
+        let content_key = self.content_key(encryption_key)?;
+        let decrypted = self.cipher.decrypt(&self.encrypted_content, &content_key)?;
         Ok(String::from_utf8(decrypted)?)
     }
 
@@ -80,13 +274,15 @@ impl PrivateMessage {
         receiver_keypair: &Keypair,
         other_participant: &PublicKey,
     ) -> Result<String> {
-        let shared_secret = generate_shared_secret(receiver_keypair, other_participant)?;
-        let shared_secret_bytes = hex::decode(&shared_secret)?;
+        let encryption_key = generate_shared_secret(receiver_keypair, other_participant)?;
 
-        let mut encryption_key = [0u8; 32];
-        encryption_key.copy_from_slice(&shared_secret_bytes);
+        self.decrypt_sender_with_key(&encryption_key)
+    }
 
-        let decrypted = decrypt(&self.encrypted_sender, &encryption_key)?;
+    /// Decrypt the sender public key with an exported conversation key
+    pub fn decrypt_sender_with_key(&self, encryption_key: &[u8; 32]) -> Result<String> {
+        let content_key = self.content_key(encryption_key)?;
+        let decrypted = self.cipher.decrypt(&self.encrypted_sender, &content_key)?;
         Ok(String::from_utf8(decrypted)?)
     }
 
@@ -96,7 +292,56 @@ impl PrivateMessage {
         decrypted_content: &str,
         decrypted_sender: &str,
     ) -> Result<bool> {
+        let (key, digest, signature) = self.signed_digest(decrypted_content, decrypted_sender)?;
+        Ok(key.verify(&digest, &signature).is_ok())
+    }
+
+    /// Verify the signatures of many messages at once
+    ///
+    /// Each entry is a message with its decrypted content and sender. All
+    /// signatures are checked in a single ed25519 batch; if the batch fails,
+    /// each message is re-checked on its own so only the bad ones are
+    /// reported. The result has one flag per entry, in order.
+    pub fn verify_batch(entries: &[(&PrivateMessage, &str, &str)]) -> Vec<bool> {
+        let mut verified = vec![false; entries.len()];
+        let mut indices = Vec::with_capacity(entries.len());
+        let mut digests = Vec::with_capacity(entries.len());
+        let mut signatures = Vec::with_capacity(entries.len());
+        let mut keys = Vec::with_capacity(entries.len());
+
+        for (index, (message, content, sender)) in entries.iter().enumerate() {
+            if let Ok((key, digest, signature)) = message.signed_digest(content, sender) {
+                indices.push(index);
+                digests.push(digest);
+                signatures.push(signature);
+                keys.push(key);
+            }
+        }
+
+        let messages: Vec<&[u8]> = digests.iter().map(|digest| digest.as_slice()).collect();
+        if ed25519_dalek::verify_batch(&messages, &signatures, &keys).is_ok() {
+            for index in indices {
+                verified[index] = true;
+            }
+        } else {
+            for (position, index) in indices.into_iter().enumerate() {
+                verified[index] = keys[position]
+                    .verify(messages[position], &signatures[position])
+                    .is_ok();
+            }
+        }
+
+        verified
+    }
+
+    /// Digest, signature and verifying key covered by this message's signature
+    fn signed_digest(
+        &self,
+        decrypted_content: &str,
+        decrypted_sender: &str,
+    ) -> Result<(VerifyingKey, [u8; 32], Signature)> {
         let sender_pk = PublicKey::try_from(decrypted_sender)?;
+        let key = VerifyingKey::from_bytes(sender_pk.as_bytes())?;
 
         let mut hasher = Hasher::new();
         hasher.update(decrypted_content.as_bytes());
@@ -112,23 +357,393 @@ impl PrivateMessage {
         sig_bytes.copy_from_slice(&self.signature_bytes);
         let signature = Signature::from_bytes(&sig_bytes);
 
-        match sender_pk.verify(message_digest.as_bytes(), &signature) {
-            Ok(()) => Ok(true),
-            Err(_) => Ok(false),
-        }
+        Ok((key, *message_digest.as_bytes(), signature))
     }
 
     /// Generate a unique message ID
+    ///
+    /// IDs have the form `{timestamp_ms}-{ulid}`, so homeserver listings
+    /// return messages in chronological order.
     pub fn generate_id() -> String {
-        Uuid::new_v4().to_string()
+        let timestamp_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64;
+
+        Self::generate_id_at(timestamp_ms)
+    }
+
+    /// Generate a unique message ID for a message sent at `timestamp_ms`
+    pub fn generate_id_at(timestamp_ms: u64) -> String {
+        format!(
+            "{:0width$}-{}",
+            timestamp_ms,
+            Ulid::new(),
+            width = TIMESTAMP_MS_DIGITS
+        )
+    }
+
+    /// Generate a message ID that only reveals the time bucket it was sent in
+    ///
+    /// Both the timestamp prefix and the time part of the ULID are rounded
+    /// down to a multiple of `bucket`, so IDs (which are visible in listings)
+    /// can't be used to time messages within a bucket.
+    pub fn generate_coarse_id(timestamp_ms: u64, bucket: Duration) -> String {
+        let bucket_ms = (bucket.as_millis() as u64).max(1);
+        let coarse_ms = timestamp_ms - timestamp_ms % bucket_ms;
+        let random = rand::random::<u128>() & ((1u128 << 80) - 1);
+
+        format!(
+            "{:0width$}-{}",
+            coarse_ms,
+            Ulid::from_parts(coarse_ms, random),
+            width = TIMESTAMP_MS_DIGITS
+        )
+    }
+
+    /// The millisecond timestamp prefix of a message ID, if it has one
+    ///
+    /// Messages stored before IDs were timestamp-prefixed have none.
+    pub fn id_timestamp_ms(id: &str) -> Option<u64> {
+        let (prefix, _) = id.split_once('-')?;
+        if prefix.len() != TIMESTAMP_MS_DIGITS || !prefix.chars().all(|c| c.is_ascii_digit()) {
+            return None;
+        }
+        prefix.parse().ok()
+    }
+
+    /// Check that a message ID is safe to use as a storage file name
+    pub fn validate_id(id: &str) -> Result<()> {
+        if id.is_empty() || id.len() > 128 {
+            return Err(anyhow!("Invalid message ID length"));
+        }
+
+        if !id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            return Err(anyhow!("Invalid message ID: {}", id));
+        }
+
+        Ok(())
     }
 }
 
+/// A message that was successfully stored on the sender's homeserver
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SentMessage {
+    pub id: String,
+    pub timestamp: u64,
+    pub url: String,
+    pub content: String,
+}
+
 /// A decrypted message for application use
+///
+/// `content` is the decrypted text exactly as it was signed, unless
+/// middleware transformed it; `body` is its typed form.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DecryptedMessage {
+    pub id: String,
     pub sender: String,
     pub content: String,
+    pub body: MessageContent,
     pub timestamp: u64,
     pub verified: bool,
+    /// Set when the sender's pinned identity changed before this message was
+    /// received and the change has not been acknowledged
+    #[serde(default)]
+    pub key_warning: bool,
+    /// ID of the message this one replies to
+    #[serde(default)]
+    pub reply_to: Option<String>,
+    /// Unix timestamp (seconds) after which the message is hidden
+    #[serde(default)]
+    pub expires_at: Option<u64>,
+    /// Application-defined key-value pairs sent with the message
+    #[serde(default)]
+    pub metadata: BTreeMap<String, String>,
+    /// Users mentioned in the text, without duplicates
+    #[serde(default, with = "public_keys")]
+    pub mentions: Vec<PublicKey>,
+    /// Where the mentions appear in the text
+    #[serde(default)]
+    pub mention_spans: Vec<Mention>,
+    /// Whether the fetching user is among `mentions`
+    #[serde(default)]
+    pub mentioned_me: bool,
+    /// Sender of the message `reply_to` points at, when it was fetched along
+    /// with this one
+    #[serde(default)]
+    pub reply_to_sender: Option<String>,
+    /// Trust level of the sender when the message was fetched with a local
+    /// store (our own messages are `Verified`)
+    #[serde(default)]
+    pub sender_trust: TrustLevel,
+    /// Set on messages from distrusted contacts, which applications should
+    /// keep out of the normal conversation view
+    #[serde(default)]
+    pub quarantined: bool,
+    /// Set on messages that match one of our mute rules, which applications
+    /// may collapse or hide (see `PrivateMessengerClient::mute`)
+    #[serde(default)]
+    pub muted: bool,
+    /// Set on messages from a stranger whose message request has not been
+    /// accepted yet
+    #[serde(default)]
+    pub message_request: bool,
+    /// Number of edits folded into the content, 0 for an unedited message
+    #[serde(default)]
+    pub edit_version: u32,
+    /// Timestamp of the newest edit folded into the content
+    #[serde(default)]
+    pub edited_at: Option<u64>,
+    /// Position of the message among the sender's messages in the
+    /// conversation, if the sender numbers them (see
+    /// `PrivateMessengerClient::with_sequence_numbers`)
+    #[serde(default)]
+    pub seq: Option<u64>,
+    /// How many of the sender's messages are missing right before this one,
+    /// going by `seq`
+    #[serde(default)]
+    pub missing_before: u64,
+    /// Set when `seq` is not above every earlier message of the sender,
+    /// i.e. the message was replayed or its timestamp disagrees with its
+    /// place in the sequence
+    #[serde(default)]
+    pub out_of_order: bool,
+    /// Name and version of the app that sent the message, if the sender
+    /// embeds it (see `PrivateMessengerClient::with_app_identity_in_messages`)
+    #[serde(default)]
+    pub sent_with: Option<String>,
+    /// BCP-47 language tag the sender gave the text, if any (see
+    /// `MessageBuilder::language`)
+    #[serde(default)]
+    pub language: Option<String>,
+}
+
+impl DecryptedMessage {
+    /// Create a decrypted message, decoding its typed body from the content
+    pub fn new(
+        id: String,
+        sender: String,
+        content: String,
+        timestamp: u64,
+        verified: bool,
+    ) -> Self {
+        let (body, options) = MessageContent::decode_with_options(&content);
+
+        // Only keep spans that really point at an `@pubky` in the text
+        let mention_spans: Vec<Mention> = match &body {
+            MessageContent::Text { text } => options
+                .mentions
+                .into_iter()
+                .filter(|mention| mention.is_valid_in(text))
+                .collect(),
+            _ => Vec::new(),
+        };
+        let mut mentions: Vec<PublicKey> = Vec::new();
+        for mention in mention_spans.iter() {
+            if let Ok(pubky) = PublicKey::try_from(mention.pubky.as_str()) {
+                if !mentions.contains(&pubky) {
+                    mentions.push(pubky);
+                }
+            }
+        }
+
+        Self {
+            id,
+            sender,
+            content,
+            body,
+            timestamp,
+            verified,
+            key_warning: false,
+            reply_to: options.reply_to,
+            expires_at: options.expires_at,
+            metadata: options.metadata,
+            mentions,
+            mention_spans,
+            mentioned_me: false,
+            reply_to_sender: None,
+            sender_trust: TrustLevel::Unknown,
+            quarantined: false,
+            muted: false,
+            message_request: false,
+            edit_version: 0,
+            edited_at: None,
+            seq: options.seq,
+            missing_before: 0,
+            out_of_order: false,
+            sent_with: options.sent_with,
+            language: options.language.filter(|tag| is_language_tag(tag)),
+        }
+    }
+
+    /// Whether the message mentions `pubky`, e.g. for mention-only notifications
+    pub fn is_mentioned(&self, pubky: &PublicKey) -> bool {
+        self.mentions.contains(pubky)
+    }
+
+    /// Attribute replies to the senders of the messages they reply to
+    ///
+    /// Sets `reply_to_sender` on every message whose `reply_to` is among
+    /// `messages`.
+    pub fn attribute_replies(messages: &mut [DecryptedMessage]) {
+        let senders: HashMap<String, String> = messages
+            .iter()
+            .map(|message| (message.id.clone(), message.sender.clone()))
+            .collect();
+        for message in messages.iter_mut() {
+            message.reply_to_sender = message
+                .reply_to
+                .as_ref()
+                .and_then(|id| senders.get(id).cloned());
+        }
+    }
+
+    /// Flag gaps and reordering in the senders' sequence numbers
+    ///
+    /// Walks the numbered messages of each sender in timestamp order,
+    /// comparing every `seq` with the highest one before it. That starts out
+    /// at the sender's entry in `last_seen`, or 0 for senders without one,
+    /// and is written back there. A message not above it is marked
+    /// `out_of_order`; one that skips numbers counts them in
+    /// `missing_before`. Unnumbered and unverified messages are left alone,
+    /// so forged numbers can't hide gaps.
+    pub fn check_sequence(messages: &mut [DecryptedMessage], last_seen: &mut HashMap<String, u64>) {
+        let mut order: Vec<usize> = (0..messages.len())
+            .filter(|&i| messages[i].verified && messages[i].seq.is_some())
+            .collect();
+        order.sort_by_key(|&i| (messages[i].timestamp, messages[i].seq));
+
+        for i in order {
+            let message = &mut messages[i];
+            let Some(seq) = message.seq else {
+                continue;
+            };
+            let last = last_seen.entry(message.sender.clone()).or_insert(0);
+            message.out_of_order = seq <= *last;
+            message.missing_before = seq.saturating_sub(*last).saturating_sub(1);
+            *last = (*last).max(seq);
+        }
+    }
+
+    /// Fold edits into the messages they change
+    ///
+    /// Removes every `MessageContent::Edit` from `messages` and applies it to
+    /// its target with `apply_edits`. Edits of messages that are not among
+    /// `messages` are returned, so they can be applied to stored copies.
+    pub fn fold_edits(messages: &mut Vec<DecryptedMessage>) -> Vec<DecryptedMessage> {
+        let (edits, rest): (Vec<_>, Vec<_>) = std::mem::take(messages)
+            .into_iter()
+            .partition(|message| message.body.is_edit());
+        *messages = rest;
+
+        let mut by_target: HashMap<String, Vec<DecryptedMessage>> = HashMap::new();
+        for edit in edits {
+            if let MessageContent::Edit(change) = &edit.body {
+                by_target
+                    .entry(change.target.clone())
+                    .or_default()
+                    .push(edit);
+            }
+        }
+        for message in messages.iter_mut() {
+            if let Some(edits) = by_target.remove(&message.id) {
+                message.apply_edits(&edits);
+            }
+        }
+
+        by_target.into_values().flatten().collect()
+    }
+
+    /// Apply the edits among `edits` that the sender made to this message,
+    /// returning whether the content changed
+    ///
+    /// Only edits with a valid signature count, so nobody can rewrite a
+    /// message by claiming to be its sender. Edits apply in version order,
+    /// starting after `edit_version`. A delta only applies to the version
+    /// right before it, so after a missing edit the content stays put until
+    /// the next snapshot. Only text messages can be edited; other options of
+    /// the message, such as `reply_to`, are kept.
+    pub fn apply_edits(&mut self, edits: &[DecryptedMessage]) -> bool {
+        let (MessageContent::Text { mut text }, options) =
+            MessageContent::decode_with_options(&self.content)
+        else {
+            return false;
+        };
+
+        let mut changes: Vec<(&MessageEdit, u64)> = edits
+            .iter()
+            .filter(|edit| edit.verified && edit.sender == self.sender)
+            .filter_map(|edit| match &edit.body {
+                MessageContent::Edit(change) if change.target == self.id => {
+                    Some((change, edit.timestamp))
+                }
+                _ => None,
+            })
+            .collect();
+        changes.sort_by_key(|(change, _)| change.version);
+
+        let mut version = self.edit_version;
+        let mut edited_at = self.edited_at;
+        for (change, timestamp) in changes {
+            let applies = match change.change {
+                EditChange::Snapshot { .. } => change.version > version,
+                EditChange::Delta { .. } => version.checked_add(1) == Some(change.version),
+            };
+            if let Some(next) = applies.then(|| change.change.apply(&text)).flatten() {
+                text = next;
+                version = change.version;
+                edited_at = Some(timestamp);
+            }
+        }
+        if version == self.edit_version {
+            return false;
+        }
+
+        let Ok(content) = MessageContent::text(text).encode_with(&options) else {
+            return false;
+        };
+        let edited = Self::new(
+            self.id.clone(),
+            self.sender.clone(),
+            content,
+            self.timestamp,
+            self.verified,
+        );
+        self.content = edited.content;
+        self.body = edited.body;
+        self.mentions = edited.mentions;
+        self.mention_spans = edited.mention_spans;
+        self.edit_version = version;
+        self.edited_at = edited_at;
+        true
+    }
+
+    /// Whether the message has expired at the given Unix time (seconds)
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
+
+/// Serialize public keys as their string form
+mod public_keys {
+    use pkarr::PublicKey;
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(keys: &[PublicKey], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(keys.iter().map(|key| key.to_string()))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<PublicKey>, D::Error> {
+        Vec::<String>::deserialize(deserializer)?
+            .iter()
+            .map(|key| PublicKey::try_from(key.as_str()).map_err(D::Error::custom))
+            .collect()
+    }
 }
@@ -0,0 +1,172 @@
+use prometheus::core::{Collector, Desc};
+use prometheus::proto::MetricFamily;
+use prometheus::{IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts};
+use std::sync::{Arc, Mutex};
+
+use crate::client::{unix_now, PrivateMessengerClient};
+
+/// Prefix of every exported metric name
+const METRIC_PREFIX: &str = "pubky_messenger";
+
+/// Exposes the metrics of a client to a Prometheus registry
+///
+/// Values are read from the client on every scrape: the request counts,
+/// error counts, and latency percentiles of `PrivateMessengerClient::stats`,
+/// the bytes transferred, and, with a local store, the depth of the event
+/// queue, the number of pending message requests, and how long ago the least
+/// recently synced conversation was synced.
+///
+/// ```no_run
+/// # fn example(client: std::sync::Arc<pubky_messenger::PrivateMessengerClient>) -> anyhow::Result<()> {
+/// use pubky_messenger::MetricsCollector;
+///
+/// let registry = prometheus::Registry::new();
+/// registry.register(Box::new(MetricsCollector::new(client)?))?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct MetricsCollector {
+    client: Arc<PrivateMessengerClient>,
+    requests: IntCounterVec,
+    request_errors: IntCounterVec,
+    request_latency: IntGaugeVec,
+    bytes_sent: IntCounter,
+    bytes_received: IntCounter,
+    queued_events: IntGauge,
+    message_requests: IntGauge,
+    sync_lag: IntGauge,
+    /// Serializes scrapes, which reset and refill the metrics above
+    scrape: Mutex<()>,
+}
+
+impl MetricsCollector {
+    /// Create a collector reading the metrics of a client
+    pub fn new(client: Arc<PrivateMessengerClient>) -> prometheus::Result<Self> {
+        Ok(Self {
+            client,
+            requests: IntCounterVec::new(
+                opts("requests_total", "Homeserver requests made"),
+                &["operation"],
+            )?,
+            request_errors: IntCounterVec::new(
+                opts(
+                    "request_errors_total",
+                    "Homeserver requests that failed with a transport error or a 5xx response",
+                ),
+                &["operation"],
+            )?,
+            request_latency: IntGaugeVec::new(
+                opts(
+                    "request_latency_milliseconds",
+                    "Latency percentiles of recent homeserver requests",
+                ),
+                &["operation", "quantile"],
+            )?,
+            bytes_sent: IntCounter::with_opts(opts("sent_bytes_total", "Request bytes uploaded"))?,
+            bytes_received: IntCounter::with_opts(opts(
+                "received_bytes_total",
+                "Response bytes downloaded",
+            ))?,
+            queued_events: IntGauge::with_opts(opts(
+                "queued_events",
+                "Timeline events queued and not yet acknowledged",
+            ))?,
+            message_requests: IntGauge::with_opts(opts(
+                "pending_message_requests",
+                "Message requests neither accepted nor declined",
+            ))?,
+            sync_lag: IntGauge::with_opts(opts(
+                "sync_lag_seconds",
+                "Seconds since the least recently synced conversation was synced",
+            ))?,
+            scrape: Mutex::new(()),
+        })
+    }
+
+    /// Refill the metrics from the client
+    fn refresh(&self) {
+        let stats = self.client.stats();
+
+        self.requests.reset();
+        self.request_errors.reset();
+        self.request_latency.reset();
+        for (operation, op) in stats.operations.iter() {
+            let operation = operation.as_str();
+            self.requests
+                .with_label_values(&[operation])
+                .inc_by(op.count);
+            self.request_errors
+                .with_label_values(&[operation])
+                .inc_by(op.errors);
+            for (quantile, ms) in [
+                ("0.5", op.p50_ms),
+                ("0.9", op.p90_ms),
+                ("0.99", op.p99_ms),
+                ("1", op.max_ms),
+            ] {
+                self.request_latency
+                    .with_label_values(&[operation, quantile])
+                    .set(ms.try_into().unwrap_or(i64::MAX));
+            }
+        }
+
+        self.bytes_sent.reset();
+        self.bytes_sent.inc_by(stats.bytes_sent);
+        self.bytes_received.reset();
+        self.bytes_received.inc_by(stats.bytes_received);
+
+        // Store metrics are best effort: a locked or missing store reads as
+        // empty rather than failing the scrape
+        let store = self.client.local_store().filter(|store| !store.is_locked());
+        let queued = store.and_then(|store| store.pending_event_count().ok());
+        let requests = store.and_then(|store| store.pending_message_requests().ok());
+        let oldest_sync = store.and_then(|store| store.oldest_sync().ok().flatten());
+
+        self.queued_events
+            .set(queued.unwrap_or(0).try_into().unwrap_or(i64::MAX));
+        self.message_requests
+            .set(requests.map_or(0, |requests| requests.len() as i64));
+        self.sync_lag.set(
+            oldest_sync
+                .map_or(0, |at| unix_now().saturating_sub(at))
+                .try_into()
+                .unwrap_or(i64::MAX),
+        );
+    }
+}
+
+impl Collector for MetricsCollector {
+    fn desc(&self) -> Vec<&Desc> {
+        let mut descs = Vec::new();
+        descs.extend(self.requests.desc());
+        descs.extend(self.request_errors.desc());
+        descs.extend(self.request_latency.desc());
+        descs.extend(self.bytes_sent.desc());
+        descs.extend(self.bytes_received.desc());
+        descs.extend(self.queued_events.desc());
+        descs.extend(self.message_requests.desc());
+        descs.extend(self.sync_lag.desc());
+        descs
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let _scrape = self.scrape.lock().unwrap_or_else(|e| e.into_inner());
+        self.refresh();
+
+        let mut families = Vec::new();
+        families.extend(self.requests.collect());
+        families.extend(self.request_errors.collect());
+        families.extend(self.request_latency.collect());
+        families.extend(self.bytes_sent.collect());
+        families.extend(self.bytes_received.collect());
+        families.extend(self.queued_events.collect());
+        families.extend(self.message_requests.collect());
+        families.extend(self.sync_lag.collect());
+        families
+    }
+}
+
+/// Options of a metric, with the crate's name prefix
+fn opts(name: &str, help: &str) -> Opts {
+    Opts::new(name, help).namespace(METRIC_PREFIX)
+}
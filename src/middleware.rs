@@ -0,0 +1,32 @@
+use anyhow::Result;
+use pkarr::PublicKey;
+
+/// Transforms message content on its way out and in
+///
+/// Registered with `PrivateMessengerClient::with_middleware` to plug
+/// translation, profanity masking, or an extra layer of encryption into
+/// sends and fetches. Middleware sees content as sent: plain text, or an
+/// encoded payload for typed content (see `MessageContent::decode`).
+///
+/// Outbound hooks run in registration order on every message we encrypt,
+/// and inbound hooks run in reverse order on every message we decrypt, so a
+/// middleware that wraps content on the way out unwraps it on the way in.
+/// Both run inside sends and fetches, so they should not block for long.
+pub trait MessageMiddleware: Send + Sync {
+    /// Transform content about to be encrypted for `peer`
+    ///
+    /// An error fails the send.
+    fn outbound(&self, peer: &PublicKey, content: String) -> Result<String> {
+        let _ = peer;
+        Ok(content)
+    }
+
+    /// Transform content just decrypted from the conversation with `peer`
+    ///
+    /// An error leaves the content as this middleware got it, so one
+    /// message can't fail a whole fetch.
+    fn inbound(&self, peer: &PublicKey, content: String) -> Result<String> {
+        let _ = peer;
+        Ok(content)
+    }
+}
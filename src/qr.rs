@@ -0,0 +1,106 @@
+use anyhow::{anyhow, Result};
+use qrcode::render::{svg, unicode};
+use qrcode::{Color, EcLevel, QrCode};
+
+use crate::identity::IdentityBundle;
+
+/// Modules of light border around the code, as required by the QR spec
+const QUIET_ZONE: usize = 4;
+
+/// Gray levels of rendered pixels
+const DARK_PIXEL: u8 = 0;
+const LIGHT_PIXEL: u8 = 255;
+
+impl IdentityBundle {
+    /// Render the invite link as an SVG QR code
+    pub fn to_qr_svg(&self) -> Result<String> {
+        Ok(self
+            .invite_qr_code()?
+            .render::<svg::Color>()
+            .min_dimensions(256, 256)
+            .build())
+    }
+
+    /// Render the invite link as a QR code of Unicode half blocks, for
+    /// terminals
+    pub fn to_qr_text(&self) -> Result<String> {
+        Ok(self
+            .invite_qr_code()?
+            .render::<unicode::Dense1x2>()
+            .dark_color(unicode::Dense1x2::Light)
+            .light_color(unicode::Dense1x2::Dark)
+            .build())
+    }
+
+    /// Render the invite link as a square 8-bit grayscale image
+    ///
+    /// Each QR module becomes `module_size` pixels on a side. Returns the
+    /// image width and its pixels in row-major order.
+    pub fn to_qr_pixels(&self, module_size: usize) -> Result<(usize, Vec<u8>)> {
+        if module_size == 0 {
+            return Err(anyhow!("QR module size must be at least one pixel"));
+        }
+
+        let code = self.invite_qr_code()?;
+        let modules = code.width() + 2 * QUIET_ZONE;
+        let colors = code.to_colors();
+        let width = modules * module_size;
+
+        let mut pixels = vec![LIGHT_PIXEL; width * width];
+        for (index, color) in colors.iter().enumerate() {
+            if *color != Color::Dark {
+                continue;
+            }
+            let x = (index % code.width() + QUIET_ZONE) * module_size;
+            let y = (index / code.width() + QUIET_ZONE) * module_size;
+            for row in y..y + module_size {
+                pixels[row * width + x..row * width + x + module_size].fill(DARK_PIXEL);
+            }
+        }
+
+        Ok((width, pixels))
+    }
+
+    /// Decode an invite link from an 8-bit grayscale image of a QR code
+    ///
+    /// `pixels` holds `width * height` gray levels in row-major order, e.g.
+    /// the luma plane of a camera frame. The first QR code holding a valid
+    /// invite link is used.
+    pub fn from_qr_pixels(width: usize, height: usize, pixels: &[u8]) -> Result<Self> {
+        if pixels.len() != width * height {
+            return Err(anyhow!(
+                "Expected {} pixels for a {}x{} image, got {}",
+                width * height,
+                width,
+                height,
+                pixels.len()
+            ));
+        }
+
+        let mut image = rqrr::PreparedImage::prepare_from_greyscale(width, height, |x, y| {
+            pixels[y * width + x]
+        });
+
+        let mut last_error = anyhow!("No QR code found in image");
+        for grid in image.detect_grids() {
+            match grid.decode() {
+                Ok((_, content)) => match Self::from_invite_link(&content) {
+                    Ok(bundle) => return Ok(bundle),
+                    Err(e) => last_error = e,
+                },
+                Err(e) => last_error = anyhow!("Failed to decode QR code: {}", e),
+            }
+        }
+
+        Err(last_error)
+    }
+
+    /// QR code of the invite link
+    ///
+    /// Uses medium error correction, which survives the blur and glare of
+    /// phone cameras while keeping the code small.
+    fn invite_qr_code(&self) -> Result<QrCode> {
+        QrCode::with_error_correction_level(self.to_invite_link(), EcLevel::M)
+            .map_err(|e| anyhow!("Failed to encode invite link as QR code: {}", e))
+    }
+}
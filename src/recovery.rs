@@ -0,0 +1,184 @@
+use anyhow::{anyhow, Result};
+use argon2::{Algorithm, Argon2, Params, Version};
+use pkarr::Keypair;
+use pubky_common::crypto::{decrypt, encrypt};
+use pubky_common::recovery_file;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use zeroize::Zeroizing;
+
+/// First line prefix of recovery files that carry their own KDF parameters
+const SPEC_LINE: &str = "pubky-messenger/recovery argon2id";
+
+/// Length of the Argon2 salt in a recovery file
+const SALT_LENGTH: usize = 16;
+
+/// Largest memory cost accepted, in KiB (1 GiB)
+pub const MAX_RECOVERY_MEMORY_KIB: u32 = 1024 * 1024;
+
+/// Largest number of passes accepted
+pub const MAX_RECOVERY_ITERATIONS: u32 = 64;
+
+/// Largest degree of parallelism accepted
+pub const MAX_RECOVERY_PARALLELISM: u32 = 16;
+
+/// Argon2id work factors used to protect a recovery file
+///
+/// Recovery files created with pubky-common use fixed parameters. Files
+/// created with custom parameters record them in their first line, so they
+/// can still be opened with `PrivateMessengerClient::from_recovery_file`.
+///
+/// The parameters are read from the file before the passphrase is checked,
+/// so they are capped (`MAX_RECOVERY_MEMORY_KIB`, `MAX_RECOVERY_ITERATIONS`,
+/// `MAX_RECOVERY_PARALLELISM`) to keep a crafted file from exhausting memory
+/// or CPU. Files can't be created beyond the caps either.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RecoveryKdfParams {
+    /// Memory cost in KiB
+    pub memory_kib: u32,
+    /// Number of passes over memory
+    pub iterations: u32,
+    /// Degree of parallelism
+    pub parallelism: u32,
+}
+
+impl Default for RecoveryKdfParams {
+    fn default() -> Self {
+        Self {
+            memory_kib: Params::DEFAULT_M_COST,
+            iterations: Params::DEFAULT_T_COST,
+            parallelism: Params::DEFAULT_P_COST,
+        }
+    }
+}
+
+impl RecoveryKdfParams {
+    /// Stronger parameters for users who want to harden their key file
+    /// (64 MiB of memory, 4 passes, 4 lanes)
+    pub fn hardened() -> Self {
+        Self {
+            memory_kib: 64 * 1024,
+            iterations: 4,
+            parallelism: 4,
+        }
+    }
+
+    /// Check that the work factors are within the caps
+    fn check_limits(&self) -> Result<()> {
+        if self.memory_kib > MAX_RECOVERY_MEMORY_KIB
+            || self.iterations > MAX_RECOVERY_ITERATIONS
+            || self.parallelism > MAX_RECOVERY_PARALLELISM
+        {
+            return Err(anyhow!(
+                "KDF parameters exceed the limits of m={},t={},p={}",
+                MAX_RECOVERY_MEMORY_KIB,
+                MAX_RECOVERY_ITERATIONS,
+                MAX_RECOVERY_PARALLELISM
+            ));
+        }
+        Ok(())
+    }
+
+    fn argon2(&self) -> Result<Argon2<'static>> {
+        self.check_limits()?;
+        let params = Params::new(self.memory_kib, self.iterations, self.parallelism, Some(32))
+            .map_err(|e| anyhow!("Invalid KDF parameters: {}", e))?;
+        Ok(Argon2::new(Algorithm::Argon2id, Version::V0x13, params))
+    }
+
+    fn derive_key(&self, passphrase: &str, salt: &[u8]) -> Result<Zeroizing<[u8; 32]>> {
+        let mut key = Zeroizing::new([0u8; 32]);
+        self.argon2()?
+            .hash_password_into(passphrase.as_bytes(), salt, &mut key[..])
+            .map_err(|e| anyhow!("Failed to derive recovery file key: {}", e))?;
+        Ok(key)
+    }
+}
+
+/// Create a recovery file protected with the given KDF parameters
+pub fn create_recovery_file(
+    keypair: &Keypair,
+    passphrase: &str,
+    params: &RecoveryKdfParams,
+) -> Result<Vec<u8>> {
+    let mut salt = [0u8; SALT_LENGTH];
+    rand::thread_rng().fill_bytes(&mut salt);
+
+    let key = params.derive_key(passphrase, &salt)?;
+    let encrypted_secret = encrypt(&keypair.secret_key(), &key);
+
+    let header = format!(
+        "{} m={},t={},p={} salt={}\n",
+        SPEC_LINE,
+        params.memory_kib,
+        params.iterations,
+        params.parallelism,
+        hex::encode(salt)
+    );
+
+    let mut out = header.into_bytes();
+    out.extend_from_slice(&encrypted_secret);
+    Ok(out)
+}
+
+/// Decrypt a recovery file in either the pubky-common or the custom KDF format
+pub fn decrypt_recovery_file(recovery_file_bytes: &[u8], passphrase: &str) -> Result<Keypair> {
+    if !recovery_file_bytes.starts_with(SPEC_LINE.as_bytes()) {
+        return recovery_file::decrypt_recovery_file(recovery_file_bytes, passphrase)
+            .map_err(|e| anyhow!("Failed to decrypt recovery file: {:?}", e));
+    }
+
+    let newline = recovery_file_bytes
+        .iter()
+        .position(|&b| b == b'\n')
+        .ok_or_else(|| anyhow!("Invalid recovery file"))?;
+    let header = std::str::from_utf8(&recovery_file_bytes[..newline])?;
+    let (params, salt) = parse_header(header)?;
+
+    let key = params.derive_key(passphrase, &salt)?;
+    let secret = Zeroizing::new(
+        decrypt(&recovery_file_bytes[newline + 1..], &key)
+            .map_err(|_| anyhow!("Failed to decrypt recovery file: wrong passphrase?"))?,
+    );
+
+    let secret_key: [u8; 32] = secret
+        .as_slice()
+        .try_into()
+        .map_err(|_| anyhow!("Invalid secret key length in recovery file"))?;
+
+    Ok(Keypair::from_secret_key(&secret_key))
+}
+
+/// Parse `<spec> m=..,t=..,p=.. salt=<hex>`
+fn parse_header(header: &str) -> Result<(RecoveryKdfParams, Vec<u8>)> {
+    let mut fields = header[SPEC_LINE.len()..].split_whitespace();
+    let cost = fields
+        .next()
+        .ok_or_else(|| anyhow!("Missing KDF parameters in recovery file"))?;
+    let salt = fields
+        .next()
+        .and_then(|field| field.strip_prefix("salt="))
+        .ok_or_else(|| anyhow!("Missing salt in recovery file"))?;
+
+    let mut params = RecoveryKdfParams::default();
+    for part in cost.split(',') {
+        let (name, value) = part
+            .split_once('=')
+            .ok_or_else(|| anyhow!("Invalid KDF parameter: {}", part))?;
+        let value: u32 = value.parse()?;
+        match name {
+            "m" => params.memory_kib = value,
+            "t" => params.iterations = value,
+            "p" => params.parallelism = value,
+            _ => return Err(anyhow!("Unknown KDF parameter: {}", name)),
+        }
+    }
+    // Before any key is derived from them
+    params.check_limits()?;
+
+    let salt = hex::decode(salt)?;
+    if salt.len() != SALT_LENGTH {
+        return Err(anyhow!("Invalid salt length in recovery file"));
+    }
+    Ok((params, salt))
+}
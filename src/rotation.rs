@@ -0,0 +1,80 @@
+use anyhow::{anyhow, Result};
+use ed25519_dalek::Signature;
+use pkarr::{Keypair, PublicKey};
+use serde::{Deserialize, Serialize};
+
+/// Domain separation prefix of the statement both keys sign
+const ROTATION_CONTEXT: &str = "pubky-messenger key rotation v1";
+
+/// Proof that an identity moved to a new keypair
+///
+/// The old key signs a statement endorsing the new one, and the new key
+/// signs the same statement to show its holder took part. Published on the
+/// old identity's homeserver and sent to its contacts.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyRotationProof {
+    pub old_pubky: String,
+    pub new_pubky: String,
+    /// Unix time in seconds of the rotation
+    pub rotated_at: u64,
+    /// Hex-encoded signature of the old key over the statement
+    pub old_signature: String,
+    /// Hex-encoded signature of the new key over the statement
+    pub new_signature: String,
+}
+
+impl KeyRotationProof {
+    /// Sign a rotation from `old` to `new`
+    pub fn new(old: &Keypair, new: &Keypair, rotated_at: u64) -> Self {
+        let old_pubky = old.public_key().to_string();
+        let new_pubky = new.public_key().to_string();
+        let statement = statement(&old_pubky, &new_pubky, rotated_at);
+
+        Self {
+            old_signature: hex::encode(old.sign(&statement).to_bytes()),
+            new_signature: hex::encode(new.sign(&statement).to_bytes()),
+            old_pubky,
+            new_pubky,
+            rotated_at,
+        }
+    }
+
+    /// Check both signatures
+    pub fn verify(&self) -> Result<()> {
+        let statement = statement(&self.old_pubky, &self.new_pubky, self.rotated_at);
+        for (pubky, signature, role) in [
+            (&self.old_pubky, &self.old_signature, "old"),
+            (&self.new_pubky, &self.new_signature, "new"),
+        ] {
+            let key = PublicKey::try_from(pubky.as_str())
+                .map_err(|e| anyhow!("Invalid {} pubky in key rotation: {}", role, e))?;
+            let bytes = hex::decode(signature.trim())
+                .ok()
+                .and_then(|bytes| <[u8; 64]>::try_from(bytes.as_slice()).ok())
+                .ok_or_else(|| anyhow!("Malformed {} key signature in key rotation", role))?;
+            key.verify(&statement, &Signature::from_bytes(&bytes))
+                .map_err(|_| anyhow!("Invalid {} key signature in key rotation", role))?;
+        }
+
+        if self.old_pubky == self.new_pubky {
+            return Err(anyhow!("Key rotation must move to a different key"));
+        }
+
+        Ok(())
+    }
+
+    /// The public key the identity moved to
+    pub fn new_public_key(&self) -> Result<PublicKey> {
+        PublicKey::try_from(self.new_pubky.as_str())
+            .map_err(|e| anyhow!("Invalid new pubky in key rotation: {}", e))
+    }
+}
+
+/// The bytes signed by both keys of a rotation
+fn statement(old_pubky: &str, new_pubky: &str, rotated_at: u64) -> Vec<u8> {
+    format!(
+        "{}\n{}\n{}\n{}",
+        ROTATION_CONTEXT, old_pubky, new_pubky, rotated_at
+    )
+    .into_bytes()
+}
@@ -0,0 +1,121 @@
+use serde::{Deserialize, Serialize};
+use unicode_normalization::UnicodeNormalization;
+
+use crate::content::{Mention, MessageContent};
+use crate::message::DecryptedMessage;
+
+/// Default maximum length of inbound text, in bytes (64 KiB)
+const DEFAULT_MAX_TEXT_BYTES: usize = 64 * 1024;
+
+/// Processing applied to the text of received messages before it reaches
+/// the application
+///
+/// Applies to the typed `body` of messages (text, poll questions and
+/// options, payment memos). `content` stays exactly as it was signed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ContentPolicy {
+    /// Normalize text to Unicode NFC, so visually identical strings compare equal
+    pub normalize: bool,
+    /// Remove control characters (except newlines and tabs) and bidirectional
+    /// override and isolate characters used for right-to-left spoofing
+    pub strip_control: bool,
+    /// Truncate longer text to this many bytes
+    pub max_text_bytes: Option<usize>,
+}
+
+impl Default for ContentPolicy {
+    fn default() -> Self {
+        Self {
+            normalize: true,
+            strip_control: true,
+            max_text_bytes: Some(DEFAULT_MAX_TEXT_BYTES),
+        }
+    }
+}
+
+impl ContentPolicy {
+    /// Leave received text untouched
+    pub fn disabled() -> Self {
+        Self {
+            normalize: false,
+            strip_control: false,
+            max_text_bytes: None,
+        }
+    }
+
+    /// Apply the policy to a piece of text
+    pub fn apply(&self, text: &str) -> String {
+        let mut text: String = if self.strip_control {
+            text.chars().filter(|&c| !is_unsafe_char(c)).collect()
+        } else {
+            text.to_string()
+        };
+
+        if self.normalize {
+            text = text.nfc().collect();
+        }
+
+        if let Some(max) = self.max_text_bytes {
+            if text.len() > max {
+                let mut end = max;
+                while !text.is_char_boundary(end) {
+                    end -= 1;
+                }
+                text.truncate(end);
+            }
+        }
+
+        text
+    }
+
+    /// Apply the policy to the body of a received message
+    ///
+    /// Mention spans are recomputed, since sanitizing can shift byte offsets.
+    pub(crate) fn apply_to_message(&self, message: &mut DecryptedMessage) {
+        self.apply_to_body(&mut message.body);
+
+        if let MessageContent::Text { text } = &message.body {
+            let mut spans: Vec<Mention> = message
+                .mentions
+                .iter()
+                .flat_map(|pubky| Mention::find(text, pubky))
+                .collect();
+            spans.sort_by_key(|mention| mention.start);
+            message.mention_spans = spans;
+        }
+    }
+
+    /// Apply the policy to every text field of a message body
+    fn apply_to_body(&self, body: &mut MessageContent) {
+        match body {
+            MessageContent::Text { text } => *text = self.apply(text),
+            MessageContent::PaymentRequest(request) => {
+                if let Some(memo) = request.memo.as_mut() {
+                    *memo = self.apply(memo);
+                }
+            }
+            MessageContent::Poll(poll) => {
+                poll.question = self.apply(&poll.question);
+                for option in poll.options.iter_mut() {
+                    *option = self.apply(option);
+                }
+            }
+            MessageContent::PaymentProof(_)
+            | MessageContent::Cover { .. }
+            | MessageContent::Binary { .. }
+            | MessageContent::Json { .. }
+            | MessageContent::LargeEmoji { .. }
+            | MessageContent::Sticker(_)
+            | MessageContent::System(_)
+            | MessageContent::Edit(_)
+            | MessageContent::Command(_) => {}
+        }
+    }
+}
+
+/// Control and bidirectional formatting characters that can spoof how text
+/// is displayed
+fn is_unsafe_char(c: char) -> bool {
+    let bidi_control = matches!(c, '\u{202A}'..='\u{202E}' | '\u{2066}'..='\u{2069}' | '\u{061C}');
+    bidi_control || (c.is_control() && c != '\n' && c != '\t')
+}
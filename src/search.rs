@@ -0,0 +1,55 @@
+use std::collections::BTreeSet;
+use unicode_normalization::UnicodeNormalization;
+use zeroize::Zeroizing;
+
+/// Key derivation contexts of the search tokens of a conversation
+const SEARCH_KEY_CONTEXT: &str = "pubky-messenger search key v1";
+const SEARCH_PATH_CONTEXT: &str = "pubky-messenger search path v1";
+
+/// Hex characters of a search token, enough to keep collisions between
+/// distinct words rare without lengthening paths needlessly
+const TOKEN_HEX_CHARS: usize = 32;
+
+/// Words shorter than this are not indexed
+const MIN_WORD_CHARS: usize = 2;
+
+/// Most tokens published for one message, bounding the writes of a send
+pub(crate) const MAX_SEARCH_TOKENS: usize = 64;
+
+/// Key the search tokens of a conversation are computed with
+///
+/// Derived from the conversation key, so both participants compute the same
+/// tokens and can query each other's side.
+pub(crate) fn search_key(conversation_key: &[u8; 32]) -> Zeroizing<[u8; 32]> {
+    Zeroizing::new(blake3::derive_key(SEARCH_KEY_CONTEXT, conversation_key))
+}
+
+/// Directory holding the search tokens of a conversation
+///
+/// Kept apart from the conversation directory so that message listings never
+/// page through tokens. Like the device settings directory, it looks like any
+/// conversation directory to the homeserver.
+pub(crate) fn search_path(conversation_key: &[u8; 32]) -> String {
+    let path_id = blake3::derive_key(SEARCH_PATH_CONTEXT, conversation_key);
+    format!("/pub/private_messages/{}/", hex::encode(path_id))
+}
+
+/// The distinct words of `text` as indexed and queried: NFKC-normalized,
+/// lowercased, split at anything that isn't a letter or digit
+pub(crate) fn search_words(text: &str) -> BTreeSet<String> {
+    let normalized: String = text.nfkc().collect::<String>().to_lowercase();
+    normalized
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| word.chars().count() >= MIN_WORD_CHARS)
+        .map(str::to_string)
+        .collect()
+}
+
+/// Token of a word: a keyed blake3 MAC of it, hex-encoded and truncated
+///
+/// Without the key, the homeserver can't tell which word a token stands
+/// for, but equal words always map to equal tokens.
+pub(crate) fn search_token(key: &[u8; 32], word: &str) -> String {
+    let mac = blake3::keyed_hash(key, word.as_bytes());
+    mac.to_hex()[..TOKEN_HEX_CHARS].to_string()
+}
@@ -0,0 +1,179 @@
+use anyhow::{anyhow, Result};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use pubky_common::crypto::{decrypt, encrypt};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::away::AwaySetting;
+use crate::filters::MuteRule;
+use crate::trust::TrustLevel;
+
+/// Longest conversation label, in characters
+pub const MAX_LABEL_CHARS: usize = 32;
+
+/// Most labels on one conversation
+pub const MAX_CONVERSATION_LABELS: usize = 16;
+
+/// A contact's trust level and when it was set
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TrustSetting {
+    pub level: TrustLevel,
+    /// Unix time in seconds of the change
+    pub updated_at: u64,
+}
+
+impl TrustSetting {
+    /// Whether this change wins over `other`
+    ///
+    /// The later change wins. Changes made in the same second are ordered by
+    /// level name, so every device settles on the same result.
+    pub fn supersedes(&self, other: &TrustSetting) -> bool {
+        (self.updated_at, self.level.as_str()) > (other.updated_at, other.level.as_str())
+    }
+}
+
+/// A mute rule, whether it is in effect, and when that last changed
+///
+/// Unmuted rules are kept so that the removal reaches other devices.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MuteSetting {
+    pub rule: MuteRule,
+    pub muted: bool,
+    /// Unix time in seconds of the change
+    pub updated_at: u64,
+}
+
+impl MuteSetting {
+    /// Whether this change wins over `other`, ordered like
+    /// `TrustSetting::supersedes`
+    pub fn supersedes(&self, other: &MuteSetting) -> bool {
+        (self.updated_at, self.muted) > (other.updated_at, other.muted)
+    }
+}
+
+/// The labels of a conversation and when they last changed
+///
+/// A conversation whose labels were all removed keeps an empty set, so that
+/// the removal reaches other devices.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LabelSetting {
+    pub labels: BTreeSet<String>,
+    /// Unix time in seconds of the change
+    pub updated_at: u64,
+}
+
+impl LabelSetting {
+    /// Check that every label is within limits
+    pub fn validate(&self) -> Result<()> {
+        if self.labels.len() > MAX_CONVERSATION_LABELS {
+            return Err(anyhow!(
+                "A conversation can have at most {} labels",
+                MAX_CONVERSATION_LABELS
+            ));
+        }
+        for label in self.labels.iter() {
+            if label.trim() != label || label.is_empty() || label.chars().count() > MAX_LABEL_CHARS
+            {
+                return Err(anyhow!(
+                    "Labels must be 1 to {} characters without surrounding whitespace",
+                    MAX_LABEL_CHARS
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether this change wins over `other`, ordered like
+    /// `TrustSetting::supersedes`
+    pub fn supersedes(&self, other: &LabelSetting) -> bool {
+        (self.updated_at, &self.labels) > (other.updated_at, &other.labels)
+    }
+}
+
+/// Contact settings shared between the devices of one identity
+///
+/// Kept encrypted on our homeserver under a path derived from our secret
+/// key, and merged entry by entry with the latest change winning.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeviceSettings {
+    /// Explicit trust levels by contact pubky
+    #[serde(default)]
+    pub trust: BTreeMap<String, TrustSetting>,
+    /// Mute rules, by rule
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub mutes: BTreeMap<String, MuteSetting>,
+    /// Conversation labels, by peer pubky
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub labels: BTreeMap<String, LabelSetting>,
+    /// The away message, see `AwayMessage`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub away: Option<AwaySetting>,
+}
+
+impl DeviceSettings {
+    /// Merge `other` into these settings, returning the contacts whose
+    /// settings changed
+    ///
+    /// Mute rules, conversation labels, and the away message are merged the
+    /// same way, each rule and conversation on its own.
+    pub fn merge(&mut self, other: &DeviceSettings) -> Vec<String> {
+        for (peer, theirs) in &other.labels {
+            let wins = match self.labels.get(peer) {
+                Some(ours) => theirs.supersedes(ours),
+                None => true,
+            };
+            if wins {
+                self.labels.insert(peer.clone(), theirs.clone());
+            }
+        }
+
+        if let Some(theirs) = &other.away {
+            let wins = match &self.away {
+                Some(ours) => theirs.supersedes(ours),
+                None => true,
+            };
+            if wins {
+                self.away = Some(theirs.clone());
+            }
+        }
+
+        for (key, theirs) in &other.mutes {
+            let wins = match self.mutes.get(key) {
+                Some(ours) => theirs.supersedes(ours),
+                None => true,
+            };
+            if wins {
+                self.mutes.insert(key.clone(), theirs.clone());
+            }
+        }
+
+        let mut changed = Vec::new();
+        for (peer, theirs) in &other.trust {
+            let wins = match self.trust.get(peer) {
+                Some(ours) => theirs.supersedes(ours),
+                None => true,
+            };
+            if wins {
+                self.trust.insert(peer.clone(), *theirs);
+                changed.push(peer.clone());
+            }
+        }
+        changed
+    }
+
+    /// Encrypt the settings into a record for the homeserver
+    pub(crate) fn seal(&self, key: &[u8; 32]) -> Result<String> {
+        let json = serde_json::to_vec(self)?;
+        Ok(STANDARD.encode(encrypt(&json, key)))
+    }
+
+    /// Decrypt settings from a homeserver record
+    pub(crate) fn open(record: &str, key: &[u8; 32]) -> Result<Self> {
+        let sealed = STANDARD
+            .decode(record.trim())
+            .map_err(|e| anyhow!("Invalid settings record: {}", e))?;
+        let json = decrypt(&sealed, key)?;
+        Ok(serde_json::from_slice(&json)?)
+    }
+}
@@ -0,0 +1,124 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+
+use crate::message::DecryptedMessage;
+
+/// Key derivation contexts of message and snapshot digests
+const MESSAGE_DIGEST_CONTEXT: &str = "pubky-messenger snapshot message v1";
+const SNAPSHOT_ROOT_CONTEXT: &str = "pubky-messenger snapshot root v1";
+
+/// Content-addressed summary of a conversation at one point in time
+///
+/// Holds a digest of every message by ID and a root digest over all of
+/// them, so two snapshots with the same root hold the same messages with the
+/// same content. No message content is kept, so a snapshot can be stored
+/// next to a backup, or sent to another device, and compared later with
+/// `PrivateMessengerClient::diff`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConversationSnapshot {
+    pub peer: String,
+    /// Unix time in seconds when the snapshot was taken
+    pub taken_at: u64,
+    /// Hex-encoded blake3 digest of each message, by message ID
+    pub messages: BTreeMap<String, String>,
+    /// Hex-encoded blake3 digest over `messages`, in ID order
+    pub root: String,
+}
+
+/// Messages that changed between a snapshot and the current conversation
+#[derive(Debug, Clone, Default)]
+pub struct SnapshotDiff {
+    /// Messages that were not in the snapshot
+    pub added: Vec<DecryptedMessage>,
+    /// IDs of snapshot messages that are gone
+    pub removed: Vec<String>,
+    /// Messages whose content, sender, time, or edit version changed
+    pub edited: Vec<DecryptedMessage>,
+}
+
+impl SnapshotDiff {
+    /// Whether the conversation still matches the snapshot
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.edited.is_empty()
+    }
+}
+
+impl ConversationSnapshot {
+    /// Summarize the messages of a conversation
+    pub fn from_messages(peer: &str, messages: &[DecryptedMessage], taken_at: u64) -> Self {
+        let messages: BTreeMap<String, String> = messages
+            .iter()
+            .map(|message| (message.id.clone(), message_digest(message)))
+            .collect();
+
+        Self {
+            peer: peer.to_string(),
+            taken_at,
+            root: root_digest(&messages),
+            messages,
+        }
+    }
+
+    /// Compare the snapshot with the current messages of its conversation
+    ///
+    /// Added and edited messages keep the order of `messages`; removed IDs
+    /// are in ID order.
+    pub fn diff_messages(&self, messages: &[DecryptedMessage]) -> SnapshotDiff {
+        let mut diff = SnapshotDiff::default();
+        for message in messages {
+            match self.messages.get(&message.id) {
+                None => diff.added.push(message.clone()),
+                Some(digest) if *digest != message_digest(message) => {
+                    diff.edited.push(message.clone())
+                }
+                Some(_) => {}
+            }
+        }
+
+        let current: HashSet<&str> = messages.iter().map(|message| message.id.as_str()).collect();
+        diff.removed = self
+            .messages
+            .keys()
+            .filter(|id| !current.contains(id.as_str()))
+            .cloned()
+            .collect();
+
+        diff
+    }
+
+    /// Whether `root` matches the message digests, i.e. the snapshot was not
+    /// altered since it was taken
+    pub fn verify(&self) -> bool {
+        self.root == root_digest(&self.messages)
+    }
+}
+
+/// Digest of what a message says and who sent it when
+///
+/// Fields are length-prefixed so that no two messages encode alike.
+fn message_digest(message: &DecryptedMessage) -> String {
+    let mut hasher = blake3::Hasher::new_derive_key(MESSAGE_DIGEST_CONTEXT);
+    for field in [
+        message.id.as_bytes(),
+        message.sender.as_bytes(),
+        message.content.as_bytes(),
+    ] {
+        hasher.update(&(field.len() as u64).to_be_bytes());
+        hasher.update(field);
+    }
+    hasher.update(&message.timestamp.to_be_bytes());
+    hasher.update(&message.edit_version.to_be_bytes());
+    hasher.finalize().to_hex().to_string()
+}
+
+/// Digest over message digests, in ID order
+fn root_digest(messages: &BTreeMap<String, String>) -> String {
+    let mut hasher = blake3::Hasher::new_derive_key(SNAPSHOT_ROOT_CONTEXT);
+    for (id, digest) in messages.iter() {
+        hasher.update(id.as_bytes());
+        hasher.update(b"\n");
+        hasher.update(digest.as_bytes());
+        hasher.update(b"\n");
+    }
+    hasher.finalize().to_hex().to_string()
+}
@@ -0,0 +1,37 @@
+use serde::{Deserialize, Serialize};
+
+use crate::message::DecryptedMessage;
+
+/// Where an inbound message should go
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SpamVerdict {
+    /// Deliver it like any other message
+    #[default]
+    Inbox,
+    /// Hold it as a message request, see `PrivateMessengerClient::with_message_requests`
+    Request,
+    /// Drop it from the fetch results
+    Block,
+}
+
+/// How much a sender had written when they sent a message, counting the
+/// messages being fetched and, with a local store, those stored earlier
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SenderActivity {
+    /// Messages from the sender in the hour up to the message, including it
+    pub last_hour: usize,
+    /// Messages from the sender in the day up to the message, including it
+    pub last_day: usize,
+}
+
+/// Classifies inbound messages as wanted or not
+///
+/// Registered with `PrivateMessengerClient::with_spam_filter` and called on
+/// every message from the peer when a conversation is fetched or synced.
+/// Classification runs inside the fetch, so filters backed by a shared
+/// blocklist should keep a local copy rather than query it per message.
+pub trait SpamFilter: Send + Sync {
+    /// Decide where `message` goes, given how active its sender has been
+    fn classify(&self, message: &DecryptedMessage, activity: &SenderActivity) -> SpamVerdict;
+}
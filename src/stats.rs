@@ -0,0 +1,124 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Number of recent latency samples kept per operation for percentiles
+const LATENCY_SAMPLES: usize = 1024;
+
+/// Latency and error counts of one kind of homeserver request
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OperationStats {
+    /// Requests made since the client was created or the stats were reset
+    pub count: u64,
+    /// Requests that failed with a transport error or a 5xx response
+    pub errors: u64,
+    /// Latency percentiles in milliseconds over the most recent requests
+    pub p50_ms: u64,
+    pub p90_ms: u64,
+    pub p99_ms: u64,
+    pub max_ms: u64,
+}
+
+/// Snapshot of request latencies and bytes transferred by a client
+///
+/// Operations are keyed by request kind: `get`, `list`, `put`, and `delete`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ClientStats {
+    pub operations: BTreeMap<String, OperationStats>,
+    /// Request bodies uploaded, in bytes
+    pub bytes_sent: u64,
+    /// Response bodies downloaded, in bytes
+    pub bytes_received: u64,
+}
+
+#[derive(Default)]
+struct OperationSamples {
+    count: u64,
+    errors: u64,
+    latencies_ms: VecDeque<u64>,
+}
+
+#[derive(Default)]
+struct StatsState {
+    operations: HashMap<&'static str, OperationSamples>,
+    bytes_sent: u64,
+    bytes_received: u64,
+}
+
+/// Collects request statistics for `ClientStats` snapshots
+#[derive(Default)]
+pub(crate) struct StatsRecorder {
+    state: Mutex<StatsState>,
+}
+
+impl StatsRecorder {
+    /// Record a finished request of the given kind
+    pub fn record(&self, operation: &'static str, elapsed: Duration, ok: bool) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let samples = state.operations.entry(operation).or_default();
+        samples.count += 1;
+        if !ok {
+            samples.errors += 1;
+        }
+        if samples.latencies_ms.len() == LATENCY_SAMPLES {
+            samples.latencies_ms.pop_front();
+        }
+        samples
+            .latencies_ms
+            .push_back(elapsed.as_millis().try_into().unwrap_or(u64::MAX));
+    }
+
+    pub fn record_sent(&self, bytes: usize) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.bytes_sent += bytes as u64;
+    }
+
+    pub fn record_received(&self, bytes: usize) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.bytes_received += bytes as u64;
+    }
+
+    pub fn snapshot(&self) -> ClientStats {
+        let state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+
+        let operations = state
+            .operations
+            .iter()
+            .map(|(operation, samples)| {
+                let mut sorted: Vec<u64> = samples.latencies_ms.iter().copied().collect();
+                sorted.sort_unstable();
+
+                let stats = OperationStats {
+                    count: samples.count,
+                    errors: samples.errors,
+                    p50_ms: percentile(&sorted, 50),
+                    p90_ms: percentile(&sorted, 90),
+                    p99_ms: percentile(&sorted, 99),
+                    max_ms: sorted.last().copied().unwrap_or(0),
+                };
+                (operation.to_string(), stats)
+            })
+            .collect();
+
+        ClientStats {
+            operations,
+            bytes_sent: state.bytes_sent,
+            bytes_received: state.bytes_received,
+        }
+    }
+
+    pub fn reset(&self) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        *state = StatsState::default();
+    }
+}
+
+/// Nearest-rank percentile of sorted samples
+fn percentile(sorted: &[u64], percent: usize) -> u64 {
+    if sorted.is_empty() {
+        return 0;
+    }
+    let rank = ((sorted.len() * percent + 99) / 100).max(1);
+    sorted[rank - 1]
+}
@@ -0,0 +1,1718 @@
+use anyhow::{anyhow, Result};
+use argon2::Argon2;
+use rand::RngCore;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ops::{Deref, DerefMut};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+use zeroize::{Zeroize, Zeroizing};
+
+use crate::audit::{AuditAction, AuditEntry};
+use crate::away::AwaySetting;
+use crate::delivery::DeliveryState;
+use crate::events::ConversationEvent;
+use crate::message::DecryptedMessage;
+use crate::settings::{LabelSetting, MuteSetting};
+use crate::trust::TrustLevel;
+
+/// Schema migrations, applied in order and tracked with `PRAGMA user_version`
+const MIGRATIONS: &[&str] = &[
+    // 1: decrypted messages with local flags
+    "CREATE TABLE messages (
+        peer TEXT NOT NULL,
+        id TEXT NOT NULL,
+        sender TEXT NOT NULL,
+        content TEXT NOT NULL,
+        timestamp INTEGER NOT NULL,
+        verified INTEGER NOT NULL,
+        starred INTEGER NOT NULL DEFAULT 0,
+        PRIMARY KEY (peer, id)
+    );
+    CREATE INDEX messages_peer_timestamp ON messages (peer, timestamp);",
+    // 2: full-text index over message content, kept in sync by triggers
+    "CREATE VIRTUAL TABLE messages_fts USING fts5(
+        content,
+        content = 'messages',
+        content_rowid = 'rowid'
+    );
+    CREATE TRIGGER messages_fts_insert AFTER INSERT ON messages BEGIN
+        INSERT INTO messages_fts (rowid, content) VALUES (new.rowid, new.content);
+    END;
+    CREATE TRIGGER messages_fts_delete AFTER DELETE ON messages BEGIN
+        INSERT INTO messages_fts (messages_fts, rowid, content)
+            VALUES ('delete', old.rowid, old.content);
+    END;
+    CREATE TRIGGER messages_fts_update AFTER UPDATE OF content ON messages BEGIN
+        INSERT INTO messages_fts (messages_fts, rowid, content)
+            VALUES ('delete', old.rowid, old.content);
+        INSERT INTO messages_fts (rowid, content) VALUES (new.rowid, new.content);
+    END;
+    INSERT INTO messages_fts (messages_fts) VALUES ('rebuild');",
+    // 3: identity pins of contacts, and warnings on messages received after a change
+    "CREATE TABLE contact_pins (
+        peer TEXT PRIMARY KEY,
+        homeserver TEXT NOT NULL,
+        pinned_at INTEGER NOT NULL,
+        changed_homeserver TEXT,
+        changed_at INTEGER
+    );
+    ALTER TABLE messages ADD COLUMN key_warning INTEGER NOT NULL DEFAULT 0;",
+    // 4: append-only audit log of client actions
+    "CREATE TABLE audit_log (
+        seq INTEGER PRIMARY KEY AUTOINCREMENT,
+        timestamp INTEGER NOT NULL,
+        action TEXT NOT NULL,
+        peer TEXT,
+        detail TEXT NOT NULL
+    );
+    CREATE TRIGGER audit_log_no_update BEFORE UPDATE ON audit_log BEGIN
+        SELECT RAISE(ABORT, 'audit log is append-only');
+    END;
+    CREATE TRIGGER audit_log_no_delete BEFORE DELETE ON audit_log BEGIN
+        SELECT RAISE(ABORT, 'audit log is append-only');
+    END;",
+    // 5: per-conversation sync state for delta fetches
+    "CREATE TABLE sync_state (
+        peer TEXT PRIMARY KEY,
+        cursor TEXT,
+        synced_at INTEGER NOT NULL
+    );
+    CREATE TABLE sync_entries (
+        peer TEXT NOT NULL,
+        url TEXT NOT NULL,
+        id TEXT NOT NULL,
+        record_hash TEXT NOT NULL,
+        PRIMARY KEY (peer, url)
+    );",
+    // 6: trust levels set explicitly per contact
+    "CREATE TABLE contact_trust (
+        peer TEXT PRIMARY KEY,
+        level TEXT NOT NULL,
+        updated_at INTEGER NOT NULL
+    );",
+    // 7: identities contacts rotated to
+    "CREATE TABLE key_rotations (
+        peer TEXT PRIMARY KEY,
+        new_pubky TEXT NOT NULL,
+        rotated_at INTEGER NOT NULL
+    );",
+    // 8: conversation settings changed through system messages
+    "CREATE TABLE conversation_settings (
+        peer TEXT NOT NULL,
+        key TEXT NOT NULL,
+        value TEXT NOT NULL,
+        updated_at INTEGER NOT NULL,
+        PRIMARY KEY (peer, key)
+    );",
+    // 9: conversations started by strangers, and what we decided about them
+    "CREATE TABLE message_requests (
+        peer TEXT PRIMARY KEY,
+        status TEXT NOT NULL,
+        first_seen INTEGER NOT NULL,
+        last_seen INTEGER NOT NULL
+    );",
+    // 10: delivery state of our outgoing messages, per recipient
+    "CREATE TABLE delivery_states (
+        peer TEXT NOT NULL,
+        message_id TEXT NOT NULL,
+        state TEXT NOT NULL,
+        sent_at INTEGER NOT NULL,
+        updated_at INTEGER NOT NULL,
+        PRIMARY KEY (peer, message_id)
+    );",
+    // 11: timeline events waiting to be acknowledged by the application
+    "CREATE TABLE event_queue (
+        seq INTEGER PRIMARY KEY AUTOINCREMENT,
+        peer TEXT NOT NULL,
+        event TEXT NOT NULL,
+        queued_at INTEGER NOT NULL
+    );",
+    // 12: edits folded into a message
+    "ALTER TABLE messages ADD COLUMN edit_version INTEGER NOT NULL DEFAULT 0;
+    ALTER TABLE messages ADD COLUMN edited_at INTEGER;",
+    // 13: sequence numbers given to our messages, and the highest seen per
+    // sender by sync
+    "CREATE TABLE sent_sequences (
+        peer TEXT NOT NULL,
+        message_id TEXT NOT NULL,
+        seq INTEGER NOT NULL,
+        PRIMARY KEY (peer, message_id)
+    );
+    CREATE TABLE seen_sequences (
+        peer TEXT NOT NULL,
+        sender TEXT NOT NULL,
+        seq INTEGER NOT NULL,
+        PRIMARY KEY (peer, sender)
+    );",
+    // 14: deleted messages whose remote deletion waits out the undo window
+    "CREATE TABLE trash (
+        peer TEXT NOT NULL,
+        message_id TEXT NOT NULL,
+        url TEXT NOT NULL,
+        message TEXT,
+        deleted_at INTEGER NOT NULL,
+        PRIMARY KEY (peer, message_id)
+    );",
+    // 15: contact book entries, with tags as a JSON array
+    "CREATE TABLE contacts (
+        peer TEXT PRIMARY KEY,
+        alias TEXT,
+        tags TEXT NOT NULL,
+        updated_at INTEGER NOT NULL
+    );",
+    // 16: mute rules, kept after unmuting so that devices can sync removals
+    "CREATE TABLE mute_rules (
+        key TEXT PRIMARY KEY,
+        rule TEXT NOT NULL,
+        muted INTEGER NOT NULL,
+        updated_at INTEGER NOT NULL
+    );",
+    // 17: the away message (a single row, NULL once cleared), and the away
+    // periods in which each sender was answered
+    "CREATE TABLE away_message (
+        id INTEGER PRIMARY KEY CHECK (id = 0),
+        message TEXT,
+        updated_at INTEGER NOT NULL
+    );
+    CREATE TABLE away_replies (
+        peer TEXT NOT NULL,
+        period_start INTEGER NOT NULL,
+        message_id TEXT NOT NULL,
+        PRIMARY KEY (peer, period_start)
+    );",
+    // 18: conversation labels as a JSON array, kept empty once all are
+    // removed so that devices can sync removals
+    "CREATE TABLE conversation_labels (
+        peer TEXT PRIMARY KEY,
+        labels TEXT NOT NULL,
+        updated_at INTEGER NOT NULL
+    );",
+];
+
+/// A message kept in the local store, along with the conversation it belongs to
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredMessage {
+    pub peer: String,
+    pub message: DecryptedMessage,
+}
+
+/// The identity of a contact pinned on first contact (trust on first use)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ContactPin {
+    pub peer: String,
+    /// Homeserver the contact's pkarr record pointed to when pinned
+    pub homeserver: String,
+    pub pinned_at: u64,
+    /// Homeserver seen since, if it differs and has not been acknowledged
+    pub changed_homeserver: Option<String>,
+    pub changed_at: Option<u64>,
+}
+
+/// A conversation started by someone we never wrote to, awaiting a decision
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MessageRequest {
+    pub peer: String,
+    /// Timestamp of the first message seen from the sender
+    pub first_seen: u64,
+    /// Timestamp of the latest message seen from the sender
+    pub last_seen: u64,
+}
+
+/// A timeline event kept in the local store until it is acknowledged
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QueuedEvent {
+    /// Position in the queue, increasing with every event
+    pub seq: u64,
+    pub peer: String,
+    pub event: ConversationEvent,
+    /// Unix time in seconds when the event was queued
+    pub queued_at: u64,
+}
+
+/// A deleted message held in the trash until its undo window passes
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrashedMessage {
+    pub peer: String,
+    pub message_id: String,
+    /// Unix time in seconds when the message was deleted
+    pub deleted_at: u64,
+    /// The stored copy of the message, if it was in the local store
+    pub message: Option<DecryptedMessage>,
+}
+
+/// What became of a message request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum RequestStatus {
+    Pending,
+    Accepted,
+    Declined,
+}
+
+impl RequestStatus {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Pending => "pending",
+            Self::Accepted => "accepted",
+            Self::Declined => "declined",
+        }
+    }
+
+    fn parse(status: &str) -> Option<Self> {
+        match status {
+            "pending" => Some(Self::Pending),
+            "accepted" => Some(Self::Accepted),
+            "declined" => Some(Self::Declined),
+            _ => None,
+        }
+    }
+}
+
+/// A homeserver record seen by the last sync of a conversation
+#[derive(Debug, Clone)]
+pub(crate) struct SyncEntry {
+    pub url: String,
+    pub id: String,
+    /// Hex-encoded blake3 hash of the stored record
+    pub record_hash: String,
+}
+
+/// Length of the Argon2 salt of an encrypted store
+const SALT_LENGTH: usize = 16;
+
+/// Local SQLite store for decrypted messages and client state
+///
+/// Nothing in the store is ever published; it only lives on this device. An
+/// encrypted store is keyed from a PIN that is independent of the identity
+/// key, and holds no open connection while locked.
+pub(crate) struct LocalStore {
+    path: PathBuf,
+    encrypted: bool,
+    conn: Mutex<Option<Connection>>,
+}
+
+/// Access to the connection of an unlocked store
+pub(crate) struct StoreConnection<'a>(MutexGuard<'a, Option<Connection>>);
+
+impl Deref for StoreConnection<'_> {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        self.0
+            .as_ref()
+            .expect("connection checked when guard was created")
+    }
+}
+
+impl DerefMut for StoreConnection<'_> {
+    fn deref_mut(&mut self) -> &mut Connection {
+        self.0
+            .as_mut()
+            .expect("connection checked when guard was created")
+    }
+}
+
+impl LocalStore {
+    /// Open (or create) an unencrypted store at the given path
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        if salt_path(&path).exists() {
+            return Err(anyhow!("Local store is encrypted and needs a PIN"));
+        }
+
+        let mut conn = Connection::open(&path)
+            .map_err(|e| anyhow!("Failed to open local store {}: {}", path.display(), e))?;
+
+        Self::migrate(&mut conn)?;
+
+        Ok(Self {
+            path,
+            encrypted: false,
+            conn: Mutex::new(Some(conn)),
+        })
+    }
+
+    /// Open (or create) a store encrypted under a key derived from `pin`
+    ///
+    /// The Argon2 salt lives in a `.salt` file next to the database.
+    pub fn open_encrypted(path: impl AsRef<Path>, pin: &str) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let salt_path = salt_path(&path);
+
+        if !salt_path.exists() {
+            if path.exists() {
+                return Err(anyhow!("Local store exists but is not encrypted"));
+            }
+
+            let mut salt = [0u8; SALT_LENGTH];
+            rand::thread_rng().fill_bytes(&mut salt);
+            std::fs::write(&salt_path, salt)?;
+        }
+
+        let store = Self {
+            path,
+            encrypted: true,
+            conn: Mutex::new(None),
+        };
+        store.unlock(pin)?;
+        Ok(store)
+    }
+
+    /// Unlock an encrypted store, failing if the PIN is wrong
+    pub fn unlock(&self, pin: &str) -> Result<()> {
+        if !self.encrypted {
+            return Err(anyhow!("Local store is not encrypted"));
+        }
+
+        let salt = std::fs::read(salt_path(&self.path))?;
+        let mut key = Zeroizing::new([0u8; 32]);
+        Argon2::default()
+            .hash_password_into(pin.as_bytes(), &salt, &mut key[..])
+            .map_err(|e| anyhow!("Failed to derive local store key: {}", e))?;
+
+        let mut conn = Connection::open(&self.path)
+            .map_err(|e| anyhow!("Failed to open local store {}: {}", self.path.display(), e))?;
+
+        // Pass the derived key as a raw key so SQLCipher skips its own KDF
+        let mut raw_key = format!("x'{}'", hex::encode(&key[..]));
+        let keyed = conn.pragma_update(None, "key", &raw_key);
+        raw_key.zeroize();
+        keyed?;
+
+        // Reading the schema fails if the key is wrong
+        conn.query_row("SELECT count(*) FROM sqlite_master", [], |_| Ok(()))
+            .map_err(|_| anyhow!("Incorrect PIN for local store"))?;
+
+        Self::migrate(&mut conn)?;
+
+        *self.lock_conn()? = Some(conn);
+        Ok(())
+    }
+
+    /// Lock an encrypted store, closing its connection
+    pub fn lock(&self) -> Result<()> {
+        if !self.encrypted {
+            return Err(anyhow!("Local store is not encrypted"));
+        }
+
+        *self.lock_conn()? = None;
+        Ok(())
+    }
+
+    /// Whether the store is currently locked
+    pub fn is_locked(&self) -> bool {
+        self.lock_conn().map(|conn| conn.is_none()).unwrap_or(true)
+    }
+
+    /// Close the store and delete its files from disk
+    pub fn destroy(self) -> Result<()> {
+        let conn = self
+            .conn
+            .into_inner()
+            .map_err(|_| anyhow!("Local store lock poisoned"))?;
+        if let Some(conn) = conn {
+            conn.close().map_err(|(_, e)| e)?;
+        }
+
+        std::fs::remove_file(&self.path)?;
+        if self.encrypted {
+            std::fs::remove_file(salt_path(&self.path))?;
+        }
+
+        // Remove journal files SQLite may have left next to the database
+        for suffix in ["-wal", "-shm", "-journal"] {
+            let mut sidecar = self.path.clone().into_os_string();
+            sidecar.push(suffix);
+            let _ = std::fs::remove_file(sidecar);
+        }
+
+        Ok(())
+    }
+
+    /// Apply any migrations the database has not seen yet
+    fn migrate(conn: &mut Connection) -> Result<()> {
+        let version: i64 = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+
+        for (i, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+            let tx = conn.transaction()?;
+            tx.execute_batch(migration)?;
+            tx.pragma_update(None, "user_version", (i + 1) as i64)?;
+            tx.commit()?;
+        }
+
+        Ok(())
+    }
+
+    fn lock_conn(&self) -> Result<MutexGuard<'_, Option<Connection>>> {
+        self.conn
+            .lock()
+            .map_err(|_| anyhow!("Local store lock poisoned"))
+    }
+
+    fn conn(&self) -> Result<StoreConnection<'_>> {
+        let guard = self.lock_conn()?;
+        if guard.is_none() {
+            return Err(anyhow!("Local store is locked"));
+        }
+        Ok(StoreConnection(guard))
+    }
+
+    /// Insert or refresh the messages of a conversation, keeping local flags
+    pub fn save_messages(&self, peer: &str, messages: &[DecryptedMessage]) -> Result<()> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+        {
+            let mut stmt = tx.prepare(
+                "INSERT INTO messages
+                    (peer, id, sender, content, timestamp, verified, key_warning, edit_version,
+                     edited_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)
+                 ON CONFLICT (peer, id) DO UPDATE SET
+                    sender = excluded.sender,
+                    content = excluded.content,
+                    timestamp = excluded.timestamp,
+                    verified = excluded.verified,
+                    key_warning = max(key_warning, excluded.key_warning),
+                    edit_version = excluded.edit_version,
+                    edited_at = excluded.edited_at",
+            )?;
+            for message in messages {
+                stmt.execute(params![
+                    peer,
+                    message.id,
+                    message.sender,
+                    message.content,
+                    message.timestamp as i64,
+                    message.verified,
+                    message.key_warning,
+                    message.edit_version,
+                    message.edited_at.map(|edited_at| edited_at as i64),
+                ])?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// The stored copy of a message of a conversation
+    pub fn stored_message(&self, peer: &str, id: &str) -> Result<Option<DecryptedMessage>> {
+        let conn = self.conn()?;
+        let message = conn
+            .query_row(
+                "SELECT peer, id, sender, content, timestamp, verified, key_warning, edit_version,
+                    edited_at
+                 FROM messages WHERE peer = ?1 AND id = ?2",
+                params![peer, id],
+                Self::stored_message_from_row,
+            )
+            .optional()?;
+        Ok(message.map(|stored| stored.message))
+    }
+
+    /// Remove messages of a conversation, e.g. after they were deleted remotely
+    pub fn delete_messages(&self, peer: &str, ids: &[String]) -> Result<()> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+        {
+            let mut stmt = tx.prepare("DELETE FROM messages WHERE peer = ?1 AND id = ?2")?;
+            for id in ids {
+                stmt.execute(params![peer, id])?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Move messages of a conversation to the trash, given as `(id, url)`
+    ///
+    /// Stored copies move along and leave the messages table. A message that
+    /// is already in the trash keeps its original deletion time.
+    pub fn trash_messages(&self, peer: &str, entries: &[(String, String)], now: u64) -> Result<()> {
+        let mut stored = Vec::with_capacity(entries.len());
+        for (id, _) in entries {
+            let message = self.stored_message(peer, id)?;
+            stored.push(message.map(|m| serde_json::to_string(&m)).transpose()?);
+        }
+
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+        {
+            let mut insert = tx.prepare(
+                "INSERT INTO trash (peer, message_id, url, message, deleted_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5)
+                 ON CONFLICT (peer, message_id) DO NOTHING",
+            )?;
+            let mut remove = tx.prepare("DELETE FROM messages WHERE peer = ?1 AND id = ?2")?;
+            for ((id, url), message) in entries.iter().zip(stored) {
+                insert.execute(params![peer, id, url, message, now as i64])?;
+                remove.execute(params![peer, id])?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Every message in the trash, oldest deletion first
+    pub fn trashed_messages(&self) -> Result<Vec<TrashedMessage>> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare(
+            "SELECT peer, message_id, message, deleted_at FROM trash
+             ORDER BY deleted_at, peer, message_id",
+        )?;
+        let rows = stmt.query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, Option<String>>(2)?,
+                row.get::<_, i64>(3)? as u64,
+            ))
+        })?;
+
+        let mut trashed = Vec::new();
+        for row in rows {
+            let (peer, message_id, message, deleted_at) = row?;
+            trashed.push(TrashedMessage {
+                peer,
+                message_id,
+                deleted_at,
+                message: message.map(|m| serde_json::from_str(&m)).transpose()?,
+            });
+        }
+        Ok(trashed)
+    }
+
+    /// IDs of the trashed messages of a conversation
+    pub fn trashed_ids(&self, peer: &str) -> Result<HashSet<String>> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare("SELECT message_id FROM trash WHERE peer = ?1")?;
+        let ids = stmt
+            .query_map(params![peer], |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(ids)
+    }
+
+    /// Trashed messages deleted at or before `deleted_by`, as
+    /// `(peer, id, url)`
+    pub fn expired_trash(&self, deleted_by: u64) -> Result<Vec<(String, String, String)>> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare(
+            "SELECT peer, message_id, url FROM trash WHERE deleted_at <= ?1 ORDER BY deleted_at",
+        )?;
+        let entries = stmt
+            .query_map(params![deleted_by as i64], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(entries)
+    }
+
+    /// Take a message of a conversation out of the trash, returning its URL
+    /// and stored copy, and putting the stored copy back
+    pub fn restore_trashed(
+        &self,
+        peer: &str,
+        message_id: &str,
+    ) -> Result<Option<(String, Option<DecryptedMessage>)>> {
+        let entry = self
+            .conn()?
+            .query_row(
+                "SELECT url, message FROM trash WHERE peer = ?1 AND message_id = ?2",
+                params![peer, message_id],
+                |row| Ok((row.get::<_, String>(0)?, row.get::<_, Option<String>>(1)?)),
+            )
+            .optional()?;
+        let Some((url, message)) = entry else {
+            return Ok(None);
+        };
+
+        let message: Option<DecryptedMessage> =
+            message.map(|m| serde_json::from_str(&m)).transpose()?;
+        if let Some(message) = message.as_ref() {
+            self.save_messages(peer, std::slice::from_ref(message))?;
+        }
+        self.remove_trashed(peer, &[message_id.to_string()])?;
+        Ok(Some((url, message)))
+    }
+
+    /// Drop messages of a conversation from the trash
+    pub fn remove_trashed(&self, peer: &str, ids: &[String]) -> Result<()> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+        {
+            let mut stmt = tx.prepare("DELETE FROM trash WHERE peer = ?1 AND message_id = ?2")?;
+            for id in ids {
+                stmt.execute(params![peer, id])?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Set the starred flag of a message, returning whether it was found
+    pub fn set_starred(&self, id: &str, starred: bool) -> Result<bool> {
+        let updated = self.conn()?.execute(
+            "UPDATE messages SET starred = ?1 WHERE id = ?2",
+            params![starred, id],
+        )?;
+        Ok(updated > 0)
+    }
+
+    /// All starred messages across conversations, oldest first
+    pub fn starred_messages(&self) -> Result<Vec<StoredMessage>> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare(
+            "SELECT peer, id, sender, content, timestamp, verified, key_warning, edit_version,
+                edited_at
+             FROM messages WHERE starred = 1 ORDER BY timestamp",
+        )?;
+        let rows = stmt.query_map([], Self::stored_message_from_row)?;
+        Ok(rows.collect::<rusqlite::Result<Vec<_>>>()?)
+    }
+
+    /// Full-text search over stored message content, best matches first
+    ///
+    /// Every whitespace-separated term of the query must match, either fully
+    /// or as a word prefix.
+    pub fn search_messages(&self, query: &str, limit: usize) -> Result<Vec<StoredMessage>> {
+        let Some(fts_query) = fts_query(query) else {
+            return Ok(Vec::new());
+        };
+
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare(
+            "SELECT m.peer, m.id, m.sender, m.content, m.timestamp, m.verified, m.key_warning,
+                m.edit_version, m.edited_at
+             FROM messages_fts JOIN messages m ON m.rowid = messages_fts.rowid
+             WHERE messages_fts MATCH ?1 ORDER BY messages_fts.rank LIMIT ?2",
+        )?;
+        let rows = stmt.query_map(
+            params![fts_query, limit as i64],
+            Self::stored_message_from_row,
+        )?;
+        Ok(rows.collect::<rusqlite::Result<Vec<_>>>()?)
+    }
+
+    /// The key warning flag of every stored message of a conversation, by ID
+    pub fn key_warnings(&self, peer: &str) -> Result<HashMap<String, bool>> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare("SELECT id, key_warning FROM messages WHERE peer = ?1")?;
+        let rows = stmt.query_map(params![peer], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        Ok(rows.collect::<rusqlite::Result<HashMap<_, _>>>()?)
+    }
+
+    /// The pinned identity of a contact, if any
+    pub fn contact_pin(&self, peer: &str) -> Result<Option<ContactPin>> {
+        let conn = self.conn()?;
+        let pin = conn
+            .query_row(
+                "SELECT peer, homeserver, pinned_at, changed_homeserver, changed_at
+                 FROM contact_pins WHERE peer = ?1",
+                params![peer],
+                |row| {
+                    Ok(ContactPin {
+                        peer: row.get(0)?,
+                        homeserver: row.get(1)?,
+                        pinned_at: row.get::<_, i64>(2)? as u64,
+                        changed_homeserver: row.get(3)?,
+                        changed_at: row.get::<_, Option<i64>>(4)?.map(|t| t as u64),
+                    })
+                },
+            )
+            .optional()?;
+        Ok(pin)
+    }
+
+    /// Pin a contact's homeserver on first contact
+    pub fn pin_contact(&self, peer: &str, homeserver: &str, now: u64) -> Result<()> {
+        self.conn()?.execute(
+            "INSERT INTO contact_pins (peer, homeserver, pinned_at) VALUES (?1, ?2, ?3)
+             ON CONFLICT (peer) DO NOTHING",
+            params![peer, homeserver, now as i64],
+        )?;
+        Ok(())
+    }
+
+    /// Record an unacknowledged change of a contact's homeserver
+    pub fn record_key_change(&self, peer: &str, homeserver: &str, now: u64) -> Result<()> {
+        self.conn()?.execute(
+            "UPDATE contact_pins SET changed_homeserver = ?2, changed_at = ?3 WHERE peer = ?1",
+            params![peer, homeserver, now as i64],
+        )?;
+        Ok(())
+    }
+
+    /// Accept a contact's changed homeserver as the new pin, returning whether
+    /// there was a change to acknowledge
+    pub fn acknowledge_key_change(&self, peer: &str, now: u64) -> Result<bool> {
+        let updated = self.conn()?.execute(
+            "UPDATE contact_pins SET
+                homeserver = changed_homeserver,
+                pinned_at = ?2,
+                changed_homeserver = NULL,
+                changed_at = NULL
+             WHERE peer = ?1 AND changed_homeserver IS NOT NULL",
+            params![peer, now as i64],
+        )?;
+        Ok(updated > 0)
+    }
+
+    /// The trust level set explicitly for a contact, if any
+    pub fn explicit_trust(&self, peer: &str) -> Result<Option<TrustLevel>> {
+        let conn = self.conn()?;
+        let level: Option<String> = conn
+            .query_row(
+                "SELECT level FROM contact_trust WHERE peer = ?1",
+                params![peer],
+                |row| row.get(0),
+            )
+            .optional()?;
+        level
+            .map(|level| {
+                TrustLevel::parse(&level).ok_or_else(|| anyhow!("Unknown trust level: {}", level))
+            })
+            .transpose()
+    }
+
+    /// Set the trust level of a contact
+    pub fn set_trust(&self, peer: &str, level: TrustLevel, now: u64) -> Result<()> {
+        self.conn()?.execute(
+            "INSERT INTO contact_trust (peer, level, updated_at) VALUES (?1, ?2, ?3)
+             ON CONFLICT (peer) DO UPDATE SET
+                level = excluded.level,
+                updated_at = excluded.updated_at",
+            params![peer, level.as_str(), now as i64],
+        )?;
+        Ok(())
+    }
+
+    /// Every explicit trust level, with the contact and when it was set
+    pub fn trust_entries(&self) -> Result<Vec<(String, TrustLevel, u64)>> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare("SELECT peer, level, updated_at FROM contact_trust")?;
+        let rows = stmt.query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, i64>(2)? as u64,
+            ))
+        })?;
+
+        let mut entries = Vec::new();
+        for row in rows {
+            let (peer, level, updated_at) = row?;
+            let level = TrustLevel::parse(&level)
+                .ok_or_else(|| anyhow!("Unknown trust level: {}", level))?;
+            entries.push((peer, level, updated_at));
+        }
+        Ok(entries)
+    }
+
+    /// Set the alias and tags of a contact, replacing earlier ones
+    pub fn set_contact(
+        &self,
+        peer: &str,
+        alias: Option<&str>,
+        tags: &[String],
+        now: u64,
+    ) -> Result<()> {
+        self.conn()?.execute(
+            "INSERT INTO contacts (peer, alias, tags, updated_at) VALUES (?1, ?2, ?3, ?4)
+             ON CONFLICT (peer) DO UPDATE SET
+                alias = excluded.alias,
+                tags = excluded.tags,
+                updated_at = excluded.updated_at",
+            params![peer, alias, serde_json::to_string(tags)?, now as i64],
+        )?;
+        Ok(())
+    }
+
+    /// Every contact book entry, with its alias and tags, by contact
+    pub fn contacts(&self) -> Result<Vec<(String, Option<String>, Vec<String>)>> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare("SELECT peer, alias, tags FROM contacts ORDER BY peer")?;
+        let rows = stmt.query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, Option<String>>(1)?,
+                row.get::<_, String>(2)?,
+            ))
+        })?;
+
+        let mut contacts = Vec::new();
+        for row in rows {
+            let (peer, alias, tags) = row?;
+            contacts.push((peer, alias, serde_json::from_str(&tags)?));
+        }
+        Ok(contacts)
+    }
+
+    /// Set the trust level of a contact unless a later change is already
+    /// stored, returning whether it was applied
+    pub fn merge_trust(&self, peer: &str, level: TrustLevel, updated_at: u64) -> Result<bool> {
+        let changed = self.conn()?.execute(
+            "INSERT INTO contact_trust (peer, level, updated_at) VALUES (?1, ?2, ?3)
+             ON CONFLICT (peer) DO UPDATE SET
+                level = excluded.level,
+                updated_at = excluded.updated_at
+             WHERE excluded.updated_at > contact_trust.updated_at
+                OR (excluded.updated_at = contact_trust.updated_at
+                    AND excluded.level > contact_trust.level)",
+            params![peer, level.as_str(), updated_at as i64],
+        )?;
+        Ok(changed > 0)
+    }
+
+    /// Every mute rule, muted or not, by rule key
+    pub fn mute_settings(&self) -> Result<BTreeMap<String, MuteSetting>> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare("SELECT key, rule, muted, updated_at FROM mute_rules")?;
+        let rows = stmt.query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, bool>(2)?,
+                row.get::<_, i64>(3)? as u64,
+            ))
+        })?;
+
+        let mut settings = BTreeMap::new();
+        for row in rows {
+            let (key, rule, muted, updated_at) = row?;
+            let setting = MuteSetting {
+                rule: serde_json::from_str(&rule)?,
+                muted,
+                updated_at,
+            };
+            settings.insert(key, setting);
+        }
+        Ok(settings)
+    }
+
+    /// Store a mute rule change made on this device
+    pub fn set_mute_setting(&self, key: &str, setting: &MuteSetting) -> Result<()> {
+        self.conn()?.execute(
+            "INSERT INTO mute_rules (key, rule, muted, updated_at) VALUES (?1, ?2, ?3, ?4)
+             ON CONFLICT (key) DO UPDATE SET
+                rule = excluded.rule,
+                muted = excluded.muted,
+                updated_at = excluded.updated_at",
+            params![
+                key,
+                serde_json::to_string(&setting.rule)?,
+                setting.muted,
+                setting.updated_at as i64
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Store a mute rule change unless a later change is already stored,
+    /// returning whether it was applied
+    pub fn merge_mute_setting(&self, key: &str, setting: &MuteSetting) -> Result<bool> {
+        let changed = self.conn()?.execute(
+            "INSERT INTO mute_rules (key, rule, muted, updated_at) VALUES (?1, ?2, ?3, ?4)
+             ON CONFLICT (key) DO UPDATE SET
+                rule = excluded.rule,
+                muted = excluded.muted,
+                updated_at = excluded.updated_at
+             WHERE excluded.updated_at > mute_rules.updated_at
+                OR (excluded.updated_at = mute_rules.updated_at
+                    AND excluded.muted > mute_rules.muted)",
+            params![
+                key,
+                serde_json::to_string(&setting.rule)?,
+                setting.muted,
+                setting.updated_at as i64
+            ],
+        )?;
+        Ok(changed > 0)
+    }
+
+    /// The labels of every conversation that ever had any, by peer
+    pub fn label_settings(&self) -> Result<BTreeMap<String, LabelSetting>> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare("SELECT peer, labels, updated_at FROM conversation_labels")?;
+        let rows = stmt.query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, i64>(2)? as u64,
+            ))
+        })?;
+
+        let mut settings = BTreeMap::new();
+        for row in rows {
+            let (peer, labels, updated_at) = row?;
+            let setting = LabelSetting {
+                labels: serde_json::from_str(&labels)?,
+                updated_at,
+            };
+            settings.insert(peer, setting);
+        }
+        Ok(settings)
+    }
+
+    /// The labels of a conversation, if it ever had any
+    pub fn label_setting(&self, peer: &str) -> Result<Option<LabelSetting>> {
+        Self::read_label_setting(&self.conn()?, peer)
+    }
+
+    /// Store a label change made on this device
+    pub fn set_label_setting(&self, peer: &str, setting: &LabelSetting) -> Result<()> {
+        Self::write_label_setting(&self.conn()?, peer, setting)
+    }
+
+    /// Store a label change unless a later change is already stored,
+    /// returning whether it was applied
+    pub fn merge_label_setting(&self, peer: &str, setting: &LabelSetting) -> Result<bool> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+        if let Some(stored) = Self::read_label_setting(&tx, peer)? {
+            if !setting.supersedes(&stored) {
+                return Ok(false);
+            }
+        }
+        Self::write_label_setting(&tx, peer, setting)?;
+        tx.commit()?;
+        Ok(true)
+    }
+
+    fn read_label_setting(conn: &Connection, peer: &str) -> Result<Option<LabelSetting>> {
+        let row: Option<(String, i64)> = conn
+            .query_row(
+                "SELECT labels, updated_at FROM conversation_labels WHERE peer = ?1",
+                params![peer],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+        let Some((labels, updated_at)) = row else {
+            return Ok(None);
+        };
+        Ok(Some(LabelSetting {
+            labels: serde_json::from_str(&labels)?,
+            updated_at: updated_at as u64,
+        }))
+    }
+
+    fn write_label_setting(conn: &Connection, peer: &str, setting: &LabelSetting) -> Result<()> {
+        conn.execute(
+            "INSERT INTO conversation_labels (peer, labels, updated_at) VALUES (?1, ?2, ?3)
+             ON CONFLICT (peer) DO UPDATE SET
+                labels = excluded.labels,
+                updated_at = excluded.updated_at",
+            params![
+                peer,
+                serde_json::to_string(&setting.labels)?,
+                setting.updated_at as i64
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// The away message setting, if one was ever made
+    pub fn away_setting(&self) -> Result<Option<AwaySetting>> {
+        Self::read_away_setting(&self.conn()?)
+    }
+
+    /// Store an away message change made on this device
+    pub fn set_away_setting(&self, setting: &AwaySetting) -> Result<()> {
+        Self::write_away_setting(&self.conn()?, setting)
+    }
+
+    /// Store an away message change unless a later change is already
+    /// stored, returning whether it was applied
+    pub fn merge_away_setting(&self, setting: &AwaySetting) -> Result<bool> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+        if let Some(stored) = Self::read_away_setting(&tx)? {
+            if !setting.supersedes(&stored) {
+                return Ok(false);
+            }
+        }
+        Self::write_away_setting(&tx, setting)?;
+        tx.commit()?;
+        Ok(true)
+    }
+
+    fn read_away_setting(conn: &Connection) -> Result<Option<AwaySetting>> {
+        let row: Option<(Option<String>, i64)> = conn
+            .query_row(
+                "SELECT message, updated_at FROM away_message WHERE id = 0",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+        let Some((message, updated_at)) = row else {
+            return Ok(None);
+        };
+        Ok(Some(AwaySetting {
+            message: message
+                .map(|message| serde_json::from_str(&message))
+                .transpose()?,
+            updated_at: updated_at as u64,
+        }))
+    }
+
+    fn write_away_setting(conn: &Connection, setting: &AwaySetting) -> Result<()> {
+        let message = setting
+            .message
+            .as_ref()
+            .map(serde_json::to_string)
+            .transpose()?;
+        conn.execute(
+            "INSERT INTO away_message (id, message, updated_at) VALUES (0, ?1, ?2)
+             ON CONFLICT (id) DO UPDATE SET
+                message = excluded.message,
+                updated_at = excluded.updated_at",
+            params![message, setting.updated_at as i64],
+        )?;
+        Ok(())
+    }
+
+    /// ID of our automatic reply to a conversation in an away period, if
+    /// it was answered
+    pub fn away_reply(&self, peer: &str, period_start: u64) -> Result<Option<String>> {
+        let conn = self.conn()?;
+        let id = conn
+            .query_row(
+                "SELECT message_id FROM away_replies WHERE peer = ?1 AND period_start = ?2",
+                params![peer, period_start as i64],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(id)
+    }
+
+    /// Record that a conversation was answered in an away period
+    pub fn record_away_reply(&self, peer: &str, period_start: u64, message_id: &str) -> Result<()> {
+        self.conn()?.execute(
+            "INSERT OR IGNORE INTO away_replies (peer, period_start, message_id)
+             VALUES (?1, ?2, ?3)",
+            params![peer, period_start as i64, message_id],
+        )?;
+        Ok(())
+    }
+
+    /// The settings of a conversation, by key
+    pub fn conversation_settings(&self, peer: &str) -> Result<BTreeMap<String, serde_json::Value>> {
+        let conn = self.conn()?;
+        let mut stmt =
+            conn.prepare("SELECT key, value FROM conversation_settings WHERE peer = ?1")?;
+        let rows = stmt.query_map(params![peer], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+        })?;
+
+        let mut settings = BTreeMap::new();
+        for row in rows {
+            let (key, value) = row?;
+            settings.insert(key, serde_json::from_str(&value)?);
+        }
+        Ok(settings)
+    }
+
+    /// Change a conversation setting unless a later change is already stored,
+    /// returning whether it was applied
+    pub fn set_conversation_setting(
+        &self,
+        peer: &str,
+        key: &str,
+        value: &serde_json::Value,
+        updated_at: u64,
+    ) -> Result<bool> {
+        let changed = self.conn()?.execute(
+            "INSERT INTO conversation_settings (peer, key, value, updated_at)
+             VALUES (?1, ?2, ?3, ?4)
+             ON CONFLICT (peer, key) DO UPDATE SET
+                value = excluded.value,
+                updated_at = excluded.updated_at
+             WHERE excluded.updated_at >= conversation_settings.updated_at",
+            params![peer, key, value.to_string(), updated_at as i64],
+        )?;
+        Ok(changed > 0)
+    }
+
+    /// IDs and timestamps of the stored messages `sender` wrote in a
+    /// conversation since `since`
+    pub fn message_times_since(
+        &self,
+        peer: &str,
+        sender: &str,
+        since: u64,
+    ) -> Result<Vec<(String, u64)>> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare(
+            "SELECT id, timestamp FROM messages
+             WHERE peer = ?1 AND sender = ?2 AND timestamp >= ?3",
+        )?;
+        let rows = stmt.query_map(params![peer, sender, since as i64], |row| {
+            Ok((row.get(0)?, row.get::<_, i64>(1)? as u64))
+        })?;
+
+        let mut times = Vec::new();
+        for row in rows {
+            times.push(row?);
+        }
+        Ok(times)
+    }
+
+    /// Peers of every conversation with stored messages
+    pub fn conversation_peers(&self) -> Result<Vec<String>> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare("SELECT DISTINCT peer FROM messages")?;
+        let rows = stmt.query_map([], |row| row.get(0))?;
+
+        let mut peers = Vec::new();
+        for row in rows {
+            peers.push(row?);
+        }
+        Ok(peers)
+    }
+
+    /// Record the delivery state of one of our messages to a recipient,
+    /// returning whether it changed
+    ///
+    /// States only move forward: a late `Uploading` never replaces `Stored`
+    /// or `Delivered`. `Failed` only replaces `Queued` and `Uploading`, and
+    /// anything replaces `Failed`, since the message can be sent again.
+    pub(crate) fn set_delivery_state(
+        &self,
+        peer: &str,
+        message_id: &str,
+        state: DeliveryState,
+        sent_at: u64,
+        now: u64,
+    ) -> Result<bool> {
+        let changed = self.conn()?.execute(
+            "INSERT INTO delivery_states (peer, message_id, state, sent_at, updated_at)
+             VALUES (?1, ?2, ?3, ?4, ?5)
+             ON CONFLICT (peer, message_id) DO UPDATE SET
+                state = excluded.state,
+                updated_at = excluded.updated_at
+             WHERE delivery_states.state = 'failed'
+                OR (excluded.state = 'failed'
+                    AND delivery_states.state IN ('queued', 'uploading'))
+                OR (excluded.state != 'failed'
+                    AND (CASE excluded.state
+                        WHEN 'queued' THEN 0
+                        WHEN 'uploading' THEN 1
+                        WHEN 'stored' THEN 2
+                        ELSE 3
+                    END) > (CASE delivery_states.state
+                        WHEN 'queued' THEN 0
+                        WHEN 'uploading' THEN 1
+                        WHEN 'stored' THEN 2
+                        ELSE 3
+                    END))",
+            params![peer, message_id, state.as_str(), sent_at as i64, now as i64],
+        )?;
+        Ok(changed > 0)
+    }
+
+    /// Mark our stored messages to a recipient sent up to `fetched_up_to` as
+    /// delivered, returning their IDs
+    pub(crate) fn mark_delivered(
+        &self,
+        peer: &str,
+        fetched_up_to: u64,
+        now: u64,
+    ) -> Result<Vec<String>> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+        let mut message_ids = Vec::new();
+        {
+            let mut stmt = tx.prepare(
+                "SELECT message_id FROM delivery_states
+                 WHERE peer = ?1 AND state = 'stored' AND sent_at <= ?2",
+            )?;
+            let rows = stmt.query_map(params![peer, fetched_up_to as i64], |row| row.get(0))?;
+            for row in rows {
+                message_ids.push(row?);
+            }
+        }
+        tx.execute(
+            "UPDATE delivery_states SET state = 'delivered', updated_at = ?3
+             WHERE peer = ?1 AND state = 'stored' AND sent_at <= ?2",
+            params![peer, fetched_up_to as i64, now as i64],
+        )?;
+        tx.commit()?;
+        Ok(message_ids)
+    }
+
+    /// Delivery states of one of our messages, one per recipient
+    pub(crate) fn delivery_states(&self, message_id: &str) -> Result<Vec<DeliveryState>> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare("SELECT state FROM delivery_states WHERE message_id = ?1")?;
+        let rows = stmt.query_map(params![message_id], |row| row.get::<_, String>(0))?;
+
+        let mut states = Vec::new();
+        for row in rows {
+            let state = row?;
+            states.push(
+                DeliveryState::parse(&state)
+                    .ok_or_else(|| anyhow!("Unknown delivery state: {}", state))?,
+            );
+        }
+        Ok(states)
+    }
+
+    /// Whether `sender` wrote any stored message of a conversation
+    pub fn has_messages_from(&self, peer: &str, sender: &str) -> Result<bool> {
+        let conn = self.conn()?;
+        let exists = conn.query_row(
+            "SELECT EXISTS (SELECT 1 FROM messages WHERE peer = ?1 AND sender = ?2)",
+            params![peer, sender],
+            |row| row.get(0),
+        )?;
+        Ok(exists)
+    }
+
+    /// The status of a sender's message request, if they ever sent one
+    pub(crate) fn request_status(&self, peer: &str) -> Result<Option<RequestStatus>> {
+        let conn = self.conn()?;
+        let status: Option<String> = conn
+            .query_row(
+                "SELECT status FROM message_requests WHERE peer = ?1",
+                params![peer],
+                |row| row.get(0),
+            )
+            .optional()?;
+        status
+            .map(|status| {
+                RequestStatus::parse(&status)
+                    .ok_or_else(|| anyhow!("Unknown message request status: {}", status))
+            })
+            .transpose()
+    }
+
+    /// Record messages seen from a stranger, opening a pending request the
+    /// first time
+    pub fn record_message_request(
+        &self,
+        peer: &str,
+        first_seen: u64,
+        last_seen: u64,
+    ) -> Result<()> {
+        self.conn()?.execute(
+            "INSERT INTO message_requests (peer, status, first_seen, last_seen)
+             VALUES (?1, 'pending', ?2, ?3)
+             ON CONFLICT (peer) DO UPDATE SET
+                first_seen = min(first_seen, excluded.first_seen),
+                last_seen = max(last_seen, excluded.last_seen)",
+            params![peer, first_seen as i64, last_seen as i64],
+        )?;
+        Ok(())
+    }
+
+    /// Accept or decline a sender's messages
+    pub(crate) fn set_request_status(
+        &self,
+        peer: &str,
+        status: RequestStatus,
+        now: u64,
+    ) -> Result<()> {
+        self.conn()?.execute(
+            "INSERT INTO message_requests (peer, status, first_seen, last_seen)
+             VALUES (?1, ?2, ?3, ?3)
+             ON CONFLICT (peer) DO UPDATE SET status = excluded.status",
+            params![peer, status.as_str(), now as i64],
+        )?;
+        Ok(())
+    }
+
+    /// Pending message requests, most recently active first
+    pub fn pending_message_requests(&self) -> Result<Vec<MessageRequest>> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare(
+            "SELECT peer, first_seen, last_seen FROM message_requests
+             WHERE status = 'pending'
+             ORDER BY last_seen DESC",
+        )?;
+        let rows = stmt.query_map([], |row| {
+            Ok(MessageRequest {
+                peer: row.get(0)?,
+                first_seen: row.get::<_, i64>(1)? as u64,
+                last_seen: row.get::<_, i64>(2)? as u64,
+            })
+        })?;
+
+        let mut requests = Vec::new();
+        for row in rows {
+            requests.push(row?);
+        }
+        Ok(requests)
+    }
+
+    /// Append timeline events of a conversation to the event queue
+    pub(crate) fn enqueue_events(
+        &self,
+        peer: &str,
+        events: &[ConversationEvent],
+        now: u64,
+    ) -> Result<()> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+        for event in events {
+            tx.execute(
+                "INSERT INTO event_queue (peer, event, queued_at) VALUES (?1, ?2, ?3)",
+                params![peer, serde_json::to_string(event)?, now as i64],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Up to `limit` unacknowledged events, oldest first
+    pub fn pending_events(&self, limit: usize) -> Result<Vec<QueuedEvent>> {
+        let conn = self.conn()?;
+        let mut stmt = conn
+            .prepare("SELECT seq, peer, event, queued_at FROM event_queue ORDER BY seq LIMIT ?1")?;
+        let rows = stmt.query_map(params![limit as i64], |row| {
+            Ok((
+                row.get::<_, i64>(0)? as u64,
+                row.get::<_, String>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, i64>(3)? as u64,
+            ))
+        })?;
+
+        let mut events = Vec::new();
+        for row in rows {
+            let (seq, peer, event, queued_at) = row?;
+            events.push(QueuedEvent {
+                seq,
+                peer,
+                event: serde_json::from_str(&event)?,
+                queued_at,
+            });
+        }
+        Ok(events)
+    }
+
+    /// Number of unacknowledged events
+    pub fn pending_event_count(&self) -> Result<u64> {
+        let count = self
+            .conn()?
+            .query_row("SELECT COUNT(*) FROM event_queue", [], |row| {
+                row.get::<_, i64>(0)
+            })?;
+        Ok(count as u64)
+    }
+
+    /// Drop queued events up to and including `seq`, returning how many
+    /// were dropped
+    pub fn ack_events(&self, seq: u64) -> Result<usize> {
+        let removed = self.conn()?.execute(
+            "DELETE FROM event_queue WHERE seq <= ?1",
+            params![i64::try_from(seq).unwrap_or(i64::MAX)],
+        )?;
+        Ok(removed)
+    }
+
+    /// The identity a contact rotated to, if any
+    pub fn key_successor(&self, peer: &str) -> Result<Option<String>> {
+        let conn = self.conn()?;
+        let successor = conn
+            .query_row(
+                "SELECT new_pubky FROM key_rotations WHERE peer = ?1",
+                params![peer],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(successor)
+    }
+
+    /// Remember that a contact rotated to a new identity, returning whether
+    /// the rotation is newer than any already recorded
+    pub fn record_key_rotation(
+        &self,
+        peer: &str,
+        new_pubky: &str,
+        rotated_at: u64,
+    ) -> Result<bool> {
+        let changed = self.conn()?.execute(
+            "INSERT INTO key_rotations (peer, new_pubky, rotated_at) VALUES (?1, ?2, ?3)
+             ON CONFLICT (peer) DO UPDATE SET
+                new_pubky = excluded.new_pubky,
+                rotated_at = excluded.rotated_at
+             WHERE excluded.rotated_at > key_rotations.rotated_at",
+            params![peer, new_pubky, rotated_at as i64],
+        )?;
+        Ok(changed > 0)
+    }
+
+    /// The cursor and time of the last sync of a conversation, if it was ever
+    /// synced
+    pub fn sync_state(&self, peer: &str) -> Result<Option<(Option<String>, u64)>> {
+        let conn = self.conn()?;
+        let state = conn
+            .query_row(
+                "SELECT cursor, synced_at FROM sync_state WHERE peer = ?1",
+                params![peer],
+                |row| Ok((row.get(0)?, row.get::<_, i64>(1)? as u64)),
+            )
+            .optional()?;
+        Ok(state)
+    }
+
+    /// Time of the least recent sync among synced conversations, if any
+    pub fn oldest_sync(&self) -> Result<Option<u64>> {
+        let oldest =
+            self.conn()?
+                .query_row("SELECT MIN(synced_at) FROM sync_state", [], |row| {
+                    row.get::<_, Option<i64>>(0)
+                })?;
+        Ok(oldest.map(|at| at as u64))
+    }
+
+    /// Forget that the last sync saw some records, so the next sync reports
+    /// them as new
+    pub fn forget_sync_entries(&self, peer: &str, urls: &[String]) -> Result<()> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+        {
+            let mut stmt = tx.prepare("DELETE FROM sync_entries WHERE peer = ?1 AND url = ?2")?;
+            for url in urls {
+                stmt.execute(params![peer, url])?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// The records seen by the last sync of a conversation, by URL
+    pub fn sync_entries(&self, peer: &str) -> Result<HashMap<String, SyncEntry>> {
+        let conn = self.conn()?;
+        let mut stmt =
+            conn.prepare("SELECT url, id, record_hash FROM sync_entries WHERE peer = ?1")?;
+        let rows = stmt.query_map(params![peer], |row| {
+            Ok(SyncEntry {
+                url: row.get(0)?,
+                id: row.get(1)?,
+                record_hash: row.get(2)?,
+            })
+        })?;
+
+        let mut entries = HashMap::new();
+        for row in rows {
+            let entry = row?;
+            entries.insert(entry.url.clone(), entry);
+        }
+        Ok(entries)
+    }
+
+    /// Record the outcome of a sync: records seen or changed, records gone,
+    /// and the new cursor
+    pub fn apply_sync(
+        &self,
+        peer: &str,
+        seen: &[SyncEntry],
+        removed_urls: &[String],
+        cursor: Option<&str>,
+        now: u64,
+    ) -> Result<()> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+        {
+            let mut upsert = tx.prepare(
+                "INSERT INTO sync_entries (peer, url, id, record_hash) VALUES (?1, ?2, ?3, ?4)
+                 ON CONFLICT (peer, url) DO UPDATE SET record_hash = excluded.record_hash",
+            )?;
+            for entry in seen {
+                upsert.execute(params![peer, entry.url, entry.id, entry.record_hash])?;
+            }
+
+            let mut remove = tx.prepare("DELETE FROM sync_entries WHERE peer = ?1 AND url = ?2")?;
+            for url in removed_urls {
+                remove.execute(params![peer, url])?;
+            }
+        }
+        tx.execute(
+            "INSERT INTO sync_state (peer, cursor, synced_at) VALUES (?1, ?2, ?3)
+             ON CONFLICT (peer) DO UPDATE SET
+                cursor = excluded.cursor,
+                synced_at = excluded.synced_at",
+            params![peer, cursor, now as i64],
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// The sequence number of one of our messages to a conversation,
+    /// allocating the next one the first time the message is numbered
+    ///
+    /// Resending a message under the same ID keeps its number.
+    pub(crate) fn next_sequence(&self, peer: &str, message_id: &str) -> Result<u64> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+        let existing: Option<i64> = tx
+            .query_row(
+                "SELECT seq FROM sent_sequences WHERE peer = ?1 AND message_id = ?2",
+                params![peer, message_id],
+                |row| row.get(0),
+            )
+            .optional()?;
+        let seq = match existing {
+            Some(seq) => seq,
+            None => {
+                let seq: i64 = tx.query_row(
+                    "SELECT COALESCE(MAX(seq), 0) + 1 FROM sent_sequences WHERE peer = ?1",
+                    params![peer],
+                    |row| row.get(0),
+                )?;
+                tx.execute(
+                    "INSERT INTO sent_sequences (peer, message_id, seq) VALUES (?1, ?2, ?3)",
+                    params![peer, message_id, seq],
+                )?;
+                seq
+            }
+        };
+        tx.commit()?;
+        Ok(seq as u64)
+    }
+
+    /// Give back the sequence number of a message that failed to send
+    ///
+    /// Only the latest number can be given back; once a later message is
+    /// numbered, this one stays taken and shows up as a gap.
+    pub(crate) fn release_sequence(&self, peer: &str, message_id: &str) -> Result<()> {
+        self.conn()?.execute(
+            "DELETE FROM sent_sequences
+             WHERE peer = ?1 AND message_id = ?2
+                AND seq = (SELECT MAX(seq) FROM sent_sequences WHERE peer = ?1)",
+            params![peer, message_id],
+        )?;
+        Ok(())
+    }
+
+    /// The highest sequence number synced so far from each sender of a
+    /// conversation
+    pub fn seen_sequences(&self, peer: &str) -> Result<HashMap<String, u64>> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare("SELECT sender, seq FROM seen_sequences WHERE peer = ?1")?;
+        let rows = stmt.query_map(params![peer], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)? as u64))
+        })?;
+
+        let mut seen = HashMap::new();
+        for row in rows {
+            let (sender, seq) = row?;
+            seen.insert(sender, seq);
+        }
+        Ok(seen)
+    }
+
+    /// Raise the highest sequence numbers seen from the senders of a
+    /// conversation
+    pub(crate) fn record_seen_sequences(
+        &self,
+        peer: &str,
+        seen: &HashMap<String, u64>,
+    ) -> Result<()> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+        {
+            let mut stmt = tx.prepare(
+                "INSERT INTO seen_sequences (peer, sender, seq) VALUES (?1, ?2, ?3)
+                 ON CONFLICT (peer, sender) DO UPDATE SET seq = max(seq, excluded.seq)",
+            )?;
+            for (sender, seq) in seen {
+                stmt.execute(params![peer, sender, *seq as i64])?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Append an entry to the audit log
+    pub fn append_audit(
+        &self,
+        timestamp: u64,
+        action: AuditAction,
+        peer: Option<&str>,
+        detail: &str,
+    ) -> Result<()> {
+        self.conn()?.execute(
+            "INSERT INTO audit_log (timestamp, action, peer, detail) VALUES (?1, ?2, ?3, ?4)",
+            params![timestamp as i64, action.as_str(), peer, detail],
+        )?;
+        Ok(())
+    }
+
+    /// The whole audit log, oldest first
+    pub fn audit_entries(&self) -> Result<Vec<AuditEntry>> {
+        let conn = self.conn()?;
+        let mut stmt = conn
+            .prepare("SELECT seq, timestamp, action, peer, detail FROM audit_log ORDER BY seq")?;
+        let rows = stmt.query_map([], |row| {
+            let action: String = row.get(2)?;
+            Ok((
+                row.get::<_, i64>(0)?,
+                row.get::<_, i64>(1)? as u64,
+                action,
+                row.get::<_, Option<String>>(3)?,
+                row.get::<_, String>(4)?,
+            ))
+        })?;
+
+        let mut entries = Vec::new();
+        for row in rows {
+            let (seq, timestamp, action, peer, detail) = row?;
+            let action = AuditAction::parse(&action)
+                .ok_or_else(|| anyhow!("Unknown audit action: {}", action))?;
+            entries.push(AuditEntry {
+                seq,
+                timestamp,
+                action,
+                peer,
+                detail,
+            });
+        }
+        Ok(entries)
+    }
+
+    /// Map a `peer, id, sender, content, timestamp, verified, key_warning` row
+    fn stored_message_from_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<StoredMessage> {
+        let mut message = DecryptedMessage::new(
+            row.get(1)?,
+            row.get(2)?,
+            row.get(3)?,
+            row.get::<_, i64>(4)? as u64,
+            row.get(5)?,
+        );
+        message.key_warning = row.get(6)?;
+        message.edit_version = row.get(7)?;
+        message.edited_at = row
+            .get::<_, Option<i64>>(8)?
+            .map(|edited_at| edited_at as u64);
+
+        Ok(StoredMessage {
+            peer: row.get(0)?,
+            message,
+        })
+    }
+}
+
+/// Path of the file holding the salt of an encrypted store
+fn salt_path(path: &Path) -> PathBuf {
+    let mut salt_path = path.as_os_str().to_owned();
+    salt_path.push(".salt");
+    PathBuf::from(salt_path)
+}
+
+/// Turn free text into an FTS5 query of quoted prefix terms
+///
+/// Quoting every term keeps FTS5 operators and punctuation in user input from
+/// being interpreted as query syntax.
+fn fts_query(query: &str) -> Option<String> {
+    let terms: Vec<String> = query
+        .split_whitespace()
+        .map(|term| format!("\"{}\"*", term.replace('"', "\"\"")))
+        .collect();
+
+    if terms.is_empty() {
+        None
+    } else {
+        Some(terms.join(" "))
+    }
+}
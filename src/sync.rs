@@ -0,0 +1,512 @@
+use anyhow::Result;
+use futures::future::BoxFuture;
+use pkarr::PublicKey;
+use rand::Rng;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::{Duration, Instant};
+
+use crate::client::{unix_now, PrivateMessengerClient};
+use crate::message::DecryptedMessage;
+
+/// Default time between sync rounds
+const DEFAULT_SYNC_INTERVAL: Duration = Duration::from_secs(3);
+
+/// Most bridged messages kept for the next round; older ones are dropped
+const MAX_PENDING_BRIDGED: usize = 1000;
+
+/// Most errors a `SyncHandle` holds until they're read; later ones are
+/// dropped
+const MAX_UNREAD_ERRORS: usize = 100;
+
+/// How often `SyncService` polls each conversation
+///
+/// A conversation is polled every `active_interval` while messages are
+/// flowing. Each round that brings nothing new doubles its interval, up to
+/// `idle_interval`, and the next new message makes it fast again. Sending a
+/// message through the client counts as activity: the conversation is polled
+/// right away and goes back to `active_interval`, so the reply isn't held
+/// back by the idle interval.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PollingConfig {
+    /// Interval while the conversation is active
+    pub active_interval: Duration,
+    /// Longest interval an idle conversation backs off to
+    pub idle_interval: Duration,
+}
+
+impl PollingConfig {
+    /// Poll at the same interval whether or not anything happens
+    pub fn fixed(interval: Duration) -> Self {
+        Self {
+            active_interval: interval,
+            idle_interval: interval,
+        }
+    }
+
+    /// Interval of a conversation after a round, given its previous one
+    fn next_interval(&self, previous: Duration, active: bool) -> Duration {
+        if active {
+            self.active_interval
+        } else {
+            previous
+                .saturating_mul(2)
+                .min(self.idle_interval)
+                .max(self.active_interval)
+        }
+    }
+}
+
+impl Default for PollingConfig {
+    fn default() -> Self {
+        Self {
+            active_interval: DEFAULT_SYNC_INTERVAL,
+            idle_interval: Duration::from_secs(5 * 60),
+        }
+    }
+}
+
+/// When a conversation is next polled
+#[derive(Debug, Clone, Copy)]
+struct PeerSchedule {
+    interval: Duration,
+    /// Start of the last round that polled the conversation
+    last: Instant,
+    next: Instant,
+}
+
+/// A message from an external system to be sent into a pubky conversation
+#[derive(Debug, Clone)]
+pub struct BridgedMessage {
+    pub peer: PublicKey,
+    pub content: String,
+}
+
+/// Outcome of a single sync round
+#[derive(Debug, Default)]
+pub struct SyncReport {
+    /// Messages that arrived since the previous round, per peer
+    pub new_messages: Vec<(PublicKey, DecryptedMessage)>,
+    /// Errors from conversations or bridges that failed during the round
+    pub errors: Vec<anyhow::Error>,
+}
+
+/// Changes to a conversation since the previous `sync_conversation` call
+#[derive(Debug, Default, Clone)]
+pub struct SyncDelta {
+    /// True when the conversation had never been synced, so every message is
+    /// reported as new
+    pub initial: bool,
+    /// Messages that appeared since the previous sync, oldest first
+    pub new_messages: Vec<DecryptedMessage>,
+    /// Known messages whose stored record changed since the previous sync
+    pub edited_messages: Vec<DecryptedMessage>,
+    /// IDs of messages that were removed from the homeserver
+    pub deleted_ids: Vec<String>,
+    /// ID of the latest message seen in the conversation, where the next
+    /// sync continues from
+    pub cursor: Option<String>,
+}
+
+/// A relay between pubky conversations and an external system (Matrix, XMPP,
+/// email, ...)
+///
+/// Bridges are registered with a `SyncService`, which hands them every new
+/// message received from a peer and sends whatever they return from
+/// `poll_inbound`.
+pub trait Bridge: Send + Sync {
+    /// Deliver a message received from `peer` to the external system
+    fn deliver<'a>(
+        &'a self,
+        peer: &'a PublicKey,
+        message: &'a DecryptedMessage,
+    ) -> BoxFuture<'a, Result<()>>;
+
+    /// Collect messages from the external system to send to pubky peers
+    fn poll_inbound(&self) -> BoxFuture<'_, Result<Vec<BridgedMessage>>>;
+}
+
+/// Background service that polls conversations for new messages
+///
+/// Messages already present when a conversation is first synced are treated
+/// as seen; only messages that arrive afterwards are reported as new. With a
+/// local store on the client, conversations are polled with
+/// `sync_conversation`, so only changes are fetched and what was seen
+/// survives restarts, and new messages are answered with the away message
+/// while one is set (see `PrivateMessengerClient::set_away_message`).
+pub struct SyncService {
+    client: Arc<PrivateMessengerClient>,
+    peers: Vec<PublicKey>,
+    polling: PollingConfig,
+    peer_intervals: HashMap<String, Duration>,
+    schedule: HashMap<String, PeerSchedule>,
+    bridges: Vec<Arc<dyn Bridge>>,
+    pending_bridged: VecDeque<BridgedMessage>,
+    seen: HashMap<String, HashSet<String>>,
+    cover_probability: f64,
+    transcript_interval: Option<Duration>,
+    transcript_sent: HashMap<String, Instant>,
+}
+
+/// Handle to a running `SyncService`
+///
+/// Errors of the background rounds are passed to the handle; read them with
+/// `take_errors` or `next_error`. Up to 100 unread errors are held, later
+/// ones are dropped until some are read.
+pub struct SyncHandle {
+    task: JoinHandle<()>,
+    errors: mpsc::Receiver<anyhow::Error>,
+}
+
+/// Sending side of the errors passed to a `SyncHandle`
+pub(crate) type ErrorSender = mpsc::Sender<anyhow::Error>;
+
+impl SyncHandle {
+    /// A channel for the errors of a background task, see `forward_errors`
+    pub(crate) fn error_channel() -> (ErrorSender, mpsc::Receiver<anyhow::Error>) {
+        mpsc::channel(MAX_UNREAD_ERRORS)
+    }
+
+    pub(crate) fn new(task: JoinHandle<()>, errors: mpsc::Receiver<anyhow::Error>) -> Self {
+        Self { task, errors }
+    }
+
+    /// Pass the errors of a round to the handle, dropping them if it's full
+    pub(crate) fn forward_errors(errors: &ErrorSender, report: SyncReport) {
+        for error in report.errors {
+            let _ = errors.try_send(error);
+        }
+    }
+
+    /// Errors of the rounds since they were last read, oldest first
+    pub fn take_errors(&mut self) -> Vec<anyhow::Error> {
+        let mut errors = Vec::new();
+        while let Ok(error) = self.errors.try_recv() {
+            errors.push(error);
+        }
+        errors
+    }
+
+    /// Wait for the next error of a round
+    pub async fn next_error(&mut self) -> Option<anyhow::Error> {
+        self.errors.recv().await
+    }
+
+    /// Stop the background sync
+    pub fn stop(self) {
+        self.task.abort();
+    }
+}
+
+impl SyncService {
+    /// Create a sync service for a client
+    pub fn new(client: Arc<PrivateMessengerClient>) -> Self {
+        Self {
+            client,
+            peers: Vec::new(),
+            polling: PollingConfig::fixed(DEFAULT_SYNC_INTERVAL),
+            peer_intervals: HashMap::new(),
+            schedule: HashMap::new(),
+            bridges: Vec::new(),
+            pending_bridged: VecDeque::new(),
+            seen: HashMap::new(),
+            cover_probability: 0.0,
+            transcript_interval: None,
+            transcript_sent: HashMap::new(),
+        }
+    }
+
+    /// Add a conversation to keep in sync
+    pub fn with_peer(mut self, peer: PublicKey) -> Self {
+        self.peers.push(peer);
+        self
+    }
+
+    /// Set the time between sync rounds (defaults to 3 seconds)
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.polling = PollingConfig::fixed(interval);
+        self
+    }
+
+    /// Poll busy conversations often and back off on idle ones
+    ///
+    /// See `PollingConfig`; `PollingConfig::default()` polls every 3 seconds
+    /// while active and slows down to every 5 minutes.
+    pub fn with_adaptive_polling(mut self, polling: PollingConfig) -> Self {
+        self.polling = polling;
+        self
+    }
+
+    /// Poll one conversation at a fixed interval, whatever its activity
+    pub fn with_peer_interval(mut self, peer: &PublicKey, interval: Duration) -> Self {
+        self.peer_intervals.insert(peer.to_string(), interval);
+        self
+    }
+
+    /// Register a bridge to an external system
+    pub fn with_bridge(mut self, bridge: Arc<dyn Bridge>) -> Self {
+        self.bridges.push(bridge);
+        self
+    }
+
+    /// Enable cover traffic
+    ///
+    /// Each round, every conversation gets a dummy encrypted record with the
+    /// given probability (clamped to `0.0..=1.0`), so homeserver observers
+    /// can't tell real activity from write timing alone. Cover records are
+    /// flagged inside the ciphertext and dropped when fetching. They stay on
+    /// the homeserver like real messages and count towards its storage.
+    pub fn with_cover_traffic(mut self, probability: f64) -> Self {
+        self.cover_probability = probability.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Exchange transcript hashes with every conversation
+    ///
+    /// Every `interval`, each conversation is sent a
+    /// `SystemMessage::TranscriptHash` (see
+    /// `PrivateMessengerClient::send_transcript_hash`), so a homeserver
+    /// hiding records from either side raises
+    /// `NotificationEvent::TranscriptMismatch`. Hashes are sent right after a
+    /// conversation is synced, the first ones on the first round. Requires a
+    /// local store on the client.
+    pub fn with_transcript_checks(mut self, interval: Duration) -> Self {
+        self.transcript_interval = Some(interval);
+        self
+    }
+
+    /// Run a single sync round
+    ///
+    /// Sends messages injected by bridges, fetches every conversation that is
+    /// due (all of them, unless adaptive polling or per-conversation
+    /// intervals hold some back), delivers new messages from peers to the
+    /// bridges, and sends any away replies that are due. A failing
+    /// conversation or bridge does not stop the round; its error is collected
+    /// in the report. Bridged messages that fail to send are kept, in order,
+    /// and retried on the next round.
+    pub async fn sync_once(&mut self) -> SyncReport {
+        let mut report = SyncReport::default();
+
+        for bridge in self.bridges.iter() {
+            match bridge.poll_inbound().await {
+                Ok(inbound) => {
+                    for bridged in inbound {
+                        if self.pending_bridged.len() >= MAX_PENDING_BRIDGED {
+                            self.pending_bridged.pop_front();
+                        }
+                        self.pending_bridged.push_back(bridged);
+                    }
+                }
+                Err(e) => {
+                    report.errors.push(e);
+                }
+            }
+        }
+        let mut failed = VecDeque::new();
+        while let Some(bridged) = self.pending_bridged.pop_front() {
+            if let Err(e) = self
+                .client
+                .send_message(&bridged.peer, &bridged.content)
+                .await
+            {
+                report.errors.push(e);
+                failed.push_back(bridged);
+            }
+        }
+        self.pending_bridged = failed;
+
+        let own_pubky = self.client.public_key_string();
+        let round_started = Instant::now();
+
+        for peer in self.peers.iter() {
+            let key = peer.to_string();
+            let sent = self.sent_since_last_round(peer);
+            if !sent
+                && self
+                    .schedule
+                    .get(&key)
+                    .is_some_and(|schedule| schedule.next > round_started)
+            {
+                continue;
+            }
+            let new_before = report.new_messages.len();
+
+            if self.client.has_local_store() {
+                let synced_up_to = unix_now().saturating_sub(1);
+                match self.client.sync_conversation(peer).await {
+                    Ok(delta) => {
+                        if !delta.initial {
+                            report
+                                .new_messages
+                                .extend(delta.new_messages.into_iter().map(|m| (peer.clone(), m)));
+                        }
+                        if self.transcript_due(&key, round_started) {
+                            match self
+                                .client
+                                .send_synced_transcript_hash(peer, synced_up_to)
+                                .await
+                            {
+                                Ok(_) => {
+                                    self.transcript_sent.insert(key.clone(), round_started);
+                                }
+                                Err(e) => report.errors.push(e),
+                            }
+                        }
+                    }
+                    Err(e) => report.errors.push(e),
+                }
+            } else {
+                match self.client.get_messages(peer).await {
+                    Ok(messages) => {
+                        let first_sync = !self.seen.contains_key(&key);
+                        let seen = self.seen.entry(key.clone()).or_default();
+
+                        for message in messages {
+                            if seen.insert(message.id.clone()) && !first_sync {
+                                report.new_messages.push((peer.clone(), message));
+                            }
+                        }
+                    }
+                    Err(e) => report.errors.push(e),
+                }
+            }
+
+            // Errors back off like idle rounds
+            let active = sent || report.new_messages.len() > new_before;
+            let interval = match self.peer_intervals.get(&key) {
+                Some(interval) => *interval,
+                None => {
+                    let previous = self
+                        .schedule
+                        .get(&key)
+                        .map_or(self.polling.active_interval, |schedule| schedule.interval);
+                    self.polling.next_interval(previous, active)
+                }
+            };
+            self.schedule.insert(
+                key,
+                PeerSchedule {
+                    interval,
+                    last: round_started,
+                    next: round_started + interval,
+                },
+            );
+        }
+
+        // Deletions held in the trash go through once their window passed
+        if let Err(e) = self.client.purge_trash().await {
+            report.errors.push(e);
+        }
+
+        if self.cover_probability > 0.0 {
+            let cover_peers: Vec<&PublicKey> = {
+                let mut rng = rand::thread_rng();
+                self.peers
+                    .iter()
+                    .filter(|_| rng.gen_bool(self.cover_probability))
+                    .collect()
+            };
+            for peer in cover_peers {
+                if let Err(e) = self.client.send_cover_message(peer).await {
+                    report.errors.push(e);
+                }
+            }
+        }
+
+        // Only relay messages from peers, so bridged sends don't loop back
+        for (peer, message) in report.new_messages.iter() {
+            if message.sender == own_pubky {
+                continue;
+            }
+            for bridge in self.bridges.iter() {
+                if let Err(e) = bridge.deliver(peer, message).await {
+                    report.errors.push(e);
+                }
+            }
+        }
+
+        if self.client.has_local_store() {
+            for (peer, message) in report.new_messages.iter() {
+                if let Err(e) = self.client.send_away_reply(peer, message).await {
+                    report.errors.push(e);
+                }
+            }
+        }
+
+        report
+    }
+
+    /// Whether a conversation should be sent a transcript hash this round
+    fn transcript_due(&self, key: &str, round_started: Instant) -> bool {
+        let Some(interval) = self.transcript_interval else {
+            return false;
+        };
+        self.transcript_sent
+            .get(key)
+            .map_or(true, |sent| *sent + interval <= round_started)
+    }
+
+    /// Bridged messages waiting to be sent, oldest first
+    pub fn pending_bridged(&self) -> impl Iterator<Item = &BridgedMessage> {
+        self.pending_bridged.iter()
+    }
+
+    /// Run the service in a background task until the handle is stopped
+    ///
+    /// Errors of each round are passed to the handle (see `SyncHandle`), and
+    /// what failed is retried on the next round.
+    pub fn spawn(mut self) -> SyncHandle {
+        let (errors, receiver) = SyncHandle::error_channel();
+        let task = tokio::spawn(async move {
+            loop {
+                let report = self.sync_once().await;
+                SyncHandle::forward_errors(&errors, report);
+                self.wait_for_round().await;
+            }
+        });
+
+        SyncHandle::new(task, receiver)
+    }
+
+    /// Wait until the next round should run, or until the client sends a
+    /// message, whichever comes first
+    pub(crate) async fn wait_for_round(&self) {
+        tokio::select! {
+            _ = tokio::time::sleep_until(self.next_round()) => {}
+            _ = self.client.next_send() => {}
+        }
+    }
+
+    /// Whether we sent `peer` a message since the conversation was last
+    /// polled
+    fn sent_since_last_round(&self, peer: &PublicKey) -> bool {
+        self.schedule
+            .get(&peer.to_string())
+            .is_some_and(|schedule| self.client.sent_since(peer, schedule.last.into_std()))
+    }
+
+    /// When the next round should run
+    ///
+    /// That's when the next conversation is due, right away if we sent one of
+    /// them a message since it was last polled, but at least every
+    /// `active_interval` while bridges are registered, so bridged messages
+    /// aren't held back by idle conversations.
+    pub(crate) fn next_round(&self) -> Instant {
+        if self
+            .peers
+            .iter()
+            .any(|peer| self.sent_since_last_round(peer))
+        {
+            return Instant::now();
+        }
+        let next_due = self.schedule.values().map(|schedule| schedule.next).min();
+        let next_active = Instant::now() + self.polling.active_interval;
+        match next_due {
+            Some(next_due) if self.bridges.is_empty() => next_due,
+            Some(next_due) => next_due.min(next_active),
+            None => next_active,
+        }
+    }
+}
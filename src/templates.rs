@@ -0,0 +1,101 @@
+use anyhow::{anyhow, Result};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use pkarr::Keypair;
+use pubky_common::crypto::{decrypt, encrypt};
+use serde::{Deserialize, Serialize};
+use zeroize::Zeroizing;
+
+/// Key derivation contexts for reply templates
+const TEMPLATES_KEY_CONTEXT: &str = "pubky-messenger reply templates key v1";
+const TEMPLATES_PATH_CONTEXT: &str = "pubky-messenger reply templates path v1";
+const TEMPLATE_NAME_CONTEXT: &str = "pubky-messenger reply template name v1";
+
+/// Longest template name, in characters
+pub const MAX_TEMPLATE_NAME_CHARS: usize = 64;
+
+/// Largest template text, in bytes
+pub const MAX_TEMPLATE_BYTES: usize = 4096;
+
+/// A reusable reply, stored encrypted on our homeserver
+///
+/// The text may contain `{placeholder}`s, filled in by `render`. Templates
+/// are shared by every device of the identity, like device settings.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReplyTemplate {
+    pub name: String,
+    pub text: String,
+    /// Unix time in seconds when the template was last saved
+    pub updated_at: u64,
+}
+
+impl ReplyTemplate {
+    /// Check that the name and text are within limits
+    pub fn validate(&self) -> Result<()> {
+        let name = self.name.trim();
+        if name.is_empty() || name.chars().count() > MAX_TEMPLATE_NAME_CHARS {
+            return Err(anyhow!(
+                "Template names must be 1 to {} characters",
+                MAX_TEMPLATE_NAME_CHARS
+            ));
+        }
+        if self.text.trim().is_empty() || self.text.len() > MAX_TEMPLATE_BYTES {
+            return Err(anyhow!(
+                "Template text must be 1 to {} bytes",
+                MAX_TEMPLATE_BYTES
+            ));
+        }
+        Ok(())
+    }
+
+    /// The text with each `{key}` replaced by its value
+    ///
+    /// Placeholders without a value are left as they are.
+    pub fn render(&self, values: &[(&str, &str)]) -> String {
+        let mut text = self.text.clone();
+        for (key, value) in values {
+            text = text.replace(&format!("{{{}}}", key), value);
+        }
+        text
+    }
+
+    /// Encrypt the template into a record for the homeserver
+    pub(crate) fn seal(&self, key: &[u8; 32]) -> Result<String> {
+        let json = serde_json::to_vec(self)?;
+        Ok(STANDARD.encode(encrypt(&json, key)))
+    }
+
+    /// Decrypt a template from a homeserver record
+    pub(crate) fn open(record: &str, key: &[u8; 32]) -> Result<Self> {
+        let sealed = STANDARD
+            .decode(record.trim())
+            .map_err(|e| anyhow!("Invalid template record: {}", e))?;
+        let json = decrypt(&sealed, key)?;
+        Ok(serde_json::from_slice(&json)?)
+    }
+}
+
+/// Key encrypting the reply templates of one identity
+pub(crate) fn templates_key(keypair: &Keypair) -> Zeroizing<[u8; 32]> {
+    let secret = Zeroizing::new(keypair.secret_key());
+    Zeroizing::new(blake3::derive_key(TEMPLATES_KEY_CONTEXT, &secret[..]))
+}
+
+/// Directory holding the reply templates of one identity
+///
+/// Like the device settings directory, it looks like any conversation
+/// directory to the homeserver.
+pub(crate) fn templates_path(keypair: &Keypair) -> String {
+    let secret = Zeroizing::new(keypair.secret_key());
+    let path_id = blake3::derive_key(TEMPLATES_PATH_CONTEXT, &secret[..]);
+    format!("/pub/private_messages/{}/", hex::encode(path_id))
+}
+
+/// File name of a template: a keyed hash of its name, so the homeserver
+/// can't read names while saving under a name replaces the earlier template
+pub(crate) fn template_file(key: &[u8; 32], name: &str) -> String {
+    let mut hasher = blake3::Hasher::new_derive_key(TEMPLATE_NAME_CONTEXT);
+    hasher.update(key);
+    hasher.update(name.trim().as_bytes());
+    hasher.finalize().to_hex().to_string()
+}
@@ -0,0 +1,77 @@
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::message::PrivateMessage;
+use crate::store::SyncEntry;
+
+/// Key derivation context of transcript hashes
+const TRANSCRIPT_CONTEXT: &str = "pubky-messenger transcript v1";
+
+/// Seconds before the sync that transcript hashes stop short of
+///
+/// Records can be written a while after the time their ID is dated, for
+/// instance with coarse timestamps, so the newest records may not have
+/// reached both sides yet when a hash is compared.
+pub const TRANSCRIPT_GRACE_SECS: u64 = 10 * 60;
+
+/// Digest of the records of a conversation written up to a point in time
+///
+/// Both participants list the same records from the same homeservers, so
+/// their digests up to the same time agree unless a homeserver hides some
+/// records from one of them. Hashes are sent up to `TRANSCRIPT_GRACE_SECS`
+/// (or the coarse timestamp bucket, if longer) before the sync they follow,
+/// so records still arriving aren't taken for hidden ones. Exchanged as
+/// `SystemMessage::TranscriptHash`, see
+/// `PrivateMessengerClient::send_transcript_hash`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TranscriptHash {
+    /// Unix time in seconds; records whose message ID is dated later are
+    /// not covered
+    pub up_to: u64,
+    /// Number of records covered
+    pub records: u64,
+    /// Hex-encoded rolling blake3 hash of the covered records
+    pub hash: String,
+}
+
+impl TranscriptHash {
+    /// Hash the records seen by sync that are dated up to `up_to`
+    ///
+    /// Each record is folded into the running hash by URL and content hash,
+    /// in URL order. Records whose ID carries no timestamp are left out.
+    pub(crate) fn from_entries<'a>(
+        entries: impl IntoIterator<Item = &'a SyncEntry>,
+        up_to: u64,
+    ) -> Self {
+        let mut covered: Vec<&SyncEntry> = entries
+            .into_iter()
+            .filter(|entry| {
+                PrivateMessage::id_timestamp_ms(&entry.id)
+                    .is_some_and(|timestamp_ms| timestamp_ms / 1000 <= up_to)
+            })
+            .collect();
+        covered.sort_by(|a, b| a.url.cmp(&b.url));
+
+        let mut hasher = blake3::Hasher::new_derive_key(TRANSCRIPT_CONTEXT);
+        for entry in covered.iter() {
+            hasher.update(entry.url.as_bytes());
+            hasher.update(b"\n");
+            hasher.update(entry.record_hash.as_bytes());
+            hasher.update(b"\n");
+        }
+
+        Self {
+            up_to,
+            records: covered.len() as u64,
+            hash: hasher.finalize().to_hex().to_string(),
+        }
+    }
+
+    /// Check that the hash is well formed
+    pub fn validate(&self) -> Result<()> {
+        if self.hash.len() != 64 || !self.hash.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(anyhow!("Transcript hash must be 64 hex characters"));
+        }
+        Ok(())
+    }
+}
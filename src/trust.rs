@@ -0,0 +1,41 @@
+use serde::{Deserialize, Serialize};
+
+/// How much a contact's identity is trusted
+///
+/// Set explicitly with `PrivateMessengerClient::set_trust`. Contacts without
+/// an explicit level are `Tofu` once their identity has been pinned on first
+/// contact, and `Unknown` before that.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TrustLevel {
+    /// Never seen, or no local store to remember the contact
+    #[default]
+    Unknown,
+    /// Identity pinned on first contact (trust on first use)
+    Tofu,
+    /// Identity confirmed out of band
+    Verified,
+    /// Identity explicitly not trusted; their messages are quarantined
+    Distrusted,
+}
+
+impl TrustLevel {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            Self::Unknown => "unknown",
+            Self::Tofu => "tofu",
+            Self::Verified => "verified",
+            Self::Distrusted => "distrusted",
+        }
+    }
+
+    pub(crate) fn parse(level: &str) -> Option<Self> {
+        match level {
+            "unknown" => Some(Self::Unknown),
+            "tofu" => Some(Self::Tofu),
+            "verified" => Some(Self::Verified),
+            "distrusted" => Some(Self::Distrusted),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,161 @@
+//! Typed `pubky://<public key>/<path>` URLs
+//!
+//! Homeserver resources are addressed by the owner's public key followed by
+//! an absolute path. [`PubkyUri`] parses and builds these URLs so callers
+//! don't have to split strings by hand.
+
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::{anyhow, Result};
+use pkarr::PublicKey;
+
+/// Scheme prefix of every Pubky URL
+pub const SCHEME: &str = "pubky://";
+
+/// Directory holding all private conversations of a user
+pub const PRIVATE_MESSAGES_PATH: &str = "/pub/private_messages/";
+
+/// Directory holding the follow records of a user
+pub const FOLLOWS_PATH: &str = "/pub/pubky.app/follows/";
+
+/// Extension of message records
+const RECORD_EXTENSION: &str = ".json";
+
+/// A parsed `pubky://<public key>/<path>` URL
+///
+/// The path is always absolute. A path ending in `/` names a directory.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct PubkyUri {
+    public_key: PublicKey,
+    path: String,
+}
+
+impl PubkyUri {
+    /// Build a URL for `path` on `public_key`'s homeserver
+    ///
+    /// A missing leading `/` is added.
+    pub fn new(public_key: &PublicKey, path: &str) -> Self {
+        let path = if path.starts_with('/') {
+            path.to_string()
+        } else {
+            format!("/{}", path)
+        };
+        Self {
+            public_key: public_key.clone(),
+            path,
+        }
+    }
+
+    /// Parse a `pubky://` URL
+    ///
+    /// Fails if the scheme is missing or the host isn't a valid public key.
+    pub fn parse(uri: &str) -> Result<Self> {
+        let rest = uri
+            .strip_prefix(SCHEME)
+            .ok_or_else(|| anyhow!("URL must start with {}: {}", SCHEME, uri))?;
+        let (host, path) = match rest.find('/') {
+            Some(index) => rest.split_at(index),
+            None => (rest, "/"),
+        };
+        let public_key = PublicKey::try_from(host)
+            .map_err(|e| anyhow!("Invalid pubky in URL {}: {}", uri, e))?;
+
+        Ok(Self {
+            public_key,
+            path: path.to_string(),
+        })
+    }
+
+    /// The public key of the homeserver owner
+    pub fn public_key(&self) -> &PublicKey {
+        &self.public_key
+    }
+
+    /// The absolute path, starting with `/`
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Whether the URL names a directory
+    pub fn is_dir(&self) -> bool {
+        self.path.ends_with('/')
+    }
+
+    /// The non-empty segments of the path
+    pub fn segments(&self) -> impl Iterator<Item = &str> {
+        self.path.split('/').filter(|segment| !segment.is_empty())
+    }
+
+    /// The last path segment, or `None` for directories
+    pub fn file_name(&self) -> Option<&str> {
+        if self.is_dir() {
+            return None;
+        }
+        self.path.rsplit('/').next()
+    }
+
+    /// A URL for `name` inside this directory
+    ///
+    /// A `/` is inserted if this URL doesn't already end with one.
+    pub fn join(&self, name: &str) -> Self {
+        let separator = if self.is_dir() { "" } else { "/" };
+        Self {
+            public_key: self.public_key.clone(),
+            path: format!("{}{}{}", self.path, separator, name.trim_start_matches('/')),
+        }
+    }
+
+    /// The path relative to directory `dir`, if the URL lies inside it
+    pub fn relative_to(&self, dir: &str) -> Option<&str> {
+        let dir = dir.trim_end_matches('/');
+        let rest = self.path.strip_prefix(dir)?.strip_prefix('/')?;
+        (!rest.is_empty()).then_some(rest)
+    }
+
+    /// The conversation directory of a private message URL
+    ///
+    /// This is `/pub/private_messages/<hash>/` for any record stored in a
+    /// conversation, including sidecar records in subdirectories.
+    pub fn conversation_path(&self) -> Option<&str> {
+        let rest = self.relative_to(PRIVATE_MESSAGES_PATH)?;
+        let hash_len = rest.find('/')?;
+        Some(&self.path[..PRIVATE_MESSAGES_PATH.len() + hash_len + 1])
+    }
+
+    /// The message ID of a message record directly inside a conversation
+    ///
+    /// Returns `None` for directories and for sidecar records (such as poll
+    /// votes) kept in subdirectories of the conversation path.
+    pub fn message_id(&self) -> Option<&str> {
+        let conversation = self.conversation_path()?;
+        let file = &self.path[conversation.len()..];
+        if file.contains('/') {
+            return None;
+        }
+        file.strip_suffix(RECORD_EXTENSION)
+            .filter(|id| !id.is_empty())
+    }
+}
+
+impl fmt::Display for PubkyUri {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}{}", SCHEME, self.public_key, self.path)
+    }
+}
+
+impl FromStr for PubkyUri {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Self::parse(s)
+    }
+}
+
+/// The host part of a `pubky://` URL without validating it
+///
+/// Cheap enough for the hot request path, where the URL was built by us.
+pub(crate) fn host_of(url: &str) -> &str {
+    let rest = url.strip_prefix(SCHEME).unwrap_or(url);
+    rest.split('/').next().unwrap_or(rest)
+}
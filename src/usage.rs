@@ -0,0 +1,39 @@
+use serde::{Deserialize, Serialize};
+
+/// Share of the quota in use at which `StorageUsage::is_near_limit` holds,
+/// in percent
+const NEAR_LIMIT_PERCENT: u64 = 90;
+
+/// Homeserver storage taken up by one conversation directory
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConversationUsage {
+    /// Conversation path on our homeserver
+    pub path: String,
+    /// The other participant, if the path belongs to a conversation in the
+    /// local store
+    pub peer: Option<String>,
+    /// Records stored under the path, including sidecar records
+    pub records: usize,
+    /// Combined size of the records, in bytes
+    pub bytes: u64,
+}
+
+/// Storage used by our messenger data on our homeserver
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StorageUsage {
+    /// Usage per conversation directory, largest first
+    pub conversations: Vec<ConversationUsage>,
+    /// Combined size of all records, in bytes
+    pub total_bytes: u64,
+    /// Storage quota set with `PrivateMessengerClient::with_storage_quota`
+    pub quota_bytes: Option<u64>,
+}
+
+impl StorageUsage {
+    /// Whether usage has reached 90% of the quota
+    pub fn is_near_limit(&self) -> bool {
+        self.quota_bytes.is_some_and(|quota| {
+            u128::from(self.total_bytes) * 100 >= u128::from(quota) * u128::from(NEAR_LIMIT_PERCENT)
+        })
+    }
+}
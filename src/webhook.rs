@@ -0,0 +1,205 @@
+use anyhow::{anyhow, Result};
+use hmac::{Hmac, Mac};
+use pkarr::PublicKey;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::VecDeque;
+use std::net::IpAddr;
+use std::sync::Arc;
+use tokio::sync::broadcast;
+use tokio::time::Duration;
+use zeroize::Zeroizing;
+
+use crate::client::{unix_now, PrivateMessengerClient};
+use crate::events::NotificationEvent;
+use crate::message::DecryptedMessage;
+use crate::sync::{PollingConfig, SyncHandle, SyncReport, SyncService};
+
+/// Header carrying the HMAC-SHA256 signature of a delivery
+pub const WEBHOOK_SIGNATURE_HEADER: &str = "X-Pubky-Signature";
+
+/// Header carrying the Unix time a delivery was signed at
+pub const WEBHOOK_TIMESTAMP_HEADER: &str = "X-Pubky-Timestamp";
+
+/// Time allowed for the webhook to answer a delivery
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Most undelivered events kept for the next round; older ones are dropped
+const MAX_PENDING_EVENTS: usize = 1000;
+
+/// An event POSTed to the webhook as JSON
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum WebhookEvent {
+    /// A new message arrived in a conversation, from either participant
+    Message {
+        peer: String,
+        message: DecryptedMessage,
+    },
+    /// The client raised a notification event
+    Notification { event: NotificationEvent },
+}
+
+/// Relays decrypted messages and client events to an HTTP webhook
+///
+/// Runs a `SyncService` over its conversations and POSTs every new message
+/// and every `NotificationEvent` of the client as a JSON `WebhookEvent`, so
+/// services that don't link Rust (ticketing, alerting) can react to pubky
+/// messages. Each request is signed: the `X-Pubky-Signature` header is
+/// `sha256=` followed by the hex HMAC-SHA256, under the shared secret, of
+/// the `X-Pubky-Timestamp` value, a `.`, and the body. Receivers should
+/// check it and reject stale timestamps.
+///
+/// The webhook gets plaintext, so it must be `https://` or on a loopback
+/// address. Events the webhook doesn't accept with a 2xx are retried on the
+/// next round, in order; beyond `MAX_PENDING_EVENTS` the oldest are dropped.
+pub struct WebhookRelay {
+    sync: SyncService,
+    events: broadcast::Receiver<NotificationEvent>,
+    http: reqwest::Client,
+    url: String,
+    user_agent: String,
+    secret: Zeroizing<Vec<u8>>,
+    pending: VecDeque<WebhookEvent>,
+}
+
+impl WebhookRelay {
+    /// Create a relay from a client to the webhook at `url`, signing with
+    /// `secret`
+    pub fn new(client: Arc<PrivateMessengerClient>, url: &str, secret: &[u8]) -> Result<Self> {
+        let parsed = reqwest::Url::parse(url)?;
+        let loopback = parsed.host_str().is_some_and(|host| {
+            host == "localhost"
+                || host
+                    .trim_start_matches('[')
+                    .trim_end_matches(']')
+                    .parse::<IpAddr>()
+                    .is_ok_and(|ip| ip.is_loopback())
+        });
+        if parsed.scheme() != "https" && !(parsed.scheme() == "http" && loopback) {
+            return Err(anyhow!(
+                "Webhooks must use https:// or a loopback http:// address"
+            ));
+        }
+        if secret.is_empty() {
+            return Err(anyhow!("Webhook secret cannot be empty"));
+        }
+
+        Ok(Self {
+            events: client.subscribe(),
+            user_agent: client.user_agent().to_string(),
+            sync: SyncService::new(client),
+            http: reqwest::Client::builder()
+                .timeout(WEBHOOK_TIMEOUT)
+                .build()?,
+            url: url.to_string(),
+            secret: Zeroizing::new(secret.to_vec()),
+            pending: VecDeque::new(),
+        })
+    }
+
+    /// Add a conversation to relay
+    pub fn with_peer(mut self, peer: PublicKey) -> Self {
+        self.sync = self.sync.with_peer(peer);
+        self
+    }
+
+    /// Poll conversations as configured, see
+    /// `SyncService::with_adaptive_polling`
+    pub fn with_polling(mut self, polling: PollingConfig) -> Self {
+        self.sync = self.sync.with_adaptive_polling(polling);
+        self
+    }
+
+    /// Run a single round: sync the conversations and deliver what's new
+    ///
+    /// Delivery stops at the first event the webhook doesn't accept; its
+    /// error is collected in the report and the rest wait for the next
+    /// round.
+    pub async fn relay_once(&mut self) -> SyncReport {
+        let mut report = self.sync.sync_once().await;
+
+        for (peer, message) in report.new_messages.iter() {
+            self.queue(WebhookEvent::Message {
+                peer: peer.to_string(),
+                message: message.clone(),
+            });
+        }
+        loop {
+            match self.events.try_recv() {
+                Ok(event) => self.queue(WebhookEvent::Notification { event }),
+                Err(broadcast::error::TryRecvError::Lagged(_)) => continue,
+                Err(_) => break,
+            }
+        }
+
+        while let Some(event) = self.pending.front() {
+            if let Err(e) = self.deliver(event).await {
+                report.errors.push(e);
+                break;
+            }
+            self.pending.pop_front();
+        }
+
+        report
+    }
+
+    /// Run the relay in a background task until the handle is stopped
+    pub fn spawn(mut self) -> SyncHandle {
+        let (errors, receiver) = SyncHandle::error_channel();
+        let task = tokio::spawn(async move {
+            loop {
+                let report = self.relay_once().await;
+                SyncHandle::forward_errors(&errors, report);
+                self.sync.wait_for_round().await;
+            }
+        });
+
+        SyncHandle::new(task, receiver)
+    }
+
+    /// Queue an event for delivery, dropping the oldest beyond the limit
+    fn queue(&mut self, event: WebhookEvent) {
+        if self.pending.len() >= MAX_PENDING_EVENTS {
+            self.pending.pop_front();
+        }
+        self.pending.push_back(event);
+    }
+
+    /// POST one signed event to the webhook
+    async fn deliver(&self, event: &WebhookEvent) -> Result<()> {
+        let body = serde_json::to_vec(event)?;
+        let timestamp = unix_now().to_string();
+
+        let response = self
+            .http
+            .post(&self.url)
+            .header(reqwest::header::USER_AGENT, &self.user_agent)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header(WEBHOOK_TIMESTAMP_HEADER, &timestamp)
+            .header(
+                WEBHOOK_SIGNATURE_HEADER,
+                webhook_signature(&self.secret, &timestamp, &body),
+            )
+            .body(body)
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            return Err(anyhow!("Webhook rejected event: {}", response.status()));
+        }
+        Ok(())
+    }
+}
+
+/// The signature header value of a delivery: `sha256=` and the hex
+/// HMAC-SHA256 of `{timestamp}.{body}`
+///
+/// Receivers written in Rust can use it to check deliveries.
+pub fn webhook_signature(secret: &[u8], timestamp: &str, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    mac.update(timestamp.as_bytes());
+    mac.update(b".");
+    mac.update(body);
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
@@ -0,0 +1,47 @@
+use anyhow::Result;
+use pubky_messenger::{Keypair, PrivateMessengerClient};
+use pubky_testnet::EphemeralTestnet;
+
+/// A local testnet with two freshly signed-up identities
+///
+/// Keep the harness alive for as long as the clients are used; dropping it
+/// shuts the homeserver down.
+pub struct Harness {
+    #[allow(dead_code)] // Not read by every test file
+    pub testnet: EphemeralTestnet,
+    pub alice: PrivateMessengerClient,
+    pub bob: PrivateMessengerClient,
+}
+
+impl Harness {
+    pub async fn start() -> Result<Self> {
+        let testnet = EphemeralTestnet::start().await?;
+        let alice = signed_up_client(&testnet).await?;
+        let bob = signed_up_client(&testnet).await?;
+
+        Ok(Self {
+            testnet,
+            alice,
+            bob,
+        })
+    }
+
+    /// A client for a fresh identity that never signed up
+    #[allow(dead_code)] // Not used by every test file
+    pub fn stranger(&self) -> Result<PrivateMessengerClient> {
+        let pubky_client = self.testnet.pubky_client_builder().build()?;
+        Ok(PrivateMessengerClient::from_pubky_client(
+            Keypair::random(),
+            pubky_client,
+        ))
+    }
+}
+
+async fn signed_up_client(testnet: &EphemeralTestnet) -> Result<PrivateMessengerClient> {
+    let pubky_client = testnet.pubky_client_builder().build()?;
+    let client = PrivateMessengerClient::from_pubky_client(Keypair::random(), pubky_client);
+    client
+        .sign_up(&testnet.homeserver_suite().public_key(), None)
+        .await?;
+    Ok(client)
+}
@@ -1,5 +1,13 @@
 use pkarr::Keypair;
-use pubky_messenger::{PrivateMessage, PrivateMessengerClient};
+use pubky_messenger::{
+    CipherSuite, CircuitBreakerConfig, ConnectivityState, ConversationId, ConversationKey,
+    DecryptionCacheConfig, FollowAnomaly, FollowDivergence, FollowedUser, Homeserver,
+    IdentityBundle, KeyDirection, KeyRotationProof, MessageContent, MessengerError,
+    NotificationEvent, PathRotation, PrivateMessage, PrivateMessengerClient, PubkyProfile,
+    PubkyUri, ReplyTemplate, StorageUsage, SystemMessage, MAX_TEMPLATE_BYTES,
+};
+use sha2::{Digest, Sha512};
+use std::time::Duration;
 
 #[test]
 fn test_message_encryption_decryption() {
@@ -29,6 +37,344 @@ fn test_message_encryption_decryption() {
     assert!(verified);
 }
 
+#[test]
+fn test_multi_recipient_message() {
+    let alice_keypair = Keypair::random();
+    let bob_keypair = Keypair::random();
+    let carol_keypair = Keypair::random();
+    let alice_pubky = alice_keypair.public_key();
+
+    let recipients = [bob_keypair.public_key(), carol_keypair.public_key()];
+    let message = PrivateMessage::new_multi(&alice_keypair, &recipients, "Hello both!", 1).unwrap();
+    assert_eq!(message.wrapped_keys.len(), 2);
+
+    // Each recipient reads the same envelope with their own conversation key
+    for recipient in [&bob_keypair, &carol_keypair] {
+        let content = message.decrypt_content(recipient, &alice_pubky).unwrap();
+        let sender = message.decrypt_sender(recipient, &alice_pubky).unwrap();
+        assert_eq!(content, "Hello both!");
+        assert!(message.verify_signature(&content, &sender).unwrap());
+    }
+
+    // Anyone else finds no key for their conversation
+    let dave_keypair = Keypair::random();
+    assert!(message
+        .decrypt_content(&dave_keypair, &alice_pubky)
+        .is_err());
+
+    // Ordinary messages keep the original envelope format
+    let single = PrivateMessage::new(&alice_keypair, &recipients[0], "Hi").unwrap();
+    let json = serde_json::to_value(&single).unwrap();
+    assert!(json.get("wrapped_keys").is_none());
+    assert!(PrivateMessage::new_multi(&alice_keypair, &[], "Nobody", 1).is_err());
+}
+
+#[test]
+fn test_sealed_envelope() {
+    let alice_keypair = Keypair::random();
+    let bob_pubky = Keypair::random().public_key();
+    let alice = PrivateMessengerClient::new(alice_keypair.clone()).unwrap();
+    let conversation_key = alice.export_conversation_key(&bob_pubky).unwrap();
+    let mut key = [0u8; 32];
+    hex::decode_to_slice(&conversation_key.key_hex, &mut key).unwrap();
+
+    let message = PrivateMessage::new_at(&alice_keypair, &bob_pubky, "Sealed", 1234).unwrap();
+
+    // Unsealed records are the JSON envelope
+    let plain = message.to_record(&key, false).unwrap();
+    assert!(plain.contains("\"timestamp\":1234"));
+    assert_eq!(
+        PrivateMessage::from_record(&plain, &key).unwrap().timestamp,
+        1234
+    );
+
+    // Sealed records hide the timestamp and signature
+    let sealed = message.to_record(&key, true).unwrap();
+    assert!(!sealed.contains("timestamp"));
+    assert!(!sealed.contains("signature"));
+    let opened = PrivateMessage::from_record(&sealed, &key).unwrap();
+    assert_eq!(opened.timestamp, 1234);
+    assert_eq!(opened.signature_bytes, message.signature_bytes);
+    assert!(PrivateMessage::from_record(&sealed, &[0u8; 32]).is_err());
+
+    // Exported conversation keys read both kinds
+    for record in [&plain, &sealed] {
+        let decrypted = conversation_key.decrypt_record("msg-1", record).unwrap();
+        assert_eq!(decrypted.content, "Sealed");
+        assert!(decrypted.verified);
+    }
+}
+
+#[test]
+fn test_cipher_suites() {
+    let key = [7u8; 32];
+    for suite in [
+        CipherSuite::XSalsa20Poly1305,
+        CipherSuite::Aes256Gcm,
+        CipherSuite::XChaCha20Poly1305,
+    ] {
+        let ciphertext = suite.encrypt(b"Hello", &key).unwrap();
+        assert_eq!(suite.decrypt(&ciphertext, &key).unwrap(), b"Hello");
+        assert!(suite.decrypt(&ciphertext, &[8u8; 32]).is_err());
+        assert_eq!(CipherSuite::parse(suite.as_str()), Some(suite));
+    }
+    assert!(CipherSuite::Aes256Gcm.decrypt(b"short", &key).is_err());
+    assert!(CipherSuite::XChaCha20Poly1305
+        .decrypt(b"short", &key)
+        .is_err());
+
+    // Nonces are prefixed to the ciphertext and never repeat
+    for (suite, nonce_len) in [
+        (CipherSuite::XSalsa20Poly1305, 24),
+        (CipherSuite::Aes256Gcm, 12),
+        (CipherSuite::XChaCha20Poly1305, 24),
+    ] {
+        assert_eq!(suite.nonce_len(), nonce_len);
+        let ciphertexts: Vec<Vec<u8>> = (0..100)
+            .map(|_| suite.encrypt(b"Same", &key).unwrap())
+            .collect();
+        // Nonce, then the sealed text and its 16-byte tag
+        assert!(ciphertexts
+            .iter()
+            .all(|ciphertext| ciphertext.len() == nonce_len + 4 + 16));
+        let nonces: std::collections::HashSet<&[u8]> = ciphertexts
+            .iter()
+            .map(|ciphertext| suite.nonce(ciphertext).unwrap())
+            .collect();
+        assert_eq!(nonces.len(), 100);
+
+        // Changing the nonce breaks decryption
+        let mut tampered = ciphertexts[0].clone();
+        tampered[0] ^= 1;
+        assert!(suite.decrypt(&tampered, &key).is_err());
+    }
+    assert_eq!(CipherSuite::Aes256Gcm.nonce(b"short"), None);
+
+    // Envelopes name the suite they were written with, unless it is the default
+    let suite = CipherSuite::Aes256Gcm;
+    let message = PrivateMessage {
+        timestamp: 1,
+        encrypted_sender: suite.encrypt(b"sender", &key).unwrap(),
+        encrypted_content: suite.encrypt(b"Hi", &key).unwrap(),
+        signature_bytes: Vec::new(),
+        wrapped_keys: Vec::new(),
+        cipher: suite,
+        direction: None,
+    };
+    let record = message.to_record(&key, false).unwrap();
+    assert!(record.contains("\"cipher\":\"aes-256-gcm\""));
+    let opened = PrivateMessage::from_record(&record, &key).unwrap();
+    assert_eq!(opened.decrypt_content_with_key(&key).unwrap(), "Hi");
+    assert_eq!(opened.nonces().len(), 2);
+
+    let keypair = Keypair::random();
+    let single = PrivateMessage::new(&keypair, &keypair.public_key(), "Hi").unwrap();
+    assert!(!serde_json::to_string(&single).unwrap().contains("cipher"));
+    assert_eq!(single.nonces().len(), 2);
+}
+
+#[test]
+fn test_per_direction_keys() {
+    let alice = Keypair::random().public_key();
+    let bob = Keypair::random().public_key();
+    let key = [7u8; 32];
+
+    // Each participant sends under a key of their own
+    let alice_to_bob = KeyDirection::of(&alice, &bob);
+    let bob_to_alice = KeyDirection::of(&bob, &alice);
+    assert_ne!(alice_to_bob, bob_to_alice);
+    assert_ne!(*alice_to_bob.key(&key), *bob_to_alice.key(&key));
+    assert_ne!(*alice_to_bob.key(&key), key);
+
+    // Readers only need the conversation key and the envelope's direction
+    let sending_key = alice_to_bob.key(&key);
+    let message = PrivateMessage {
+        timestamp: 1,
+        encrypted_sender: CipherSuite::default()
+            .encrypt(alice.to_string().as_bytes(), &sending_key)
+            .unwrap(),
+        encrypted_content: CipherSuite::default().encrypt(b"Hi", &sending_key).unwrap(),
+        signature_bytes: Vec::new(),
+        wrapped_keys: Vec::new(),
+        cipher: CipherSuite::default(),
+        direction: Some(alice_to_bob),
+    };
+    let record = message.to_record(&key, false).unwrap();
+    let opened = PrivateMessage::from_record(&record, &key).unwrap();
+    assert_eq!(opened.decrypt_content_with_key(&key).unwrap(), "Hi");
+    assert_eq!(
+        opened.decrypt_sender_with_key(&key).unwrap(),
+        alice.to_string()
+    );
+
+    // Messages without a direction are encrypted under the conversation key
+    assert!(PrivateMessage {
+        direction: None,
+        ..opened
+    }
+    .decrypt_content_with_key(&key)
+    .is_err());
+}
+
+#[test]
+fn test_batch_signature_verification() {
+    let alice_keypair = Keypair::random();
+    let bob_keypair = Keypair::random();
+    let alice_pubky = alice_keypair.public_key();
+    let bob_pubky = bob_keypair.public_key();
+
+    let mut messages: Vec<PrivateMessage> = (0..4)
+        .map(|i| {
+            PrivateMessage::new(&alice_keypair, &bob_pubky, &format!("Message {}", i)).unwrap()
+        })
+        .collect();
+    // Tamper with one signature
+    messages[2].signature_bytes[0] ^= 0xff;
+
+    let decrypted: Vec<(String, String)> = messages
+        .iter()
+        .map(|message| {
+            (
+                message.decrypt_content(&bob_keypair, &alice_pubky).unwrap(),
+                message.decrypt_sender(&bob_keypair, &alice_pubky).unwrap(),
+            )
+        })
+        .collect();
+    let entries: Vec<(&PrivateMessage, &str, &str)> = messages
+        .iter()
+        .zip(decrypted.iter())
+        .map(|(message, (content, sender))| (message, content.as_str(), sender.as_str()))
+        .collect();
+
+    assert_eq!(
+        PrivateMessage::verify_batch(&entries),
+        vec![true, true, false, true]
+    );
+    assert_eq!(
+        PrivateMessage::verify_batch(&entries[..2]),
+        vec![true, true]
+    );
+    assert!(PrivateMessage::verify_batch(&[]).is_empty());
+}
+
+#[test]
+fn test_identity_bundle_invite_links() {
+    let pubky = Keypair::random().public_key();
+    let bundle = IdentityBundle::new(&pubky, Some("Zoë & friends".to_string()));
+
+    // Eight groups of four hex digits
+    assert_eq!(bundle.fingerprint.len(), 39);
+    assert_eq!(bundle.fingerprint, IdentityBundle::fingerprint_of(&pubky));
+
+    let link = bundle.to_invite_link();
+    assert!(link.starts_with(&format!("pubky://{}?", pubky)));
+    assert!(!link.contains(' '));
+    let parsed = IdentityBundle::from_invite_link(&link).unwrap();
+    assert_eq!(parsed, bundle);
+    assert_eq!(parsed.public_key().unwrap(), pubky);
+
+    // Names and fingerprints are optional
+    let bare = IdentityBundle::from_invite_link(&format!("pubky://{}", pubky)).unwrap();
+    assert_eq!(bare.name, None);
+    assert_eq!(bare.fingerprint, bundle.fingerprint);
+
+    // A fingerprint for another key is rejected
+    let other = IdentityBundle::new(&Keypair::random().public_key(), None);
+    let tampered = format!(
+        "pubky://{}?fp={}",
+        pubky,
+        other.fingerprint.replace(' ', "")
+    );
+    assert!(IdentityBundle::from_invite_link(&tampered).is_err());
+    assert!(IdentityBundle::from_invite_link("https://example.com").is_err());
+}
+
+#[test]
+fn test_key_rotation_proof() {
+    let old = Keypair::random();
+    let new = Keypair::random();
+
+    let proof = KeyRotationProof::new(&old, &new, 1_700_000_000);
+    assert!(proof.verify().is_ok());
+    assert_eq!(proof.new_public_key().unwrap(), new.public_key());
+
+    // Rotation notices are validated like any other content
+    let notice = MessageContent::System(SystemMessage::KeyRotation(proof.clone()));
+    assert!(notice.validate().is_ok());
+    assert_eq!(MessageContent::decode(&notice.encode().unwrap()), notice);
+
+    // Every field is covered by both signatures
+    let mut redirected = proof.clone();
+    redirected.new_pubky = Keypair::random().public_key().to_string();
+    assert!(redirected.verify().is_err());
+
+    let mut backdated = proof.clone();
+    backdated.rotated_at -= 1;
+    assert!(backdated.verify().is_err());
+
+    // The new key has to take part
+    let mut unaccepted = KeyRotationProof::new(&old, &Keypair::random(), proof.rotated_at);
+    unaccepted.new_pubky = proof.new_pubky.clone();
+    assert!(unaccepted.verify().is_err());
+    assert!(SystemMessage::KeyRotation(unaccepted).validate().is_err());
+}
+
+#[test]
+fn test_pubky_uri_parsing() {
+    let pubky = Keypair::random().public_key();
+    let url = format!(
+        "pubky://{}/pub/private_messages/abc123/0001-xyz.json",
+        pubky
+    );
+
+    let uri = PubkyUri::parse(&url).unwrap();
+    assert_eq!(uri.public_key(), &pubky);
+    assert_eq!(uri.path(), "/pub/private_messages/abc123/0001-xyz.json");
+    assert_eq!(
+        uri.segments().collect::<Vec<_>>(),
+        vec!["pub", "private_messages", "abc123", "0001-xyz.json"]
+    );
+    assert_eq!(uri.file_name(), Some("0001-xyz.json"));
+    assert_eq!(
+        uri.conversation_path(),
+        Some("/pub/private_messages/abc123/")
+    );
+    assert_eq!(uri.message_id(), Some("0001-xyz"));
+    assert_eq!(uri.to_string(), url);
+
+    // Building gives the same URL
+    let dir = PubkyUri::new(&pubky, "pub/private_messages/abc123/");
+    assert!(dir.is_dir());
+    assert_eq!(dir.file_name(), None);
+    assert_eq!(dir.message_id(), None);
+    assert_eq!(dir.join("0001-xyz.json"), uri);
+
+    // Sidecar records are not messages
+    let vote = dir.join("votes/0001-xyz.json");
+    assert_eq!(
+        vote.conversation_path(),
+        Some("/pub/private_messages/abc123/")
+    );
+    assert_eq!(vote.message_id(), None);
+
+    assert!(PubkyUri::parse("https://example.com/pub/").is_err());
+    assert!(PubkyUri::parse("pubky://not-a-key/pub/").is_err());
+}
+
+#[test]
+fn test_storage_usage_near_limit() {
+    let usage = |total_bytes, quota_bytes| StorageUsage {
+        total_bytes,
+        quota_bytes,
+        ..StorageUsage::default()
+    };
+
+    assert!(!usage(u64::MAX, None).is_near_limit());
+    assert!(!usage(899, Some(1000)).is_near_limit());
+    assert!(usage(900, Some(1000)).is_near_limit());
+    assert!(usage(u64::MAX, Some(u64::MAX)).is_near_limit());
+}
+
 #[test]
 fn test_client_creation() {
     let keypair = Keypair::random();
@@ -44,7 +390,611 @@ fn test_message_id_generation() {
     // IDs should be unique
     assert_ne!(id1, id2);
 
-    // IDs should be valid UUIDs
-    assert_eq!(id1.len(), 36); // UUID v4 string length
-    assert_eq!(id2.len(), 36);
+    // IDs are a millisecond timestamp followed by a ULID
+    assert_eq!(id1.len(), 40);
+    assert!(PrivateMessage::validate_id(&id1).is_ok());
+    assert!(PrivateMessage::id_timestamp_ms(&id1).is_some());
+
+    // And sort chronologically
+    let earlier = PrivateMessage::generate_id_at(999_999_999_999);
+    let later = PrivateMessage::generate_id_at(1_000_000_000_000);
+    assert!(earlier < later);
+    assert_eq!(
+        PrivateMessage::id_timestamp_ms(&earlier),
+        Some(999_999_999_999)
+    );
+
+    // Older UUID IDs have no timestamp
+    assert_eq!(
+        PrivateMessage::id_timestamp_ms("550e8400-e29b-41d4-a716-446655440000"),
+        None
+    );
+}
+
+#[test]
+fn test_coarse_message_ids() {
+    let bucket = Duration::from_secs(300);
+    let id1 = PrivateMessage::generate_coarse_id(1_700_000_123_456, bucket);
+    let id2 = PrivateMessage::generate_coarse_id(1_700_000_234_567, bucket);
+
+    // Both the prefix and the ULID only reveal the start of the bucket
+    assert_ne!(id1, id2);
+    assert!(PrivateMessage::validate_id(&id1).is_ok());
+    for id in [&id1, &id2] {
+        assert_eq!(PrivateMessage::id_timestamp_ms(id), Some(1_700_000_100_000));
+        let ulid: ulid::Ulid = id[14..].parse().unwrap();
+        assert_eq!(ulid.timestamp_ms(), 1_700_000_100_000);
+    }
+
+    let client = PrivateMessengerClient::new(Keypair::random()).unwrap();
+    assert!(client.with_coarse_timestamps(Duration::ZERO).is_err());
+}
+
+#[test]
+fn test_message_id_validation() {
+    // Generated IDs are always valid
+    assert!(PrivateMessage::validate_id(&PrivateMessage::generate_id()).is_ok());
+    assert!(PrivateMessage::validate_id("client-supplied_id-1").is_ok());
+
+    // IDs must be safe to use as a file name
+    assert!(PrivateMessage::validate_id("").is_err());
+    assert!(PrivateMessage::validate_id("../other").is_err());
+    assert!(PrivateMessage::validate_id("a/b").is_err());
+    assert!(PrivateMessage::validate_id(&"a".repeat(129)).is_err());
+}
+
+#[test]
+fn test_conversation_key_export() {
+    let alice_keypair = Keypair::random();
+    let bob_keypair = Keypair::random();
+    let carol_keypair = Keypair::random();
+
+    let alice = PrivateMessengerClient::new(alice_keypair.clone()).unwrap();
+    let bob_pubky = bob_keypair.public_key();
+
+    // Alice exports the key of her conversation with Bob
+    let key = alice.export_conversation_key(&bob_pubky).unwrap();
+
+    // Both participants derive the same key and path
+    let bob_key = PrivateMessengerClient::new(bob_keypair)
+        .unwrap()
+        .export_conversation_key(&alice_keypair.public_key())
+        .unwrap();
+    assert_eq!(key.key_hex, bob_key.key_hex);
+    assert_eq!(key.conversation_path, bob_key.conversation_path);
+
+    // A third party can read messages from the conversation
+    let message = PrivateMessage::new(&alice_keypair, &bob_pubky, "For the auditor").unwrap();
+    let decrypted = key.decrypt_message("msg-1", &message).unwrap();
+    assert_eq!(decrypted.content, "For the auditor");
+    assert_eq!(decrypted.sender, alice_keypair.public_key().to_string());
+    assert!(decrypted.verified);
+
+    // But not messages from other conversations
+    let other =
+        PrivateMessage::new(&alice_keypair, &carol_keypair.public_key(), "Private").unwrap();
+    assert!(key.decrypt_message("msg-2", &other).is_err());
+}
+
+#[test]
+fn test_path_rotation_settings() {
+    let rotation = PathRotation::default();
+    assert_eq!(rotation.epoch_of(0), 0);
+    assert_eq!(rotation.epoch_of(rotation.epoch_seconds - 1), 0);
+    assert_eq!(rotation.epoch_of(rotation.epoch_seconds), 1);
+
+    let client = PrivateMessengerClient::new(Keypair::random()).unwrap();
+    assert!(client.with_path_rotation(rotation).is_ok());
+
+    // Epochs shorter than a day are rejected
+    let client = PrivateMessengerClient::new(Keypair::random()).unwrap();
+    let too_short = PathRotation {
+        epoch_seconds: 60,
+        lookback_epochs: 1,
+    };
+    assert!(client.with_path_rotation(too_short).is_err());
+}
+
+#[test]
+fn test_profile_verified_is_not_stored() {
+    // Profiles from the homeserver start out unverified
+    let profile: PubkyProfile = serde_json::from_str(r#"{"name":"Alice"}"#).unwrap();
+    assert!(!profile.profile_verified);
+
+    // And the flag is never written back into the profile JSON
+    let verified = PubkyProfile {
+        profile_verified: true,
+        ..profile
+    };
+    let json = serde_json::to_string(&verified).unwrap();
+    assert!(!json.contains("profile_verified"));
+}
+
+#[test]
+fn test_follow_anomalies_serialization() {
+    // Follows read without verification carry no anomalies
+    let user: FollowedUser = serde_json::from_str(r#"{"name":null,"pubky":"abc"}"#).unwrap();
+    assert!(user.anomalies.is_empty());
+    assert!(!serde_json::to_string(&user).unwrap().contains("anomalies"));
+
+    let flagged = FollowedUser {
+        anomalies: vec![FollowAnomaly::ForeignUrl, FollowAnomaly::FutureTimestamp],
+        ..user
+    };
+    let json = serde_json::to_string(&flagged).unwrap();
+    assert!(json.contains(r#""anomalies":["foreign_url","future_timestamp"]"#));
+}
+
+#[test]
+fn test_follow_anomaly_detection() {
+    let owner = Keypair::random().public_key().to_string();
+    let other = Keypair::random().public_key().to_string();
+    let now = 1_700_000_000;
+    let url = format!("pubky://{}/pub/pubky.app/follows/{}", owner, other);
+    let record = |created_at: u64| format!(r#"{{"created_at":{}}}"#, created_at);
+
+    assert!(FollowAnomaly::of_record(&owner, &url, Some(&record(now)), now).is_empty());
+    // Small clock differences are tolerated
+    assert!(FollowAnomaly::of_record(&owner, &url, Some(&record(now + 60)), now).is_empty());
+    assert_eq!(
+        FollowAnomaly::of_record(&owner, &url, Some(&record(now + 86_400)), now),
+        [FollowAnomaly::FutureTimestamp]
+    );
+    for record in [None, Some("not json"), Some(r#"{"created_at":"today"}"#)] {
+        assert_eq!(
+            FollowAnomaly::of_record(&owner, &url, record, now),
+            [FollowAnomaly::InvalidRecord]
+        );
+    }
+
+    // Records outside the owner's own follows directory
+    for foreign in [
+        format!("pubky://{}/pub/pubky.app/follows/{}", other, owner),
+        format!("pubky://{}/pub/pubky.app/follows/nested/{}", owner, other),
+        format!("pubky://{}/pub/pubky.app/posts/{}", owner, other),
+        "not a url".to_string(),
+    ] {
+        assert_eq!(
+            FollowAnomaly::of_record(&owner, &foreign, Some(&record(now)), now),
+            [FollowAnomaly::ForeignUrl]
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_nexus_settings() {
+    let client = PrivateMessengerClient::new(Keypair::random()).unwrap();
+    assert!(client.sync_follows_from_nexus().await.is_err());
+
+    assert!(PrivateMessengerClient::new(Keypair::random())
+        .unwrap()
+        .with_nexus("ftp://nexus.example.com")
+        .is_err());
+    assert!(PrivateMessengerClient::new(Keypair::random())
+        .unwrap()
+        .with_nexus("not a url")
+        .is_err());
+    assert!(PrivateMessengerClient::new(Keypair::random())
+        .unwrap()
+        .with_nexus("https://nexus.example.com/")
+        .is_ok());
+
+    let divergence = FollowDivergence::Unlisted {
+        pubky: "abc".to_string(),
+    };
+    assert_eq!(
+        serde_json::to_string(&divergence).unwrap(),
+        r#"{"kind":"unlisted","pubky":"abc"}"#
+    );
+}
+
+#[test]
+fn test_peer_unreachable_error() {
+    let config = CircuitBreakerConfig::default();
+    assert_eq!(config.failure_threshold, 3);
+    assert_eq!(config.cooldown, Duration::from_secs(30));
+
+    let client = PrivateMessengerClient::new(Keypair::random())
+        .unwrap()
+        .with_circuit_breaker(CircuitBreakerConfig {
+            failure_threshold: 1,
+            cooldown: Duration::from_secs(5),
+        });
+    assert!(!client.public_key_string().is_empty());
+
+    // The typed error survives a round trip through anyhow
+    let error: anyhow::Error = MessengerError::PeerUnreachable {
+        peer: "peer".to_string(),
+        retry_after_secs: 5,
+    }
+    .into();
+    assert!(matches!(
+        error.downcast_ref::<MessengerError>(),
+        Some(MessengerError::PeerUnreachable {
+            retry_after_secs: 5,
+            ..
+        })
+    ));
+    assert!(error.to_string().contains("unreachable"));
+}
+
+#[test]
+fn test_request_failed_error() {
+    let error: anyhow::Error = MessengerError::RequestFailed {
+        url: "pubky://bob/pub/private_messages/abc/1".to_string(),
+        owner: "bob".to_string(),
+        homeserver: Homeserver::Peer,
+        status: Some(503),
+        retry_after_secs: Some(30),
+    }
+    .into();
+    let Some(failure) = error.downcast_ref::<MessengerError>() else {
+        panic!("expected a MessengerError");
+    };
+    assert!(failure.is_retryable());
+    assert_eq!(failure.retry_after_secs(), Some(30));
+    assert_eq!(
+        error.to_string(),
+        "Homeserver of bob returned 503 for pubky://bob/pub/private_messages/abc/1, retry in 30s"
+    );
+
+    // Unreachable own homeserver: the hint points at the connection
+    let unreachable = MessengerError::RequestFailed {
+        url: "pubky://alice/pub/private_messages/abc/1".to_string(),
+        owner: "alice".to_string(),
+        homeserver: Homeserver::Ours,
+        status: None,
+        retry_after_secs: None,
+    };
+    assert!(unreachable.is_retryable());
+    assert!(unreachable
+        .to_string()
+        .starts_with("Our homeserver is unreachable"));
+    assert!(unreachable.hint().contains("connection"));
+
+    // Rejected requests aren't worth retrying
+    let rejected = MessengerError::RequestFailed {
+        url: "pubky://alice/pub/private_messages/abc/1".to_string(),
+        owner: "alice".to_string(),
+        homeserver: Homeserver::Ours,
+        status: Some(403),
+        retry_after_secs: None,
+    };
+    assert!(!rejected.is_retryable());
+    assert!(rejected.hint().contains("Sign in"));
+
+    // The typed error survives added context
+    let error =
+        anyhow::Error::new(std::io::Error::other("connection refused")).context(unreachable);
+    assert!(matches!(
+        error.downcast_ref::<MessengerError>(),
+        Some(MessengerError::RequestFailed { status: None, .. })
+    ));
+}
+
+#[test]
+fn test_stats_start_empty() {
+    let client = PrivateMessengerClient::new(Keypair::random()).unwrap();
+    let stats = client.stats();
+    assert!(stats.operations.is_empty());
+    assert_eq!(stats.bytes_sent, 0);
+    assert_eq!(stats.bytes_received, 0);
+
+    client.reset_stats();
+    assert_eq!(client.stats(), stats);
+}
+
+#[test]
+fn test_small_order_peer_key_rejected() {
+    // The identity point: a valid encoding whose shared secret is all zeros
+    let weak =
+        pkarr::PublicKey::try_from("yryyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyy").unwrap();
+    let keypair = Keypair::random();
+
+    let error = PrivateMessage::new(&keypair, &weak, "Hello").unwrap_err();
+    assert_eq!(
+        error.downcast_ref::<MessengerError>(),
+        Some(&MessengerError::InvalidPeerKey {
+            peer: weak.to_string()
+        })
+    );
+    assert!(ConversationKey::derive(&keypair, &weak).is_err());
+
+    // Ordinary keys still work
+    let peer = Keypair::random().public_key();
+    assert!(ConversationKey::derive(&keypair, &peer).is_ok());
+}
+
+#[test]
+fn test_conversation_key_hex_round_trip() {
+    for _ in 0..64 {
+        let alice = Keypair::random();
+        let bob = Keypair::random();
+        let key = ConversationKey::derive(&alice, &bob.public_key()).unwrap();
+        let other_side = ConversationKey::derive(&bob, &alice.public_key()).unwrap();
+        assert_eq!(key.key_hex, other_side.key_hex);
+
+        // Same encoding and paths as before the constant-time helpers
+        let bytes = hex::decode(&key.key_hex).unwrap();
+        assert_eq!(key.key_hex, hex::encode(&bytes));
+        assert_eq!(key.key_bytes().unwrap().to_vec(), bytes);
+        assert_eq!(
+            key.conversation_path,
+            format!(
+                "/pub/private_messages/{}/",
+                blake3::hash(key.key_hex.as_bytes()).to_hex()
+            )
+        );
+
+        let mut upper = key.clone();
+        upper.key_hex = key.key_hex.to_uppercase();
+        assert_eq!(upper.key_bytes().unwrap(), key.key_bytes().unwrap());
+    }
+
+    let mut key =
+        ConversationKey::derive(&Keypair::random(), &Keypair::random().public_key()).unwrap();
+    for invalid in [
+        "0".repeat(63),
+        "0".repeat(65),
+        format!("{}g", "0".repeat(63)),
+    ] {
+        key.key_hex = invalid;
+        assert!(key.key_bytes().is_err());
+    }
+    key.key_hex = format!("{}é", "0".repeat(62));
+    assert!(key.key_bytes().is_err());
+}
+
+#[test]
+fn test_key_hex_digits_exhaustively() {
+    // Every byte value is encoded like `hex::encode` does
+    let mut seen = [false; 256];
+    let mut keys = 0;
+    while !seen.iter().all(|&seen| seen) {
+        let key =
+            ConversationKey::derive(&Keypair::random(), &Keypair::random().public_key()).unwrap();
+        let bytes = key.key_bytes().unwrap();
+        assert_eq!(key.key_hex, hex::encode(bytes));
+        for byte in bytes {
+            seen[byte as usize] = true;
+        }
+        keys += 1;
+        assert!(keys < 10_000, "not every byte value came up");
+    }
+
+    // Every ASCII character decodes as a hex digit exactly when it is one, in
+    // either position of a byte. Other bytes only occur in multi-byte
+    // characters, which are rejected.
+    let mut key =
+        ConversationKey::derive(&Keypair::random(), &Keypair::random().public_key()).unwrap();
+    for c in (0u8..128).map(char::from) {
+        let expected = c.to_digit(16).map(|value| value as u8);
+        key.key_hex = format!("{}0{}", c, "0".repeat(62));
+        assert_eq!(
+            key.key_bytes().ok().map(|bytes| bytes[0]),
+            expected.map(|value| value << 4),
+            "{:?} as high digit",
+            c
+        );
+        key.key_hex = format!("0{}{}", c, "0".repeat(62));
+        assert_eq!(
+            key.key_bytes().ok().map(|bytes| bytes[0]),
+            expected,
+            "{:?} as low digit",
+            c
+        );
+    }
+}
+
+#[test]
+fn test_conversation_id() {
+    let alice = Keypair::random();
+    let bob = Keypair::random();
+    let id = ConversationId::direct(&alice.public_key(), &bob.public_key());
+    assert_eq!(
+        id,
+        ConversationId::direct(&bob.public_key(), &alice.public_key())
+    );
+    assert!(!id.is_group());
+    assert_eq!(id.peer_of(&alice.public_key()), Some(bob.public_key()));
+    assert_eq!(id.peer_of(&bob.public_key()), Some(alice.public_key()));
+    assert_eq!(id.peer_of(&Keypair::random().public_key()), None);
+
+    // Both participants find the conversation under the same path
+    let key = ConversationKey::derive(&alice, &bob.public_key()).unwrap();
+    assert_eq!(id.path(&alice).unwrap(), key.conversation_path);
+    assert_eq!(id.path(&bob).unwrap(), key.conversation_path);
+    assert!(id.path(&Keypair::random()).is_err());
+
+    let parsed: ConversationId = id.to_string().parse().unwrap();
+    assert_eq!(parsed, id);
+    assert!(id.to_string().starts_with("direct:"));
+
+    let client = PrivateMessengerClient::new(alice.clone()).unwrap();
+    assert_eq!(client.conversation_id(&bob.public_key()), id);
+    assert_eq!(client.conversation(&bob.public_key()).unwrap().id(), &id);
+
+    let event = NotificationEvent::MessageDeleted {
+        peer: bob.public_key().to_string(),
+        message_id: "1".to_string(),
+    };
+    assert_eq!(event.conversation_id(&alice.public_key()), Some(id));
+    let event = NotificationEvent::ConnectivityChanged {
+        state: ConnectivityState::Online,
+    };
+    assert_eq!(event.conversation_id(&alice.public_key()), None);
+}
+
+#[test]
+fn test_saved_messages_key() {
+    let keypair = Keypair::random();
+    let me = keypair.public_key();
+
+    let message = PrivateMessage::new(&keypair, &me, "Note to self").unwrap();
+    assert_eq!(
+        message.decrypt_content(&keypair, &me).unwrap(),
+        "Note to self"
+    );
+    assert_eq!(
+        message.decrypt_sender(&keypair, &me).unwrap(),
+        me.to_string()
+    );
+
+    // A key of its own, not shared with any peer
+    let saved = ConversationKey::derive(&keypair, &me).unwrap();
+
+    // The Diffie-Hellman of our key with itself, which notes to ourselves
+    // used before Saved Messages, so they stay readable
+    let hash = Sha512::digest(keypair.secret_key());
+    let mut x25519_secret = [0u8; 32];
+    x25519_secret.copy_from_slice(&hash[..32]);
+    let x25519_secret = x25519_dalek::StaticSecret::from(x25519_secret);
+    let own_x25519 = x25519_dalek::PublicKey::from(&x25519_secret);
+    assert_eq!(
+        saved.key_hex,
+        hex::encode(x25519_secret.diffie_hellman(&own_x25519).as_bytes())
+    );
+    let with_peer = ConversationKey::derive(&keypair, &Keypair::random().public_key()).unwrap();
+    assert_ne!(saved.key_hex, with_peer.key_hex);
+    assert_eq!(
+        saved.key_hex,
+        ConversationKey::derive(&keypair, &me).unwrap().key_hex
+    );
+
+    let id = ConversationId::direct(&me, &me);
+    assert!(id.is_self());
+    assert_eq!(id.peer_of(&me), Some(me.clone()));
+    assert_eq!(id.path(&keypair).unwrap(), saved.conversation_path);
+    assert!(!ConversationId::direct(&me, &Keypair::random().public_key()).is_self());
+}
+
+#[test]
+fn test_group_conversation_id() {
+    let group = ConversationId::group("67E55044-10B1-426F-9247-BB680E5FE0C8").unwrap();
+    assert!(group.is_group());
+    assert_eq!(
+        group.to_string(),
+        "group:67e55044-10b1-426f-9247-bb680e5fe0c8"
+    );
+    assert_eq!(group.to_string().parse::<ConversationId>().unwrap(), group);
+    assert_eq!(
+        serde_json::to_value(&group).unwrap(),
+        serde_json::json!({"kind": "group", "group_id": "67e55044-10b1-426f-9247-bb680e5fe0c8"})
+    );
+    assert_eq!(group.peer_of(&Keypair::random().public_key()), None);
+
+    // Every member finds the group under the same path
+    let path = group.path(&Keypair::random()).unwrap();
+    assert_eq!(group.path(&Keypair::random()).unwrap(), path);
+    assert!(path.starts_with("/pub/private_messages/"));
+
+    assert_ne!(ConversationId::new_group(), ConversationId::new_group());
+    assert!(ConversationId::group("not-a-uuid").is_err());
+    assert!("chat:abc".parse::<ConversationId>().is_err());
+}
+
+#[test]
+fn test_self_test() {
+    let client = PrivateMessengerClient::new(Keypair::random()).unwrap();
+    client.self_test().unwrap();
+
+    let client = PrivateMessengerClient::from_secret_key(&[7u8; 32]).unwrap();
+    client.self_test().unwrap();
+}
+
+#[test]
+fn test_connectivity_starts_online() {
+    let client = PrivateMessengerClient::new(Keypair::random()).unwrap();
+    assert_eq!(client.connectivity(), ConnectivityState::Online);
+    assert_eq!(ConnectivityState::default(), ConnectivityState::Online);
+
+    let event = NotificationEvent::ConnectivityChanged {
+        state: ConnectivityState::Offline,
+    };
+    assert_eq!(
+        serde_json::to_string(&event).unwrap(),
+        r#"{"type":"connectivity_changed","state":"offline"}"#
+    );
+}
+
+#[test]
+fn test_decryption_cache_settings() {
+    let config = DecryptionCacheConfig::default();
+    assert_eq!(config.capacity, 1024);
+    assert_eq!(config.ttl, Duration::from_secs(600));
+
+    // A capacity of 0 disables the cache
+    let client = PrivateMessengerClient::new(Keypair::random())
+        .unwrap()
+        .with_decryption_cache(DecryptionCacheConfig {
+            capacity: 0,
+            ..config
+        });
+    assert!(client.stats().operations.is_empty());
+}
+
+#[test]
+fn test_conversation_handle() {
+    let client = PrivateMessengerClient::new(Keypair::random()).unwrap();
+    let peer = Keypair::random().public_key();
+
+    let conversation = client.conversation(&peer).unwrap();
+    assert_eq!(conversation.peer(), &peer);
+
+    // The handle points at the same storage path as an exported key
+    let key = client.export_conversation_key(&peer).unwrap();
+    assert_eq!(conversation.path(), key.conversation_path);
+}
+
+#[test]
+fn test_app_identity() {
+    let client = PrivateMessengerClient::new(Keypair::random()).unwrap();
+    assert!(client.user_agent().starts_with("pubky-messenger/"));
+
+    // Embedding needs an identity to embed
+    assert!(PrivateMessengerClient::new(Keypair::random())
+        .unwrap()
+        .with_app_identity_in_messages()
+        .is_err());
+
+    let client = client
+        .with_app_identity("my-app", "1.4.2")
+        .unwrap()
+        .with_app_identity_in_messages()
+        .unwrap();
+    assert!(client
+        .user_agent()
+        .starts_with("my-app/1.4.2 pubky-messenger/"));
+
+    for (name, version) in [("", "1.0"), ("my app", "1.0"), ("app", "1.0\r\nX: y")] {
+        assert!(PrivateMessengerClient::new(Keypair::random())
+            .unwrap()
+            .with_app_identity(name, version)
+            .is_err());
+    }
+}
+
+#[test]
+fn test_reply_template_rendering() {
+    let template = ReplyTemplate {
+        name: "welcome".to_string(),
+        text: "Hi {name}, ticket {ticket} is open. {unknown}".to_string(),
+        updated_at: 0,
+    };
+    assert!(template.validate().is_ok());
+    assert_eq!(
+        template.render(&[("name", "Ada"), ("ticket", "#42")]),
+        "Hi Ada, ticket #42 is open. {unknown}"
+    );
+
+    let too_long = ReplyTemplate {
+        text: "x".repeat(MAX_TEMPLATE_BYTES + 1),
+        ..template.clone()
+    };
+    assert!(too_long.validate().is_err());
+    let unnamed = ReplyTemplate {
+        name: "  ".to_string(),
+        ..template
+    };
+    assert!(unnamed.validate().is_err());
 }
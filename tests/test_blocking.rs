@@ -0,0 +1,12 @@
+#![cfg(feature = "blocking")]
+
+use pubky_messenger::{BlockingMessengerClient, Keypair};
+
+#[test]
+fn test_blocking_client_creation() {
+    let keypair = Keypair::random();
+    let client = BlockingMessengerClient::new(keypair.clone()).unwrap();
+
+    assert_eq!(client.public_key_string(), keypair.public_key().to_string());
+    assert_eq!(client.client().public_key(), keypair.public_key());
+}
@@ -1,20 +1,15 @@
+#![cfg(feature = "testnet")]
+
+mod common;
+
 use anyhow::Result;
-use pubky_messenger::PrivateMessengerClient;
-use std::fs;
-
-// Helper function to load client from pkarr file
-async fn load_client(pkarr_file: &str, password: &str) -> Result<PrivateMessengerClient> {
-    let recovery_file_bytes = fs::read(pkarr_file)?;
-    let client = PrivateMessengerClient::from_recovery_file(&recovery_file_bytes, Some(password))?;
-    client.sign_in().await?;
-    Ok(client)
-}
+use common::Harness;
 
 #[tokio::test]
 async fn test_delete_message() -> Result<()> {
-    // Load both clients
-    let client1 = load_client("p1.pkarr", "password").await?;
-    let client2 = load_client("p2.pkarr", "password").await?;
+    // Start a local homeserver with two fresh identities
+    let harness = Harness::start().await?;
+    let (client1, client2) = (&harness.alice, &harness.bob);
 
     let client2_pubky = client2.public_key();
 
@@ -27,7 +22,7 @@ async fn test_delete_message() -> Result<()> {
         .duration_since(std::time::UNIX_EPOCH)?
         .as_millis();
     let message_content = format!("Test message to be deleted [{}]", timestamp);
-    let message_id = client1.send_message(&client2_pubky, &message_content).await?;
+    let message_id = client1.send_message(&client2_pubky, &message_content).await?.id;
 
     // Wait a moment for the message to be stored
     tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
@@ -74,9 +69,9 @@ async fn test_delete_message() -> Result<()> {
 
 #[tokio::test]
 async fn test_delete_messages() -> Result<()> {
-    // Load both clients
-    let client1 = load_client("p1.pkarr", "password").await?;
-    let client2 = load_client("p2.pkarr", "password").await?;
+    // Start a local homeserver with two fresh identities
+    let harness = Harness::start().await?;
+    let (client1, client2) = (&harness.alice, &harness.bob);
 
     let client2_pubky = client2.public_key();
 
@@ -96,9 +91,9 @@ async fn test_delete_messages() -> Result<()> {
     let keep_msg = format!("Message to keep [{}]", timestamp);
 
     let message_ids = vec![
-        client1.send_message(&client2_pubky, &msg1).await?,
-        client1.send_message(&client2_pubky, &msg2).await?,
-        client1.send_message(&client2_pubky, &msg3).await?,
+        client1.send_message(&client2_pubky, &msg1).await?.id,
+        client1.send_message(&client2_pubky, &msg2).await?.id,
+        client1.send_message(&client2_pubky, &msg3).await?.id,
     ];
 
     // Also send a message that won't be deleted
@@ -152,9 +147,9 @@ async fn test_delete_messages() -> Result<()> {
 
 #[tokio::test]
 async fn test_clear_messages() -> Result<()> {
-    // Load both clients
-    let client1 = load_client("p1.pkarr", "password").await?;
-    let client2 = load_client("p2.pkarr", "password").await?;
+    // Start a local homeserver with two fresh identities
+    let harness = Harness::start().await?;
+    let (client1, client2) = (&harness.alice, &harness.bob);
 
     let client2_pubky = client2.public_key();
     let client1_pubky = client1.public_key();
@@ -215,9 +210,9 @@ async fn test_clear_messages() -> Result<()> {
 
 #[tokio::test]
 async fn test_delete_non_existent_message() -> Result<()> {
-    // Load client
-    let client1 = load_client("p1.pkarr", "password").await?;
-    let client2 = load_client("p2.pkarr", "password").await?;
+    // Start a local homeserver with two fresh identities
+    let harness = Harness::start().await?;
+    let (client1, client2) = (&harness.alice, &harness.bob);
 
     let client2_pubky = client2.public_key();
 
@@ -244,8 +239,9 @@ async fn test_delete_non_existent_message() -> Result<()> {
 
 #[tokio::test]
 async fn test_clear_empty_conversation() -> Result<()> {
-    // Load clients
-    let client1 = load_client("p1.pkarr", "password").await?;
+    // Start a local homeserver
+    let harness = Harness::start().await?;
+    let client1 = &harness.alice;
 
     // Create a random keypair for a conversation that definitely has no messages
     let random_keypair = pkarr::Keypair::random();
@@ -264,9 +260,9 @@ async fn test_clear_empty_conversation() -> Result<()> {
 
 #[tokio::test]
 async fn test_delete_messages_with_empty_list() -> Result<()> {
-    // Load client
-    let client1 = load_client("p1.pkarr", "password").await?;
-    let client2 = load_client("p2.pkarr", "password").await?;
+    // Start a local homeserver with two fresh identities
+    let harness = Harness::start().await?;
+    let (client1, client2) = (&harness.alice, &harness.bob);
 
     let client2_pubky = client2.public_key();
 
@@ -284,14 +280,14 @@ async fn test_delete_messages_with_empty_list() -> Result<()> {
 
 #[tokio::test]
 async fn test_delete_mixed_valid_invalid_ids() -> Result<()> {
-    // Load both clients
-    let client1 = load_client("p1.pkarr", "password").await?;
-    let client2 = load_client("p2.pkarr", "password").await?;
+    // Start a local homeserver with two fresh identities
+    let harness = Harness::start().await?;
+    let (client1, client2) = (&harness.alice, &harness.bob);
 
     let client2_pubky = client2.public_key();
 
     // Send a real message
-    let valid_id = client1.send_message(&client2_pubky, "Valid message").await?;
+    let valid_id = client1.send_message(&client2_pubky, "Valid message").await?.id;
 
     // Mix valid and invalid IDs
     let mixed_ids = vec![
@@ -0,0 +1,1713 @@
+#![cfg(feature = "testnet")]
+
+mod common;
+
+use anyhow::Result;
+use common::Harness;
+use futures::StreamExt;
+use pubky_messenger::{
+    Bot, BotContext, CipherSuite, CircuitBreakerConfig, ConversationEvent, ConversationKey,
+    DecryptedMessage, DeliveryState, HealthStatus, Keypair, ListOptions, MessageContent,
+    MessageMiddleware, MessengerError, NotificationEvent, PathRotation, PollingConfig,
+    PrivateMessage, PrivateMessengerClient, PubkyProfile, PublicKey, SenderActivity, SpamFilter,
+    SpamVerdict, StickerSource, SyncService, TrustLevel, MAX_STICKER_BYTES,
+};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+fn temp_store_path() -> PathBuf {
+    std::env::temp_dir().join(format!("pubky-messenger-test-{}.db", uuid::Uuid::new_v4()))
+}
+
+fn unix_now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+#[tokio::test]
+async fn test_send_and_fetch_both_ways() -> Result<()> {
+    let harness = Harness::start().await?;
+    let (alice, bob) = (&harness.alice, &harness.bob);
+
+    let sent = alice.send_message(&bob.public_key(), "Hello Bob!").await?;
+    bob.send_message(&alice.public_key(), "Hi Alice!").await?;
+
+    // Both sides see the whole conversation, verified and in order
+    for (client, peer) in [(alice, bob.public_key()), (bob, alice.public_key())] {
+        let messages = client.get_messages(&peer).await?;
+        let contents: Vec<&str> = messages.iter().map(|m| m.content.as_str()).collect();
+        assert_eq!(contents.len(), 2);
+        assert!(contents.contains(&"Hello Bob!"));
+        assert!(contents.contains(&"Hi Alice!"));
+        assert!(messages.iter().all(|message| message.verified));
+    }
+
+    // The sender is decrypted from the envelope
+    let messages = bob.get_messages(&alice.public_key()).await?;
+    let received = messages
+        .iter()
+        .find(|message| message.id == sent.id)
+        .expect("sent message is fetched");
+    assert_eq!(received.sender, alice.public_key_string());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_list_message_page_newest_first() -> Result<()> {
+    let harness = Harness::start().await?;
+    let (alice, bob) = (&harness.alice, &harness.bob);
+
+    let mut sent = Vec::new();
+    for content in ["One", "Two", "Three"] {
+        sent.push(alice.send_message(&bob.public_key(), content).await?.id);
+    }
+    sent.push(bob.send_message(&alice.public_key(), "Four").await?.id);
+
+    // Both sides are merged, newest first
+    let mut options = ListOptions {
+        limit: Some(3),
+        reverse: true,
+        ..ListOptions::default()
+    };
+    let page = bob.list_message_page(&alice.public_key(), &options).await?;
+    let ids: Vec<&str> = page.iter().map(|entry| entry.id.as_str()).collect();
+    assert_eq!(ids, [sent[3].as_str(), sent[2].as_str(), sent[1].as_str()]);
+
+    // The cursor picks up where the page ended
+    options.cursor = page.last().map(|entry| entry.id.clone());
+    let page = bob.list_message_page(&alice.public_key(), &options).await?;
+    let ids: Vec<&str> = page.iter().map(|entry| entry.id.as_str()).collect();
+    assert_eq!(ids, [sent[0].as_str()]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_list_message_page_limits_the_listing() -> Result<()> {
+    let harness = Harness::start().await?;
+    let (alice, bob) = (&harness.alice, &harness.bob);
+
+    bob.send_message(&alice.public_key(), "First").await?;
+    for i in 0..6 {
+        alice
+            .send_message(&bob.public_key(), &format!("Message {}", i))
+            .await?;
+    }
+
+    // Each side only lists as many entries as the page holds
+    let received = bob.stats().bytes_received;
+    let options = ListOptions {
+        limit: Some(2),
+        reverse: true,
+        ..ListOptions::default()
+    };
+    let page = bob.list_message_page(&alice.public_key(), &options).await?;
+    assert_eq!(page.len(), 2);
+    let listed = bob.stats().bytes_received - received;
+    assert!(listed <= 4 * page[0].url.len() as u64, "{}", listed);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_list_conversations_summarizes_each_peer_once() -> Result<()> {
+    let harness = Harness::start().await?;
+    let (alice, bob) = (&harness.alice, &harness.bob);
+
+    alice.send_message(&bob.public_key(), "First").await?;
+    bob.send_message(&alice.public_key(), "Latest").await?;
+
+    let peers = [bob.public_key(), bob.public_key()];
+    let summaries: Vec<_> = alice.list_conversations(&peers).collect().await;
+    assert_eq!(summaries.len(), 1);
+
+    let summary = summaries.into_iter().next().unwrap()?;
+    assert_eq!(summary.peer, bob.public_key_string());
+    let last = summary.last_message.expect("conversation has messages");
+    assert_eq!(last.content, "Latest");
+    assert_eq!(last.sender, bob.public_key_string());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_saved_messages_conversation() -> Result<()> {
+    let harness = Harness::start().await?;
+    let (alice, bob) = (&harness.alice, &harness.bob);
+    let me = alice.public_key();
+
+    alice.send_message(&me, "Buy milk").await?;
+    alice.send_message(&me, "Call Bob").await?;
+
+    // Our one side of the conversation is listed once
+    let messages = alice.get_messages(&me).await?;
+    let contents: Vec<&str> = messages.iter().map(|m| m.content.as_str()).collect();
+    assert_eq!(contents, ["Buy milk", "Call Bob"]);
+    assert!(messages
+        .iter()
+        .all(|m| m.verified && m.sender == me.to_string()));
+    assert!(alice.conversation_id(&me).is_self());
+
+    // Saved Messages joins the chat list without being asked for
+    bob.send_message(&me, "Hi").await?;
+    let summaries: Vec<_> = alice
+        .list_conversations(&[bob.public_key()])
+        .collect()
+        .await;
+    assert_eq!(summaries.len(), 2);
+    let saved = summaries
+        .into_iter()
+        .map(|summary| summary.unwrap())
+        .find(|summary| summary.saved_messages)
+        .expect("Saved Messages is listed");
+    assert_eq!(saved.peer, me.to_string());
+    assert_eq!(saved.last_message.unwrap().content, "Call Bob");
+
+    // ...but not while it is empty
+    let summaries: Vec<_> = bob.list_conversations(&[me.clone()]).collect().await;
+    assert_eq!(summaries.len(), 1);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_get_recent_messages_fetches_newest() -> Result<()> {
+    let harness = Harness::start().await?;
+    let (alice, bob) = (&harness.alice, &harness.bob);
+
+    for content in ["One", "Two", "Three"] {
+        alice.send_message(&bob.public_key(), content).await?;
+    }
+    bob.send_message(&alice.public_key(), "Four").await?;
+
+    let recent = bob.get_recent_messages(&alice.public_key(), 2).await?;
+    let contents: Vec<&str> = recent.iter().map(|m| m.content.as_str()).collect();
+    assert_eq!(contents, ["Three", "Four"]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_warm_conversation_writes_nothing() -> Result<()> {
+    let Harness {
+        testnet: _testnet,
+        alice,
+        bob,
+    } = Harness::start().await?;
+    let bob = bob.with_local_store(temp_store_path())?;
+
+    alice.send_message(&bob.public_key(), "Warm up").await?;
+    bob.warm_conversation(&alice.public_key()).await?;
+    assert!(bob.search_messages("warm", 10)?.is_empty());
+
+    let recent = bob.get_recent_messages(&alice.public_key(), 10).await?;
+    assert_eq!(recent.len(), 1);
+    assert_eq!(bob.search_messages("warm", 10)?.len(), 1);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_typed_content_round_trip() -> Result<()> {
+    let harness = Harness::start().await?;
+    let (alice, bob) = (&harness.alice, &harness.bob);
+
+    let poll = alice
+        .create_poll(
+            &bob.public_key(),
+            "Lunch?",
+            vec!["Pizza".to_string(), "Sushi".to_string()],
+        )
+        .await?;
+    bob.vote_on_poll(&alice.public_key(), &poll.id, 1).await?;
+
+    let results = alice.get_poll_results(&bob.public_key(), &poll.id).await?;
+    assert_eq!(results.counts, vec![0, 1]);
+
+    let messages = bob.get_messages(&alice.public_key()).await?;
+    assert_eq!(messages.len(), 1);
+    assert!(matches!(messages[0].body, MessageContent::Poll(_)));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_poll_results_skip_malformed_votes() -> Result<()> {
+    let harness = Harness::start().await?;
+    let pubky_client = harness.testnet.pubky_client_builder().build()?;
+    let keypair = Keypair::random();
+    let mallory = PrivateMessengerClient::from_pubky_client(keypair.clone(), pubky_client.clone());
+    mallory
+        .sign_up(&harness.testnet.homeserver_suite().public_key(), None)
+        .await?;
+    let alice = &harness.alice;
+
+    let poll = alice
+        .create_poll(
+            &mallory.public_key(),
+            "Lunch?",
+            vec!["Pizza".to_string(), "Sushi".to_string()],
+        )
+        .await?;
+    alice
+        .vote_on_poll(&mallory.public_key(), &poll.id, 0)
+        .await?;
+
+    // A vote record that doesn't decrypt
+    let key = ConversationKey::derive(&keypair, &alice.public_key())?;
+    let vote_url = format!(
+        "pubky://{}{}votes/{}.json",
+        mallory.public_key(),
+        key.conversation_path,
+        poll.id
+    );
+    pubky_client
+        .put(&vote_url)
+        .body("not a vote")
+        .send()
+        .await?
+        .error_for_status()?;
+
+    let results = alice
+        .get_poll_results(&mallory.public_key(), &poll.id)
+        .await?;
+    assert_eq!(results.counts, vec![1, 0]);
+    assert_eq!(results.votes.len(), 1);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_long_histories_decrypt_in_order() -> Result<()> {
+    let harness = Harness::start().await?;
+    let pubky_client = harness.testnet.pubky_client_builder().build()?;
+    let keypair = Keypair::random();
+    let mallory = PrivateMessengerClient::from_pubky_client(keypair.clone(), pubky_client.clone());
+    mallory
+        .sign_up(&harness.testnet.homeserver_suite().public_key(), None)
+        .await?;
+    let bob = &harness.bob;
+
+    // Enough messages to be split across the decryption workers
+    let start = unix_now() - 1000;
+    let history: Vec<(u64, String)> = (0..60)
+        .map(|i| (start + i, format!("Message {}", i)))
+        .collect();
+    mallory.import_messages(&bob.public_key(), history).await?;
+
+    // A record that doesn't parse, and one with a bad signature
+    let key = ConversationKey::derive(&keypair, &bob.public_key())?;
+    let conversation_url = format!("pubky://{}{}", mallory.public_key(), key.conversation_path);
+    pubky_client
+        .put(format!(
+            "{}{}.json",
+            conversation_url,
+            PrivateMessage::generate_id_at((start + 30) * 1000)
+        ))
+        .body("not a message")
+        .send()
+        .await?
+        .error_for_status()?;
+    let key_bytes = key.key_bytes()?;
+    let suite = CipherSuite::default();
+    let forged = PrivateMessage {
+        timestamp: start + 60,
+        encrypted_sender: suite.encrypt(mallory.public_key().to_string().as_bytes(), &key_bytes)?,
+        encrypted_content: suite.encrypt(b"Forged", &key_bytes)?,
+        signature_bytes: vec![0; 64],
+        wrapped_keys: Vec::new(),
+        cipher: suite,
+        direction: None,
+    };
+    pubky_client
+        .put(format!(
+            "{}{}.json",
+            conversation_url,
+            PrivateMessage::generate_id_at((start + 60) * 1000)
+        ))
+        .body(forged.to_record(&key_bytes, false)?)
+        .send()
+        .await?
+        .error_for_status()?;
+
+    let mut expected: Vec<(String, bool)> =
+        (0..60).map(|i| (format!("Message {}", i), true)).collect();
+    expected.push(("Forged".to_string(), false));
+
+    // The second fetch is served from the decryption cache
+    for _ in 0..2 {
+        let messages = bob.get_messages(&mallory.public_key()).await?;
+        let received: Vec<(String, bool)> = messages
+            .iter()
+            .map(|message| (message.content.clone(), message.verified))
+            .collect();
+        assert_eq!(received, expected);
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_panic_wipe_removes_everything() -> Result<()> {
+    let Harness {
+        testnet,
+        alice,
+        bob,
+    } = Harness::start().await?;
+    let store_path = temp_store_path();
+    let alice = alice.with_local_store(&store_path)?;
+
+    alice
+        .send_message(&bob.public_key(), "Burn after reading")
+        .await?;
+    alice.get_messages(&bob.public_key()).await?;
+    assert!(store_path.exists());
+
+    // More records than one listing page holds
+    let start = unix_now() - 1000;
+    let history: Vec<(u64, String)> = (0..120)
+        .map(|i| (start + i, format!("Message {}", i)))
+        .collect();
+    alice.import_messages(&bob.public_key(), history).await?;
+
+    let alice_pubky = alice.public_key();
+    alice.panic_wipe(true).await?;
+    assert!(!store_path.exists());
+    assert!(bob.get_messages(&alice_pubky).await?.is_empty());
+
+    // Every step runs, and every failure is reported
+    let store_path = temp_store_path();
+    let stranger = PrivateMessengerClient::from_pubky_client(
+        Keypair::random(),
+        testnet.pubky_client_builder().build()?,
+    )
+    .with_local_store(&store_path)?;
+    std::fs::remove_file(&store_path)?;
+    let error = stranger.panic_wipe(false).await.unwrap_err().to_string();
+    assert!(error.contains("sign out"), "{}", error);
+    assert!(error.contains("local store"), "{}", error);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_only_transient_upload_failures_are_retried() -> Result<()> {
+    let harness = Harness::start().await?;
+    let (alice, bob) = (&harness.alice, &harness.bob);
+    let puts = |client: &PrivateMessengerClient| {
+        client
+            .stats()
+            .operations
+            .get("put")
+            .map_or(0, |stats| stats.count)
+    };
+
+    // Uploads the homeserver rejects fail on the first attempt
+    let keypair = Keypair::random();
+    let signed_up = PrivateMessengerClient::from_pubky_client(
+        keypair.clone(),
+        harness.testnet.pubky_client_builder().build()?,
+    );
+    signed_up
+        .sign_up(&harness.testnet.homeserver_suite().public_key(), None)
+        .await?;
+    let no_session = PrivateMessengerClient::from_pubky_client(
+        keypair,
+        harness.testnet.pubky_client_builder().build()?,
+    );
+    assert!(no_session
+        .send_message(&bob.public_key(), "Hi")
+        .await
+        .is_err());
+    assert_eq!(puts(&no_session), 1);
+
+    // Uploads that don't get through are resent, then given up on
+    let stranger = harness.stranger()?;
+    assert!(stranger
+        .send_message(&bob.public_key(), "Hi")
+        .await
+        .is_err());
+    assert_eq!(puts(&stranger), 3);
+
+    // A message resent under its ID is stored once
+    let sent = alice.send_message(&bob.public_key(), "Once").await?;
+    alice
+        .send_message_with_id(&bob.public_key(), "Once", &sent.id)
+        .await?;
+    assert_eq!(bob.get_messages(&alice.public_key()).await?.len(), 1);
+
+    // Imports go through the same uploads, and are counted like sends
+    let before = puts(alice);
+    let history: Vec<(u64, String)> = (0..3)
+        .map(|i| (unix_now() - 100 + i, format!("Imported {}", i)))
+        .collect();
+    let progress = alice.import_messages(&bob.public_key(), history).await?;
+    assert_eq!(progress.uploaded, 3);
+    assert_eq!(puts(alice), before + 3);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_delete_removes_message_for_both_sides() -> Result<()> {
+    let harness = Harness::start().await?;
+    let (alice, bob) = (&harness.alice, &harness.bob);
+
+    let sent = alice.send_message(&bob.public_key(), "Oops").await?;
+    assert_eq!(bob.get_messages(&alice.public_key()).await?.len(), 1);
+
+    alice.delete_message(&sent.id, &bob.public_key()).await?;
+
+    assert!(alice.get_messages(&bob.public_key()).await?.is_empty());
+    assert!(bob.get_messages(&alice.public_key()).await?.is_empty());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_delete_messages_between_matches_id_times() -> Result<()> {
+    let harness = Harness::start().await?;
+    let (alice, bob) = (&harness.alice, &harness.bob);
+    let peer = bob.public_key();
+
+    let before = alice.send_message_at(&peer, "Before", 1_999).await?;
+    let first = alice.send_message_at(&peer, "First", 2_000).await?;
+    let last = alice.send_message_at(&peer, "Last", 3_000).await?;
+    let after = alice.send_message_at(&peer, "After", 3_001).await?;
+    // IDs without a send time, as stored before IDs were timestamp-prefixed
+    let legacy = alice
+        .send_message_with_id(&peer, "Legacy", "6f9619ff-8b86-d011-b42d-00cf4fc964ff")
+        .await?;
+
+    // Both bounds are inclusive, to the second
+    let mut deleted = alice.delete_messages_between(&peer, 2_000, 3_000).await?;
+    deleted.sort();
+    let mut expected = vec![first.id, last.id];
+    expected.sort();
+    assert_eq!(deleted, expected);
+
+    // Legacy IDs are matched by their envelope timestamp
+    let now = unix_now();
+    let deleted = alice
+        .delete_messages_between(&peer, now - 600, now + 600)
+        .await?;
+    assert_eq!(deleted, vec![legacy.id]);
+
+    let mut remaining: Vec<String> = alice
+        .get_messages(&peer)
+        .await?
+        .into_iter()
+        .map(|message| message.id)
+        .collect();
+    remaining.sort();
+    let mut expected = vec![before.id, after.id];
+    expected.sort();
+    assert_eq!(remaining, expected);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_rotation_finds_records_on_the_static_path() -> Result<()> {
+    let Harness {
+        testnet: _testnet,
+        alice,
+        bob,
+    } = Harness::start().await?;
+
+    // Written before Alice turned rotation on, and by Bob who never does
+    let early = alice
+        .send_message(&bob.public_key(), "Before rotation")
+        .await?;
+    let poll = bob
+        .create_poll(
+            &alice.public_key(),
+            "Lunch?",
+            vec!["Yes".to_string(), "No".to_string()],
+        )
+        .await?;
+
+    let alice = alice.with_path_rotation(PathRotation::default())?;
+    alice.vote_on_poll(&bob.public_key(), &poll.id, 0).await?;
+    bob.vote_on_poll(&alice.public_key(), &poll.id, 1).await?;
+    for (client, peer) in [(&alice, bob.public_key()), (&bob, alice.public_key())] {
+        let results = client.get_poll_results(&peer, &poll.id).await?;
+        assert_eq!(results.counts, vec![1, 1]);
+    }
+
+    alice.delete_message(&early.id, &bob.public_key()).await?;
+    let remaining = bob.get_messages(&alice.public_key()).await?;
+    assert!(remaining.iter().all(|message| message.id != early.id));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_clear_messages_with_rotation_reads_every_page() -> Result<()> {
+    let harness = Harness::start().await?;
+    let stranger = harness.stranger()?;
+    let alice = harness.alice.with_path_rotation(PathRotation::default())?;
+    let bob = harness.bob.with_path_rotation(PathRotation::default())?;
+
+    // More records than one listing page holds, next to another conversation
+    let start = unix_now() - 1000;
+    let history: Vec<(u64, String)> = (0..120)
+        .map(|i| (start + i, format!("Message {}", i)))
+        .collect();
+    alice.import_messages(&bob.public_key(), history).await?;
+    alice
+        .send_message(&stranger.public_key(), "Keep me")
+        .await?;
+
+    alice.clear_messages(&bob.public_key()).await?;
+    assert!(bob.get_messages(&alice.public_key()).await?.is_empty());
+    assert_eq!(stranger.get_messages(&alice.public_key()).await?.len(), 1);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_sync_conversation_returns_deltas() -> Result<()> {
+    let Harness {
+        testnet: _testnet,
+        alice,
+        bob,
+    } = Harness::start().await?;
+    let bob = bob.with_local_store(temp_store_path())?;
+
+    let old = alice
+        .send_message_at(&bob.public_key(), "Old", unix_now() - 7 * 24 * 60 * 60)
+        .await?;
+    let first = alice.send_message(&bob.public_key(), "First").await?;
+
+    // The first sync reports the whole conversation
+    let delta = bob.sync_conversation(&alice.public_key()).await?;
+    assert!(delta.initial);
+    assert_eq!(delta.new_messages.len(), 2);
+    assert_eq!(delta.new_messages[0].id, old.id);
+    assert_eq!(delta.cursor.as_deref(), Some(first.id.as_str()));
+
+    // Nothing changed, nothing reported, although later syncs only list
+    // from the cursor and no longer see the old message
+    let delta = bob.sync_conversation(&alice.public_key()).await?;
+    assert!(!delta.initial);
+    assert!(delta.new_messages.is_empty());
+    assert!(delta.edited_messages.is_empty());
+    assert!(delta.deleted_ids.is_empty());
+
+    // New, rewritten, and deleted records show up in the next delta
+    let second = alice.send_message(&bob.public_key(), "Second").await?;
+    alice
+        .send_message_with_id(&bob.public_key(), "First, edited", &first.id)
+        .await?;
+    let delta = bob.sync_conversation(&alice.public_key()).await?;
+    assert_eq!(delta.new_messages.len(), 1);
+    assert_eq!(delta.new_messages[0].id, second.id);
+    assert_eq!(delta.edited_messages.len(), 1);
+    assert_eq!(delta.edited_messages[0].content, "First, edited");
+
+    alice.delete_message(&second.id, &bob.public_key()).await?;
+    let delta = bob.sync_conversation(&alice.public_key()).await?;
+    assert_eq!(delta.deleted_ids, vec![second.id]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_sync_cleans_up_remote_deletions() -> Result<()> {
+    let Harness {
+        testnet: _testnet,
+        alice,
+        bob,
+    } = Harness::start().await?;
+    let bob = bob.with_local_store(temp_store_path())?;
+
+    let sent = alice.send_message(&bob.public_key(), "Regrettable").await?;
+    bob.sync_conversation(&alice.public_key()).await?;
+    assert_eq!(bob.search_messages("regrettable", 10)?.len(), 1);
+
+    let mut events = bob.subscribe();
+    alice.delete_message(&sent.id, &bob.public_key()).await?;
+
+    // Alice's side now lists nothing, which is only trusted the second time
+    let delta = bob.sync_conversation(&alice.public_key()).await?;
+    assert!(delta.deleted_ids.is_empty());
+    assert!(events.try_recv().is_err());
+    assert_eq!(bob.search_messages("regrettable", 10)?.len(), 1);
+    bob.sync_conversation(&alice.public_key()).await?;
+
+    // Subscribers hear about it and the local copy is gone
+    assert_eq!(
+        events.try_recv()?,
+        NotificationEvent::MessageDeleted {
+            peer: alice.public_key_string(),
+            message_id: sent.id,
+        }
+    );
+    assert!(bob.search_messages("regrettable", 10)?.is_empty());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_conversation_timeline_follows_sync() -> Result<()> {
+    let Harness {
+        testnet: _testnet,
+        alice,
+        bob,
+    } = Harness::start().await?;
+    let bob = bob.with_local_store(temp_store_path())?;
+    let mut timeline = bob.subscribe_conversation(&alice.public_key());
+
+    let sent = alice.send_message(&bob.public_key(), "Hi").await?;
+    bob.sync_conversation(&alice.public_key()).await?;
+    assert!(matches!(
+        timeline.next().await,
+        Some(ConversationEvent::Message { message }) if message.id == sent.id
+    ));
+
+    // Emptying Alice's side takes a second sync to confirm
+    alice.delete_message(&sent.id, &bob.public_key()).await?;
+    bob.sync_conversation(&alice.public_key()).await?;
+    bob.sync_conversation(&alice.public_key()).await?;
+    assert!(matches!(
+        timeline.next().await,
+        Some(ConversationEvent::Deleted { message_id }) if message_id == sent.id
+    ));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_event_queue_keeps_events_until_acked() -> Result<()> {
+    let Harness {
+        testnet: _testnet,
+        alice,
+        bob,
+    } = Harness::start().await?;
+    let bob = bob
+        .with_local_store(temp_store_path())?
+        .with_event_queue()?;
+
+    let sent = alice.send_message(&bob.public_key(), "Queued").await?;
+    bob.sync_conversation(&alice.public_key()).await?;
+
+    // Nobody was subscribed, but the event waits in the queue
+    for _ in 0..2 {
+        let events = bob.pending_events(10)?;
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].peer, alice.public_key_string());
+        assert!(matches!(
+            &events[0].event,
+            ConversationEvent::Message { message } if message.id == sent.id
+        ));
+    }
+
+    let seq = bob.pending_events(10)?[0].seq;
+    assert_eq!(bob.ack_events(seq)?, 1);
+    assert!(bob.pending_events(10)?.is_empty());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_fetch_markers_tell_sender_history_was_pulled() -> Result<()> {
+    let Harness {
+        testnet: _testnet,
+        alice,
+        bob,
+    } = Harness::start().await?;
+    let bob = bob.with_fetch_markers();
+
+    let sent = alice
+        .send_message(&bob.public_key(), "Did you get this?")
+        .await?;
+    assert_eq!(alice.peer_last_fetched(&bob.public_key()).await?, None);
+
+    bob.get_messages(&alice.public_key()).await?;
+    assert_eq!(
+        alice.peer_last_fetched(&bob.public_key()).await?,
+        Some(sent.timestamp)
+    );
+
+    // Markers are opt-in
+    alice.get_messages(&bob.public_key()).await?;
+    assert_eq!(bob.peer_last_fetched(&alice.public_key()).await?, None);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_delivery_states_follow_fetch_markers() -> Result<()> {
+    let Harness {
+        testnet: _testnet,
+        alice,
+        bob,
+    } = Harness::start().await?;
+    let alice = alice
+        .with_local_store(temp_store_path())?
+        .with_fetch_markers();
+    let bob = bob.with_fetch_markers();
+
+    let mut events = alice.subscribe();
+    let sent = alice.send_message(&bob.public_key(), "Ping").await?;
+    assert_eq!(alice.message_state(&sent.id)?, Some(DeliveryState::Stored));
+    for state in [
+        DeliveryState::Queued,
+        DeliveryState::Uploading,
+        DeliveryState::Stored,
+    ] {
+        assert_eq!(
+            events.try_recv()?,
+            NotificationEvent::DeliveryStateChanged {
+                peer: bob.public_key_string(),
+                message_id: sent.id.clone(),
+                state,
+            }
+        );
+    }
+
+    // Bob's fetch marker shows up on Alice's next sync
+    bob.get_messages(&alice.public_key()).await?;
+    alice.sync_conversation(&bob.public_key()).await?;
+    assert_eq!(
+        alice.message_state(&sent.id)?,
+        Some(DeliveryState::Delivered)
+    );
+    assert_eq!(alice.message_state("unknown")?, None);
+
+    // Sending the same message again doesn't take its state back
+    let mut events = alice.subscribe();
+    alice
+        .send_message_with_id(&bob.public_key(), "Ping", &sent.id)
+        .await?;
+    assert_eq!(
+        alice.message_state(&sent.id)?,
+        Some(DeliveryState::Delivered)
+    );
+    assert!(!std::iter::from_fn(|| events.try_recv().ok())
+        .any(|event| matches!(event, NotificationEvent::DeliveryStateChanged { .. })));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_verified_follows_have_no_anomalies() -> Result<()> {
+    let Harness {
+        testnet: _testnet,
+        alice,
+        bob,
+    } = Harness::start().await?;
+    let bob = bob.with_follow_verification();
+
+    alice.put_follow(&bob.public_key_string()).await?;
+
+    let follows = bob
+        .get_followed_users_for(&alice.public_key_string())
+        .await?;
+    assert_eq!(follows.len(), 1);
+    assert_eq!(follows[0].pubky, bob.public_key_string());
+    assert!(follows[0].anomalies.is_empty());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_key_rotation_carries_trust_to_new_identity() -> Result<()> {
+    let Harness {
+        testnet: _testnet,
+        alice,
+        bob,
+    } = Harness::start().await?;
+    let bob = bob.with_local_store(temp_store_path())?;
+    bob.set_trust(&alice.public_key(), TrustLevel::Verified)?;
+
+    let new_keypair = Keypair::random();
+    let proof = alice
+        .rotate_identity(&new_keypair, &[bob.public_key()])
+        .await?;
+    assert_eq!(proof.new_pubky, new_keypair.public_key().to_string());
+
+    // The proof is published for anyone to check
+    assert_eq!(
+        bob.fetch_key_rotation(&alice.public_key()).await?,
+        Some(proof.clone())
+    );
+    assert_eq!(bob.fetch_key_rotation(&bob.public_key()).await?, None);
+
+    // Fetching the notice records the rotation once and carries trust over
+    let mut events = bob.subscribe();
+    bob.get_messages(&alice.public_key()).await?;
+    bob.get_messages(&alice.public_key()).await?;
+    assert_eq!(
+        events.try_recv()?,
+        NotificationEvent::KeyRotated {
+            peer: alice.public_key_string(),
+            new_pubky: proof.new_pubky.clone(),
+        }
+    );
+    assert!(events.try_recv().is_err());
+    assert_eq!(
+        bob.key_successor(&alice.public_key())?,
+        Some(new_keypair.public_key())
+    );
+    assert_eq!(
+        bob.trust_level(&new_keypair.public_key())?,
+        TrustLevel::Verified
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_system_messages_change_conversation_settings() -> Result<()> {
+    let Harness {
+        testnet: _testnet,
+        alice,
+        bob,
+    } = Harness::start().await?;
+    let alice = alice.with_local_store(temp_store_path())?;
+    let bob = bob.with_local_store(temp_store_path())?;
+
+    alice.set_message_expiry(&bob.public_key(), Some(7)).await?;
+    assert_eq!(
+        alice.conversation_settings(&bob.public_key())?["expires_in_days"],
+        7
+    );
+
+    // The peer's client applies the change when it fetches the notice
+    let messages = bob.get_messages(&alice.public_key()).await?;
+    assert!(messages[0].body.is_system());
+    assert_eq!(
+        bob.conversation_settings(&alice.public_key())?["expires_in_days"],
+        7
+    );
+
+    // and new messages from either side pick up the expiry, however they
+    // are sent
+    let sent = [
+        bob.message(&alice.public_key())
+            .text("Gone in a week")
+            .send()
+            .await?,
+        bob.send_message(&alice.public_key(), "Also gone").await?,
+        alice
+            .send_content(&bob.public_key(), &MessageContent::text("Me too"))
+            .await?,
+    ];
+    let messages = alice.get_messages(&bob.public_key()).await?;
+    for sent in sent.iter() {
+        let received = messages
+            .iter()
+            .find(|message| message.id == sent.id)
+            .expect("sent message is fetched");
+        assert!(received.expires_at.is_some());
+    }
+    // The notice itself stays
+    assert!(messages
+        .iter()
+        .any(|message| message.body.is_system() && message.expires_at.is_none()));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_message_requests_hold_strangers_until_accepted() -> Result<()> {
+    let Harness {
+        testnet: _testnet,
+        alice,
+        bob,
+    } = Harness::start().await?;
+    let bob = bob
+        .with_local_store(temp_store_path())?
+        .with_message_requests()?;
+
+    alice
+        .send_message(&bob.public_key(), "Hi, we met at the meetup")
+        .await?;
+
+    // A stranger's conversation lands in the requests
+    let messages = bob.get_messages(&alice.public_key()).await?;
+    assert!(messages[0].message_request);
+    let requests = bob.list_message_requests()?;
+    assert_eq!(requests.len(), 1);
+    assert_eq!(requests[0].peer, alice.public_key_string());
+
+    // Declining drops their messages
+    bob.decline_message_request(&alice.public_key())?;
+    assert!(bob.get_messages(&alice.public_key()).await?.is_empty());
+    assert!(bob.list_message_requests()?.is_empty());
+
+    // Accepting makes it a normal conversation
+    bob.accept_message_request(&alice.public_key())?;
+    let messages = bob.get_messages(&alice.public_key()).await?;
+    assert_eq!(messages.len(), 1);
+    assert!(!messages[0].message_request);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_replying_accepts_a_message_request() -> Result<()> {
+    let Harness {
+        testnet: _testnet,
+        alice,
+        bob,
+    } = Harness::start().await?;
+    let alice = alice.with_local_store(temp_store_path())?;
+    let bob = bob
+        .with_local_store(temp_store_path())?
+        .with_message_requests()?;
+
+    alice
+        .send_message(&bob.public_key(), "Hi, we met at the meetup")
+        .await?;
+    alice
+        .set_conversation_setting(&bob.public_key(), "theme", serde_json::json!("dark"))
+        .await?;
+
+    // A stranger can't change the settings of a pending request
+    bob.get_messages(&alice.public_key()).await?;
+    assert_eq!(bob.list_message_requests()?.len(), 1);
+    assert!(bob
+        .conversation_settings(&alice.public_key())?
+        .get("theme")
+        .is_none());
+
+    // Replying accepts the request, and their settings apply from then on
+    bob.send_message(&alice.public_key(), "Oh hi!").await?;
+    let messages = bob.get_messages(&alice.public_key()).await?;
+    assert!(messages.iter().all(|message| !message.message_request));
+    assert!(bob.list_message_requests()?.is_empty());
+    assert_eq!(
+        bob.conversation_settings(&alice.public_key())?["theme"],
+        serde_json::json!("dark")
+    );
+
+    Ok(())
+}
+
+/// Blocks obvious spam and holds back senders writing more than twice an hour
+struct ChattyFilter;
+
+impl SpamFilter for ChattyFilter {
+    fn classify(&self, message: &DecryptedMessage, activity: &SenderActivity) -> SpamVerdict {
+        if message.content.contains("BUY NOW") {
+            SpamVerdict::Block
+        } else if activity.last_hour > 2 {
+            SpamVerdict::Request
+        } else {
+            SpamVerdict::Inbox
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_spam_filter_routes_inbound_messages() -> Result<()> {
+    let Harness {
+        testnet: _testnet,
+        alice,
+        bob,
+    } = Harness::start().await?;
+    let bob = bob.with_spam_filter(Arc::new(ChattyFilter));
+
+    alice.send_message(&bob.public_key(), "Hello").await?;
+    alice
+        .send_message(&bob.public_key(), "BUY NOW, limited offer")
+        .await?;
+    bob.send_message(&alice.public_key(), "Who is this?")
+        .await?;
+
+    // Spam is dropped, our own messages are never filtered
+    let messages = bob.get_messages(&alice.public_key()).await?;
+    assert_eq!(messages.len(), 2);
+    assert!(messages.iter().all(|message| !message.message_request));
+
+    // Bursts are held back as requests, the messages before them are not
+    alice.send_message(&bob.public_key(), "Hello?").await?;
+    alice
+        .send_message(&bob.public_key(), "Are you there?")
+        .await?;
+    let messages = bob.get_messages(&alice.public_key()).await?;
+    let requests: Vec<bool> = messages
+        .iter()
+        .filter(|message| message.sender == alice.public_key_string())
+        .map(|message| message.message_request)
+        .collect();
+    assert_eq!(requests, [false, true, true]);
+
+    Ok(())
+}
+
+/// Wraps content in an extra layer that only peers with the same
+/// middleware can read
+struct Reverse;
+
+impl MessageMiddleware for Reverse {
+    fn outbound(&self, _peer: &PublicKey, content: String) -> Result<String> {
+        Ok(format!(
+            "reversed:{}",
+            content.chars().rev().collect::<String>()
+        ))
+    }
+
+    fn inbound(&self, _peer: &PublicKey, content: String) -> Result<String> {
+        let reversed = content
+            .strip_prefix("reversed:")
+            .ok_or_else(|| anyhow::anyhow!("not reversed"))?;
+        Ok(reversed.chars().rev().collect())
+    }
+}
+
+/// Masks a word in received messages
+struct Mask;
+
+impl MessageMiddleware for Mask {
+    fn inbound(&self, _peer: &PublicKey, content: String) -> Result<String> {
+        Ok(content.replace("darn", "****"))
+    }
+}
+
+#[tokio::test]
+async fn test_middleware_transforms_content() -> Result<()> {
+    let Harness {
+        testnet: _testnet,
+        alice,
+        bob,
+    } = Harness::start().await?;
+    let alice = alice.with_middleware(Arc::new(Reverse));
+    let bob = bob
+        .with_middleware(Arc::new(Reverse))
+        .with_middleware(Arc::new(Mask));
+
+    let sent = alice.send_message(&bob.public_key(), "Oh darn").await?;
+    assert_eq!(sent.content, "Oh darn");
+
+    // Inbound middleware runs in reverse, so masking sees unwrapped text
+    let messages = bob.get_messages(&alice.public_key()).await?;
+    assert_eq!(messages[0].content, "Oh ****");
+    let messages = alice.get_messages(&bob.public_key()).await?;
+    assert_eq!(messages[0].content, "Oh darn");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_reply_templates() -> Result<()> {
+    let harness = Harness::start().await?;
+    let (alice, bob) = (&harness.alice, &harness.bob);
+    assert!(alice.templates().await?.is_empty());
+
+    alice
+        .save_template("refund", "Hi {name}, your refund is on its way.")
+        .await?;
+    alice.save_template("hello", "Hello!").await?;
+    alice
+        .save_template("hello", "Hello, how can I help?")
+        .await?;
+    assert!(alice.save_template(" ", "Hello").await.is_err());
+
+    // Saving under a name replaces the earlier template
+    let names: Vec<String> = alice
+        .templates()
+        .await?
+        .into_iter()
+        .map(|template| template.name)
+        .collect();
+    assert_eq!(names, ["hello", "refund"]);
+    assert_eq!(
+        alice.template("hello").await?.unwrap().text,
+        "Hello, how can I help?"
+    );
+
+    // Templates are encrypted with a key only our keypair derives
+    assert!(bob.template("hello").await?.is_none());
+
+    alice
+        .send_template(&bob.public_key(), "refund", &[("name", "Bob")])
+        .await?;
+    let messages = bob.get_messages(&alice.public_key()).await?;
+    assert_eq!(messages[0].content, "Hi Bob, your refund is on its way.");
+    assert!(alice
+        .send_template(&bob.public_key(), "missing", &[])
+        .await
+        .is_err());
+
+    alice.delete_template("hello").await?;
+    assert!(alice.template("hello").await?.is_none());
+    assert_eq!(alice.templates().await?.len(), 1);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_storage_usage_counts_sent_messages() -> Result<()> {
+    let Harness {
+        testnet: _testnet,
+        alice,
+        bob,
+    } = Harness::start().await?;
+    let alice = alice
+        .with_local_store(temp_store_path())?
+        .with_storage_quota(1);
+
+    alice.send_message(&bob.public_key(), "Hello").await?;
+    alice
+        .send_message(&bob.public_key(), "Still there?")
+        .await?;
+    alice.sync_conversation(&bob.public_key()).await?;
+
+    let mut events = alice.subscribe();
+    let usage = alice.storage_usage().await?;
+
+    // Both messages land in one conversation, matched to its peer
+    assert_eq!(usage.conversations.len(), 1);
+    assert_eq!(usage.conversations[0].peer, Some(bob.public_key_string()));
+    assert!(usage.conversations[0].records >= 2);
+    assert_eq!(usage.total_bytes, usage.conversations[0].bytes);
+
+    // A one byte quota is long exceeded
+    assert!(usage.is_near_limit());
+    assert_eq!(
+        events.try_recv()?,
+        NotificationEvent::StorageNearLimit {
+            used_bytes: usage.total_bytes,
+            quota_bytes: 1,
+        }
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_health_check() -> Result<()> {
+    let harness = Harness::start().await?;
+    assert_eq!(harness.alice.health_check().await, HealthStatus::Healthy);
+
+    // Without a homeserver there is nowhere to write
+    let stranger = harness.stranger()?;
+    assert!(matches!(
+        stranger.health_check().await,
+        HealthStatus::Unreachable { .. }
+    ));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_adaptive_polling_skips_conversations_not_due() -> Result<()> {
+    let Harness {
+        testnet: _testnet,
+        alice,
+        bob,
+    } = Harness::start().await?;
+    let bob = Arc::new(bob);
+    let list_count = || {
+        bob.stats()
+            .operations
+            .get("list")
+            .map_or(0, |stats| stats.count)
+    };
+
+    let mut service = SyncService::new(bob.clone())
+        .with_peer(alice.public_key())
+        .with_adaptive_polling(PollingConfig {
+            active_interval: Duration::from_secs(3600),
+            idle_interval: Duration::from_secs(7200),
+        });
+
+    service.sync_once().await;
+    let lists = list_count();
+    assert!(lists > 0);
+
+    // The conversation isn't due again for an hour
+    service.sync_once().await;
+    assert_eq!(list_count(), lists);
+
+    // Unless we write to it, which polls it right away
+    bob.send_message(&alice.public_key(), "Still there?")
+        .await?;
+    alice.send_message(&bob.public_key(), "Yes, here").await?;
+    let report = service.sync_once().await;
+    assert!(list_count() > lists);
+    assert!(report
+        .new_messages
+        .iter()
+        .any(|(_, message)| message.content == "Yes, here"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_edits_are_sent_as_deltas() -> Result<()> {
+    let Harness {
+        testnet: _testnet,
+        alice,
+        bob,
+    } = Harness::start().await?;
+    let bob = bob.with_local_store(temp_store_path())?;
+
+    let sent = alice
+        .send_message(&bob.public_key(), "Lunch at noon?")
+        .await?;
+    bob.sync_conversation(&alice.public_key()).await?;
+
+    // Edit the message as fetched, twice
+    let messages = alice.get_messages(&bob.public_key()).await?;
+    alice
+        .edit_message(&bob.public_key(), &messages[0], "Lunch at one?")
+        .await?;
+    let messages = alice.get_messages(&bob.public_key()).await?;
+    assert_eq!(messages.len(), 1);
+    assert_eq!(messages[0].edit_version, 1);
+    alice
+        .edit_message(&bob.public_key(), &messages[0], "Lunch at one, or two?")
+        .await?;
+
+    // The stored copy is edited in place rather than a new message appearing
+    let delta = bob.sync_conversation(&alice.public_key()).await?;
+    assert!(delta.new_messages.is_empty());
+    assert_eq!(delta.edited_messages.len(), 1);
+    assert_eq!(delta.edited_messages[0].id, sent.id);
+    assert_eq!(delta.edited_messages[0].content, "Lunch at one, or two?");
+    assert_eq!(delta.edited_messages[0].edit_version, 2);
+
+    // Only the sender can edit
+    assert!(bob
+        .edit_message(&alice.public_key(), &messages[0], "Never")
+        .await
+        .is_err());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_uploaded_stickers_round_trip() -> Result<()> {
+    let Harness {
+        testnet: _testnet,
+        alice,
+        bob,
+    } = Harness::start().await?;
+
+    let image = b"RIFF....WEBPVP8 not really a sticker".to_vec();
+    let sticker = alice.upload_sticker(&image, "image/webp").await?;
+
+    // Uploading the same image again reuses the stored copy
+    assert_eq!(alice.upload_sticker(&image, "image/webp").await?, sticker);
+
+    alice.send_sticker(&bob.public_key(), sticker).await?;
+    let messages = bob.get_messages(&alice.public_key()).await?;
+    let MessageContent::Sticker(received) = &messages[0].body else {
+        panic!("expected a sticker, got {:?}", messages[0].body);
+    };
+    assert_eq!(bob.fetch_sticker(received).await?, image);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_oversized_sticker_downloads_are_cut_off() -> Result<()> {
+    let harness = Harness::start().await?;
+    let pubky_client = harness.testnet.pubky_client_builder().build()?;
+    let alice = PrivateMessengerClient::from_pubky_client(Keypair::random(), pubky_client.clone());
+    alice
+        .sign_up(&harness.testnet.homeserver_suite().public_key(), None)
+        .await?;
+
+    let mut sticker = alice.upload_sticker(b"small", "image/png").await?;
+    let StickerSource::Blob { url, hash, .. } = &mut sticker.source else {
+        panic!("expected an uploaded sticker");
+    };
+
+    // Replace the image with one too large to be a sticker, matching its hash
+    let blob = vec![0u8; MAX_STICKER_BYTES + 1024];
+    pubky_client
+        .put(url.as_str())
+        .body(blob.clone())
+        .send()
+        .await?
+        .error_for_status()?;
+    *hash = blake3::hash(&blob).to_hex().to_string();
+
+    let error = harness.bob.fetch_sticker(&sticker).await.unwrap_err();
+    assert!(error.to_string().contains("exceeds"), "{}", error);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_cipher_suite_per_conversation() -> Result<()> {
+    let Harness {
+        testnet: _testnet,
+        alice,
+        bob,
+    } = Harness::start().await?;
+    let alice = alice.with_local_store(temp_store_path())?;
+
+    alice.send_message(&bob.public_key(), "Default").await?;
+    alice
+        .set_cipher_suite(&bob.public_key(), CipherSuite::Aes256Gcm)
+        .await?;
+    alice.send_message(&bob.public_key(), "AES").await?;
+
+    // Messages stay readable whichever suite they were written with
+    let contents: Vec<String> = bob
+        .get_messages(&alice.public_key())
+        .await?
+        .into_iter()
+        .filter(|message| !message.body.is_system())
+        .map(|message| message.content)
+        .collect();
+    assert_eq!(contents, ["Default", "AES"]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_per_direction_keys() -> Result<()> {
+    let Harness {
+        testnet: _testnet,
+        alice,
+        bob,
+    } = Harness::start().await?;
+    let alice = alice.with_per_direction_keys();
+    let bob = bob.with_per_direction_keys();
+
+    // The same text in both directions is read by both sides
+    alice.send_message(&bob.public_key(), "Same").await?;
+    bob.send_message(&alice.public_key(), "Same").await?;
+    for (reader, other) in [(&alice, &bob), (&bob, &alice)] {
+        let messages = reader.get_messages(&other.public_key()).await?;
+        assert_eq!(messages.len(), 2);
+        assert!(messages
+            .iter()
+            .all(|message| message.content == "Same" && message.verified));
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_sequence_numbers_reveal_dropped_messages() -> Result<()> {
+    let Harness {
+        testnet: _testnet,
+        alice,
+        bob,
+    } = Harness::start().await?;
+    let alice = alice
+        .with_local_store(temp_store_path())?
+        .with_sequence_numbers()?;
+    let bob = bob.with_local_store(temp_store_path())?;
+
+    let first = alice.send_message(&bob.public_key(), "One").await?;
+    let second = alice.send_message(&bob.public_key(), "Two").await?;
+    // Resending under the same ID keeps the number
+    alice
+        .send_message_with_id(&bob.public_key(), "Two", &second.id)
+        .await?;
+    alice.send_message(&bob.public_key(), "Three").await?;
+
+    let delta = bob.sync_conversation(&alice.public_key()).await?;
+    let seqs: Vec<Option<u64>> = delta.new_messages.iter().map(|m| m.seq).collect();
+    assert_eq!(seqs, [Some(1), Some(2), Some(3)]);
+    assert!(delta
+        .new_messages
+        .iter()
+        .all(|message| message.missing_before == 0 && !message.out_of_order));
+    assert_eq!(delta.new_messages[0].body, MessageContent::text("One"));
+
+    // A message dropped from the homeserver shows up as a gap
+    alice.delete_message(&first.id, &bob.public_key()).await?;
+    alice.send_message(&bob.public_key(), "Five").await?;
+    alice.delete_message(&second.id, &bob.public_key()).await?;
+    let messages = bob.get_messages(&alice.public_key()).await?;
+    let gaps: Vec<u64> = messages.iter().map(|m| m.missing_before).collect();
+    assert_eq!(gaps, [2, 0]);
+
+    // Sync continues from the numbers it saw before
+    alice.send_message(&bob.public_key(), "Six").await?;
+    let delta = bob.sync_conversation(&alice.public_key()).await?;
+    let gaps: Vec<u64> = delta
+        .new_messages
+        .iter()
+        .map(|m| m.missing_before)
+        .collect();
+    assert_eq!(gaps, [0, 0]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_transcript_hashes_reveal_diverging_views() -> Result<()> {
+    let Harness {
+        testnet: _testnet,
+        alice,
+        bob,
+    } = Harness::start().await?;
+    let alice = alice.with_local_store(temp_store_path())?;
+    let bob = bob.with_local_store(temp_store_path())?;
+
+    // Hashes leave out the last few minutes, so date the conversation
+    // before them
+    let an_hour_ago = unix_now() - 60 * 60;
+    alice
+        .send_message_at(&bob.public_key(), "Hello", an_hour_ago)
+        .await?;
+    let reply = bob
+        .send_message_at(&alice.public_key(), "Hi", an_hour_ago + 1)
+        .await?;
+
+    let mut events = bob.subscribe();
+    let mut mismatches = move || -> Vec<NotificationEvent> {
+        std::iter::from_fn(|| events.try_recv().ok())
+            .filter(|event| matches!(event, NotificationEvent::TranscriptMismatch { .. }))
+            .collect()
+    };
+
+    // Matching views raise nothing
+    alice.send_transcript_hash(&bob.public_key()).await?;
+    bob.sync_conversation(&alice.public_key()).await?;
+    assert!(mismatches().is_empty());
+
+    // Nor does a record written after Alice hashed but dated a little
+    // earlier, as with coarse timestamps
+    alice.send_transcript_hash(&bob.public_key()).await?;
+    bob.send_message_at(&alice.public_key(), "Late", unix_now() - 2 * 60)
+        .await?;
+    bob.sync_conversation(&alice.public_key()).await?;
+    assert!(mismatches().is_empty());
+
+    // A record Alice hashed is gone from Bob's view by the time he checks
+    let sent = alice.send_transcript_hash(&bob.public_key()).await?;
+    bob.delete_message(&reply.id, &alice.public_key()).await?;
+    bob.sync_conversation(&alice.public_key()).await?;
+    let mismatches = mismatches();
+    assert_eq!(mismatches.len(), 1);
+    let NotificationEvent::TranscriptMismatch {
+        message_id,
+        local_records,
+        remote_records,
+        ..
+    } = &mismatches[0]
+    else {
+        unreachable!();
+    };
+    assert_eq!(message_id, &sent.id);
+    assert_eq!(local_records + 1, *remote_records);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_search_tokens_find_messages_by_keyword() -> Result<()> {
+    let Harness {
+        testnet: _testnet,
+        alice,
+        bob,
+    } = Harness::start().await?;
+    let alice = alice.with_search_tokens();
+
+    alice
+        .send_message(&bob.public_key(), "Meeting at the café tomorrow")
+        .await?;
+    let lunch = alice
+        .send_message(&bob.public_key(), "Lunch tomorrow?")
+        .await?;
+    // Bob publishes no tokens, so his messages can't be found
+    bob.send_message(&alice.public_key(), "Tomorrow works")
+        .await?;
+
+    let found = bob.search_remote(&alice.public_key(), "TOMORROW").await?;
+    assert_eq!(found.len(), 2);
+
+    // Every word of the query must match
+    let found = bob
+        .search_remote(&alice.public_key(), "tomorrow, lunch")
+        .await?;
+    assert_eq!(found.len(), 1);
+    assert_eq!(found[0].id, lunch.id);
+    let message = bob
+        .fetch_message(&alice.public_key(), &found[0])
+        .await?
+        .unwrap();
+    assert_eq!(message.content, "Lunch tomorrow?");
+
+    assert!(alice
+        .search_remote(&bob.public_key(), "dinner")
+        .await?
+        .is_empty());
+
+    // Deleting messages deletes their tokens
+    alice.delete_message(&lunch.id, &bob.public_key()).await?;
+    assert!(bob
+        .search_remote(&alice.public_key(), "lunch")
+        .await?
+        .is_empty());
+    assert_eq!(
+        bob.search_remote(&alice.public_key(), "tomorrow")
+            .await?
+            .len(),
+        1
+    );
+    alice.clear_messages(&bob.public_key()).await?;
+    assert!(bob
+        .search_remote(&alice.public_key(), "tomorrow")
+        .await?
+        .is_empty());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_bot_routes_commands_and_rate_limits_senders() -> Result<()> {
+    let Harness {
+        testnet: _testnet,
+        alice,
+        bob,
+    } = Harness::start().await?;
+    let alice = Arc::new(alice);
+
+    let mut bot = Bot::new(alice.clone())
+        .with_peer(bob.public_key())
+        .with_rate_limit(2, Duration::from_secs(3600))
+        .on_command("/echo", |ctx: BotContext| async move {
+            let args = ctx.args().unwrap_or_default().to_string();
+            ctx.reply(args).await?;
+            Ok(())
+        })
+        .on_content("poll", |ctx: BotContext| async move {
+            ctx.reply("Voting is closed").await?;
+            Ok(())
+        });
+    bot.poll_once().await;
+
+    let echo = bob
+        .send_message(&alice.public_key(), "/echo  hello ")
+        .await?;
+    // Neither a command nor a routed kind
+    bob.send_message(&alice.public_key(), "/echoes").await?;
+    bob.create_poll(
+        &alice.public_key(),
+        "Lunch?",
+        vec!["Yes".into(), "No".into()],
+    )
+    .await?;
+    let report = bot.poll_once().await;
+    assert!(report.errors.is_empty());
+
+    let replies: Vec<DecryptedMessage> = bob
+        .get_messages(&alice.public_key())
+        .await?
+        .into_iter()
+        .filter(|message| message.sender == alice.public_key_string())
+        .collect();
+    assert_eq!(replies.len(), 2);
+    let echoed = replies
+        .iter()
+        .find(|message| message.reply_to.as_deref() == Some(echo.id.as_str()))
+        .unwrap();
+    assert_eq!(echoed.body, MessageContent::text("hello"));
+    assert!(replies
+        .iter()
+        .any(|message| message.body == MessageContent::text("Voting is closed")));
+
+    // Bob used up his two messages for the hour
+    bob.send_message(&alice.public_key(), "/echo again").await?;
+    bot.poll_once().await;
+    let replies = bob
+        .get_messages(&alice.public_key())
+        .await?
+        .into_iter()
+        .filter(|message| message.sender == alice.public_key_string())
+        .count();
+    assert_eq!(replies, 2);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_put_profile_keeps_fields_of_other_apps() -> Result<()> {
+    let harness = Harness::start().await?;
+    let pubky_client = harness.testnet.pubky_client_builder().build()?;
+    let alice = PrivateMessengerClient::from_pubky_client(Keypair::random(), pubky_client.clone());
+    alice
+        .sign_up(&harness.testnet.homeserver_suite().public_key(), None)
+        .await?;
+
+    // A profile written by another app, with a field we don't know
+    let profile_url = format!("pubky://{}/pub/pubky.app/profile.json", alice.public_key());
+    pubky_client
+        .put(&profile_url)
+        .body(
+            r#"{"name":"Alice","bio":null,"links":[{"title":"Blog","url":"https://example.com"}]}"#,
+        )
+        .send()
+        .await?
+        .error_for_status()?;
+
+    alice
+        .put_profile(&PubkyProfile {
+            name: "Alice B.".to_string(),
+            bio: Some("Hello".to_string()),
+            image: None,
+            status: None,
+            profile_verified: false,
+        })
+        .await?;
+
+    let stored: serde_json::Value = pubky_client.get(&profile_url).send().await?.json().await?;
+    assert_eq!(stored["name"], "Alice B.");
+    assert_eq!(stored["links"][0]["title"], "Blog");
+
+    // The merged profile is what got signed
+    let profile = harness
+        .bob
+        .get_profile(&alice.public_key())
+        .await?
+        .expect("the profile was stored");
+    assert_eq!(profile.bio.as_deref(), Some("Hello"));
+    assert!(profile.profile_verified);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_unreachable_peer_degrades_to_our_side() -> Result<()> {
+    let harness = Harness::start().await?;
+    let stranger = harness.stranger()?;
+    let Harness {
+        testnet: _testnet,
+        alice,
+        ..
+    } = harness;
+    let alice = alice.with_circuit_breaker(CircuitBreakerConfig {
+        failure_threshold: 1,
+        cooldown: Duration::from_secs(60),
+    });
+
+    // The stranger never signed up, so their homeserver can't be reached
+    alice
+        .send_message(&stranger.public_key(), "Anyone there?")
+        .await?;
+    for _ in 0..2 {
+        let messages = alice.get_messages(&stranger.public_key()).await?;
+        let contents: Vec<&str> = messages.iter().map(|m| m.content.as_str()).collect();
+        assert_eq!(contents, ["Anyone there?"]);
+    }
+
+    // Other fetches fail fast while the circuit is open
+    let error = alice
+        .get_recent_messages(&stranger.public_key(), 10)
+        .await
+        .unwrap_err();
+    assert!(matches!(
+        error.downcast_ref::<MessengerError>(),
+        Some(MessengerError::PeerUnreachable { .. })
+    ));
+
+    Ok(())
+}
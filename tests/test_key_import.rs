@@ -0,0 +1,59 @@
+use anyhow::Result;
+use pkarr::Keypair;
+use pubky_messenger::PrivateMessengerClient;
+
+// NIP-19 example key
+const NSEC: &str = "nsec1vl029mgpspedva04g90vltkh6fvh240zqtv9k0t9af8935ke9laqsnlfe5";
+const NSEC_HEX: &str = "67dea2ed018072d675f5415ecfaed7d2597555e202d85b3d65ea4e58d2d92ffa";
+
+#[test]
+fn test_from_secret_key() -> Result<()> {
+    let keypair = Keypair::random();
+    let client = PrivateMessengerClient::from_secret_key(&keypair.secret_key())?;
+
+    assert_eq!(client.public_key_string(), keypair.public_key().to_string());
+    Ok(())
+}
+
+#[test]
+fn test_from_hex_secret_key() -> Result<()> {
+    let keypair = Keypair::random();
+    let hex_secret = hex::encode(keypair.secret_key());
+
+    let client = PrivateMessengerClient::from_hex_secret_key(&hex_secret)?;
+    assert_eq!(client.public_key_string(), keypair.public_key().to_string());
+
+    // Wrong lengths and non-hex input are rejected
+    assert!(PrivateMessengerClient::from_hex_secret_key(&hex_secret[..62]).is_err());
+    assert!(PrivateMessengerClient::from_hex_secret_key("not hex").is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_from_nsec() -> Result<()> {
+    let from_nsec = PrivateMessengerClient::from_nsec(NSEC)?;
+    let from_hex = PrivateMessengerClient::from_hex_secret_key(NSEC_HEX)?;
+
+    // The nsec decodes to the same secret bytes
+    assert_eq!(from_nsec.public_key_string(), from_hex.public_key_string());
+
+    // Other prefixes are rejected
+    let npub = "npub10elfcs4fr0l0r8af98jlmgdh9c8tcxjvz9qkw038js35mp4dma8qzvjptg";
+    assert!(PrivateMessengerClient::from_nsec(npub).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_from_bech32_secret_key() -> Result<()> {
+    // Any prefix is accepted when none is expected
+    let client = PrivateMessengerClient::from_bech32_secret_key(NSEC, None)?;
+    let expected = PrivateMessengerClient::from_hex_secret_key(NSEC_HEX)?;
+    assert_eq!(client.public_key_string(), expected.public_key_string());
+
+    assert!(PrivateMessengerClient::from_bech32_secret_key(NSEC, Some("other")).is_err());
+    assert!(PrivateMessengerClient::from_bech32_secret_key("invalid", None).is_err());
+
+    Ok(())
+}
@@ -0,0 +1,651 @@
+use anyhow::Result;
+use futures::future::BoxFuture;
+use futures::StreamExt;
+use pkarr::{Keypair, PublicKey};
+use pubky_messenger::{
+    AwayMessage, AwaySchedule, AwaySetting, Bridge, BridgedMessage, Contact, DecryptedMessage,
+    DeviceSettings, LabelSetting, MessengerError, MuteRule, MuteSetting, PrivateMessengerClient,
+    SyncService, TrustLevel, TrustSetting,
+};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+// Helper function to create a unique store path in the temp directory
+fn temp_store_path() -> PathBuf {
+    std::env::temp_dir().join(format!("pubky-messenger-test-{}.db", uuid::Uuid::new_v4()))
+}
+
+#[test]
+fn test_local_store_required_for_stars() {
+    let client = PrivateMessengerClient::new(Keypair::random()).unwrap();
+
+    // Starring needs the local store
+    assert!(client.star_message("some-id").is_err());
+    assert!(client.list_starred().is_err());
+}
+
+#[tokio::test]
+async fn test_local_store_required_for_sync() {
+    let client = PrivateMessengerClient::new(Keypair::random()).unwrap();
+    let peer = Keypair::random().public_key();
+
+    // Sync state lives in the local store
+    assert!(client.sync_conversation(&peer).await.is_err());
+    assert!(client.sync_device_settings().await.is_err());
+    assert!(client.conversation_settings(&peer).is_err());
+    assert!(client.set_message_expiry(&peer, Some(7)).await.is_err());
+}
+
+#[test]
+fn test_local_store_required_for_message_requests() {
+    let client = PrivateMessengerClient::new(Keypair::random()).unwrap();
+
+    // Requests and decisions about them live in the local store
+    assert!(client.list_message_requests().is_err());
+    assert!(client
+        .accept_message_request(&Keypair::random().public_key())
+        .is_err());
+    assert!(client.with_message_requests().is_err());
+}
+
+#[test]
+fn test_event_queue_starts_empty() -> Result<()> {
+    let client = PrivateMessengerClient::new(Keypair::random())?;
+    assert!(client.pending_events(10).is_err());
+
+    let client = client
+        .with_local_store(temp_store_path())?
+        .with_event_queue()?;
+    assert!(client.pending_events(10)?.is_empty());
+    assert_eq!(client.ack_events(u64::MAX)?, 0);
+
+    Ok(())
+}
+
+#[test]
+fn test_local_store_starred_messages() -> Result<()> {
+    let path = temp_store_path();
+    let client = PrivateMessengerClient::new(Keypair::random())?.with_local_store(&path)?;
+
+    // A fresh store has no starred messages
+    assert!(client.list_starred()?.is_empty());
+
+    // Unknown messages cannot be starred
+    assert!(client.star_message("unknown-id").is_err());
+
+    // Reopening an existing store works
+    drop(client);
+    let client = PrivateMessengerClient::new(Keypair::random())?.with_local_store(&path)?;
+    assert!(client.list_starred()?.is_empty());
+
+    std::fs::remove_file(&path)?;
+    Ok(())
+}
+
+#[test]
+fn test_local_store_search() -> Result<()> {
+    let path = temp_store_path();
+    let client = PrivateMessengerClient::new(Keypair::random())?.with_local_store(&path)?;
+
+    // Empty queries and queries with FTS syntax characters are handled
+    assert!(client.search_messages("", 10)?.is_empty());
+    assert!(client.search_messages("hello", 10)?.is_empty());
+    assert!(client
+        .search_messages("\"quoted\" AND (NOT x*) -y", 10)?
+        .is_empty());
+
+    std::fs::remove_file(&path)?;
+    Ok(())
+}
+
+#[test]
+fn test_encrypted_local_store_lock_unlock() -> Result<()> {
+    let path = temp_store_path();
+    let client = PrivateMessengerClient::new(Keypair::random())?
+        .with_encrypted_local_store(&path, "1234")?;
+
+    assert!(!client.is_locked());
+    assert!(client.list_starred()?.is_empty());
+
+    // A locked store rejects local-only features
+    client.lock()?;
+    assert!(client.is_locked());
+    assert!(client.list_starred().is_err());
+
+    // A wrong PIN leaves the store locked
+    assert!(client.unlock("0000").is_err());
+    assert!(client.is_locked());
+
+    client.unlock("1234")?;
+    assert!(!client.is_locked());
+    assert!(client.list_starred()?.is_empty());
+    drop(client);
+
+    // The encrypted store cannot be opened without its PIN
+    assert!(PrivateMessengerClient::new(Keypair::random())?
+        .with_local_store(&path)
+        .is_err());
+    assert!(PrivateMessengerClient::new(Keypair::random())?
+        .with_encrypted_local_store(&path, "4321")
+        .is_err());
+
+    std::fs::remove_file(&path)?;
+    std::fs::remove_file(format!("{}.salt", path.display()))?;
+    Ok(())
+}
+
+#[test]
+fn test_contact_pins() -> Result<()> {
+    let path = temp_store_path();
+    let client = PrivateMessengerClient::new(Keypair::random())?.with_local_store(&path)?;
+    let contact = Keypair::random().public_key();
+
+    // Contacts are pinned when their conversation is first fetched
+    assert!(client.contact_pin(&contact)?.is_none());
+
+    // There is nothing to acknowledge without a pending change
+    assert!(client.acknowledge_key_change(&contact).is_err());
+
+    std::fs::remove_file(&path)?;
+    Ok(())
+}
+
+#[test]
+fn test_trust_levels() -> Result<()> {
+    let keypair = Keypair::random();
+    let contact = Keypair::random().public_key();
+
+    // Trust is remembered in the local store
+    let client = PrivateMessengerClient::new(keypair.clone())?;
+    assert!(client.trust_level(&contact).is_err());
+    assert!(client.set_trust(&contact, TrustLevel::Verified).is_err());
+
+    let path = temp_store_path();
+    let client = PrivateMessengerClient::new(keypair.clone())?.with_local_store(&path)?;
+
+    // Contacts that were never fetched or pinned are unknown
+    assert_eq!(client.trust_level(&contact)?, TrustLevel::Unknown);
+
+    client.set_trust(&contact, TrustLevel::Distrusted)?;
+    assert_eq!(client.trust_level(&contact)?, TrustLevel::Distrusted);
+    drop(client);
+
+    // And survives reopening the store
+    let client = PrivateMessengerClient::new(keypair)?.with_local_store(&path)?;
+    assert_eq!(client.trust_level(&contact)?, TrustLevel::Distrusted);
+    client.set_trust(&contact, TrustLevel::Verified)?;
+    assert_eq!(client.trust_level(&contact)?, TrustLevel::Verified);
+
+    std::fs::remove_file(&path)?;
+    Ok(())
+}
+
+#[test]
+fn test_contacts_export_import() -> Result<()> {
+    let alice = Keypair::random().public_key();
+    let bob = Keypair::random().public_key();
+
+    let client =
+        PrivateMessengerClient::new(Keypair::random())?.with_local_store(temp_store_path())?;
+    client.set_contact(&alice, Some("Alice, from work"), &["work", "friends"])?;
+    client.set_trust(&bob, TrustLevel::Distrusted)?;
+    assert!(client.set_contact(&alice, Some(""), &[]).is_err());
+    assert!(client.set_contact(&alice, None, &["a;b"]).is_err());
+
+    let contacts = client.contacts()?;
+    assert_eq!(contacts.len(), 2);
+
+    // Both formats round-trip into another client
+    for exported in [client.export_contacts()?, client.export_contacts_csv()?] {
+        assert_eq!(Contact::parse(&exported)?, contacts);
+
+        let other =
+            PrivateMessengerClient::new(Keypair::random())?.with_local_store(temp_store_path())?;
+        assert_eq!(other.import_contacts(&exported)?, 2);
+        assert_eq!(other.contacts()?, contacts);
+        assert_eq!(other.trust_level(&bob)?, TrustLevel::Distrusted);
+    }
+
+    // Nothing is imported when any contact is invalid
+    let other =
+        PrivateMessengerClient::new(Keypair::random())?.with_local_store(temp_store_path())?;
+    let csv = format!("pubky,alias\n{},Alice\nnot-a-key,Mallory\n", alice);
+    assert!(other.import_contacts(csv.as_bytes()).is_err());
+    assert!(other.contacts()?.is_empty());
+
+    // Columns may come in any order and be left out
+    let csv = format!("alias,pubky\r\n\"Alice \"\"A\"\"\",{}\r\n", alice);
+    assert_eq!(other.import_contacts(csv.as_bytes())?, 1);
+    assert_eq!(other.contacts()?[0].alias.as_deref(), Some("Alice \"A\""));
+
+    Ok(())
+}
+
+#[test]
+fn test_device_settings_merge() {
+    let settings = |entries: &[(&str, TrustLevel, u64)]| DeviceSettings {
+        trust: entries
+            .iter()
+            .map(|&(peer, level, updated_at)| {
+                (peer.to_string(), TrustSetting { level, updated_at })
+            })
+            .collect(),
+        ..Default::default()
+    };
+
+    let phone = settings(&[
+        ("alice", TrustLevel::Verified, 100),
+        ("bob", TrustLevel::Verified, 300),
+    ]);
+    let laptop = settings(&[
+        ("alice", TrustLevel::Distrusted, 200),
+        ("bob", TrustLevel::Distrusted, 200),
+        ("carol", TrustLevel::Tofu, 50),
+    ]);
+
+    // The latest change to each contact wins, and new contacts are added
+    let mut merged = phone.clone();
+    let mut changed = merged.merge(&laptop);
+    changed.sort();
+    assert_eq!(changed, vec!["alice", "carol"]);
+    assert_eq!(merged.trust["alice"].level, TrustLevel::Distrusted);
+    assert_eq!(merged.trust["bob"].level, TrustLevel::Verified);
+    assert_eq!(merged.trust["carol"].level, TrustLevel::Tofu);
+
+    // Merging in either order converges
+    let mut other_way = laptop.clone();
+    other_way.merge(&phone);
+    assert_eq!(other_way, merged);
+
+    // Changes made in the same second still settle on one result
+    let a = settings(&[("alice", TrustLevel::Verified, 100)]);
+    let b = settings(&[("alice", TrustLevel::Distrusted, 100)]);
+    let (mut ab, mut ba) = (a.clone(), b.clone());
+    ab.merge(&b);
+    ba.merge(&a);
+    assert_eq!(ab, ba);
+}
+
+#[tokio::test]
+async fn test_read_only_conversations() -> Result<()> {
+    let peer = Keypair::random().public_key();
+    let client = PrivateMessengerClient::new(Keypair::random())?;
+    assert!(client.set_read_only(&peer, true).is_err());
+    assert!(!client.is_read_only(&peer));
+
+    let client = client.with_local_store(temp_store_path())?;
+    client.set_read_only(&peer, true)?;
+    assert!(client.is_read_only(&peer));
+    assert_eq!(
+        client.conversation_settings(&peer)?["read_only"],
+        serde_json::json!(true)
+    );
+
+    // Sends fail before anything goes out
+    let error = client.send_message(&peer, "Hello").await.unwrap_err();
+    assert_eq!(
+        error.downcast_ref::<MessengerError>(),
+        Some(&MessengerError::ConversationReadOnly {
+            peer: peer.to_string()
+        })
+    );
+    assert!(client
+        .send_broadcast(&[peer.clone()], "Hello")
+        .await
+        .unwrap_err()
+        .is::<MessengerError>());
+    assert!(client
+        .set_conversation_setting(&peer, "read_only", serde_json::json!(false))
+        .await
+        .is_err());
+
+    client.set_read_only(&peer, false)?;
+    assert!(!client.is_read_only(&peer));
+
+    Ok(())
+}
+
+/// Bridge handing out queued messages once
+#[derive(Default)]
+struct QueueBridge {
+    inbound: Mutex<Vec<BridgedMessage>>,
+}
+
+impl Bridge for QueueBridge {
+    fn deliver<'a>(
+        &'a self,
+        _peer: &'a PublicKey,
+        _message: &'a DecryptedMessage,
+    ) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async { Ok(()) })
+    }
+
+    fn poll_inbound(&self) -> BoxFuture<'_, Result<Vec<BridgedMessage>>> {
+        let inbound = std::mem::take(&mut *self.inbound.lock().unwrap());
+        Box::pin(async move { Ok(inbound) })
+    }
+}
+
+#[tokio::test]
+async fn test_failed_bridged_messages_stay_queued() -> Result<()> {
+    let peer = Keypair::random().public_key();
+    let client =
+        PrivateMessengerClient::new(Keypair::random())?.with_local_store(temp_store_path())?;
+    // Sends to a read-only conversation fail before anything goes out
+    client.set_read_only(&peer, true)?;
+    let client = Arc::new(client);
+
+    let bridge = Arc::new(QueueBridge::default());
+    let bridged = |content: &str| BridgedMessage {
+        peer: peer.clone(),
+        content: content.to_string(),
+    };
+    bridge
+        .inbound
+        .lock()
+        .unwrap()
+        .extend([bridged("first"), bridged("second")]);
+    let mut service = SyncService::new(client.clone()).with_bridge(bridge.clone());
+
+    let report = service.sync_once().await;
+    assert_eq!(report.errors.len(), 2);
+    assert!(report.errors[0].is::<MessengerError>());
+
+    // Kept in order and retried with the next inbound messages
+    bridge.inbound.lock().unwrap().push(bridged("third"));
+    let report = service.sync_once().await;
+    assert_eq!(report.errors.len(), 3);
+    let pending: Vec<&str> = service
+        .pending_bridged()
+        .map(|bridged| bridged.content.as_str())
+        .collect();
+    assert_eq!(pending, ["first", "second", "third"]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_sync_handle_passes_on_round_errors() -> Result<()> {
+    let peer = Keypair::random().public_key();
+    let client =
+        PrivateMessengerClient::new(Keypair::random())?.with_local_store(temp_store_path())?;
+    client.set_read_only(&peer, true)?;
+
+    let bridge = Arc::new(QueueBridge::default());
+    bridge.inbound.lock().unwrap().push(BridgedMessage {
+        peer: peer.clone(),
+        content: "Hello".to_string(),
+    });
+    let mut handle = SyncService::new(Arc::new(client))
+        .with_bridge(bridge)
+        .with_interval(Duration::from_millis(10))
+        .spawn();
+
+    let error = tokio::time::timeout(Duration::from_secs(5), handle.next_error())
+        .await?
+        .expect("the service is running");
+    assert_eq!(
+        error.downcast_ref::<MessengerError>(),
+        Some(&MessengerError::ConversationReadOnly {
+            peer: peer.to_string()
+        })
+    );
+
+    // The message is retried every round
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert!(!handle.take_errors().is_empty());
+    handle.stop();
+
+    Ok(())
+}
+
+#[test]
+fn test_mute_rules() -> Result<()> {
+    let client = PrivateMessengerClient::new(Keypair::random())?;
+    assert!(client.mute(MuteRule::word("spoiler")).is_err());
+
+    let client = client.with_local_store(temp_store_path())?;
+    client.mute(MuteRule::word("spoiler"))?;
+    client.mute(MuteRule::word(" Spoiler "))?;
+    client.mute(MuteRule::sender("^yyy"))?;
+    assert_eq!(client.mute_rules()?.len(), 2);
+
+    assert!(client.mute(MuteRule::pattern("(unclosed")).is_err());
+    assert!(client.mute(MuteRule::word("  ")).is_err());
+
+    assert!(client.unmute(&MuteRule::word("SPOILER"))?);
+    assert!(!client.unmute(&MuteRule::word("spoiler"))?);
+    assert_eq!(client.mute_rules()?, vec![MuteRule::sender("^yyy")]);
+
+    Ok(())
+}
+
+#[test]
+fn test_device_settings_merge_mutes() {
+    let settings = |muted: bool, updated_at: u64| DeviceSettings {
+        mutes: [(
+            "word:spoiler".to_string(),
+            MuteSetting {
+                rule: MuteRule::word("spoiler"),
+                muted,
+                updated_at,
+            },
+        )]
+        .into(),
+        ..Default::default()
+    };
+
+    // An unmute on another device wins over an earlier mute
+    let mut phone = settings(true, 100);
+    phone.merge(&settings(false, 200));
+    assert!(!phone.mutes["word:spoiler"].muted);
+
+    // And an earlier change doesn't undo it
+    phone.merge(&settings(true, 150));
+    assert!(!phone.mutes["word:spoiler"].muted);
+}
+
+#[test]
+fn test_conversation_labels() -> Result<()> {
+    let client = PrivateMessengerClient::new(Keypair::random())?;
+    let peer = Keypair::random().public_key();
+    assert!(client.set_conversation_labels(&peer, &["Work"]).is_err());
+
+    let client = client.with_local_store(temp_store_path())?;
+    let other = Keypair::random().public_key();
+    assert!(client.conversation_labels(&peer)?.is_empty());
+
+    client.set_conversation_labels(&peer, &["Work", " Family ", "Work"])?;
+    assert_eq!(client.conversation_labels(&peer)?, vec!["Family", "Work"]);
+    assert!(client.add_conversation_label(&other, "Work")?);
+    assert!(!client.add_conversation_label(&other, "Work")?);
+    assert_eq!(client.labels()?, vec!["Family", "Work"]);
+
+    assert!(client.set_conversation_labels(&peer, &["  "]).is_err());
+    assert!(client
+        .add_conversation_label(&peer, &"x".repeat(33))
+        .is_err());
+
+    assert!(client.remove_conversation_label(&peer, "Family")?);
+    assert!(!client.remove_conversation_label(&peer, "Family")?);
+    assert_eq!(client.labels()?, vec!["Work"]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_list_conversations_with_label_requires_store() {
+    let client = PrivateMessengerClient::new(Keypair::random()).unwrap();
+    let peer = Keypair::random().public_key();
+
+    let summaries: Vec<_> = client
+        .list_conversations_with_label(&[peer], "Work")
+        .collect()
+        .await;
+    assert_eq!(summaries.len(), 1);
+    assert!(summaries[0].is_err());
+}
+
+#[test]
+fn test_device_settings_merge_labels() {
+    let settings = |labels: &[&str], updated_at: u64| DeviceSettings {
+        labels: [(
+            "peer".to_string(),
+            LabelSetting {
+                labels: labels.iter().map(|label| label.to_string()).collect(),
+                updated_at,
+            },
+        )]
+        .into(),
+        ..Default::default()
+    };
+
+    // Labels changed on another device later win, removals included
+    let mut phone = settings(&["Work"], 100);
+    phone.merge(&settings(&[], 200));
+    assert!(phone.labels["peer"].labels.is_empty());
+
+    phone.merge(&settings(&["Family"], 150));
+    assert!(phone.labels["peer"].labels.is_empty());
+}
+
+#[test]
+fn test_away_message() -> Result<()> {
+    let client = PrivateMessengerClient::new(Keypair::random())?;
+    assert!(client
+        .set_away_message(AwayMessage::new("Back Monday"))
+        .is_err());
+
+    let client = client.with_local_store(temp_store_path())?;
+    assert_eq!(client.away_message()?, None);
+    assert!(!client.clear_away_message()?);
+
+    assert!(client.set_away_message(AwayMessage::new("  ")).is_err());
+    let empty_window = AwaySchedule::Daily {
+        start_minute: 60,
+        end_minute: 60,
+    };
+    assert!(client
+        .set_away_message(AwayMessage::new("Asleep").with_schedule(empty_window))
+        .is_err());
+
+    let nights =
+        AwayMessage::new("Asleep, will answer tomorrow").with_schedule(AwaySchedule::Daily {
+            start_minute: 22 * 60,
+            end_minute: 7 * 60,
+        });
+    client.set_away_message(nights.clone())?;
+    assert_eq!(client.away_message()?, Some(nights));
+
+    assert!(client.clear_away_message()?);
+    assert_eq!(client.away_message()?, None);
+
+    Ok(())
+}
+
+#[test]
+fn test_away_schedule_periods() {
+    let day = 24 * 60 * 60;
+    let nights = AwaySchedule::Daily {
+        start_minute: 22 * 60,
+        end_minute: 7 * 60,
+    };
+
+    // A night window runs past midnight, and both halves are one period
+    let night = 10 * day + 22 * 60 * 60;
+    assert_eq!(nights.period_start(night + 60 * 60, 0), Some(night));
+    assert_eq!(nights.period_start(night + 8 * 60 * 60, 0), Some(night));
+    assert_eq!(nights.period_start(11 * day + 12 * 60 * 60, 0), None);
+
+    let trip = AwaySchedule::Between {
+        starts_at: 1000,
+        ends_at: 2000,
+    };
+    assert_eq!(trip.period_start(1500, 0), Some(1000));
+    assert_eq!(trip.period_start(2000, 0), None);
+
+    // Always away counts from when the message was set
+    assert_eq!(AwaySchedule::Always.period_start(1500, 1200), Some(1200));
+    assert_eq!(AwaySchedule::Always.period_start(1100, 1200), None);
+}
+
+#[test]
+fn test_device_settings_merge_away() {
+    let settings = |text: Option<&str>, updated_at: u64| DeviceSettings {
+        away: Some(AwaySetting {
+            message: text.map(AwayMessage::new),
+            updated_at,
+        }),
+        ..Default::default()
+    };
+
+    // An away message set on another device reaches this one
+    let mut phone = DeviceSettings::default();
+    phone.merge(&settings(Some("On holiday"), 100));
+    assert_eq!(phone, settings(Some("On holiday"), 100));
+
+    // Clearing it later wins, and an earlier change doesn't undo that
+    phone.merge(&settings(None, 200));
+    phone.merge(&settings(Some("In a meeting"), 150));
+    assert_eq!(phone, settings(None, 200));
+}
+
+#[test]
+fn test_audit_log() -> Result<()> {
+    // The audit log lives in the local store
+    assert!(PrivateMessengerClient::new(Keypair::random())?
+        .with_audit_log()
+        .is_err());
+
+    let path = temp_store_path();
+    let client = PrivateMessengerClient::new(Keypair::random())?
+        .with_local_store(&path)?
+        .with_audit_log()?;
+
+    // Nothing has been done yet
+    assert!(client.audit_log()?.is_empty());
+    assert_eq!(client.export_audit_log()?, "");
+
+    std::fs::remove_file(&path)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_trash_and_undo_delete() -> Result<()> {
+    let peer = Keypair::random().public_key();
+    let client = PrivateMessengerClient::new(Keypair::random())?;
+    assert!(client.undo_delete("unknown-id", &peer).is_err());
+    assert!(PrivateMessengerClient::new(Keypair::random())?
+        .with_trash(Duration::from_secs(60))
+        .is_err());
+
+    let client = client
+        .with_local_store(temp_store_path())?
+        .with_trash(Duration::from_secs(60 * 60))?;
+
+    // Deleting only moves the message to the trash, without a request
+    let id = "0000000000001-abcdef";
+    client.delete_message(id, &peer).await?;
+    let trash = client.list_trash()?;
+    assert_eq!(trash.len(), 1);
+    assert_eq!(trash[0].message_id, id);
+    assert_eq!(trash[0].peer, peer.to_string());
+
+    // Nothing is purged before the window passes
+    assert_eq!(client.purge_trash().await?, 0);
+
+    // The same ID in another conversation is a different message
+    let other_peer = Keypair::random().public_key();
+    client.delete_message(id, &other_peer).await?;
+    assert!(client.undo_delete(id, &other_peer)?);
+    let trash = client.list_trash()?;
+    assert_eq!(trash.len(), 1);
+    assert_eq!(trash[0].peer, peer.to_string());
+
+    assert!(client.undo_delete(id, &peer)?);
+    assert!(client.list_trash()?.is_empty());
+    assert!(!client.undo_delete(id, &peer)?);
+
+    Ok(())
+}
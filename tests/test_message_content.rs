@@ -0,0 +1,789 @@
+use pubky_messenger::{
+    is_language_tag, Command, ContentPolicy, ConversationSnapshot, DecryptedMessage, EditChange,
+    Keypair, Mention, MessageContent, MessageEdit, PaymentProof, PaymentRequest, Poll, Sticker,
+    StickerSource, SystemMessage, TranscriptHash, EDIT_SNAPSHOT_INTERVAL, MAX_BINARY_BYTES,
+};
+use std::collections::HashMap;
+
+fn invoice(hrp: &str) -> String {
+    // Shape-valid invoice: the data part only needs to use the bech32 charset
+    format!("{}1{}", hrp, "p".repeat(110))
+}
+
+fn request(uri: &str, amount_sat: Option<u64>) -> PaymentRequest {
+    PaymentRequest {
+        uri: uri.to_string(),
+        amount_sat,
+        memo: None,
+    }
+}
+
+#[test]
+fn test_plain_text_is_sent_unchanged() {
+    let content = MessageContent::text("Hello Bob!");
+    assert_eq!(content.encode().unwrap(), "Hello Bob!");
+    assert_eq!(MessageContent::decode("Hello Bob!"), content);
+
+    // Text that merely looks like JSON stays text
+    assert_eq!(
+        MessageContent::decode("{\"not\": \"a payload\"}"),
+        MessageContent::text("{\"not\": \"a payload\"}")
+    );
+}
+
+#[test]
+fn test_payment_request_roundtrip() {
+    let content = MessageContent::PaymentRequest(PaymentRequest {
+        uri: "bitcoin:bc1qar0srrr7xfkvy5l643lydnw9re59gtzzwf5mdq?amount=0.0015".to_string(),
+        amount_sat: Some(150_000),
+        memo: Some("Dinner".to_string()),
+    });
+    assert!(content.validate().is_ok());
+
+    let encoded = content.encode().unwrap();
+    assert_eq!(MessageContent::decode(&encoded), content);
+}
+
+#[test]
+fn test_bip21_validation() {
+    assert!(
+        request("bitcoin:bc1qar0srrr7xfkvy5l643lydnw9re59gtzzwf5mdq", None)
+            .validate()
+            .is_ok()
+    );
+    assert!(request(
+        "BITCOIN:BC1QAR0SRRR7XFKVY5L643LYDNW9RE59GTZZWF5MDQ?amount=1",
+        Some(100_000_000)
+    )
+    .validate()
+    .is_ok());
+
+    // Amount must agree with the URI
+    assert!(request(
+        "bitcoin:bc1qar0srrr7xfkvy5l643lydnw9re59gtzzwf5mdq?amount=0.0015",
+        Some(1)
+    )
+    .validate()
+    .is_err());
+    // More than 8 decimals is not a valid BTC amount
+    assert!(request(
+        "bitcoin:bc1qar0srrr7xfkvy5l643lydnw9re59gtzzwf5mdq?amount=0.000000001",
+        None
+    )
+    .validate()
+    .is_err());
+    assert!(request("bitcoin:?amount=1", None).validate().is_err());
+    assert!(request("https://example.com/pay", None).validate().is_err());
+    // Amounts too large to count in millisatoshis are invalid, not a panic
+    assert!(request(
+        &format!(
+            "bitcoin:bc1qar0srrr7xfkvy5l643lydnw9re59gtzzwf5mdq?amount={}",
+            u128::MAX
+        ),
+        None
+    )
+    .validate()
+    .is_err());
+}
+
+#[test]
+fn test_lightning_validation() {
+    assert!(request(&invoice("lnbc"), None).validate().is_ok());
+    assert!(request(&format!("lightning:{}", invoice("lntb")), None)
+        .validate()
+        .is_ok());
+
+    // 2500u = 250,000 sat
+    assert!(request(&invoice("lnbc2500u"), Some(250_000))
+        .validate()
+        .is_ok());
+    assert!(request(&invoice("lnbc2500u"), Some(2_500))
+        .validate()
+        .is_err());
+    // Invoices may request sub-satoshi amounts (10p = 1 msat)
+    assert!(request(&invoice("lnbcrt10p"), None).validate().is_ok());
+
+    assert!(request(&invoice("lnxx"), None).validate().is_err());
+    assert!(request(&invoice("lnbc2500x"), None).validate().is_err());
+    assert!(request("lnbc1short", None).validate().is_err());
+    // Amounts too large to count in millisatoshis are invalid, not a panic
+    let huge = u128::MAX / 10;
+    assert!(request(&invoice(&format!("lnbc{}", huge)), None)
+        .validate()
+        .is_err());
+    assert!(request(&invoice(&format!("lnbc{}n", huge)), None)
+        .validate()
+        .is_err());
+    // So is a multi-byte amount multiplier
+    assert!(request(&format!("lightning:{}", invoice("lnbcé")), None)
+        .validate()
+        .is_err());
+}
+
+#[test]
+fn test_payment_proof() {
+    let preimage = "0001020304050607080900010203040506070809000102030405060708090102";
+    let proof = PaymentProof::lightning("request-1", preimage);
+    assert!(proof.validate().is_ok());
+
+    let content = MessageContent::PaymentProof(proof.clone());
+    assert_eq!(MessageContent::decode(&content.encode().unwrap()), content);
+
+    // Exactly one of preimage or txid, as 32 bytes of hex
+    let mut both = proof.clone();
+    both.txid = Some(preimage.to_string());
+    assert!(both.validate().is_err());
+    assert!(PaymentProof::on_chain("request-1", "abcd")
+        .validate()
+        .is_err());
+    assert!(PaymentProof::on_chain("../request", preimage)
+        .validate()
+        .is_err());
+
+    // A proof settles the payment request it references
+    let request = MessageContent::PaymentRequest(PaymentRequest {
+        uri: invoice("lnbc"),
+        amount_sat: None,
+        memo: None,
+    });
+    let request_message = DecryptedMessage::new(
+        "request-1".to_string(),
+        "sender".to_string(),
+        request.encode().unwrap(),
+        0,
+        true,
+    );
+    assert!(proof.settles(&request_message));
+
+    let text_message = DecryptedMessage::new(
+        "request-1".to_string(),
+        "sender".to_string(),
+        "Hello".to_string(),
+        0,
+        true,
+    );
+    assert!(!proof.settles(&text_message));
+}
+
+#[test]
+fn test_poll_validation() {
+    let poll = |question: &str, options: &[&str]| Poll {
+        question: question.to_string(),
+        options: options.iter().map(|option| option.to_string()).collect(),
+    };
+
+    let content = MessageContent::Poll(poll("Where should we eat?", &["Pizza", "Sushi"]));
+    assert!(content.validate().is_ok());
+    assert_eq!(MessageContent::decode(&content.encode().unwrap()), content);
+
+    assert!(poll("", &["Pizza", "Sushi"]).validate().is_err());
+    assert!(poll("Where?", &["Pizza"]).validate().is_err());
+    assert!(poll("Where?", &["Pizza", " Pizza "]).validate().is_err());
+    assert!(poll("Where?", &["Pizza", ""]).validate().is_err());
+
+    let too_many: Vec<String> = (0..21).map(|i| i.to_string()).collect();
+    let too_many: Vec<&str> = too_many.iter().map(|option| option.as_str()).collect();
+    assert!(poll("Pick a number", &too_many).validate().is_err());
+}
+
+#[test]
+fn test_cover_content_is_flagged() {
+    let cover = MessageContent::Cover {
+        padding: "x".repeat(32),
+    };
+    let decoded = MessageContent::decode(&cover.encode().unwrap());
+    assert!(decoded.is_cover());
+
+    // Real messages never decode as cover, even if they mention it
+    assert!(!MessageContent::decode("{\"type\":\"cover\"}").is_cover());
+    assert!(!MessageContent::text("cover").is_cover());
+}
+
+#[test]
+fn test_message_options_are_decoded() {
+    let content = r#"{"v":1,"type":"text","text":"See you","reply_to":"msg-1","expires_at":1000,"padding":"xyz"}"#;
+    let message = DecryptedMessage::new(
+        "msg-2".to_string(),
+        "sender".to_string(),
+        content.to_string(),
+        1,
+        true,
+    );
+
+    assert_eq!(message.body, MessageContent::text("See you"));
+    assert_eq!(message.reply_to.as_deref(), Some("msg-1"));
+    assert_eq!(message.expires_at, Some(1000));
+    assert!(!message.is_expired(999));
+    assert!(message.is_expired(1000));
+
+    // Plain text carries no options
+    let plain = DecryptedMessage::new(
+        "msg-3".to_string(),
+        "sender".to_string(),
+        "Hi".to_string(),
+        1,
+        true,
+    );
+    assert_eq!(plain.reply_to, None);
+    assert!(!plain.is_expired(u64::MAX));
+}
+
+#[test]
+fn test_replies_are_attributed() {
+    let reply = r#"{"v":1,"type":"text","text":"Agreed","reply_to":"msg-1"}"#;
+    let orphan = r#"{"v":1,"type":"text","text":"What?","reply_to":"missing"}"#;
+    let message = |id: &str, sender: &str, content: &str| {
+        DecryptedMessage::new(
+            id.to_string(),
+            sender.to_string(),
+            content.to_string(),
+            1,
+            true,
+        )
+    };
+    let mut messages = vec![
+        message("msg-1", "alice", "Pizza?"),
+        message("msg-2", "bob", reply),
+        message("msg-3", "bob", orphan),
+    ];
+
+    DecryptedMessage::attribute_replies(&mut messages);
+    assert_eq!(messages[0].reply_to_sender, None);
+    assert_eq!(messages[1].reply_to_sender.as_deref(), Some("alice"));
+    assert_eq!(messages[2].reply_to_sender, None);
+}
+
+#[test]
+fn test_message_metadata_is_decoded() {
+    let content = r#"{"v":1,"type":"text","text":"Hi","metadata":{"client":"my-app/1.2"}}"#;
+    let message = DecryptedMessage::new(
+        "msg-1".to_string(),
+        "sender".to_string(),
+        content.to_string(),
+        1,
+        true,
+    );
+
+    assert_eq!(message.body, MessageContent::text("Hi"));
+    assert_eq!(
+        message.metadata.get("client").map(String::as_str),
+        Some("my-app/1.2")
+    );
+}
+
+#[test]
+fn test_mentions_are_decoded() {
+    let carol = Keypair::random().public_key();
+    let dave = Keypair::random().public_key();
+    let text = format!("Ask @{} or @{}", carol, carol);
+
+    let spans = Mention::find(&text, &carol);
+    assert_eq!(spans.len(), 2);
+    assert!(spans.iter().all(|span| span.is_valid_in(&text)));
+
+    // A span that doesn't point at the reference is dropped
+    let mut mentions = spans.clone();
+    mentions.push(Mention {
+        pubky: dave.to_string(),
+        start: 0,
+        end: 3,
+    });
+    let content = serde_json::json!({
+        "v": 1,
+        "type": "text",
+        "text": text,
+        "mentions": mentions,
+    });
+
+    let message = DecryptedMessage::new(
+        "msg-1".to_string(),
+        "sender".to_string(),
+        content.to_string(),
+        1,
+        true,
+    );
+    assert_eq!(message.mentions, vec![carol.clone()]);
+    assert_eq!(message.mention_spans, spans);
+    assert!(message.is_mentioned(&carol));
+    assert!(!message.is_mentioned(&dave));
+}
+
+#[test]
+fn test_content_policy() {
+    let policy = ContentPolicy::default();
+
+    // Right-to-left override and control characters are removed
+    assert_eq!(policy.apply("invoice\u{202E}fdp.exe"), "invoicefdp.exe");
+    assert_eq!(policy.apply("a\u{0007}b\nc\td"), "ab\nc\td");
+
+    // Decomposed characters are normalized to NFC
+    assert_eq!(policy.apply("e\u{0301}"), "\u{00E9}");
+
+    // Long text is truncated on a character boundary
+    let short = ContentPolicy {
+        max_text_bytes: Some(3),
+        ..policy
+    };
+    assert_eq!(short.apply("\u{00E9}\u{00E9}"), "\u{00E9}");
+
+    let disabled = ContentPolicy::disabled();
+    assert_eq!(disabled.apply("a\u{202E}b"), "a\u{202E}b");
+}
+
+#[test]
+fn test_binary_payload_round_trip() {
+    let content = MessageContent::Binary {
+        content_type: "application/x-protobuf".to_string(),
+        data: vec![0, 1, 2, 255],
+    };
+    assert!(content.validate().is_ok());
+
+    let encoded = content.encode().unwrap();
+    assert_eq!(MessageContent::decode(&encoded), content);
+
+    let untagged = MessageContent::Binary {
+        content_type: String::new(),
+        data: vec![1],
+    };
+    assert!(untagged.validate().is_err());
+
+    let too_large = MessageContent::Binary {
+        content_type: "application/octet-stream".to_string(),
+        data: vec![0; MAX_BINARY_BYTES + 1],
+    };
+    assert!(too_large.validate().is_err());
+}
+
+#[test]
+fn test_json_payload_round_trip() {
+    let content = MessageContent::Json {
+        type_tag: "chess/move".to_string(),
+        value: serde_json::json!({ "from": "e2", "to": "e4" }),
+    };
+    assert!(content.validate().is_ok());
+    assert_eq!(MessageContent::decode(&content.encode().unwrap()), content);
+
+    let untagged = MessageContent::Json {
+        type_tag: " ".to_string(),
+        value: serde_json::Value::Null,
+    };
+    assert!(untagged.validate().is_err());
+}
+
+#[test]
+fn test_content_kinds_match_payload_types() {
+    let contents = [
+        MessageContent::text("Hi"),
+        MessageContent::Poll(Poll {
+            question: "Lunch?".to_string(),
+            options: vec!["Yes".to_string(), "No".to_string()],
+        }),
+        MessageContent::System(SystemMessage::ExpiryChanged {
+            expires_in_days: None,
+        }),
+    ];
+    for content in contents.iter().skip(1) {
+        let payload: serde_json::Value = serde_json::from_str(&content.encode().unwrap()).unwrap();
+        assert_eq!(payload["type"], content.kind());
+    }
+    assert_eq!(contents[0].kind(), "text");
+}
+
+#[test]
+fn test_system_messages() {
+    let expiry = MessageContent::System(SystemMessage::ExpiryChanged {
+        expires_in_days: Some(7),
+    });
+    assert!(expiry.validate().is_ok());
+    assert!(expiry.is_system());
+    assert_eq!(MessageContent::decode(&expiry.encode().unwrap()), expiry);
+
+    let setting = MessageContent::System(SystemMessage::SettingChanged {
+        key: "theme".to_string(),
+        value: serde_json::json!("dark"),
+    });
+    assert_eq!(MessageContent::decode(&setting.encode().unwrap()), setting);
+
+    let member = Keypair::random().public_key().to_string();
+    let membership = SystemMessage::MembershipChanged {
+        group_id: "book-club".to_string(),
+        added: vec![member],
+        removed: Vec::new(),
+    };
+    assert!(membership.validate().is_ok());
+    assert_eq!(membership.summary(), "Added 1 and removed 0 group members");
+
+    // Malformed control messages are rejected before sending
+    let never = SystemMessage::ExpiryChanged {
+        expires_in_days: Some(0),
+    };
+    assert!(never.validate().is_err());
+    let unnamed = SystemMessage::SettingChanged {
+        key: String::new(),
+        value: serde_json::Value::Null,
+    };
+    assert!(unnamed.validate().is_err());
+    let stranger = SystemMessage::MembershipChanged {
+        group_id: "book-club".to_string(),
+        added: vec!["not-a-key".to_string()],
+        removed: Vec::new(),
+    };
+    assert!(stranger.validate().is_err());
+
+    let transcript = SystemMessage::TranscriptHash(TranscriptHash {
+        up_to: 1_700_000_000,
+        records: 12,
+        hash: "ab".repeat(32),
+    });
+    assert!(transcript.validate().is_ok());
+    assert_eq!(
+        transcript.summary(),
+        "Checked 12 messages against the peer's view"
+    );
+    let encoded = MessageContent::System(transcript.clone()).encode().unwrap();
+    assert_eq!(
+        MessageContent::decode(&encoded),
+        MessageContent::System(transcript)
+    );
+    let truncated = SystemMessage::TranscriptHash(TranscriptHash {
+        up_to: 1_700_000_000,
+        records: 12,
+        hash: "ab".repeat(16),
+    });
+    assert!(truncated.validate().is_err());
+}
+
+#[test]
+fn test_edit_deltas() {
+    let old = "Meet me at the café at 5";
+    let new = "Meet me at the café at 6pm";
+    let edit = MessageEdit::between("msg-1", 1, old, new);
+    assert_eq!(
+        edit.change,
+        EditChange::Delta {
+            start: old.len() - 1,
+            delete: 1,
+            insert: "6pm".to_string(),
+        }
+    );
+    assert_eq!(edit.change.apply(old).as_deref(), Some(new));
+
+    // Deltas that do not fit the text are rejected
+    assert_eq!(edit.change.apply("Meet"), None);
+
+    // Snapshots are sent periodically and when a delta would not be smaller
+    let snapshot = MessageEdit::between("msg-1", EDIT_SNAPSHOT_INTERVAL, old, new);
+    assert_eq!(
+        snapshot.change,
+        EditChange::Snapshot {
+            text: new.to_string()
+        }
+    );
+    let rewrite = MessageEdit::between("msg-1", 1, "abc", "xyz");
+    assert!(matches!(rewrite.change, EditChange::Snapshot { .. }));
+}
+
+#[test]
+fn test_edits_are_folded() {
+    let message = |id: &str, sender: &str, content: String, timestamp: u64| {
+        DecryptedMessage::new(id.to_string(), sender.to_string(), content, timestamp, true)
+    };
+    let edit = |version: u32, old: &str, new: &str| {
+        MessageContent::Edit(MessageEdit::between("msg-1", version, old, new))
+            .encode()
+            .unwrap()
+    };
+    let original = r#"{"v":1,"type":"text","text":"Helo wrld","reply_to":"msg-0"}"#;
+
+    let mut messages = vec![
+        message("msg-1", "alice", original.to_string(), 1),
+        message("msg-2", "alice", edit(1, "Helo wrld", "Hello wrld"), 2),
+        message("msg-3", "alice", edit(2, "Hello wrld", "Hello world"), 3),
+        // Edits by anyone but the sender are ignored
+        message("msg-4", "bob", edit(3, "Hello world", "Goodbye"), 4),
+        // Edits of messages that were not fetched are handed back
+        message(
+            "msg-5",
+            "alice",
+            MessageContent::Edit(MessageEdit::between("msg-9", 1, "a", "b"))
+                .encode()
+                .unwrap(),
+            5,
+        ),
+    ];
+    let orphans = DecryptedMessage::fold_edits(&mut messages);
+
+    assert_eq!(messages.len(), 1);
+    assert_eq!(
+        messages[0].body,
+        MessageContent::Text {
+            text: "Hello world".to_string()
+        }
+    );
+    assert_eq!(messages[0].reply_to.as_deref(), Some("msg-0"));
+    assert_eq!(messages[0].edit_version, 2);
+    assert_eq!(messages[0].edited_at, Some(3));
+    assert_eq!(orphans.len(), 1);
+    assert_eq!(orphans[0].id, "msg-5");
+
+    // After a missing edit, only a snapshot moves the message on
+    let mut gapped = message("msg-1", "alice", "one".to_string(), 1);
+    let edits = [
+        message("msg-2", "alice", edit(2, "two", "three"), 2),
+        message(
+            "msg-3",
+            "alice",
+            edit(EDIT_SNAPSHOT_INTERVAL, "x", "four"),
+            3,
+        ),
+    ];
+    assert!(!gapped.apply_edits(&edits[..1]));
+    assert!(gapped.apply_edits(&edits));
+    assert_eq!(gapped.content, "four");
+    assert_eq!(gapped.edit_version, EDIT_SNAPSHOT_INTERVAL);
+
+    // An edit claiming the sender without a valid signature is ignored
+    let mut target = message("msg-1", "alice", "one".to_string(), 1);
+    let forged = DecryptedMessage::new(
+        "msg-2".to_string(),
+        "alice".to_string(),
+        edit(1, "one", "forged"),
+        2,
+        false,
+    );
+    assert!(!target.apply_edits(&[forged]));
+    assert_eq!(target.content, "one");
+    assert!(target.verified);
+}
+
+#[test]
+fn test_large_emoji_and_stickers() {
+    // Emoji-only input, including joined sequences, becomes large emoji
+    let content = MessageContent::large_emoji(" 👍🏽👨‍👩‍👧 ").unwrap();
+    assert_eq!(
+        content,
+        MessageContent::LargeEmoji {
+            emoji: "👍🏽👨‍👩‍👧".to_string()
+        }
+    );
+    assert!(content.validate().is_ok());
+    assert_eq!(MessageContent::decode(&content.encode().unwrap()), content);
+    assert!(MessageContent::large_emoji("ok 👍").is_none());
+    assert!(MessageContent::large_emoji(&"😀".repeat(30)).is_none());
+
+    let sticker = Sticker::from_pack("classic", "waving-cat");
+    assert!(MessageContent::Sticker(sticker.clone()).validate().is_ok());
+    let encoded = MessageContent::Sticker(sticker.clone()).encode().unwrap();
+    assert_eq!(
+        MessageContent::decode(&encoded),
+        MessageContent::Sticker(sticker)
+    );
+
+    let blob = |content_type: &str| Sticker {
+        source: StickerSource::Blob {
+            url: "pubky://example/pub/pubky-messenger/stickers/1".to_string(),
+            hash: "ab".repeat(32),
+            key: "cd".repeat(32),
+            content_type: content_type.to_string(),
+        },
+        emoji: Some("🐱".to_string()),
+    };
+    assert!(blob("image/webp").validate().is_ok());
+    assert!(blob("text/html").validate().is_err());
+    assert!(Sticker::from_pack("", "cat").validate().is_err());
+}
+
+#[test]
+fn test_sequence_gaps_and_reordering() {
+    let numbered = |id: &str, sender: &str, seq: u64, timestamp: u64| {
+        let content = format!(r#"{{"v":1,"type":"text","text":"{}","seq":{}}}"#, id, seq);
+        DecryptedMessage::new(id.to_string(), sender.to_string(), content, timestamp, true)
+    };
+
+    let mut messages = vec![
+        numbered("a1", "alice", 1, 10),
+        numbered("b2", "bob", 2, 11),
+        numbered("a4", "alice", 4, 12),
+        // Replayed under a new ID
+        numbered("a4-again", "alice", 4, 13),
+        // Unnumbered messages are not checked
+        DecryptedMessage::new("b".into(), "bob".into(), "Hi".into(), 14, true),
+    ];
+    assert_eq!(messages[0].seq, Some(1));
+
+    assert_eq!(messages[0].sent_with, None);
+
+    let tagged = DecryptedMessage::new(
+        "t".into(),
+        "alice".into(),
+        r#"{"v":1,"type":"text","text":"Hi","sent_with":"my-app/1.4.2"}"#.into(),
+        15,
+        true,
+    );
+    assert_eq!(tagged.sent_with.as_deref(), Some("my-app/1.4.2"));
+    assert_eq!(tagged.body, MessageContent::text("Hi"));
+
+    let mut last_seen = HashMap::new();
+    DecryptedMessage::check_sequence(&mut messages, &mut last_seen);
+    let flags: Vec<(u64, bool)> = messages
+        .iter()
+        .map(|message| (message.missing_before, message.out_of_order))
+        .collect();
+    assert_eq!(
+        flags,
+        [(0, false), (1, false), (2, false), (0, true), (0, false)]
+    );
+    assert_eq!(last_seen["alice"], 4);
+    assert_eq!(last_seen["bob"], 2);
+
+    // A later batch continues from the numbers seen so far
+    let mut later = vec![numbered("a5", "alice", 5, 20), numbered("b2", "bob", 2, 21)];
+    DecryptedMessage::check_sequence(&mut later, &mut last_seen);
+    assert_eq!(later[0].missing_before, 0);
+    assert!(!later[0].out_of_order);
+    assert!(later[1].out_of_order);
+
+    // A forged number can't cover up a gap
+    let content = r#"{"v":1,"type":"text","text":"a6","seq":6}"#.to_string();
+    let forged = DecryptedMessage::new("a6".into(), "alice".into(), content, 22, false);
+    let mut latest = vec![forged, numbered("a7", "alice", 7, 23)];
+    DecryptedMessage::check_sequence(&mut latest, &mut last_seen);
+    assert_eq!(latest[0].missing_before, 0);
+    assert_eq!(latest[1].missing_before, 1);
+    assert_eq!(last_seen["alice"], 7);
+}
+
+#[test]
+fn test_command_parsing_and_rendering() {
+    let command =
+        Command::parse(r#"/remind 10 "buy milk" 2.5 true note="at 5" urgent=false"#).unwrap();
+    assert_eq!(command.name, "remind");
+    assert_eq!(
+        command.args,
+        [
+            serde_json::json!(10),
+            serde_json::json!("buy milk"),
+            serde_json::json!(2.5),
+            serde_json::json!(true),
+        ]
+    );
+    assert_eq!(command.get::<u32>(0).unwrap(), 10);
+    assert_eq!(command.get::<String>(1).unwrap(), "buy milk");
+    assert!(command.get::<String>(0).is_err());
+    assert!(command.get::<u32>(4).is_err());
+    assert_eq!(
+        command.get_option::<String>("note").unwrap().as_deref(),
+        Some("at 5")
+    );
+    assert_eq!(command.get_option::<bool>("urgent").unwrap(), Some(false));
+    assert_eq!(command.get_option::<bool>("missing").unwrap(), None);
+
+    // Rendered text parses back to the same command
+    let built = Command::new("deploy")
+        .arg("42")
+        .arg(7)
+        .arg("a=b")
+        .arg(r#"say "hi""#)
+        .option("env", "prod eu");
+    assert_eq!(
+        built.to_text(),
+        r#"/deploy "42" 7 "a=b" "say \"hi\"" env="prod eu""#
+    );
+    assert_eq!(Command::parse(&built.to_text()), Some(built.clone()));
+
+    let content = MessageContent::Command(built);
+    assert!(content.validate().is_ok());
+    assert_eq!(content.kind(), "command");
+    assert_eq!(MessageContent::decode(&content.encode().unwrap()), content);
+
+    // Not commands
+    assert_eq!(Command::parse("remind me"), None);
+    assert_eq!(Command::parse("/"), None);
+    assert_eq!(Command::parse("/héllo"), None);
+    assert_eq!(Command::parse(r#"/say "unterminated"#), None);
+    assert!(Command::new("two words").validate().is_err());
+}
+
+#[test]
+fn test_conversation_snapshot_diff() {
+    let message = |id: &str, text: &str| {
+        DecryptedMessage::new(id.to_string(), "alice".into(), text.to_string(), 10, true)
+    };
+    let before = vec![
+        message("a", "one"),
+        message("b", "two"),
+        message("c", "three"),
+    ];
+    let snapshot = ConversationSnapshot::from_messages("peer", &before, 100);
+    assert!(snapshot.verify());
+    assert!(snapshot.diff_messages(&before).is_empty());
+
+    // The root depends on content, not on fetch order
+    let mut reordered = before.clone();
+    reordered.reverse();
+    assert_eq!(
+        ConversationSnapshot::from_messages("peer", &reordered, 100).root,
+        snapshot.root
+    );
+
+    let after = vec![
+        message("a", "one"),
+        message("c", "THREE"),
+        message("d", "four"),
+    ];
+    let diff = snapshot.diff_messages(&after);
+    assert_eq!(diff.removed, ["b"]);
+    assert_eq!(diff.edited.len(), 1);
+    assert_eq!(diff.edited[0].id, "c");
+    assert_eq!(diff.added.len(), 1);
+    assert_eq!(diff.added[0].id, "d");
+
+    // A snapshot altered after it was taken no longer verifies
+    let mut tampered = snapshot.clone();
+    tampered.messages.remove("b");
+    assert!(!tampered.verify());
+}
+
+#[test]
+fn test_language_tags() {
+    for tag in [
+        "en",
+        "pt-BR",
+        "zh-Hant-TW",
+        "sr-Latn",
+        "es-419",
+        "x-klingon",
+    ] {
+        assert!(is_language_tag(tag), "{tag}");
+    }
+    for tag in [
+        "",
+        "e",
+        "en_US",
+        "en-",
+        "en--US",
+        "toolongtag",
+        "en-US-abcdefghi",
+        "日本",
+    ] {
+        assert!(!is_language_tag(tag), "{tag}");
+    }
+
+    let tagged = |language: &str| {
+        DecryptedMessage::new(
+            "t".into(),
+            "alice".into(),
+            format!(
+                r#"{{"v":1,"type":"text","text":"Olá","language":"{}"}}"#,
+                language
+            ),
+            1,
+            true,
+        )
+    };
+    assert_eq!(tagged("pt-BR").language.as_deref(), Some("pt-BR"));
+    assert_eq!(tagged("pt-BR").body, MessageContent::text("Olá"));
+
+    // Malformed tags are dropped rather than passed to the application
+    assert_eq!(tagged("not a tag").language, None);
+}
@@ -0,0 +1,56 @@
+#![cfg(feature = "prometheus")]
+
+use pubky_messenger::{Keypair, MetricsCollector, PrivateMessengerClient};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+fn temp_store_path() -> PathBuf {
+    std::env::temp_dir().join(format!("pubky-messenger-test-{}.db", uuid::Uuid::new_v4()))
+}
+
+#[test]
+fn test_metrics_collector_exports_client_metrics() {
+    let client = Arc::new(
+        PrivateMessengerClient::new(Keypair::random())
+            .unwrap()
+            .with_local_store(temp_store_path())
+            .unwrap(),
+    );
+
+    let registry = prometheus::Registry::new();
+    registry
+        .register(Box::new(MetricsCollector::new(client).unwrap()))
+        .unwrap();
+
+    let families = registry.gather();
+    let names: Vec<&str> = families.iter().map(|family| family.get_name()).collect();
+    for name in [
+        "pubky_messenger_sent_bytes_total",
+        "pubky_messenger_received_bytes_total",
+        "pubky_messenger_queued_events",
+        "pubky_messenger_pending_message_requests",
+        "pubky_messenger_sync_lag_seconds",
+    ] {
+        assert!(names.contains(&name), "missing {name}");
+    }
+
+    let queued = families
+        .iter()
+        .find(|family| family.get_name() == "pubky_messenger_queued_events")
+        .unwrap();
+    assert_eq!(queued.get_metric()[0].get_gauge().get_value(), 0.0);
+}
+
+#[test]
+fn test_metrics_collector_registers_once() {
+    let client = Arc::new(PrivateMessengerClient::new(Keypair::random()).unwrap());
+    let registry = prometheus::Registry::new();
+    registry
+        .register(Box::new(MetricsCollector::new(client.clone()).unwrap()))
+        .unwrap();
+
+    // A second collector would export the same names
+    assert!(registry
+        .register(Box::new(MetricsCollector::new(client).unwrap()))
+        .is_err());
+}
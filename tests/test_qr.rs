@@ -0,0 +1,28 @@
+#![cfg(feature = "qr")]
+
+use pubky_messenger::{IdentityBundle, Keypair};
+
+#[test]
+fn test_qr_invite_round_trip() {
+    let pubky = Keypair::random().public_key();
+    let bundle = IdentityBundle::new(&pubky, Some("Alice".to_string()));
+
+    let (width, pixels) = bundle.to_qr_pixels(4).unwrap();
+    assert_eq!(pixels.len(), width * width);
+
+    let decoded = IdentityBundle::from_qr_pixels(width, width, &pixels).unwrap();
+    assert_eq!(decoded, bundle);
+
+    assert!(bundle.to_qr_svg().unwrap().starts_with("<?xml"));
+    assert!(!bundle.to_qr_text().unwrap().is_empty());
+}
+
+#[test]
+fn test_qr_decoding_rejects_blank_images() {
+    let pixels = vec![255u8; 64 * 64];
+    assert!(IdentityBundle::from_qr_pixels(64, 64, &pixels).is_err());
+    assert!(IdentityBundle::from_qr_pixels(64, 64, &pixels[1..]).is_err());
+
+    let bundle = IdentityBundle::new(&Keypair::random().public_key(), None);
+    assert!(bundle.to_qr_pixels(0).is_err());
+}
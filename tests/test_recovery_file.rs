@@ -0,0 +1,92 @@
+use anyhow::Result;
+use pkarr::Keypair;
+use pubky_messenger::{PrivateMessengerClient, RecoveryKdfParams, MAX_RECOVERY_MEMORY_KIB};
+
+// Cheap parameters to keep the tests fast
+fn test_params() -> RecoveryKdfParams {
+    RecoveryKdfParams {
+        memory_kib: 1024,
+        iterations: 1,
+        parallelism: 1,
+    }
+}
+
+#[test]
+fn test_export_recovery_file_roundtrip() -> Result<()> {
+    let client = PrivateMessengerClient::new(Keypair::random())?;
+
+    let recovery_file = client.export_recovery_file(Some("password"))?;
+    let restored = PrivateMessengerClient::from_recovery_file(&recovery_file, Some("password"))?;
+
+    assert_eq!(client.public_key_string(), restored.public_key_string());
+    Ok(())
+}
+
+#[test]
+fn test_export_recovery_file_with_params_roundtrip() -> Result<()> {
+    let client = PrivateMessengerClient::new(Keypair::random())?;
+
+    let recovery_file =
+        client.export_recovery_file_with_params(Some("password"), &test_params())?;
+    let restored = PrivateMessengerClient::from_recovery_file(&recovery_file, Some("password"))?;
+    assert_eq!(client.public_key_string(), restored.public_key_string());
+
+    // A wrong passphrase is rejected
+    assert!(PrivateMessengerClient::from_recovery_file(&recovery_file, Some("wrong")).is_err());
+
+    // Two exports use different salts
+    let other_file = client.export_recovery_file_with_params(Some("password"), &test_params())?;
+    assert_ne!(recovery_file, other_file);
+
+    Ok(())
+}
+
+#[test]
+fn test_export_recovery_file_invalid_params() -> Result<()> {
+    let client = PrivateMessengerClient::new(Keypair::random())?;
+
+    let params = RecoveryKdfParams {
+        memory_kib: 1,
+        iterations: 0,
+        parallelism: 0,
+    };
+    assert!(client
+        .export_recovery_file_with_params(Some("password"), &params)
+        .is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_recovery_file_params_are_capped() -> Result<()> {
+    let client = PrivateMessengerClient::new(Keypair::random())?;
+
+    let params = RecoveryKdfParams {
+        memory_kib: MAX_RECOVERY_MEMORY_KIB + 1,
+        ..test_params()
+    };
+    assert!(client
+        .export_recovery_file_with_params(Some("password"), &params)
+        .is_err());
+
+    // A crafted header is rejected before any key is derived
+    let recovery_file =
+        client.export_recovery_file_with_params(Some("password"), &test_params())?;
+    let header_end = recovery_file.iter().position(|&b| b == b'\n').unwrap();
+    let header = std::str::from_utf8(&recovery_file[..header_end])?;
+    for (from, to) in [
+        ("m=1024,", "m=4294967295,"),
+        (",t=1,", ",t=4294967295,"),
+        (",p=1 ", ",p=255 "),
+    ] {
+        assert!(header.contains(from));
+        let mut crafted = header.replacen(from, to, 1).into_bytes();
+        crafted.extend_from_slice(&recovery_file[header_end..]);
+        let error = PrivateMessengerClient::from_recovery_file(&crafted, Some("password"))
+            .err()
+            .expect("the parameters are over the limits");
+        assert!(error.to_string().contains("limits"));
+    }
+
+    Ok(())
+}
@@ -0,0 +1,62 @@
+#![cfg(feature = "webhook")]
+
+use pubky_messenger::{
+    webhook_signature, Keypair, NotificationEvent, PrivateMessengerClient, WebhookEvent,
+    WebhookRelay,
+};
+use std::sync::Arc;
+
+#[test]
+fn test_webhook_signature() {
+    assert_eq!(
+        webhook_signature(b"secret", "1700000000", b"{}"),
+        "sha256=b8569b78799ff9e3cbff0fc2d63a33a2b57f3282abd07c37ae5e8e7d79a5f163"
+    );
+    assert_ne!(
+        webhook_signature(b"secret", "1700000001", b"{}"),
+        webhook_signature(b"secret", "1700000000", b"{}")
+    );
+}
+
+#[test]
+fn test_webhook_event_json() {
+    let event = WebhookEvent::Notification {
+        event: NotificationEvent::MessageDeleted {
+            peer: Keypair::random().public_key().to_string(),
+            message_id: "m1".to_string(),
+        },
+    };
+    let json = serde_json::to_value(&event).unwrap();
+    assert_eq!(json["type"], "notification");
+    assert_eq!(json["event"]["type"], "message_deleted");
+    assert_eq!(json["event"]["message_id"], "m1");
+}
+
+#[tokio::test]
+async fn test_webhook_relay_requires_safe_urls() {
+    let client = Arc::new(PrivateMessengerClient::new(Keypair::random()).unwrap());
+
+    for url in [
+        "https://hooks.example.com/pubky",
+        "http://127.0.0.1:8080/pubky",
+        "http://[::1]:8080/pubky",
+        "http://localhost/pubky",
+    ] {
+        assert!(
+            WebhookRelay::new(client.clone(), url, b"secret").is_ok(),
+            "{url}"
+        );
+    }
+
+    for url in [
+        "http://hooks.example.com/pubky",
+        "ftp://127.0.0.1/",
+        "not a url",
+    ] {
+        assert!(
+            WebhookRelay::new(client.clone(), url, b"secret").is_err(),
+            "{url}"
+        );
+    }
+    assert!(WebhookRelay::new(client, "https://hooks.example.com/", b"").is_err());
+}